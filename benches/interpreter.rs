@@ -0,0 +1,112 @@
+// Runs every .fsh script in this folder through the interpreter binary and reports the timings.
+// Use `cargo bench` to run all of them, or `cargo bench -- <name>` to only run matching scripts.
+//
+// `cargo bench --bench interpreter -- --baseline <fish binary>` runs the scripts with another
+// build of fish as well, taking turns, and reports how much faster this one is. To compare two
+// commits, build the older one and run this file in a worktree of the newer one, so the scripts
+// are written in the syntax of the time. For the commit that added the benchmarks, against the one
+// before it:
+//
+//   added=$(git log --diff-filter=A --format=%h -- benches/interpreter.rs)
+//   git worktree add ../fish-before "$added^"
+//   git worktree add ../fish-after "$added"
+//   cargo build --release --manifest-path ../fish-before/Cargo.toml
+//   cp benches/interpreter.rs ../fish-after/benches/
+//   cd ../fish-after
+//   cargo bench --bench interpreter -- --baseline ../fish-before/target/release/fish-lang
+use std::{
+  env, fs,
+  path::Path,
+  process::{Command, Stdio},
+  time::{Duration, Instant},
+};
+
+const RUNS: u32 = 5;
+
+fn main() {
+  let mut filter = None;
+  let mut baseline = None;
+  // cargo passes --bench as well
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--baseline" => baseline = Some(args.next().expect("--baseline needs a fish binary")),
+      _ if arg.starts_with('-') => (),
+      _ => filter = Some(arg),
+    }
+  }
+  let binary = env!("CARGO_BIN_EXE_fish-lang");
+  let folder = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+
+  let mut scripts: Vec<_> = fs::read_dir(&folder)
+    .expect("Could not read the benches folder")
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| path.extension().is_some_and(|extension| extension == "fsh"))
+    .collect();
+  scripts.sort();
+
+  print!("{:<20} {:>12} {:>12}", "script", "min", "mean");
+  if baseline.is_some() {
+    print!(" {:>12} {:>12} {:>8}", "baseline min", "mean", "speedup");
+  }
+  println!();
+  for script in scripts {
+    let name = script.file_stem().unwrap().to_string_lossy().to_string();
+    if filter
+      .as_ref()
+      .is_some_and(|filter| !name.contains(filter.as_str()))
+    {
+      continue;
+    }
+    let mut timings = Vec::new();
+    let mut baseline_timings = Vec::new();
+    for _ in 0..RUNS {
+      timings.push(time(binary, &script));
+      if let Some(baseline) = &baseline {
+        baseline_timings.push(time(baseline, &script));
+      }
+    }
+    let (min, mean) = summary(&timings);
+    print!("{:<20} {:>12.2?} {:>12.2?}", name, min, mean);
+    if baseline.is_some() {
+      let (baseline_min, baseline_mean) = summary(&baseline_timings);
+      let speedup = baseline_min.as_secs_f64() / min.as_secs_f64();
+      print!(
+        " {:>12.2?} {:>12.2?} {:>7.2}x",
+        baseline_min, baseline_mean, speedup
+      );
+    }
+    println!();
+  }
+}
+
+fn time(binary: &str, script: &Path) -> Duration {
+  let start = Instant::now();
+  let output = Command::new(binary)
+    .arg(script)
+    .stdin(Stdio::null())
+    .output()
+    .unwrap_or_else(|error| panic!("Could not run {}: {}", binary, error));
+  let elapsed = start.elapsed();
+  // Older builds print their errors to stdout and exit with 0
+  let printed = format!(
+    "{}{}",
+    String::from_utf8_lossy(&output.stdout),
+    String::from_utf8_lossy(&output.stderr)
+  );
+  let error = printed.lines().find(|line| line.starts_with("Error"));
+  assert!(
+    output.status.success() && error.is_none(),
+    "{} failed with {}: {}",
+    script.display(),
+    binary,
+    error.unwrap_or_default()
+  );
+  elapsed
+}
+
+// The min and the mean
+fn summary(timings: &[Duration]) -> (Duration, Duration) {
+  let min = *timings.iter().min().unwrap();
+  (min, timings.iter().sum::<Duration>() / timings.len() as u32)
+}
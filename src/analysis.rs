@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+
+use crate::{
+  parser::{
+    Function, Identifier, Instruction, InstructionKind, Pattern, TypeAnnotation, Value, Variant,
+  },
+  tokenizer::{Operator, Position, Span},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+  Variable,
+  Parameter,
+  Function,
+  Enum,
+  Variant,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+  pub name: String,
+  pub kind: SymbolKind,
+  // The name where it is declared
+  pub span: Span,
+  // The whole declaration, a function or enum with its body
+  pub extent: Span,
+  // Where a variable can be used, from its declaration to the end of the block it is in
+  pub scope: Span,
+  // What it looks like where it is declared, like the signature of a function, empty for variables
+  pub detail: String,
+  // The enum of a variant
+  pub parent: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Reference {
+  pub span: Span,
+  pub symbol: usize,
+}
+
+/*
+ Where every variable, parameter, function, enum and variant in a script is declared and where
+ each of them is used, for editors. Variables are tracked the same way the resolver does it, so
+ every use is tied to the declaration the interpreter would use for it. Functions, enums and
+ variants can be used anywhere, also before their definition.
+*/
+#[derive(Debug, Default)]
+pub struct Analysis {
+  pub symbols: Vec<Symbol>,
+  pub references: Vec<Reference>,
+}
+
+// A variable a pass over the instructions keeps in the blocks it is in
+pub trait Named {
+  fn name(&self) -> &str;
+}
+
+// The variable a name refers to, blocks are kept the way the resolver keeps them so it is the last
+// one declared with that name in the innermost block that has one
+pub fn lookup<'a, V: Named>(scopes: &'a mut [Vec<V>], name: &str) -> Option<&'a mut V> {
+  scopes
+    .iter_mut()
+    .rev()
+    .find_map(|scope| scope.iter_mut().rfind(|variable| variable.name() == name))
+}
+
+pub fn analyze(instructions: &[Instruction]) -> Analysis {
+  let mut analyzer = Analyzer {
+    analysis: Analysis::default(),
+    scopes: Vec::new(),
+    globals: HashMap::new(),
+  };
+  analyzer.collect_globals(instructions);
+  analyzer.block(instructions, Span::default(), true);
+  analyzer.analysis
+}
+
+impl Analysis {
+  // The symbol declared or used at an offset, the end of a name still counts as on it
+  pub fn symbol_at(&self, offset: usize) -> Option<usize> {
+    let declaration = self
+      .symbols
+      .iter()
+      .position(|symbol| contains(symbol.span, offset));
+    declaration.or_else(|| {
+      self
+        .references
+        .iter()
+        .find(|reference| contains(reference.span, offset))
+        .map(|reference| reference.symbol)
+    })
+  }
+
+  pub fn references_to(&self, symbol: usize) -> impl Iterator<Item = Span> + '_ {
+    self
+      .references
+      .iter()
+      .filter(move |reference| reference.symbol == symbol)
+      .map(|reference| reference.span)
+  }
+
+  // Everything that can be used by name at an offset
+  pub fn visible_at(&self, offset: usize) -> impl Iterator<Item = &Symbol> {
+    self.symbols.iter().filter(move |symbol| match symbol.kind {
+      SymbolKind::Variable | SymbolKind::Parameter => {
+        contains(symbol.scope, offset)
+          && symbol.span.start.offset <= offset
+          && !self.symbols.iter().any(|function| {
+            // Function bodies do not see the variables around them
+            function.kind == SymbolKind::Function
+              && contains(function.extent, offset)
+              && !contains(function.extent, symbol.span.start.offset)
+          })
+      }
+      SymbolKind::Function | SymbolKind::Enum | SymbolKind::Variant => true,
+    })
+  }
+}
+
+fn contains(span: Span, offset: usize) -> bool {
+  span.start.offset <= offset && offset <= span.end.offset
+}
+
+struct Analyzer {
+  analysis: Analysis,
+  // The variables of every block around the current one
+  scopes: Vec<Vec<usize>>,
+  // Functions, enums and variants by name
+  globals: HashMap<String, usize>,
+}
+
+impl Analyzer {
+  fn add(&mut self, symbol: Symbol) -> usize {
+    self.analysis.symbols.push(symbol);
+    self.analysis.symbols.len() - 1
+  }
+
+  fn reference(&mut self, span: Span, symbol: usize) {
+    self.analysis.references.push(Reference { span, symbol });
+  }
+
+  fn collect_globals(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      match &instruction.kind {
+        InstructionKind::Function(function) => {
+          let symbol = self.add(Symbol {
+            name: function.name.clone(),
+            kind: SymbolKind::Function,
+            span: function.name_span,
+            extent: instruction.span,
+            scope: Span::default(),
+            detail: signature(function),
+            parent: None,
+          });
+          self.globals.entry(function.name.clone()).or_insert(symbol);
+          self.collect_globals(&function.instructions);
+        }
+        InstructionKind::Enum {
+          name,
+          name_span,
+          variants,
+        } => {
+          let parent = self.add(Symbol {
+            name: name.clone(),
+            kind: SymbolKind::Enum,
+            span: *name_span,
+            extent: instruction.span,
+            scope: Span::default(),
+            detail: format!("enum {}", name),
+            parent: None,
+          });
+          self.globals.entry(name.clone()).or_insert(parent);
+          for variant in variants {
+            let symbol = self.add(Symbol {
+              name: variant.name.clone(),
+              kind: SymbolKind::Variant,
+              span: variant.span,
+              extent: variant.span,
+              scope: Span::default(),
+              detail: format!("{}.{}", name, variant_detail(variant)),
+              parent: Some(parent),
+            });
+            self.globals.entry(variant.name.clone()).or_insert(symbol);
+          }
+        }
+        InstructionKind::If { instructions, .. }
+        | InstructionKind::Else { instructions }
+        | InstructionKind::While { instructions, .. }
+        | InstructionKind::Scope { instructions }
+        | InstructionKind::Test { instructions, .. } => self.collect_globals(instructions),
+        InstructionKind::Match { arms, .. } => {
+          for arm in arms {
+            self.collect_globals(&arm.instructions);
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+
+  // `extent` is the instruction the block belongs to, variables declared in it are visible up to
+  // its end. At the top level they are visible up to the end of the file
+  fn block(&mut self, instructions: &[Instruction], extent: Span, top_level: bool) {
+    self.scopes.push(Vec::new());
+    let extent = match top_level {
+      true => Span {
+        start: Position::default(),
+        end: Position {
+          offset: usize::MAX,
+          ..Position::default()
+        },
+      },
+      false => extent,
+    };
+    self.instructions(instructions, extent);
+    self.scopes.pop();
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction], extent: Span) {
+    for instruction in instructions {
+      self.instruction(instruction, extent);
+    }
+  }
+
+  fn instruction(&mut self, instruction: &Instruction, extent: Span) {
+    match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      }
+      | InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        self.value(condition, extent);
+        self.block(instructions, instruction.span, false);
+      }
+      InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+        self.block(instructions, instruction.span, false);
+      }
+      InstructionKind::Value { value } | InstructionKind::Print { message: value } => {
+        self.value(value, extent);
+      }
+      InstructionKind::Input { variable } => self.assignment(variable, extent),
+      InstructionKind::Match { value, arms } => {
+        self.value(value, extent);
+        for arm in arms {
+          self.scopes.push(Vec::new());
+          self.pattern(&arm.pattern, instruction.span);
+          if let Some(guard) = &arm.guard {
+            self.value(guard, instruction.span);
+          }
+          self.instructions(&arm.instructions, instruction.span);
+          self.scopes.pop();
+        }
+      }
+      InstructionKind::Let {
+        variable,
+        annotation,
+        value,
+      } => {
+        self.annotation(annotation.as_ref());
+        self.value(value, extent);
+        self.declare(variable, SymbolKind::Variable, extent);
+      }
+      InstructionKind::Function(function) => {
+        for parameter in &function.parameters {
+          self.annotation(parameter.annotation.as_ref());
+        }
+        self.annotation(function.return_type.as_ref());
+        let outer = std::mem::take(&mut self.scopes);
+        self.scopes.push(Vec::new());
+        for parameter in &function.parameters {
+          self.declare(&parameter.variable, SymbolKind::Parameter, instruction.span);
+        }
+        self.instructions(&function.instructions, instruction.span);
+        self.scopes = outer;
+      }
+      InstructionKind::Return { value } => {
+        if let Some(value) = value {
+          self.value(value, extent);
+        }
+      }
+      InstructionKind::Assert { condition, message } => {
+        self.value(condition, extent);
+        if let Some(message) = message {
+          self.value(message, extent);
+        }
+      }
+      InstructionKind::Test { instructions, .. } => {
+        let outer = std::mem::take(&mut self.scopes);
+        self.block(instructions, instruction.span, false);
+        self.scopes = outer;
+      }
+      InstructionKind::Break | InstructionKind::Enum { .. } => (),
+    }
+  }
+
+  fn pattern(&mut self, pattern: &Pattern, extent: Span) {
+    match pattern {
+      Pattern::Identifier(identifier) => match self.global(&identifier.name, SymbolKind::Variant) {
+        Some(variant) => self.reference(identifier.span, variant),
+        None => self.declare(identifier, SymbolKind::Variable, extent),
+      },
+      Pattern::Variant { name, span, fields } => {
+        if let Some(variant) = self.global(name, SymbolKind::Variant) {
+          self.reference(*span, variant);
+        }
+        for field in fields {
+          self.pattern(field, extent);
+        }
+      }
+      Pattern::Wildcard | Pattern::Literal(_) => (),
+    }
+  }
+
+  fn annotation(&mut self, annotation: Option<&TypeAnnotation>) {
+    if let Some(annotation) = annotation {
+      if let Some(symbol) = self.global(&annotation.name, SymbolKind::Enum) {
+        self.reference(annotation.span, symbol);
+      }
+    }
+  }
+
+  fn value(&mut self, value: &Value, extent: Span) {
+    match value {
+      Value::Identifier(identifier) => {
+        if let Some(variable) = self.lookup(&identifier.name) {
+          self.reference(identifier.span, variable);
+        } else if let Some(variant) = self.global(&identifier.name, SymbolKind::Variant) {
+          self.reference(identifier.span, variant);
+        }
+      }
+      Value::Expression(expression) => {
+        if let Some(right) = expression.get_right() {
+          self.value(right, extent);
+        }
+        match (expression.get_operator(), expression.get_left()) {
+          (Operator::Assign, Value::Identifier(identifier)) => self.assignment(identifier, extent),
+          (_, left) => self.value(left, extent),
+        }
+      }
+      Value::Call {
+        name,
+        span,
+        arguments,
+      } => {
+        let callee = self
+          .global(name, SymbolKind::Function)
+          .or_else(|| self.global(name, SymbolKind::Variant));
+        if let Some(callee) = callee {
+          self.reference(*span, callee);
+        }
+        for argument in arguments {
+          self.value(argument, extent);
+        }
+      }
+      Value::List(items) => {
+        for item in items {
+          self.value(item, extent);
+        }
+      }
+      Value::Field { value, .. } => self.value(value, extent),
+      Value::Index { value, index, .. } => {
+        self.value(value, extent);
+        self.value(index, extent);
+      }
+      Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None => (),
+    }
+  }
+
+  fn assignment(&mut self, identifier: &Identifier, extent: Span) {
+    match self.lookup(&identifier.name) {
+      Some(variable) => self.reference(identifier.span, variable),
+      None => self.declare(identifier, SymbolKind::Variable, extent),
+    }
+  }
+
+  fn global(&self, name: &str, kind: SymbolKind) -> Option<usize> {
+    let symbol = *self.globals.get(name)?;
+    (self.analysis.symbols[symbol].kind == kind).then_some(symbol)
+  }
+
+  fn lookup(&self, name: &str) -> Option<usize> {
+    self.scopes.iter().rev().find_map(|scope| {
+      scope
+        .iter()
+        .rev()
+        .find(|&&symbol| self.analysis.symbols[symbol].name == name)
+        .copied()
+    })
+  }
+
+  fn declare(&mut self, identifier: &Identifier, kind: SymbolKind, extent: Span) {
+    let symbol = self.add(Symbol {
+      name: identifier.name.clone(),
+      kind,
+      span: identifier.span,
+      extent: identifier.span,
+      scope: Span {
+        start: identifier.span.start,
+        end: extent.end,
+      },
+      detail: String::new(),
+      parent: None,
+    });
+    let scope = self.scopes.last_mut().expect("No scope to declare in");
+    scope.push(symbol);
+  }
+}
+
+// fn name(a: int, b) -> bool
+fn signature(function: &Function) -> String {
+  let parameters: Vec<String> = function
+    .parameters
+    .iter()
+    .map(|parameter| match &parameter.annotation {
+      Some(annotation) => format!("{}: {}", parameter.variable.name, annotation.name),
+      None => parameter.variable.name.clone(),
+    })
+    .collect();
+  let mut signature = format!("fn {}({})", function.name, parameters.join(", "));
+  if let Some(return_type) = &function.return_type {
+    signature.push_str(&format!(" -> {}", return_type.name));
+  }
+  signature
+}
+
+fn variant_detail(variant: &Variant) -> String {
+  match variant.fields.is_empty() {
+    true => variant.name.clone(),
+    false => format!("{}({})", variant.name, variant.fields.join(", ")),
+  }
+}
@@ -0,0 +1,993 @@
+use std::{collections::HashMap, fmt::Write, mem};
+
+use crate::{
+  limits::DEFAULT_MAX_CALLS,
+  number::Number,
+  parser::{
+    Expression, Function, Identifier, Instruction, InstructionKind, MatchArm, Pattern, Value,
+  },
+  resolver::Slot,
+  tokenizer::Operator,
+};
+
+const RUNTIME: &str = include_str!("c_runtime.c");
+
+/*
+ Turns a script into a C99 program that does what the interpreter would do when running it, for
+ places where a native executable is wanted. It only needs the C standard library, `cc -O2
+ script.c -lm` builds it. The instructions have to be resolved first.
+
+ Every block becomes a C block with an array for the variables in its frame, which are released
+ when it ends. Values are computed into temporaries one operation at a time, so everything runs in
+ the same order as in the interpreter and fails the same way. Functions, enums and the variants
+ a call or name refers to are looked up while running, since that is when they get defined.
+
+ The limits of the interpreter are not checked, apart from the one on nested calls, which every
+ script runs with.
+*/
+pub fn transpile(instructions: &[Instruction]) -> String {
+  let mut generator = Generator::default();
+  generator.collect_names(instructions);
+  generator.body.indent = 1;
+  generator.block("", instructions);
+  let script = mem::take(&mut generator.body.code);
+  generator.output(&script)
+}
+
+// The code of the C function being generated
+#[derive(Default)]
+struct Body {
+  code: String,
+  indent: usize,
+  temporaries: usize,
+  frames: Vec<Frame>,
+  // What a break or return has to release on its way out, innermost last
+  cleanups: Vec<Cleanup>,
+  // Whether this is a fish function rather than the top of the script
+  function: bool,
+}
+
+struct Frame {
+  id: usize,
+  // How many variables the resolver put in it, known once all of its code was generated
+  size: usize,
+}
+
+enum Cleanup {
+  Frame(usize),
+  Value(String),
+  // A while loop, a break jumps to the label after it
+  Loop { label: String, used: bool },
+}
+
+#[derive(Default)]
+struct Generator {
+  body: Body,
+  strings: Vec<String>,
+  string_ids: HashMap<String, usize>,
+  // Every variant and function name of the script, a name refers to the same one everywhere
+  variants: Vec<String>,
+  variant_ids: HashMap<String, usize>,
+  functions: Vec<String>,
+  function_ids: HashMap<String, usize>,
+  // Names that are called, every one gets a function that finds out what it refers to
+  calls: Vec<String>,
+  call_ids: HashMap<String, usize>,
+  field_lists: Vec<Vec<String>>,
+  // The C functions of fish functions
+  definitions: Vec<String>,
+  frames: usize,
+  labels: usize,
+}
+
+// The index of the name in the list, adding it if it is not there yet
+fn intern(names: &mut Vec<String>, ids: &mut HashMap<String, usize>, name: &str) -> usize {
+  if let Some(id) = ids.get(name) {
+    return *id;
+  }
+  names.push(name.to_string());
+  ids.insert(name.to_string(), names.len() - 1);
+  names.len() - 1
+}
+
+// Anything that is not plain ASCII is escaped, including ? which could start a trigraph
+fn c_string(text: &str) -> String {
+  let mut literal = String::from("\"");
+  for byte in text.bytes() {
+    match byte {
+      b'"' | b'\\' | b'?' => {
+        literal.push('\\');
+        literal.push(byte as char);
+      }
+      b' '..=b'~' => literal.push(byte as char),
+      _ => {
+        let _ = write!(literal, "\\{:03o}", byte);
+      }
+    }
+  }
+  literal.push('"');
+  literal
+}
+
+fn c_number(number: Number) -> String {
+  match number {
+    Number::Integer(i64::MIN) => "fish_integer(INT64_MIN)".to_string(),
+    Number::Integer(integer) => format!("fish_integer(INT64_C({}))", integer),
+    Number::Float(float) if float.is_nan() => "fish_float(NAN)".to_string(),
+    Number::Float(float) if float.is_infinite() && float > 0.0 => {
+      "fish_float(HUGE_VAL)".to_string()
+    }
+    Number::Float(float) if float.is_infinite() => "fish_float(-HUGE_VAL)".to_string(),
+    Number::Float(float) => format!("fish_float({:?})", float),
+  }
+}
+
+fn comparison(operator: Operator) -> Option<&'static str> {
+  let constant = match operator {
+    Operator::Equal => "FISH_EQUAL",
+    Operator::NotEqual => "FISH_NOT_EQUAL",
+    Operator::LessThan => "FISH_LESS",
+    Operator::LessThanOrEqual => "FISH_LESS_EQUAL",
+    Operator::GreaterThan => "FISH_GREATER",
+    Operator::GreaterThanOrEqual => "FISH_GREATER_EQUAL",
+    _ => return None,
+  };
+  Some(constant)
+}
+
+fn arithmetic(operator: Operator) -> Option<&'static str> {
+  let function = match operator {
+    Operator::Add | Operator::AddAssign => "fish_add",
+    Operator::Subtract | Operator::SubtractAssign => "fish_subtract",
+    Operator::Multiply | Operator::MultiplyAssign => "fish_multiply",
+    Operator::Divide | Operator::DivideAssign => "fish_divide",
+    Operator::Modulo | Operator::ModuloAssign => "fish_modulo",
+    Operator::Exponent => "fish_power",
+    Operator::And => "fish_and",
+    Operator::Or => "fish_or",
+    _ => return None,
+  };
+  Some(function)
+}
+
+// A frame's variables are released through a line that is filled in when the frame is closed,
+// there is nothing to release when it turned out to have none
+fn release_marker(frame: usize) -> String {
+  format!("\u{1}{}\u{1}", frame)
+}
+
+impl Generator {
+  // Tests never run in a script, so what they define is left out
+  fn collect_names(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      match &instruction.kind {
+        InstructionKind::If { instructions, .. }
+        | InstructionKind::Else { instructions }
+        | InstructionKind::While { instructions, .. }
+        | InstructionKind::Scope { instructions } => self.collect_names(instructions),
+        InstructionKind::Match { arms, .. } => {
+          for arm in arms {
+            self.collect_names(&arm.instructions);
+          }
+        }
+        InstructionKind::Enum { variants, .. } => {
+          for variant in variants {
+            intern(&mut self.variants, &mut self.variant_ids, &variant.name);
+          }
+        }
+        InstructionKind::Function(function) => {
+          intern(&mut self.functions, &mut self.function_ids, &function.name);
+          self.collect_names(&function.instructions);
+        }
+        _ => (),
+      }
+    }
+  }
+
+  fn output(&self, script: &str) -> String {
+    let mut c = format!(
+      "/* Compiled from a fish script */\n#define FISH_MAX_CALLS {}\n{}\n",
+      DEFAULT_MAX_CALLS, RUNTIME
+    );
+    for (id, string) in self.strings.iter().enumerate() {
+      let _ = writeln!(
+        c,
+        "static FishString string_{} = {{-1, {}, {}}};",
+        id,
+        string.len(),
+        c_string(string)
+      );
+    }
+    for (id, name) in self.variants.iter().enumerate() {
+      let _ = writeln!(
+        c,
+        "static FishVariant variant_{} = {{{}, NULL, 0, NULL}};",
+        id,
+        c_string(name)
+      );
+    }
+    for (id, fields) in self.field_lists.iter().enumerate() {
+      let fields: Vec<String> = fields.iter().map(|field| c_string(field)).collect();
+      let _ = writeln!(
+        c,
+        "static const char *const fields_{}[] = {{{}}};",
+        id,
+        fields.join(", ")
+      );
+    }
+    for (id, name) in self.functions.iter().enumerate() {
+      let _ = writeln!(
+        c,
+        "static FishFunction function_{} = {{{}, 0, NULL}};",
+        id,
+        c_string(name)
+      );
+    }
+    for id in 0..self.definitions.len() {
+      let _ = writeln!(c, "static FishValue fish_function_{}(FishValue *args);", id);
+    }
+    // A function of the script comes first, then a variant and then the builtin exit
+    for (id, name) in self.calls.iter().enumerate() {
+      let _ = writeln!(
+        c,
+        "\nstatic FishValue call_{}(FishValue *args, size_t count) {{",
+        id
+      );
+      if let Some(function) = self.function_ids.get(name) {
+        let _ = writeln!(
+          c,
+          "  if (function_{0}.code != NULL) {{\n    return fish_call(&function_{0}, args, count);\n  }}",
+          function
+        );
+      }
+      if let Some(variant) = self.variant_ids.get(name) {
+        let _ = writeln!(
+          c,
+          "  if (variant_{0}.enum_name != NULL) {{\n    return fish_construct(&variant_{0}, args, count);\n  }}",
+          variant
+        );
+      }
+      if name == crate::interpreter::EXIT {
+        let _ = writeln!(c, "  return fish_exit(args, count);\n}}");
+      } else {
+        let _ = writeln!(
+          c,
+          "  fish_undefined_function({});\n  return fish_none();\n}}",
+          c_string(name)
+        );
+      }
+    }
+    for definition in &self.definitions {
+      let _ = write!(c, "\n{}", definition);
+    }
+    let _ = write!(c, "\nstatic void fish_script(void) {{\n{}}}\n", script);
+    c
+  }
+
+  fn line(&mut self, line: impl AsRef<str>) {
+    let _ = writeln!(
+      self.body.code,
+      "{:1$}{2}",
+      "",
+      self.body.indent * 2,
+      line.as_ref()
+    );
+  }
+
+  fn temporary(&mut self) -> String {
+    self.body.temporaries += 1;
+    format!("t{}", self.body.temporaries)
+  }
+
+  fn label(&mut self, kind: &str) -> String {
+    self.labels += 1;
+    format!("{}_{}_end", kind, self.labels)
+  }
+
+  // The instructions in a frame of their own, as a C block that starts with the head
+  fn block(&mut self, head: &str, instructions: &[Instruction]) {
+    let outer = self.open_frame();
+    self.instructions(instructions);
+    self.close_frame(head, outer);
+  }
+
+  // The code in a frame goes into a buffer of its own until the frame's size is known
+  fn open_frame(&mut self) -> String {
+    let id = self.frames;
+    self.frames += 1;
+    self.body.frames.push(Frame { id, size: 0 });
+    self.body.cleanups.push(Cleanup::Frame(id));
+    self.body.indent += 1;
+    mem::take(&mut self.body.code)
+  }
+
+  fn close_frame(&mut self, head: &str, outer: String) {
+    let frame = self.body.frames.pop().expect("No frame to close");
+    self.body.cleanups.pop();
+    let inner = mem::replace(&mut self.body.code, outer);
+    let marker = release_marker(frame.id);
+    let release = format!("FISH_RELEASE_FRAME(f{});", frame.id);
+    let opening = match head {
+      "" => "{".to_string(),
+      head => format!("{} {{", head),
+    };
+    self.body.indent -= 1;
+    self.line(opening);
+    self.body.indent += 1;
+    if frame.size > 0 {
+      self.line(format!(
+        "FishValue f{}[{}] = {{{{0}}}};",
+        frame.id, frame.size
+      ));
+    }
+    for line in inner.lines() {
+      match line.trim_start() == marker {
+        true if frame.size > 0 => {
+          let indent = line.len() - line.trim_start().len();
+          self.body.code.push_str(&line[..indent]);
+          self.body.code.push_str(&release);
+          self.body.code.push('\n');
+        }
+        true => (),
+        false => {
+          self.body.code.push_str(line);
+          self.body.code.push('\n');
+        }
+      }
+    }
+    if frame.size > 0 {
+      self.line(&release);
+    }
+    self.body.indent -= 1;
+    self.line("}");
+  }
+
+  // Where the variable in the slot is, as a C lvalue
+  fn place(&mut self, slot: Slot) -> String {
+    let index = self
+      .body
+      .frames
+      .len()
+      .checked_sub(slot.depth + 1)
+      .expect("Slot is outside of the function");
+    let frame = &mut self.body.frames[index];
+    frame.size = frame.size.max(slot.index + 1);
+    format!("f{}[{}]", frame.id, slot.index)
+  }
+
+  // Releases everything up to the innermost loop, or everything when leaving the function
+  fn leave(&mut self, to_loop: bool) -> Option<String> {
+    let mut lines = Vec::new();
+    let mut target = None;
+    for cleanup in self.body.cleanups.iter_mut().rev() {
+      match cleanup {
+        Cleanup::Frame(id) => lines.push(release_marker(*id)),
+        Cleanup::Value(value) => lines.push(format!("fish_release({});", value)),
+        Cleanup::Loop { label, used } if to_loop => {
+          *used = true;
+          target = Some(label.clone());
+          break;
+        }
+        Cleanup::Loop { .. } => (),
+      }
+    }
+    for line in lines {
+      self.line(line);
+    }
+    target
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    let mut index = 0;
+    while index < instructions.len() {
+      let instruction = &instructions[index];
+      index += 1;
+      match &instruction.kind {
+        InstructionKind::If {
+          condition,
+          instructions: body,
+        } => {
+          let condition = self.value(condition);
+          self.block(
+            &format!("if (fish_condition({}, \"if condition\"))", condition),
+            body,
+          );
+          // An else only runs right after an if that did not, one anywhere else never runs
+          if let Some(Instruction {
+            kind: InstructionKind::Else { instructions },
+            ..
+          }) = instructions.get(index)
+          {
+            self.block("else", instructions);
+            index += 1;
+          }
+        }
+        InstructionKind::Else { .. } => (),
+        InstructionKind::While {
+          condition,
+          instructions,
+        } => self.while_loop(condition, instructions),
+        InstructionKind::Scope { instructions } => self.block("", instructions),
+        InstructionKind::Value { value } => match value {
+          Value::Expression(expression) if expression.get_operator().is_assignment() => {
+            self.assign(expression, false);
+          }
+          value => {
+            let value = self.value(value);
+            self.line(format!("fish_release({});", value));
+          }
+        },
+        InstructionKind::Break => match self.leave(true) {
+          Some(label) => self.line(format!("goto {};", label)),
+          // Outside of a loop a break ends the function or the script
+          None if self.body.function => self.line("return fish_none();"),
+          None => self.line("return;"),
+        },
+        InstructionKind::Print { message } => {
+          let message = self.value(message);
+          self.line(format!("fish_print({});", message));
+        }
+        InstructionKind::Input { variable } => match variable.slot {
+          Some(slot) => {
+            let place = self.place(slot);
+            self.store(&place, "fish_input()");
+          }
+          None => self.line(format!(
+            "fish_fail(\"Variable '%s' is not defined\", {});",
+            c_string(&variable.name)
+          )),
+        },
+        InstructionKind::Enum { name, variants, .. } => {
+          for variant in variants {
+            let id = intern(&mut self.variants, &mut self.variant_ids, &variant.name);
+            let fields = match variant.fields.is_empty() {
+              true => "NULL".to_string(),
+              false => {
+                self.field_lists.push(variant.fields.clone());
+                format!("fields_{}", self.field_lists.len() - 1)
+              }
+            };
+            self.line(format!(
+              "fish_define_variant(&variant_{}, {}, {}, {});",
+              id,
+              c_string(name),
+              variant.fields.len(),
+              fields
+            ));
+          }
+        }
+        InstructionKind::Match { value, arms } => self.match_arms(value, arms),
+        InstructionKind::Let {
+          variable, value, ..
+        } => {
+          let value = self.value(value);
+          let slot = variable.slot.expect("Let was not resolved");
+          let place = self.place(slot);
+          self.store(&place, &value);
+        }
+        InstructionKind::Function(function) => self.function(function),
+        InstructionKind::Return { value } => {
+          let value = match value {
+            Some(value) => self.value(value),
+            None => "fish_none()".to_string(),
+          };
+          self.leave(false);
+          match self.body.function {
+            true => self.line(format!("return {};", value)),
+            false => {
+              self.line(format!("fish_release({});", value));
+              self.line("return;");
+            }
+          }
+        }
+        InstructionKind::Assert { condition, message } => {
+          self.assert(instruction, condition, message.as_ref())
+        }
+        // Only the test runner runs tests
+        InstructionKind::Test { .. } => (),
+      }
+    }
+  }
+
+  fn store(&mut self, place: &str, value: &str) {
+    self.line(format!("fish_release({});", place));
+    self.line(format!("{} = {};", place, value));
+  }
+
+  // The condition is checked in the frame around the loop, every iteration gets a new frame
+  fn while_loop(&mut self, condition: &Value, instructions: &[Instruction]) {
+    let label = self.label("loop");
+    self.line("for (;;) {");
+    self.body.indent += 1;
+    let condition = self.value(condition);
+    self.line(format!(
+      "if (!fish_condition({}, \"while condition\")) {{",
+      condition
+    ));
+    self.line("  break;");
+    self.line("}");
+    self
+      .body
+      .cleanups
+      .push(Cleanup::Loop { label, used: false });
+    self.block("", instructions);
+    let Some(Cleanup::Loop { label, used }) = self.body.cleanups.pop() else {
+      panic!("Loop cleanup was popped early");
+    };
+    self.body.indent -= 1;
+    self.line("}");
+    if used {
+      self.line(format!("{}:;", label));
+    }
+  }
+
+  // Every arm gets a frame for its bindings, guard and instructions
+  fn match_arms(&mut self, value: &Value, arms: &[MatchArm]) {
+    let subject = self.value(value);
+    let subject = match subject.starts_with('t') {
+      true => subject,
+      false => {
+        let temporary = self.temporary();
+        self.line(format!("FishValue {} = {};", temporary, subject));
+        temporary
+      }
+    };
+    let done = self.temporary();
+    self.line(format!("int {} = 0;", done));
+    self.body.cleanups.push(Cleanup::Value(subject.clone()));
+    for arm in arms {
+      let outer = self.open_frame();
+      let matched = self.temporary();
+      self.line(format!("int {} = 1;", matched));
+      self.pattern(&arm.pattern, &subject, &matched);
+      self.line(format!("if ({}) {{", matched));
+      self.body.indent += 1;
+      if let Some(guard) = &arm.guard {
+        let guard = self.value(guard);
+        self.line(format!(
+          "{} = fish_condition({}, \"match guard\");",
+          matched, guard
+        ));
+      }
+      self.body.indent -= 1;
+      self.line("}");
+      self.line(format!("if ({}) {{", matched));
+      self.body.indent += 1;
+      self.line(format!("{} = 1;", done));
+      self.instructions(&arm.instructions);
+      self.body.indent -= 1;
+      self.line("}");
+      self.close_frame(&format!("if (!{})", done), outer);
+    }
+    self.body.cleanups.pop();
+    self.line(format!("if (!{}) {{", done));
+    self.line(format!("  fish_no_arm({});", subject));
+    self.line("}");
+    self.line(format!("fish_release({});", subject));
+  }
+
+  // Clears the flag when the borrowed subject does not match, binding variables while it does
+  fn pattern(&mut self, pattern: &Pattern, subject: &str, matched: &str) {
+    match pattern {
+      Pattern::Wildcard => (),
+      Pattern::Literal(literal) => {
+        let literal = self.value(literal);
+        self.line(format!(
+          "if ({} && !fish_equal({}, {})) {{",
+          matched, subject, literal
+        ));
+        self.line(format!("  {} = 0;", matched));
+        self.line("}");
+      }
+      Pattern::Identifier(identifier) => match identifier.slot {
+        Some(slot) => {
+          let place = self.place(slot);
+          self.line(format!("if ({}) {{", matched));
+          self.body.indent += 1;
+          self.store(&place, &format!("fish_retain({})", subject));
+          self.body.indent -= 1;
+          self.line("}");
+        }
+        // A variant without fields
+        None => {
+          self.line(format!(
+            "if ({} && !fish_is_variant({}, {}, -1)) {{",
+            matched,
+            subject,
+            c_string(&identifier.name)
+          ));
+          self.line(format!("  {} = 0;", matched));
+          self.line("}");
+        }
+      },
+      Pattern::Variant { name, fields, .. } => {
+        self.line(format!(
+          "if ({} && !fish_is_variant({}, {}, {})) {{",
+          matched,
+          subject,
+          c_string(name),
+          fields.len()
+        ));
+        self.line(format!("  {} = 0;", matched));
+        self.line("}");
+        if fields.is_empty() {
+          return;
+        }
+        self.line(format!("if ({}) {{", matched));
+        self.body.indent += 1;
+        for (index, field) in fields.iter().enumerate() {
+          self.pattern(
+            field,
+            &format!("FISH_ENUM_FIELD({}, {})", subject, index),
+            matched,
+          );
+        }
+        self.body.indent -= 1;
+        self.line("}");
+      }
+    }
+  }
+
+  // A fish function becomes a C function that takes its arguments in an array
+  fn function(&mut self, function: &Function) {
+    let id = self.definitions.len();
+    // Reserve the place, a function inside of this one gets the next one
+    self.definitions.push(String::new());
+    let outer = mem::replace(
+      &mut self.body,
+      Body {
+        indent: 1,
+        function: true,
+        ..Body::default()
+      },
+    );
+    let code = self.open_frame();
+    for index in 0..function.parameters.len() {
+      let place = self.place(Slot { depth: 0, index });
+      self.line(format!("{} = args[{}];", place, index));
+    }
+    self.instructions(&function.instructions);
+    self.close_frame("", code);
+    let body = mem::replace(&mut self.body, outer);
+    self.definitions[id] = format!(
+      "static FishValue fish_function_{}(FishValue *args) {{\n{}  (void)args;\n  return fish_none();\n}}\n",
+      id, body.code
+    );
+    let record = intern(&mut self.functions, &mut self.function_ids, &function.name);
+    self.line(format!(
+      "fish_define_function(&function_{}, {}, fish_function_{});",
+      record,
+      function.parameters.len(),
+      id
+    ));
+  }
+
+  // A failed comparison reports both sides, like in the interpreter
+  fn assert(&mut self, instruction: &Instruction, condition: &Value, message: Option<&Value>) {
+    let mut compared = condition;
+    while let Value::Expression(expression) = compared {
+      if *expression.get_operator() != Operator::Brackets {
+        break;
+      }
+      compared = expression.get_left();
+    }
+    let sides = match compared {
+      Value::Expression(expression) => comparison(*expression.get_operator()).map(|constant| {
+        let left = self.value(expression.get_left());
+        let left = self.keep(left);
+        let right = self.value(expression.get_right().expect("No right for comparison"));
+        let right = self.keep(right);
+        (left, constant, right, expression.get_operator().to_string())
+      }),
+      _ => None,
+    };
+    let passed = match &sides {
+      Some((left, constant, right, _)) => {
+        format!("fish_compare({}, {}, {})", constant, left, right)
+      }
+      None => {
+        let condition = self.value(condition);
+        format!("fish_assert_condition({})", condition)
+      }
+    };
+    self.line(format!("if (!{}) {{", passed));
+    self.body.indent += 1;
+    let message = match message {
+      Some(message) => {
+        let message = self.value(message);
+        format!("&{}", self.keep(message))
+      }
+      None => "NULL".to_string(),
+    };
+    let compared = match &sides {
+      Some((left, _, right, symbol)) => format!("&{}, {}, &{}", left, c_string(symbol), right),
+      None => "NULL, NULL, NULL".to_string(),
+    };
+    let position = instruction.span.start;
+    self.line(format!(
+      "fish_assert_failed({}, {}, {}, {});",
+      position.line, position.column, message, compared
+    ));
+    self.body.indent -= 1;
+    self.line("}");
+    if let Some((left, _, right, _)) = sides {
+      self.line(format!("fish_release({});", left));
+      self.line(format!("fish_release({});", right));
+    }
+  }
+
+  // A value that has to be used more than once, in a temporary even when it is a constant
+  fn keep(&mut self, value: String) -> String {
+    if value.starts_with('t') {
+      return value;
+    }
+    let temporary = self.temporary();
+    self.line(format!("FishValue {} = {};", temporary, value));
+    temporary
+  }
+
+  // Generates the code for a value and returns a C expression for it, which has to be used
+  // exactly once. Anything that can fail or has an effect is computed into a temporary first
+  fn value(&mut self, value: &Value) -> String {
+    match value {
+      Value::Number(number) => c_number(*number),
+      Value::String(string) => {
+        let id = intern(&mut self.strings, &mut self.string_ids, string);
+        format!("fish_constant(&string_{})", id)
+      }
+      Value::Boolean(boolean) => format!("fish_boolean({})", *boolean as u8),
+      Value::None => "fish_none()".to_string(),
+      Value::List(items) => {
+        let items: Vec<String> = items.iter().map(|item| self.value(item)).collect();
+        let list = self.temporary();
+        self.line(format!(
+          "FishValue {} = fish_list_new({});",
+          list,
+          items.len()
+        ));
+        for (index, item) in items.iter().enumerate() {
+          self.line(format!("FISH_ITEMS({})[{}] = {};", list, index, item));
+        }
+        list
+      }
+      Value::Identifier(identifier) => self.identifier(identifier),
+      Value::Call {
+        name, arguments, ..
+      } => {
+        let arguments: Vec<String> = arguments
+          .iter()
+          .map(|argument| self.value(argument))
+          .collect();
+        let id = intern(&mut self.calls, &mut self.call_ids, name);
+        let result = self.temporary();
+        if arguments.is_empty() {
+          self.line(format!("FishValue {} = call_{}(NULL, 0);", result, id));
+        } else {
+          let array = self.temporary();
+          self.line(format!(
+            "FishValue {}[{}] = {{{}}};",
+            array,
+            arguments.len(),
+            arguments.join(", ")
+          ));
+          self.line(format!(
+            "FishValue {} = call_{}({}, {});",
+            result,
+            id,
+            array,
+            arguments.len()
+          ));
+        }
+        result
+      }
+      Value::Field { .. } | Value::Index { .. } => self.access(value),
+      Value::Expression(expression) => self.expression(expression),
+    }
+  }
+
+  // A variable, or when it was not assigned yet a variant without fields or `args`
+  fn identifier(&mut self, identifier: &Identifier) -> String {
+    let variant = match self.variant_ids.get(&identifier.name) {
+      Some(id) => format!("&variant_{}", id),
+      None => "NULL".to_string(),
+    };
+    let fallback = format!(
+      "fish_global({}, {}, {})",
+      variant,
+      (identifier.name == crate::interpreter::ARGS) as u8,
+      c_string(&identifier.name)
+    );
+    let result = self.temporary();
+    match identifier.slot {
+      Some(slot) => {
+        let place = self.place(slot);
+        self.line(format!("FishValue {} = fish_retain({});", result, place));
+        self.line(format!("if ({}.tag == FISH_UNDEFINED) {{", result));
+        self.line(format!("  {} = {};", result, fallback));
+        self.line("}");
+      }
+      None => self.line(format!("FishValue {} = {};", result, fallback)),
+    }
+    result
+  }
+
+  fn expression(&mut self, expression: &Expression) -> String {
+    let operator = *expression.get_operator();
+    match operator {
+      Operator::Brackets => return self.value(expression.get_left()),
+      operator if operator.is_assignment() => {
+        return self
+          .assign(expression, true)
+          .expect("Assignment has no result")
+      }
+      _ => (),
+    }
+    let left = self.value(expression.get_left());
+    let result = self.temporary();
+    let right = match operator {
+      Operator::Not => {
+        self.line(format!("FishValue {} = fish_not({});", result, left));
+        return result;
+      }
+      // The right side is only evaluated when the left is none
+      Operator::Coalesce => {
+        self.line(format!("FishValue {} = {};", result, left));
+        self.line(format!("if ({}.tag == FISH_NONE) {{", result));
+        self.body.indent += 1;
+        let right = self.value(expression.get_right().expect("No right for coalesce"));
+        self.line(format!("{} = {};", result, right));
+        self.body.indent -= 1;
+        self.line("}");
+        return result;
+      }
+      _ => self.value(expression.get_right().expect("No right for operator")),
+    };
+    let operation = match (comparison(operator), arithmetic(operator)) {
+      (Some(operator), _) => format!("fish_comparison({}, {}, {})", operator, left, right),
+      (_, Some(function)) => format!("{}({}, {})", function, left, right),
+      _ => panic!("{:?} is not a binary operator", operator),
+    };
+    self.line(format!("FishValue {} = {};", result, operation));
+    result
+  }
+
+  // Returns the assigned value when it is needed
+  fn assign(&mut self, expression: &Expression, keep: bool) -> Option<String> {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    let right = expression.get_right().expect("No right for assignment");
+    let slot = match left {
+      Value::Identifier(Identifier {
+        slot: Some(slot), ..
+      }) => *slot,
+      Value::Identifier(identifier) => {
+        self.line(format!(
+          "fish_fail(\"Variable '%s' is not defined\", {});",
+          c_string(&identifier.name)
+        ));
+        return keep.then(|| "fish_none()".to_string());
+      }
+      _ => {
+        self.line("fish_fail(\"Type mismatch: Expected identifier on left side of assignment\");");
+        return keep.then(|| "fish_none()".to_string());
+      }
+    };
+    let value = match arithmetic(operator) {
+      Some(function) => {
+        let current = self.value(left);
+        let right = self.value(right);
+        let result = self.temporary();
+        self.line(format!(
+          "FishValue {} = {}({}, {});",
+          result, function, current, right
+        ));
+        result
+      }
+      None => self.value(right),
+    };
+    let place = self.place(slot);
+    self.store(&place, &value);
+    keep.then(|| {
+      let result = self.temporary();
+      self.line(format!("FishValue {} = fish_retain({});", result, place));
+      result
+    })
+  }
+
+  // A chain of field and index accesses, an optional one that finds none makes the whole chain
+  // none without going on
+  fn access(&mut self, value: &Value) -> String {
+    if !value.has_optional() {
+      return self.access_step(value, None);
+    }
+    let result = self.temporary();
+    let label = self.label("access");
+    self.line(format!("FishValue {};", result));
+    let last = self.access_step(value, Some((&result, &label)));
+    self.line(format!("{} = {};", result, last));
+    self.line(format!("{}:;", label));
+    result
+  }
+
+  fn access_step(&mut self, value: &Value, short: Option<(&str, &str)>) -> String {
+    let (base, optional) = match value {
+      Value::Field {
+        value, optional, ..
+      }
+      | Value::Index {
+        value, optional, ..
+      } => (value, *optional),
+      value => return self.value(value),
+    };
+    let base = self.access_step(base, short);
+    let base = self.keep(base);
+    if let (true, Some((result, label))) = (optional, short) {
+      self.line(format!("if ({}.tag == FISH_NONE) {{", base));
+      self.line(format!("  {} = fish_none();", result));
+      self.line(format!("  goto {};", label));
+      self.line("}");
+    }
+    let result = self.temporary();
+    match value {
+      Value::Field { field, .. } => self.line(format!(
+        "FishValue {} = fish_field({}, {});",
+        result,
+        base,
+        c_string(field)
+      )),
+      Value::Index { index, .. } => {
+        let index = self.value(index);
+        self.line(format!(
+          "FishValue {} = fish_index({}, {});",
+          result, base, index
+        ));
+      }
+      _ => unreachable!(),
+    }
+    result
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::samples;
+  use std::fs;
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn programs_do_what_the_interpreter_does() {
+    let dir = samples::temp_dir("c");
+    let mut built = 0;
+    let samples = samples::all().into_iter().chain(samples::edge_cases());
+    for (index, (name, code)) in samples.enumerate() {
+      let Some(expected) = samples::interpret(&code) else {
+        continue;
+      };
+      let source = dir.join(format!("{}.c", index));
+      let program = dir.join(index.to_string());
+      fs::write(&source, transpile(&samples::instructions(&code))).unwrap();
+      let args = [
+        "-O2".as_ref(),
+        "-o".as_ref(),
+        program.as_os_str(),
+        source.as_os_str(),
+        "-lm".as_ref(),
+      ];
+      let Some(compiled) = samples::run_program("cc", &args) else {
+        eprintln!("Skipping the C programs, there is no cc to compile them");
+        return;
+      };
+      assert_eq!(
+        compiled.code, 0,
+        "{} does not compile: {}",
+        name, compiled.error
+      );
+      let ended = samples::run_program(&program, &[]).unwrap();
+      assert_eq!(ended, expected, "{}", name);
+      built += 1;
+    }
+    fs::remove_dir_all(dir).unwrap();
+    assert!(built > 10, "Only {} samples could be built", built);
+  }
+}
@@ -0,0 +1,974 @@
+/*
+ The runtime of a fish script compiled to C. Values behave the way they do in the interpreter and
+ errors print the same messages. Strings, lists and enum values are reference counted, a value that
+ is passed to a function here is used up by it unless the function says it only borrows it.
+*/
+#include <inttypes.h>
+#include <math.h>
+#include <stdarg.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+enum {
+  FISH_UNDEFINED,
+  FISH_NONE,
+  FISH_BOOLEAN,
+  FISH_INTEGER,
+  FISH_FLOAT,
+  FISH_STRING,
+  FISH_LIST,
+  FISH_ENUM
+};
+
+enum { FISH_EQUAL, FISH_NOT_EQUAL, FISH_LESS, FISH_LESS_EQUAL, FISH_GREATER, FISH_GREATER_EQUAL };
+
+typedef struct FishString FishString;
+typedef struct FishList FishList;
+typedef struct FishEnum FishEnum;
+
+/* A variable that was not assigned yet is FISH_UNDEFINED, which is all zeroes */
+typedef struct {
+  int tag;
+  union {
+    int boolean;
+    int64_t integer;
+    double number;
+    FishString *string;
+    FishList *list;
+    FishEnum *enumeration;
+  } as;
+} FishValue;
+
+/* Constants have a negative count and are never freed */
+struct FishString {
+  long references;
+  size_t length;
+  const char *bytes;
+};
+
+struct FishList {
+  long references;
+  size_t length;
+  FishValue items[];
+};
+
+/* A variant is known once the enum that has it was defined, until then enum_name is NULL */
+typedef struct {
+  const char *name;
+  const char *enum_name;
+  size_t field_count;
+  const char *const *fields;
+} FishVariant;
+
+struct FishEnum {
+  long references;
+  FishVariant *variant;
+  const char *enum_name;
+  size_t length;
+  FishValue fields[];
+};
+
+/* A function is known once its definition ran, until then code is NULL */
+typedef struct {
+  const char *name;
+  size_t arity;
+  FishValue (*code)(FishValue *arguments);
+} FishFunction;
+
+/* A script only uses some of the runtime, which should not make the compiler warn */
+#if defined(__GNUC__)
+#define FISH_RUNTIME static __attribute__((unused))
+#else
+#define FISH_RUNTIME static
+#endif
+/* Constant strings are never freed, but GCC can not tell when it inlines fish_release */
+#if defined(__GNUC__) && __GNUC__ >= 11
+#pragma GCC diagnostic ignored "-Wfree-nonheap-object"
+#endif
+
+typedef struct {
+  char *bytes;
+  size_t length;
+  size_t capacity;
+} FishBuffer;
+
+static FishValue fish_args;
+static size_t fish_calls;
+
+static void fish_script(void);
+
+FISH_RUNTIME void fish_fail(const char *format, ...) {
+  va_list arguments;
+  fflush(stdout);
+  fputs("Error interpreting code: ", stderr);
+  va_start(arguments, format);
+  vfprintf(stderr, format, arguments);
+  va_end(arguments);
+  fputc('\n', stderr);
+  exit(1);
+}
+
+/* Where the interpreter panics, which exits with 101 */
+FISH_RUNTIME void fish_panic(const char *message) {
+  fflush(stdout);
+  fprintf(stderr, "%s\n", message);
+  exit(101);
+}
+
+FISH_RUNTIME void *fish_allocate(size_t size) {
+  void *memory = malloc(size);
+  if (memory == NULL) {
+    fish_panic("Out of memory");
+  }
+  return memory;
+}
+
+FISH_RUNTIME FishValue fish_none(void) {
+  FishValue value;
+  value.tag = FISH_NONE;
+  value.as.integer = 0;
+  return value;
+}
+
+FISH_RUNTIME FishValue fish_boolean(int boolean) {
+  FishValue value;
+  value.tag = FISH_BOOLEAN;
+  value.as.boolean = boolean != 0;
+  return value;
+}
+
+FISH_RUNTIME FishValue fish_integer(int64_t integer) {
+  FishValue value;
+  value.tag = FISH_INTEGER;
+  value.as.integer = integer;
+  return value;
+}
+
+FISH_RUNTIME FishValue fish_float(double number) {
+  FishValue value;
+  value.tag = FISH_FLOAT;
+  value.as.number = number;
+  return value;
+}
+
+FISH_RUNTIME FishValue fish_constant(FishString *string) {
+  FishValue value;
+  value.tag = FISH_STRING;
+  value.as.string = string;
+  return value;
+}
+
+FISH_RUNTIME FishValue fish_string(const char *bytes, size_t length) {
+  FishString *string = fish_allocate(sizeof(FishString) + length + 1);
+  char *copy = (char *)(string + 1);
+  memcpy(copy, bytes, length);
+  copy[length] = '\0';
+  string->references = 1;
+  string->length = length;
+  string->bytes = copy;
+  return fish_constant(string);
+}
+
+FISH_RUNTIME FishValue fish_list_new(size_t length) {
+  FishValue value;
+  FishList *list = fish_allocate(sizeof(FishList) + length * sizeof(FishValue));
+  list->references = 1;
+  list->length = length;
+  value.tag = FISH_LIST;
+  value.as.list = list;
+  return value;
+}
+
+#define FISH_ITEMS(value) ((value).as.list->items)
+
+FISH_RUNTIME FishValue fish_retain(FishValue value) {
+  long *references = NULL;
+  switch (value.tag) {
+  case FISH_STRING:
+    references = &value.as.string->references;
+    break;
+  case FISH_LIST:
+    references = &value.as.list->references;
+    break;
+  case FISH_ENUM:
+    references = &value.as.enumeration->references;
+    break;
+  }
+  if (references != NULL && *references >= 0) {
+    ++*references;
+  }
+  return value;
+}
+
+FISH_RUNTIME void fish_release(FishValue value) {
+  size_t index;
+  switch (value.tag) {
+  case FISH_STRING:
+    if (value.as.string->references > 0 && --value.as.string->references == 0) {
+      free(value.as.string);
+    }
+    break;
+  case FISH_LIST:
+    if (--value.as.list->references == 0) {
+      for (index = 0; index < value.as.list->length; index++) {
+        fish_release(value.as.list->items[index]);
+      }
+      free(value.as.list);
+    }
+    break;
+  case FISH_ENUM:
+    if (--value.as.enumeration->references == 0) {
+      for (index = 0; index < value.as.enumeration->length; index++) {
+        fish_release(value.as.enumeration->fields[index]);
+      }
+      free(value.as.enumeration);
+    }
+    break;
+  }
+}
+
+/* The variables of a block, when it ends or is left through a break or return */
+FISH_RUNTIME void fish_release_frame(FishValue *slots, size_t count) {
+  size_t index;
+  for (index = 0; index < count; index++) {
+    fish_release(slots[index]);
+  }
+}
+
+#define FISH_RELEASE_FRAME(frame) fish_release_frame(frame, sizeof(frame) / sizeof((frame)[0]))
+
+FISH_RUNTIME void fish_buffer_write(FishBuffer *buffer, const char *bytes, size_t length) {
+  if (buffer->length + length > buffer->capacity) {
+    size_t capacity = buffer->capacity * 2 + length + 16;
+    char *grown = realloc(buffer->bytes, capacity);
+    if (grown == NULL) {
+      fish_panic("Out of memory");
+    }
+    buffer->bytes = grown;
+    buffer->capacity = capacity;
+  }
+  memcpy(buffer->bytes + buffer->length, bytes, length);
+  buffer->length += length;
+}
+
+FISH_RUNTIME void fish_buffer_text(FishBuffer *buffer, const char *text) {
+  fish_buffer_write(buffer, text, strlen(text));
+}
+
+/*
+ Floats are written like Rust writes them: the fewest digits that read back as the same float,
+ never with an exponent, and without a fraction when there is none
+*/
+FISH_RUNTIME void fish_write_float(FishBuffer *buffer, double number) {
+  char text[40];
+  char digits[24];
+  size_t count = 0;
+  int precision;
+  int exponent;
+  int point;
+  char *character;
+  if (number != number) {
+    fish_buffer_text(buffer, "NaN");
+    return;
+  }
+  if (number == HUGE_VAL || number == -HUGE_VAL) {
+    fish_buffer_text(buffer, number > 0 ? "inf" : "-inf");
+    return;
+  }
+  if (number == 0) {
+    fish_buffer_text(buffer, signbit(number) ? "-0" : "0");
+    return;
+  }
+  for (precision = 1; precision <= 17; precision++) {
+    snprintf(text, sizeof text, "%.*e", precision - 1, number);
+    if (strtod(text, NULL) == number) {
+      break;
+    }
+  }
+  character = text;
+  if (*character == '-') {
+    fish_buffer_text(buffer, "-");
+    character++;
+  }
+  for (; *character != 'e'; character++) {
+    if (*character != '.') {
+      digits[count++] = *character;
+    }
+  }
+  exponent = atoi(character + 1);
+  while (count > 1 && digits[count - 1] == '0') {
+    count--;
+  }
+  point = exponent + 1;
+  if (point <= 0) {
+    fish_buffer_text(buffer, "0.");
+    for (; point < 0; point++) {
+      fish_buffer_text(buffer, "0");
+    }
+    fish_buffer_write(buffer, digits, count);
+  } else if ((size_t)point >= count) {
+    fish_buffer_write(buffer, digits, count);
+    for (; (size_t)point > count; point--) {
+      fish_buffer_text(buffer, "0");
+    }
+  } else {
+    fish_buffer_write(buffer, digits, point);
+    fish_buffer_text(buffer, ".");
+    fish_buffer_write(buffer, digits + point, count - point);
+  }
+}
+
+FISH_RUNTIME void fish_display(FishBuffer *buffer, FishValue value) {
+  char text[24];
+  size_t index;
+  switch (value.tag) {
+  case FISH_NONE:
+    fish_buffer_text(buffer, "none");
+    break;
+  case FISH_BOOLEAN:
+    fish_buffer_text(buffer, value.as.boolean ? "true" : "false");
+    break;
+  case FISH_INTEGER:
+    snprintf(text, sizeof text, "%" PRId64, value.as.integer);
+    fish_buffer_text(buffer, text);
+    break;
+  case FISH_FLOAT:
+    fish_write_float(buffer, value.as.number);
+    break;
+  case FISH_STRING:
+    fish_buffer_write(buffer, value.as.string->bytes, value.as.string->length);
+    break;
+  case FISH_LIST:
+    fish_buffer_text(buffer, "[");
+    for (index = 0; index < value.as.list->length; index++) {
+      if (index > 0) {
+        fish_buffer_text(buffer, ", ");
+      }
+      fish_display(buffer, value.as.list->items[index]);
+    }
+    fish_buffer_text(buffer, "]");
+    break;
+  case FISH_ENUM:
+    fish_buffer_text(buffer, value.as.enumeration->variant->name);
+    if (value.as.enumeration->length > 0) {
+      fish_buffer_text(buffer, "(");
+      for (index = 0; index < value.as.enumeration->length; index++) {
+        if (index > 0) {
+          fish_buffer_text(buffer, ", ");
+        }
+        fish_display(buffer, value.as.enumeration->fields[index]);
+      }
+      fish_buffer_text(buffer, ")");
+    }
+    break;
+  }
+}
+
+/* Like fish_display, but strings are quoted the way Rust quotes them */
+FISH_RUNTIME void fish_describe(FishBuffer *buffer, FishValue value) {
+  char escape[16];
+  size_t index;
+  unsigned char byte;
+  if (value.tag != FISH_STRING) {
+    fish_display(buffer, value);
+    return;
+  }
+  fish_buffer_text(buffer, "\"");
+  for (index = 0; index < value.as.string->length; index++) {
+    byte = (unsigned char)value.as.string->bytes[index];
+    switch (byte) {
+    case '"':
+      fish_buffer_text(buffer, "\\\"");
+      break;
+    case '\\':
+      fish_buffer_text(buffer, "\\\\");
+      break;
+    case '\n':
+      fish_buffer_text(buffer, "\\n");
+      break;
+    case '\r':
+      fish_buffer_text(buffer, "\\r");
+      break;
+    case '\t':
+      fish_buffer_text(buffer, "\\t");
+      break;
+    case '\0':
+      fish_buffer_text(buffer, "\\0");
+      break;
+    default:
+      if (byte < 0x20 || byte == 0x7f) {
+        snprintf(escape, sizeof escape, "\\u{%x}", byte);
+        fish_buffer_text(buffer, escape);
+      } else {
+        fish_buffer_write(buffer, (const char *)&byte, 1);
+      }
+    }
+  }
+  fish_buffer_text(buffer, "\"");
+}
+
+FISH_RUNTIME void fish_print(FishValue value) {
+  static FishBuffer buffer;
+  buffer.length = 0;
+  fish_display(&buffer, value);
+  fish_buffer_write(&buffer, "\n", 1);
+  fwrite(buffer.bytes, 1, buffer.length, stdout);
+  fish_release(value);
+}
+
+/* The characters str::trim removes */
+FISH_RUNTIME int fish_is_space(uint32_t character) {
+  return (character >= 0x09 && character <= 0x0d) || character == 0x20 || character == 0x85 ||
+         character == 0xa0 || character == 0x1680 || (character >= 0x2000 && character <= 0x200a) ||
+         character == 0x2028 || character == 0x2029 || character == 0x202f ||
+         character == 0x205f || character == 0x3000;
+}
+
+/* The character starting at bytes, and how many bytes it takes */
+FISH_RUNTIME uint32_t fish_decode(const unsigned char *bytes, size_t length, size_t *size) {
+  if (bytes[0] < 0x80 || length < 2) {
+    *size = 1;
+    return bytes[0];
+  }
+  if (bytes[0] < 0xe0) {
+    *size = 2;
+    return ((uint32_t)(bytes[0] & 0x1f) << 6) | (bytes[1] & 0x3f);
+  }
+  if (bytes[0] < 0xf0 || length < 4) {
+    *size = length < 3 ? length : 3;
+    return ((uint32_t)(bytes[0] & 0x0f) << 12) | ((uint32_t)(bytes[1] & 0x3f) << 6) |
+           (length < 3 ? 0 : (bytes[2] & 0x3f));
+  }
+  *size = 4;
+  return ((uint32_t)(bytes[0] & 0x07) << 18) | ((uint32_t)(bytes[1] & 0x3f) << 12) |
+         ((uint32_t)(bytes[2] & 0x3f) << 6) | (bytes[3] & 0x3f);
+}
+
+/* A line from stdin without the whitespace around it, none at the end of the input */
+FISH_RUNTIME FishValue fish_input(void) {
+  FishBuffer line = {NULL, 0, 0};
+  FishValue value;
+  const unsigned char *bytes;
+  size_t start = 0;
+  size_t end;
+  size_t size;
+  int character;
+  fflush(stdout);
+  while ((character = getchar()) != EOF) {
+    char byte = (char)character;
+    fish_buffer_write(&line, &byte, 1);
+    if (character == '\n') {
+      break;
+    }
+  }
+  if (line.length == 0) {
+    return fish_none();
+  }
+  bytes = (const unsigned char *)line.bytes;
+  while (start < line.length && fish_is_space(fish_decode(bytes + start, line.length - start, &size))) {
+    start += size;
+  }
+  end = line.length;
+  while (end > start) {
+    size_t back = end - 1;
+    while (back > start && (bytes[back] & 0xc0) == 0x80) {
+      back--;
+    }
+    if (!fish_is_space(fish_decode(bytes + back, end - back, &size))) {
+      break;
+    }
+    end = back;
+  }
+  value = fish_string(line.bytes + start, end - start);
+  free(line.bytes);
+  return value;
+}
+
+FISH_RUNTIME int fish_is_number(FishValue value) {
+  return value.tag == FISH_INTEGER || value.tag == FISH_FLOAT;
+}
+
+FISH_RUNTIME double fish_to_float(FishValue value) {
+  return value.tag == FISH_INTEGER ? (double)value.as.integer : value.as.number;
+}
+
+FISH_RUNTIME FishValue fish_add(FishValue left, FishValue right) {
+  if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+    int64_t a = left.as.integer;
+    int64_t b = right.as.integer;
+    if ((b > 0 && a > INT64_MAX - b) || (b < 0 && a < INT64_MIN - b)) {
+      fish_fail("Integer overflow when adding");
+    }
+    return fish_integer(a + b);
+  }
+  if (fish_is_number(left) && fish_is_number(right)) {
+    return fish_float(fish_to_float(left) + fish_to_float(right));
+  }
+  if (left.tag == FISH_STRING && right.tag == FISH_STRING) {
+    size_t length = left.as.string->length + right.as.string->length;
+    FishString *string = fish_allocate(sizeof(FishString) + length + 1);
+    char *bytes = (char *)(string + 1);
+    memcpy(bytes, left.as.string->bytes, left.as.string->length);
+    memcpy(bytes + left.as.string->length, right.as.string->bytes, right.as.string->length);
+    bytes[length] = '\0';
+    string->references = 1;
+    string->length = length;
+    string->bytes = bytes;
+    fish_release(left);
+    fish_release(right);
+    return fish_constant(string);
+  }
+  if (left.tag == FISH_LIST && right.tag == FISH_LIST) {
+    size_t index;
+    size_t length = left.as.list->length;
+    FishValue list = fish_list_new(length + right.as.list->length);
+    for (index = 0; index < length; index++) {
+      FISH_ITEMS(list)[index] = fish_retain(left.as.list->items[index]);
+    }
+    for (index = 0; index < right.as.list->length; index++) {
+      FISH_ITEMS(list)[length + index] = fish_retain(right.as.list->items[index]);
+    }
+    fish_release(left);
+    fish_release(right);
+    return list;
+  }
+  fish_fail("Type mismatch: Expected 2 strings, 2 numbers or 2 lists when adding");
+  return fish_none();
+}
+
+FISH_RUNTIME FishValue fish_subtract(FishValue left, FishValue right) {
+  if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+    int64_t a = left.as.integer;
+    int64_t b = right.as.integer;
+    if ((b < 0 && a > INT64_MAX + b) || (b > 0 && a < INT64_MIN + b)) {
+      fish_fail("Integer overflow when subtracting");
+    }
+    return fish_integer(a - b);
+  }
+  if (fish_is_number(left) && fish_is_number(right)) {
+    return fish_float(fish_to_float(left) - fish_to_float(right));
+  }
+  fish_fail("Type mismatch: Expected 2 numbers when subtracting");
+  return fish_none();
+}
+
+FISH_RUNTIME FishValue fish_multiply(FishValue left, FishValue right) {
+  if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+    int64_t a = left.as.integer;
+    int64_t b = right.as.integer;
+    int overflow;
+    if (a > 0) {
+      overflow = b > 0 ? a > INT64_MAX / b : b < INT64_MIN / a;
+    } else {
+      overflow = b > 0 ? a < INT64_MIN / b : a != 0 && b < INT64_MAX / a;
+    }
+    if (overflow) {
+      fish_fail("Integer overflow when multiplying");
+    }
+    return fish_integer(a * b);
+  }
+  if (fish_is_number(left) && fish_is_number(right)) {
+    return fish_float(fish_to_float(left) * fish_to_float(right));
+  }
+  fish_fail("Type mismatch: Expected 2 numbers when multiplying");
+  return fish_none();
+}
+
+/* Integer division rounds towards zero, like in C */
+FISH_RUNTIME FishValue fish_divide(FishValue left, FishValue right) {
+  if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+    if (right.as.integer == 0) {
+      fish_fail("Division by zero");
+    }
+    if (left.as.integer == INT64_MIN && right.as.integer == -1) {
+      fish_fail("Integer overflow when dividing");
+    }
+    return fish_integer(left.as.integer / right.as.integer);
+  }
+  if (fish_is_number(left) && fish_is_number(right)) {
+    return fish_float(fish_to_float(left) / fish_to_float(right));
+  }
+  fish_fail("Type mismatch: Expected 2 numbers when dividing");
+  return fish_none();
+}
+
+FISH_RUNTIME FishValue fish_modulo(FishValue left, FishValue right) {
+  if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+    if (right.as.integer == 0) {
+      fish_fail("Division by zero");
+    }
+    if (left.as.integer == INT64_MIN && right.as.integer == -1) {
+      fish_fail("Integer overflow when taking modulo");
+    }
+    return fish_integer(left.as.integer % right.as.integer);
+  }
+  if (fish_is_number(left) && fish_is_number(right)) {
+    return fish_float(fmod(fish_to_float(left), fish_to_float(right)));
+  }
+  fish_fail("Type mismatch: Expected 2 numbers when taking modulo");
+  return fish_none();
+}
+
+/* Always a float, even for two integers */
+FISH_RUNTIME FishValue fish_power(FishValue left, FishValue right) {
+  if (fish_is_number(left) && fish_is_number(right)) {
+    return fish_float(pow(fish_to_float(left), fish_to_float(right)));
+  }
+  fish_fail("Type mismatch: Expected 2 numbers when taking exponent");
+  return fish_none();
+}
+
+/* Borrows both values. Integers and floats are equal when they are the same number */
+FISH_RUNTIME int fish_equal(FishValue left, FishValue right) {
+  size_t index;
+  if (fish_is_number(left) && fish_is_number(right)) {
+    if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+      return left.as.integer == right.as.integer;
+    }
+    return fish_to_float(left) == fish_to_float(right);
+  }
+  if (left.tag != right.tag) {
+    return 0;
+  }
+  switch (left.tag) {
+  case FISH_NONE:
+    return 1;
+  case FISH_BOOLEAN:
+    return left.as.boolean == right.as.boolean;
+  case FISH_STRING:
+    return left.as.string->length == right.as.string->length &&
+           memcmp(left.as.string->bytes, right.as.string->bytes, left.as.string->length) == 0;
+  case FISH_LIST:
+    if (left.as.list->length != right.as.list->length) {
+      return 0;
+    }
+    for (index = 0; index < left.as.list->length; index++) {
+      if (!fish_equal(left.as.list->items[index], right.as.list->items[index])) {
+        return 0;
+      }
+    }
+    return 1;
+  case FISH_ENUM:
+    if (strcmp(left.as.enumeration->enum_name, right.as.enumeration->enum_name) != 0 ||
+        strcmp(left.as.enumeration->variant->name, right.as.enumeration->variant->name) != 0 ||
+        left.as.enumeration->length != right.as.enumeration->length) {
+      return 0;
+    }
+    for (index = 0; index < left.as.enumeration->length; index++) {
+      if (!fish_equal(left.as.enumeration->fields[index], right.as.enumeration->fields[index])) {
+        return 0;
+      }
+    }
+    return 1;
+  }
+  return 0;
+}
+
+/* Borrows both values */
+FISH_RUNTIME int fish_compare(int operator, FishValue left, FishValue right) {
+  if (operator == FISH_EQUAL) {
+    return fish_equal(left, right);
+  }
+  if (operator == FISH_NOT_EQUAL) {
+    return !fish_equal(left, right);
+  }
+  if (!fish_is_number(left) || !fish_is_number(right)) {
+    fish_fail("Type mismatch: Expected 2 numbers ");
+  }
+  if (left.tag == FISH_INTEGER && right.tag == FISH_INTEGER) {
+    int64_t a = left.as.integer;
+    int64_t b = right.as.integer;
+    switch (operator) {
+    case FISH_LESS:
+      return a < b;
+    case FISH_LESS_EQUAL:
+      return a <= b;
+    case FISH_GREATER:
+      return a > b;
+    default:
+      return a >= b;
+    }
+  } else {
+    double a = fish_to_float(left);
+    double b = fish_to_float(right);
+    switch (operator) {
+    case FISH_LESS:
+      return a < b;
+    case FISH_LESS_EQUAL:
+      return a <= b;
+    case FISH_GREATER:
+      return a > b;
+    default:
+      return a >= b;
+    }
+  }
+}
+
+FISH_RUNTIME FishValue fish_comparison(int operator, FishValue left, FishValue right) {
+  int result = fish_compare(operator, left, right);
+  fish_release(left);
+  fish_release(right);
+  return fish_boolean(result);
+}
+
+/* Both sides are evaluated, so && and || only need booleans and never own anything */
+FISH_RUNTIME FishValue fish_and(FishValue left, FishValue right) {
+  if (left.tag != FISH_BOOLEAN || right.tag != FISH_BOOLEAN) {
+    fish_fail("Type mismatch: Expected 2 booleans ");
+  }
+  return fish_boolean(left.as.boolean && right.as.boolean);
+}
+
+FISH_RUNTIME FishValue fish_or(FishValue left, FishValue right) {
+  if (left.tag != FISH_BOOLEAN || right.tag != FISH_BOOLEAN) {
+    fish_fail("Type mismatch: Expected 2 booleans ");
+  }
+  return fish_boolean(left.as.boolean || right.as.boolean);
+}
+
+FISH_RUNTIME FishValue fish_not(FishValue value) {
+  if (value.tag != FISH_BOOLEAN) {
+    fish_fail("Type mismatch: Expected 1 boolean ");
+  }
+  return fish_boolean(!value.as.boolean);
+}
+
+/* What is the condition of an if, a while or a match guard */
+FISH_RUNTIME int fish_condition(FishValue value, const char *what) {
+  if (value.tag != FISH_BOOLEAN) {
+    fish_fail("Type mismatch: Expected boolean for %s", what);
+  }
+  return value.as.boolean;
+}
+
+FISH_RUNTIME FishValue fish_index(FishValue value, FishValue index) {
+  FishValue item = fish_none();
+  int64_t position;
+  int found = 0;
+  if (index.tag != FISH_INTEGER) {
+    fish_fail("Type mismatch: Expected integer as index");
+  }
+  position = index.as.integer;
+  if (position < 0) {
+    fish_fail("Index %" PRId64 " is out of bounds", position);
+  }
+  switch (value.tag) {
+  case FISH_LIST:
+    if ((uint64_t)position < value.as.list->length) {
+      item = fish_retain(value.as.list->items[position]);
+      found = 1;
+    }
+    break;
+  case FISH_ENUM:
+    if ((uint64_t)position < value.as.enumeration->length) {
+      item = fish_retain(value.as.enumeration->fields[position]);
+      found = 1;
+    }
+    break;
+  case FISH_STRING: {
+    /* Strings are indexed by character, not by byte */
+    const unsigned char *bytes = (const unsigned char *)value.as.string->bytes;
+    size_t length = value.as.string->length;
+    size_t offset = 0;
+    size_t size = 0;
+    int64_t skipped = 0;
+    while (offset < length) {
+      fish_decode(bytes + offset, length - offset, &size);
+      if (skipped == position) {
+        item = fish_string((const char *)bytes + offset, size);
+        found = 1;
+        break;
+      }
+      offset += size;
+      skipped++;
+    }
+    break;
+  }
+  default:
+    fish_fail("Type mismatch: Expected list, string or enum value when indexing");
+  }
+  if (!found) {
+    fish_fail("Index %" PRId64 " is out of bounds", position);
+  }
+  fish_release(value);
+  return item;
+}
+
+/* The field is looked up in the variant as it is defined now, like in the interpreter */
+FISH_RUNTIME FishValue fish_field(FishValue value, const char *field) {
+  FishValue item;
+  FishEnum *enumeration;
+  size_t position;
+  if (value.tag != FISH_ENUM) {
+    fish_fail("Type mismatch: Expected enum value when accessing a field");
+  }
+  enumeration = value.as.enumeration;
+  for (position = 0; position < enumeration->variant->field_count; position++) {
+    if (strcmp(enumeration->variant->fields[position], field) == 0) {
+      break;
+    }
+  }
+  if (position >= enumeration->variant->field_count || position >= enumeration->length) {
+    fish_fail("Field '%s' is not defined", field);
+  }
+  item = fish_retain(enumeration->fields[position]);
+  fish_release(value);
+  return item;
+}
+
+FISH_RUNTIME void fish_define_variant(FishVariant *variant, const char *enum_name,
+                                      size_t field_count, const char *const *fields) {
+  if (variant->enum_name != NULL && strcmp(variant->enum_name, enum_name) != 0) {
+    fish_fail("Type mismatch: Variant '%s' is already defined by enum '%s'", variant->name,
+              variant->enum_name);
+  }
+  variant->enum_name = enum_name;
+  variant->field_count = field_count;
+  variant->fields = fields;
+}
+
+FISH_RUNTIME FishValue fish_construct(FishVariant *variant, FishValue *fields, size_t count) {
+  FishValue value;
+  FishEnum *enumeration;
+  if (variant->field_count != count) {
+    fish_fail("Argument mismatch: Variant '%s' takes %lu values but %lu were given", variant->name,
+              (unsigned long)variant->field_count, (unsigned long)count);
+  }
+  enumeration = fish_allocate(sizeof(FishEnum) + count * sizeof(FishValue));
+  enumeration->references = 1;
+  enumeration->variant = variant;
+  enumeration->enum_name = variant->enum_name;
+  enumeration->length = count;
+  if (count > 0) {
+    memcpy(enumeration->fields, fields, count * sizeof(FishValue));
+  }
+  value.tag = FISH_ENUM;
+  value.as.enumeration = enumeration;
+  return value;
+}
+
+/*
+ A name that is not a variable, or a variable that was not assigned yet: a variant without
+ fields, or the list of arguments when the name is args
+*/
+FISH_RUNTIME FishValue fish_global(FishVariant *variant, int args, const char *name) {
+  if (variant != NULL && variant->enum_name != NULL) {
+    if (variant->field_count != 0) {
+      fish_fail("Argument mismatch: Variant '%s' takes %lu values", name,
+                (unsigned long)variant->field_count);
+    }
+    return fish_construct(variant, NULL, 0);
+  }
+  if (args) {
+    return fish_retain(fish_args);
+  }
+  fish_fail("Variable '%s' is not defined", name);
+  return fish_none();
+}
+
+/* Borrows the value. A count of -1 matches any amount of fields */
+FISH_RUNTIME int fish_is_variant(FishValue value, const char *name, long count) {
+  return value.tag == FISH_ENUM && strcmp(value.as.enumeration->variant->name, name) == 0 &&
+         (count < 0 || value.as.enumeration->length == (size_t)count);
+}
+
+#define FISH_ENUM_FIELD(value, index) ((value).as.enumeration->fields[index])
+
+FISH_RUNTIME void fish_no_arm(FishValue value) {
+  FishBuffer buffer = {NULL, 0, 0};
+  fish_display(&buffer, value);
+  fish_buffer_write(&buffer, "", 1);
+  fish_fail("No match arm matched the value %s", buffer.bytes);
+}
+
+FISH_RUNTIME void fish_define_function(FishFunction *function, size_t arity,
+                                       FishValue (*code)(FishValue *arguments)) {
+  function->arity = arity;
+  function->code = code;
+}
+
+FISH_RUNTIME FishValue fish_call(FishFunction *function, FishValue *arguments, size_t count) {
+  FishValue value;
+  if (function->arity != count) {
+    fish_fail("Argument mismatch: Function '%s' takes %lu arguments but %lu were given",
+              function->name, (unsigned long)function->arity, (unsigned long)count);
+  }
+  if (fish_calls >= FISH_MAX_CALLS) {
+    fish_fail("Limit exceeded, more than %d function calls were nested", FISH_MAX_CALLS);
+  }
+  fish_calls++;
+  value = function->code(arguments);
+  fish_calls--;
+  return value;
+}
+
+FISH_RUNTIME void fish_undefined_function(const char *name) {
+  fish_fail("Function '%s' is not defined", name);
+}
+
+/* exit() and exit(code), the code has to be one a process can exit with, from 0 to 255 */
+FISH_RUNTIME FishValue fish_exit(FishValue *arguments, size_t count) {
+  if (count == 0) {
+    fflush(stdout);
+    exit(0);
+  }
+  if (count > 1) {
+    fish_fail("Argument mismatch: Function 'exit' takes 1 argument but %lu were given",
+              (unsigned long)count);
+  }
+  if (arguments[0].tag != FISH_INTEGER) {
+    fish_fail("Type mismatch: Expected integer as exit code");
+  }
+  if (arguments[0].as.integer < 0 || arguments[0].as.integer > 255) {
+    fish_fail("Type mismatch: Exit code %" PRId64 " is not between 0 and 255",
+              arguments[0].as.integer);
+  }
+  fflush(stdout);
+  exit((int)arguments[0].as.integer);
+  return fish_none();
+}
+
+/* The condition of an assert that is not a comparison */
+FISH_RUNTIME int fish_assert_condition(FishValue value) {
+  if (value.tag != FISH_BOOLEAN) {
+    fish_fail("Type mismatch: Expected boolean for assert condition");
+  }
+  return value.as.boolean;
+}
+
+/* Takes the message, borrows the sides of the comparison when there is one */
+FISH_RUNTIME void fish_assert_failed(long line, long column, FishValue *message,
+                                     FishValue *left, const char *operator, FishValue *right) {
+  FishBuffer buffer = {NULL, 0, 0};
+  char position[48];
+  snprintf(position, sizeof position, "Assertion failed at %ld:%ld", line, column);
+  fish_buffer_text(&buffer, position);
+  if (message != NULL) {
+    fish_buffer_text(&buffer, ": ");
+    fish_display(&buffer, *message);
+  }
+  if (left != NULL) {
+    fish_buffer_text(&buffer, " (");
+    fish_describe(&buffer, *left);
+    fish_buffer_text(&buffer, " ");
+    fish_buffer_text(&buffer, operator);
+    fish_buffer_text(&buffer, " ");
+    fish_describe(&buffer, *right);
+    fish_buffer_text(&buffer, " is false)");
+  }
+  fish_buffer_write(&buffer, "", 1);
+  fish_fail("%s", buffer.bytes);
+}
+
+int main(int argc, char **argv) {
+  int index;
+  fish_args = fish_list_new(argc > 1 ? (size_t)(argc - 1) : 0);
+  for (index = 1; index < argc; index++) {
+    FISH_ITEMS(fish_args)[index - 1] = fish_string(argv[index], strlen(argv[index]));
+  }
+  fish_script();
+  return 0;
+}
@@ -0,0 +1,1039 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+  limits::{self, Limit},
+  number::Number,
+  parser::{
+    Expression, Function, Identifier, Instruction, InstructionKind, MatchArm, Parameter, Pattern,
+    TypeAnnotation, Value, Variant,
+  },
+  tokenizer::{Operator, Position, Span, OPERATORS},
+};
+
+/*
+ Compiled programs, .fshc files, which can be run without tokenizing and parsing them again.
+
+ A file starts with a header of 16 bytes, all numbers in it are little endian:
+  - the magic bytes "\x7fFSH", no code starts with DEL so a script is never taken for a program
+  - the format version, 2 bytes, which goes up whenever an older reader would misread a file
+  - a CRC-32 of everything after it, 4 bytes
+  - flags, 2 bytes, 1 when the file has spans
+  - the length of the body after the header, 4 bytes
+
+ The body is made of sections, and every number in it is an unsigned LEB128 unless said otherwise:
+  - the string table, a count and then the length and UTF-8 bytes of every string. Names and
+    string constants are stored in it once and referred to by their index everywhere else
+  - the constant pool, a count and then a tag for every constant: 0 and an integer as 8 bytes, 1
+    and a float as 8 bytes or 2 and the index of a string
+  - the instructions, their length in bytes and then the instructions of the script
+  - the spans, only with the flag, a count and then the start and end of every node that has a
+    span in the order the nodes come in the instructions. Without them errors while running can
+    not point at where they happened
+*/
+pub const MAGIC: &[u8; 4] = b"\x7fFSH";
+pub const VERSION: u16 = 2;
+
+const HEADER_LENGTH: usize = 16;
+const FLAG_SPANS: u16 = 1;
+
+#[derive(Debug)]
+pub enum LoadError {
+  NotCompiled,
+  UnsupportedVersion(u16),
+  ChecksumMismatch,
+  Truncated,
+  Invalid(String),
+  LimitExceeded(Limit),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::NotCompiled => write!(f, "Not a compiled fish program"),
+      LoadError::UnsupportedVersion(version) => write!(
+        f,
+        "Format version {} is not supported, only {} is, compile the script again",
+        version, VERSION
+      ),
+      LoadError::ChecksumMismatch => write!(f, "The checksum does not match, the file is damaged"),
+      LoadError::Truncated => write!(f, "The file ends too early"),
+      LoadError::Invalid(message) => write!(f, "Invalid program, {}", message),
+      LoadError::LimitExceeded(limit) => write!(f, "Limit exceeded, {}", limit),
+    }
+  }
+}
+
+// Whether the bytes look like a compiled program rather than code
+pub fn is_compiled(bytes: &[u8]) -> bool {
+  bytes.starts_with(MAGIC)
+}
+
+pub fn write(instructions: &[Instruction], spans: bool) -> Vec<u8> {
+  let mut writer = Writer {
+    spans: spans.then(Vec::new),
+    ..Writer::default()
+  };
+  writer.instructions(instructions);
+
+  let mut body = Vec::new();
+  push_unsigned(&mut body, writer.strings.len());
+  for string in &writer.strings {
+    push_unsigned(&mut body, string.len());
+    body.extend_from_slice(string.as_bytes());
+  }
+  push_unsigned(&mut body, writer.constants.len());
+  for constant in &writer.constants {
+    match *constant {
+      Constant::Integer(integer) => {
+        body.push(0);
+        body.extend_from_slice(&integer.to_le_bytes());
+      }
+      Constant::Float(bits) => {
+        body.push(1);
+        body.extend_from_slice(&bits.to_le_bytes());
+      }
+      Constant::String(index) => {
+        body.push(2);
+        push_unsigned(&mut body, index);
+      }
+    }
+  }
+  push_unsigned(&mut body, writer.code.len());
+  body.extend_from_slice(&writer.code);
+  if let Some(spans) = &writer.spans {
+    push_unsigned(&mut body, spans.len());
+    for span in spans {
+      for position in [span.start, span.end] {
+        push_unsigned(&mut body, position.offset);
+        push_unsigned(&mut body, position.line);
+        push_unsigned(&mut body, position.column);
+      }
+    }
+  }
+
+  let flags = if spans { FLAG_SPANS } else { 0 };
+  let length = u32::try_from(body.len()).expect("Compiled program over 4 GiB");
+  let mut checked = Vec::with_capacity(6 + body.len());
+  checked.extend_from_slice(&flags.to_le_bytes());
+  checked.extend_from_slice(&length.to_le_bytes());
+  checked.extend_from_slice(&body);
+
+  let mut bytes = Vec::with_capacity(HEADER_LENGTH + body.len());
+  bytes.extend_from_slice(MAGIC);
+  bytes.extend_from_slice(&VERSION.to_le_bytes());
+  bytes.extend_from_slice(&crc32(&checked).to_le_bytes());
+  bytes.extend_from_slice(&checked);
+  bytes
+}
+
+/*
+ Reads back what `write` wrote. The magic bytes and the version are checked before the checksum,
+ so a file from another version of fish says so instead of looking damaged. Blocks, values and
+ patterns can be nested as deep as the parser allows in code. Variables still have to be resolved
+ afterwards.
+*/
+pub fn read(bytes: &[u8], max_nesting: Option<usize>) -> Result<Vec<Instruction>, LoadError> {
+  if !is_compiled(bytes) {
+    return Err(LoadError::NotCompiled);
+  }
+  let mut header = Reader::new(&bytes[MAGIC.len()..]);
+  let version = u16::from_le_bytes(header.array()?);
+  if version != VERSION {
+    return Err(LoadError::UnsupportedVersion(version));
+  }
+  let checksum = u32::from_le_bytes(header.array()?);
+  let checked = header.rest();
+  let flags = u16::from_le_bytes(header.array()?);
+  let length = u32::from_le_bytes(header.array()?) as usize;
+  let body = header.rest();
+  if body.len() < length {
+    return Err(LoadError::Truncated);
+  }
+  if body.len() > length {
+    return Err(invalid("there are bytes after its end"));
+  }
+  if crc32(checked) != checksum {
+    return Err(LoadError::ChecksumMismatch);
+  }
+  if flags & !FLAG_SPANS != 0 {
+    return Err(invalid(format!("unknown flags {:#06x}", flags)));
+  }
+
+  let mut body = Reader::new(body);
+  let mut strings = Vec::new();
+  for _ in 0..body.count()? {
+    let length = body.count()?;
+    let string = std::str::from_utf8(body.take(length)?)
+      .map_err(|_| invalid("a string is not valid UTF-8"))?;
+    strings.push(string.to_string());
+  }
+  let mut constants = Vec::new();
+  for _ in 0..body.count()? {
+    let constant = match body.byte()? {
+      0 => Value::Number(Number::Integer(i64::from_le_bytes(body.array()?))),
+      1 => Value::Number(Number::Float(f64::from_bits(u64::from_le_bytes(
+        body.array()?,
+      )))),
+      2 => {
+        let string = lookup(&strings, body.count()?, "string")?;
+        Value::String(Rc::from(string.as_str()))
+      }
+      tag => return Err(invalid(format!("unknown constant tag {}", tag))),
+    };
+    constants.push(constant);
+  }
+  let code_length = body.count()?;
+  let code = body.take(code_length)?;
+  let spans = match flags & FLAG_SPANS != 0 {
+    true => {
+      let mut spans = Vec::new();
+      for _ in 0..body.count()? {
+        spans.push(Span {
+          start: body.position()?,
+          end: body.position()?,
+        });
+      }
+      Some(spans)
+    }
+    false => None,
+  };
+  if !body.is_empty() {
+    return Err(invalid("there are bytes after the last section"));
+  }
+
+  let mut decoder = Decoder {
+    code: Reader::new(code),
+    strings,
+    constants,
+    spans: spans.map(Vec::into_iter),
+    depth: 0,
+    max_depth: max_nesting,
+    chain: 0,
+    max_chain: limits::max_chain(max_nesting),
+  };
+  let instructions = decoder.instructions()?;
+  if !decoder.code.is_empty() {
+    return Err(invalid("there are bytes after the last instruction"));
+  }
+  if decoder
+    .spans
+    .is_some_and(|mut spans| spans.next().is_some())
+  {
+    return Err(invalid("there are more spans than nodes"));
+  }
+  Ok(instructions)
+}
+
+fn invalid(message: impl Into<String>) -> LoadError {
+  LoadError::Invalid(message.into())
+}
+
+fn lookup<'a, T>(table: &'a [T], index: usize, what: &str) -> Result<&'a T, LoadError> {
+  table
+    .get(index)
+    .ok_or_else(|| invalid(format!("there is no {} {}", what, index)))
+}
+
+// The CRC-32 used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for byte in bytes {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+    }
+  }
+  !crc
+}
+
+// Seven bits at a time, starting with the lowest, with the high bit set on all but the last byte
+fn push_unsigned(bytes: &mut Vec<u8>, number: usize) {
+  let mut number = number as u64;
+  while number >= 0x80 {
+    bytes.push(number as u8 | 0x80);
+    number >>= 7;
+  }
+  bytes.push(number as u8);
+}
+
+// Instructions and values start with one of these
+mod opcode {
+  pub const IF: u8 = 0;
+  pub const ELSE: u8 = 1;
+  pub const WHILE: u8 = 2;
+  pub const SCOPE: u8 = 3;
+  pub const VALUE: u8 = 4;
+  pub const BREAK: u8 = 5;
+  pub const PRINT: u8 = 6;
+  pub const INPUT: u8 = 7;
+  pub const ENUM: u8 = 8;
+  pub const MATCH: u8 = 9;
+  pub const LET: u8 = 10;
+  pub const FUNCTION: u8 = 11;
+  pub const RETURN: u8 = 12;
+  pub const ASSERT: u8 = 13;
+  pub const TEST: u8 = 14;
+
+  pub const CONSTANT: u8 = 0;
+  pub const TRUE: u8 = 1;
+  pub const FALSE: u8 = 2;
+  pub const NONE: u8 = 3;
+  pub const LIST: u8 = 4;
+  pub const IDENTIFIER: u8 = 5;
+  // Followed by the operator as its index in OPERATORS
+  pub const EXPRESSION: u8 = 6;
+  pub const CALL: u8 = 7;
+  pub const FIELD: u8 = 8;
+  pub const INDEX: u8 = 9;
+
+  pub const WILDCARD: u8 = 0;
+  pub const LITERAL: u8 = 1;
+  pub const BINDING: u8 = 2;
+  pub const VARIANT: u8 = 3;
+}
+
+// Floats by their bits, so they can be told apart and looked up
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Constant {
+  Integer(i64),
+  Float(u64),
+  String(usize),
+}
+
+#[derive(Default)]
+struct Writer {
+  strings: Vec<String>,
+  string_indices: HashMap<String, usize>,
+  constants: Vec<Constant>,
+  constant_indices: HashMap<Constant, usize>,
+  code: Vec<u8>,
+  // None when the spans are left out
+  spans: Option<Vec<Span>>,
+}
+
+impl Writer {
+  fn unsigned(&mut self, number: usize) {
+    push_unsigned(&mut self.code, number);
+  }
+
+  fn flag(&mut self, flag: bool) {
+    self.code.push(flag as u8);
+  }
+
+  fn span(&mut self, span: Span) {
+    if let Some(spans) = &mut self.spans {
+      spans.push(span);
+    }
+  }
+
+  fn string_index(&mut self, string: &str) -> usize {
+    if let Some(index) = self.string_indices.get(string) {
+      return *index;
+    }
+    self.strings.push(string.to_string());
+    self
+      .string_indices
+      .insert(string.to_string(), self.strings.len() - 1);
+    self.strings.len() - 1
+  }
+
+  fn string(&mut self, string: &str) {
+    let index = self.string_index(string);
+    self.unsigned(index);
+  }
+
+  fn constant(&mut self, constant: Constant) {
+    let index = *self.constant_indices.entry(constant).or_insert_with(|| {
+      self.constants.push(constant);
+      self.constants.len() - 1
+    });
+    self.code.push(opcode::CONSTANT);
+    self.unsigned(index);
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    self.unsigned(instructions.len());
+    for instruction in instructions {
+      self.instruction(instruction);
+    }
+  }
+
+  fn instruction(&mut self, instruction: &Instruction) {
+    self.span(instruction.span);
+    match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      } => {
+        self.code.push(opcode::IF);
+        self.value(condition);
+        self.instructions(instructions);
+      }
+      InstructionKind::Else { instructions } => {
+        self.code.push(opcode::ELSE);
+        self.instructions(instructions);
+      }
+      InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        self.code.push(opcode::WHILE);
+        self.value(condition);
+        self.instructions(instructions);
+      }
+      InstructionKind::Scope { instructions } => {
+        self.code.push(opcode::SCOPE);
+        self.instructions(instructions);
+      }
+      InstructionKind::Value { value } => {
+        self.code.push(opcode::VALUE);
+        self.value(value);
+      }
+      InstructionKind::Break => self.code.push(opcode::BREAK),
+      InstructionKind::Print { message } => {
+        self.code.push(opcode::PRINT);
+        self.value(message);
+      }
+      InstructionKind::Input { variable } => {
+        self.code.push(opcode::INPUT);
+        self.identifier(variable);
+      }
+      InstructionKind::Enum {
+        name,
+        name_span,
+        variants,
+      } => {
+        self.code.push(opcode::ENUM);
+        self.span(*name_span);
+        self.string(name);
+        self.unsigned(variants.len());
+        for variant in variants {
+          self.span(variant.span);
+          self.string(&variant.name);
+          self.unsigned(variant.fields.len());
+          for field in &variant.fields {
+            self.string(field);
+          }
+        }
+      }
+      InstructionKind::Match { value, arms } => {
+        self.code.push(opcode::MATCH);
+        self.value(value);
+        self.unsigned(arms.len());
+        for arm in arms {
+          self.pattern(&arm.pattern);
+          self.optional_value(arm.guard.as_ref());
+          self.instructions(&arm.instructions);
+        }
+      }
+      InstructionKind::Let {
+        variable,
+        annotation,
+        value,
+      } => {
+        self.code.push(opcode::LET);
+        self.identifier(variable);
+        self.annotation(annotation.as_ref());
+        self.value(value);
+      }
+      InstructionKind::Function(function) => {
+        self.code.push(opcode::FUNCTION);
+        self.span(function.name_span);
+        self.string(&function.name);
+        self.unsigned(function.parameters.len());
+        for parameter in &function.parameters {
+          self.identifier(&parameter.variable);
+          self.annotation(parameter.annotation.as_ref());
+        }
+        self.annotation(function.return_type.as_ref());
+        self.instructions(&function.instructions);
+      }
+      InstructionKind::Return { value } => {
+        self.code.push(opcode::RETURN);
+        self.optional_value(value.as_ref());
+      }
+      InstructionKind::Assert { condition, message } => {
+        self.code.push(opcode::ASSERT);
+        self.value(condition);
+        self.optional_value(message.as_ref());
+      }
+      InstructionKind::Test { name, instructions } => {
+        self.code.push(opcode::TEST);
+        self.string(name);
+        self.instructions(instructions);
+      }
+    }
+  }
+
+  fn identifier(&mut self, identifier: &Identifier) {
+    self.span(identifier.span);
+    self.string(&identifier.name);
+  }
+
+  fn annotation(&mut self, annotation: Option<&TypeAnnotation>) {
+    self.flag(annotation.is_some());
+    if let Some(annotation) = annotation {
+      self.span(annotation.span);
+      self.string(&annotation.name);
+    }
+  }
+
+  fn optional_value(&mut self, value: Option<&Value>) {
+    self.flag(value.is_some());
+    if let Some(value) = value {
+      self.value(value);
+    }
+  }
+
+  fn values(&mut self, values: &[Value]) {
+    self.unsigned(values.len());
+    for value in values {
+      self.value(value);
+    }
+  }
+
+  fn value(&mut self, value: &Value) {
+    match value {
+      Value::Number(Number::Integer(integer)) => self.constant(Constant::Integer(*integer)),
+      Value::Number(Number::Float(float)) => self.constant(Constant::Float(float.to_bits())),
+      Value::String(string) => {
+        let index = self.string_index(string);
+        self.constant(Constant::String(index));
+      }
+      Value::Boolean(true) => self.code.push(opcode::TRUE),
+      Value::Boolean(false) => self.code.push(opcode::FALSE),
+      Value::None => self.code.push(opcode::NONE),
+      Value::List(items) => {
+        self.code.push(opcode::LIST);
+        self.values(items);
+      }
+      Value::Identifier(identifier) => {
+        self.code.push(opcode::IDENTIFIER);
+        self.identifier(identifier);
+      }
+      Value::Expression(expression) => {
+        self.code.push(opcode::EXPRESSION);
+        let operator = OPERATORS
+          .iter()
+          .position(|operator| operator == expression.get_operator())
+          .expect("Operator missing from OPERATORS");
+        self.code.push(operator as u8);
+        self.value(expression.get_left());
+        if let Some(right) = expression.get_right() {
+          self.value(right);
+        }
+      }
+      Value::Call {
+        name,
+        span,
+        arguments,
+      } => {
+        self.code.push(opcode::CALL);
+        self.span(*span);
+        self.string(name);
+        self.values(arguments);
+      }
+      Value::Field {
+        value,
+        field,
+        optional,
+      } => {
+        self.code.push(opcode::FIELD);
+        self.value(value);
+        self.string(field);
+        self.flag(*optional);
+      }
+      Value::Index {
+        value,
+        index,
+        optional,
+      } => {
+        self.code.push(opcode::INDEX);
+        self.value(value);
+        self.value(index);
+        self.flag(*optional);
+      }
+    }
+  }
+
+  fn pattern(&mut self, pattern: &Pattern) {
+    match pattern {
+      Pattern::Wildcard => self.code.push(opcode::WILDCARD),
+      Pattern::Literal(value) => {
+        self.code.push(opcode::LITERAL);
+        self.value(value);
+      }
+      Pattern::Identifier(identifier) => {
+        self.code.push(opcode::BINDING);
+        self.identifier(identifier);
+      }
+      Pattern::Variant { name, span, fields } => {
+        self.code.push(opcode::VARIANT);
+        self.span(*span);
+        self.string(name);
+        self.unsigned(fields.len());
+        for field in fields {
+          self.pattern(field);
+        }
+      }
+    }
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8]) -> Self {
+    Self { bytes }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+
+  fn rest(&self) -> &'a [u8] {
+    self.bytes
+  }
+
+  fn take(&mut self, length: usize) -> Result<&'a [u8], LoadError> {
+    if length > self.bytes.len() {
+      return Err(LoadError::Truncated);
+    }
+    let (taken, rest) = self.bytes.split_at(length);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+    Ok(self.take(N)?.try_into().unwrap())
+  }
+
+  fn byte(&mut self) -> Result<u8, LoadError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn flag(&mut self) -> Result<bool, LoadError> {
+    match self.byte()? {
+      0 => Ok(false),
+      1 => Ok(true),
+      byte => Err(invalid(format!("expected 0 or 1, found {}", byte))),
+    }
+  }
+
+  // A count, length or index. Nothing is allocated up front from one, a damaged file could claim
+  // billions of items
+  fn count(&mut self) -> Result<usize, LoadError> {
+    let mut number = 0u64;
+    for shift in (0..64).step_by(7) {
+      let byte = self.byte()?;
+      number |= ((byte & 0x7f) as u64) << shift;
+      if byte & 0x80 == 0 {
+        if shift == 63 && byte > 1 {
+          break;
+        }
+        return usize::try_from(number).map_err(|_| invalid("a number is too big"));
+      }
+    }
+    Err(invalid("a number is too big"))
+  }
+
+  fn position(&mut self) -> Result<Position, LoadError> {
+    Ok(Position {
+      offset: self.count()?,
+      line: self.count()?,
+      column: self.count()?,
+    })
+  }
+}
+
+struct Decoder<'a> {
+  code: Reader<'a>,
+  strings: Vec<String>,
+  constants: Vec<Value>,
+  spans: Option<std::vec::IntoIter<Span>>,
+  depth: usize,
+  max_depth: Option<usize>,
+  // Operators to the left of the value being decoded, in its expression and the ones around it
+  chain: usize,
+  max_chain: Option<usize>,
+}
+
+impl Decoder<'_> {
+  // Blocks, values and patterns go through here, like they do in the parser
+  fn nested<T>(
+    &mut self,
+    decode: impl FnOnce(&mut Self) -> Result<T, LoadError>,
+  ) -> Result<T, LoadError> {
+    if let Some(max_depth) = self.max_depth {
+      if self.depth >= max_depth {
+        return Err(LoadError::LimitExceeded(Limit::Nesting(max_depth)));
+      }
+    }
+    self.depth += 1;
+    let result = decode(self);
+    self.depth -= 1;
+    result
+  }
+
+  // The right side of an operator, which is as deep as the operator like it is in the parser
+  fn chained(&mut self) -> Result<Value, LoadError> {
+    if let Some(max_chain) = self.max_chain {
+      if self.chain >= max_chain {
+        return Err(LoadError::LimitExceeded(Limit::Chain(max_chain)));
+      }
+    }
+    self.chain += 1;
+    self.depth -= 1;
+    let result = self.value();
+    self.depth += 1;
+    self.chain -= 1;
+    result
+  }
+
+  fn span(&mut self) -> Result<Span, LoadError> {
+    match &mut self.spans {
+      Some(spans) => spans
+        .next()
+        .ok_or_else(|| invalid("there are fewer spans than nodes")),
+      None => Ok(Span::default()),
+    }
+  }
+
+  fn string(&mut self) -> Result<String, LoadError> {
+    let index = self.code.count()?;
+    Ok(lookup(&self.strings, index, "string")?.clone())
+  }
+
+  fn instructions(&mut self) -> Result<Vec<Instruction>, LoadError> {
+    self.nested(|decoder| {
+      let mut instructions = Vec::new();
+      for _ in 0..decoder.code.count()? {
+        instructions.push(decoder.instruction()?);
+      }
+      Ok(instructions)
+    })
+  }
+
+  fn instruction(&mut self) -> Result<Instruction, LoadError> {
+    let span = self.span()?;
+    let kind = match self.code.byte()? {
+      opcode::IF => InstructionKind::If {
+        condition: self.value()?,
+        instructions: self.instructions()?,
+      },
+      opcode::ELSE => InstructionKind::Else {
+        instructions: self.instructions()?,
+      },
+      opcode::WHILE => InstructionKind::While {
+        condition: self.value()?,
+        instructions: self.instructions()?,
+      },
+      opcode::SCOPE => InstructionKind::Scope {
+        instructions: self.instructions()?,
+      },
+      opcode::VALUE => InstructionKind::Value {
+        value: self.value()?,
+      },
+      opcode::BREAK => InstructionKind::Break,
+      opcode::PRINT => InstructionKind::Print {
+        message: self.value()?,
+      },
+      opcode::INPUT => InstructionKind::Input {
+        variable: self.identifier()?,
+      },
+      opcode::ENUM => {
+        let name_span = self.span()?;
+        let name = self.string()?;
+        let mut variants = Vec::new();
+        for _ in 0..self.code.count()? {
+          let span = self.span()?;
+          let name = self.string()?;
+          let mut fields = Vec::new();
+          for _ in 0..self.code.count()? {
+            fields.push(self.string()?);
+          }
+          variants.push(Variant { name, span, fields });
+        }
+        InstructionKind::Enum {
+          name,
+          name_span,
+          variants,
+        }
+      }
+      opcode::MATCH => {
+        let value = self.value()?;
+        let mut arms = Vec::new();
+        for _ in 0..self.code.count()? {
+          arms.push(MatchArm {
+            pattern: self.pattern()?,
+            guard: self.optional_value()?,
+            instructions: self.instructions()?,
+          });
+        }
+        InstructionKind::Match { value, arms }
+      }
+      opcode::LET => InstructionKind::Let {
+        variable: self.identifier()?,
+        annotation: self.annotation()?,
+        value: self.value()?,
+      },
+      opcode::FUNCTION => {
+        let name_span = self.span()?;
+        let name = self.string()?;
+        let mut parameters = Vec::new();
+        for _ in 0..self.code.count()? {
+          parameters.push(Parameter {
+            variable: self.identifier()?,
+            annotation: self.annotation()?,
+          });
+        }
+        InstructionKind::Function(Function {
+          name,
+          name_span,
+          parameters,
+          return_type: self.annotation()?,
+          instructions: self.instructions()?,
+        })
+      }
+      opcode::RETURN => InstructionKind::Return {
+        value: self.optional_value()?,
+      },
+      opcode::ASSERT => InstructionKind::Assert {
+        condition: self.value()?,
+        message: self.optional_value()?,
+      },
+      opcode::TEST => InstructionKind::Test {
+        name: self.string()?,
+        instructions: self.instructions()?,
+      },
+      opcode => return Err(invalid(format!("unknown instruction {}", opcode))),
+    };
+    Ok(Instruction { kind, span })
+  }
+
+  fn identifier(&mut self) -> Result<Identifier, LoadError> {
+    let span = self.span()?;
+    Ok(Identifier::new(self.string()?, span))
+  }
+
+  fn annotation(&mut self) -> Result<Option<TypeAnnotation>, LoadError> {
+    if !self.code.flag()? {
+      return Ok(None);
+    }
+    let span = self.span()?;
+    Ok(Some(TypeAnnotation {
+      name: self.string()?,
+      span,
+    }))
+  }
+
+  fn optional_value(&mut self) -> Result<Option<Value>, LoadError> {
+    match self.code.flag()? {
+      true => Ok(Some(self.value()?)),
+      false => Ok(None),
+    }
+  }
+
+  fn values(&mut self) -> Result<Vec<Value>, LoadError> {
+    let mut values = Vec::new();
+    for _ in 0..self.code.count()? {
+      values.push(self.value()?);
+    }
+    Ok(values)
+  }
+
+  fn value(&mut self) -> Result<Value, LoadError> {
+    self.nested(|decoder| {
+      let value = match decoder.code.byte()? {
+        opcode::CONSTANT => {
+          let index = decoder.code.count()?;
+          lookup(&decoder.constants, index, "constant")?.clone()
+        }
+        opcode::TRUE => Value::Boolean(true),
+        opcode::FALSE => Value::Boolean(false),
+        opcode::NONE => Value::None,
+        opcode::LIST => Value::List(decoder.values()?),
+        opcode::IDENTIFIER => Value::Identifier(decoder.identifier()?),
+        opcode::EXPRESSION => {
+          let index = decoder.code.byte()? as usize;
+          let operator = *lookup(&OPERATORS, index, "operator")?;
+          let left = decoder.value()?;
+          let expression = match operator {
+            Operator::Not | Operator::Brackets => Expression::new_not_or_bracket(operator, left),
+            _ => Expression::new(operator, left, decoder.chained()?),
+          };
+          Value::Expression(Box::new(expression))
+        }
+        opcode::CALL => Value::Call {
+          span: decoder.span()?,
+          name: decoder.string()?,
+          arguments: decoder.values()?,
+        },
+        opcode::FIELD => Value::Field {
+          value: Box::new(decoder.value()?),
+          field: decoder.string()?,
+          optional: decoder.code.flag()?,
+        },
+        opcode::INDEX => Value::Index {
+          value: Box::new(decoder.value()?),
+          index: Box::new(decoder.value()?),
+          optional: decoder.code.flag()?,
+        },
+        opcode => return Err(invalid(format!("unknown value {}", opcode))),
+      };
+      Ok(value)
+    })
+  }
+
+  fn pattern(&mut self) -> Result<Pattern, LoadError> {
+    self.nested(|decoder| {
+      let pattern = match decoder.code.byte()? {
+        opcode::WILDCARD => Pattern::Wildcard,
+        opcode::LITERAL => match decoder.value()? {
+          literal @ (Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None) => {
+            Pattern::Literal(literal)
+          }
+          _ => {
+            return Err(invalid(
+              "a literal pattern is not a number, string, boolean or none",
+            ))
+          }
+        },
+        opcode::BINDING => Pattern::Identifier(decoder.identifier()?),
+        opcode::VARIANT => {
+          let span = decoder.span()?;
+          let name = decoder.string()?;
+          let mut fields = Vec::new();
+          for _ in 0..decoder.code.count()? {
+            fields.push(decoder.pattern()?);
+          }
+          Pattern::Variant { name, span, fields }
+        }
+        opcode => return Err(invalid(format!("unknown pattern {}", opcode))),
+      };
+      Ok(pattern)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    limits::Limits,
+    parser, samples,
+    samples::{parse, without_spans},
+    syntax_json, tokenizer,
+  };
+
+  fn samples() -> Vec<(String, String)> {
+    let mut samples = samples::all();
+    samples.extend(samples::edge_cases());
+    samples
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn programs_read_back_as_they_were_written() {
+    for (name, code) in samples() {
+      let instructions = parse(&code);
+      let json = syntax_json::ast_to_json(&instructions);
+      let read_back = read(&write(&instructions, true), None)
+        .unwrap_or_else(|error| panic!("{} does not read back: {}", name, error));
+      assert_eq!(syntax_json::ast_to_json(&read_back), json, "{}", name);
+
+      let read_back = read(&write(&instructions, false), None)
+        .unwrap_or_else(|error| panic!("{} does not read back: {}", name, error));
+      // Without spans in the file, the nodes read back with default spans
+      let mut read_back = syntax_json::ast_to_json(&read_back);
+      let mut json = json;
+      without_spans(&mut read_back);
+      without_spans(&mut json);
+      assert_eq!(read_back, json, "{}", name);
+    }
+  }
+
+  #[test]
+  fn programs_without_spans_are_smaller() {
+    let instructions = parse("fn f(a) { return a + 1; }; print(f(1));");
+    assert!(write(&instructions, false).len() < write(&instructions, true).len());
+  }
+
+  #[test]
+  fn damaged_programs_are_not_read() {
+    let bytes = write(&parse("x = [1, 2.5, \"three\"]; print(x);"), true);
+    assert!(read(&bytes, None).is_ok());
+
+    assert!(matches!(read(b"x = 1;", None), Err(LoadError::NotCompiled)));
+
+    let mut newer = bytes.clone();
+    newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert!(matches!(
+      read(&newer, None),
+      Err(LoadError::UnsupportedVersion(version)) if version == VERSION + 1
+    ));
+
+    // The checksum, the flags or the body changed, which is found before anything else is read
+    for index in (6..12).chain(HEADER_LENGTH..bytes.len()) {
+      let mut damaged = bytes.clone();
+      damaged[index] ^= 0x20;
+      let result = read(&damaged, None);
+      assert!(
+        matches!(result, Err(LoadError::ChecksumMismatch)),
+        "byte {}: {:?}",
+        index,
+        result
+      );
+    }
+
+    for length in MAGIC.len()..bytes.len() {
+      assert!(
+        matches!(read(&bytes[..length], None), Err(LoadError::Truncated)),
+        "cut at {}",
+        length
+      );
+    }
+  }
+
+  #[test]
+  fn programs_are_nested_no_deeper_than_their_code() {
+    let code =
+      "x = [[[[[1]]]]]; if (x) { match x { A(B(_)) => { print(!x[0] + 1 - (2 - 3) - 4); } }; };";
+    let bytes = write(&parse(code), true);
+    for max_nesting in 1..20 {
+      let limits = Limits {
+        max_nesting: Some(max_nesting),
+        ..Limits::default()
+      };
+      let tokens = tokenizer::tokenize(code).unwrap();
+      if parser::parse(tokens, &limits).1.is_empty() {
+        assert!(read(&bytes, Some(max_nesting)).is_ok());
+      }
+    }
+    assert!(matches!(
+      read(&bytes, Some(4)),
+      Err(LoadError::LimitExceeded(Limit::Nesting(4)))
+    ));
+
+    // Operators in a row count towards the chain like they do in the parser
+    let sum = |terms: usize| format!("print({});", vec!["1"; terms].join(" + "));
+    let limits = Limits {
+      max_nesting: Some(3),
+      ..Limits::default()
+    };
+    let max_chain = limits::max_chain(limits.max_nesting).unwrap();
+    for terms in [max_chain + 1, max_chain + 2] {
+      let tokens = tokenizer::tokenize(&sum(terms)).unwrap();
+      let parsed = parser::parse(tokens, &limits).1.is_empty();
+      let read = read(&write(&parse(&sum(terms)), true), limits.max_nesting);
+      assert_eq!(parsed, terms == max_chain + 1, "{} terms", terms);
+      assert_eq!(read.is_ok(), parsed, "{} terms", terms);
+    }
+    assert!(matches!(
+      read(&write(&parse(&sum(max_chain + 2)), true), Some(3)),
+      Err(LoadError::LimitExceeded(Limit::Chain(limit))) if limit == max_chain
+    ));
+  }
+}
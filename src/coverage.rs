@@ -0,0 +1,302 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt::Write,
+};
+
+use crate::{
+  interpreter::{Hooks, Inspector, InterpreterError, Scope},
+  parser::{Instruction, InstructionKind},
+};
+
+// An if or while, with how often its condition was true and how often false
+#[derive(Debug)]
+struct Branch {
+  line: usize,
+  kind: &'static str,
+  taken: [u64; 2],
+}
+
+#[derive(Debug)]
+struct Function {
+  name: String,
+  line: usize,
+  calls: u64,
+}
+
+/*
+ `--coverage`, tracks which instructions ran and which way every if and while went. Instructions
+ and branches are known by where they start, which is different for every instruction.
+
+ An else belongs to the branch of its if, it runs exactly when the condition was false.
+*/
+pub struct Coverage {
+  // How often the instructions starting at an offset ran, with their line
+  statements: BTreeMap<usize, (usize, u64)>,
+  branches: BTreeMap<usize, Branch>,
+  functions: Vec<Function>,
+  // Where each function is in `functions`, by the offset of its name
+  function_indices: HashMap<usize, usize>,
+}
+
+impl Coverage {
+  pub fn new(instructions: &[Instruction]) -> Self {
+    let mut coverage = Self {
+      statements: BTreeMap::new(),
+      branches: BTreeMap::new(),
+      functions: Vec::new(),
+      function_indices: HashMap::new(),
+    };
+    coverage.collect(instructions);
+    coverage
+  }
+
+  fn collect(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      let start = instruction.span.start;
+      self.statements.insert(start.offset, (start.line, 0));
+      let kind = match &instruction.kind {
+        InstructionKind::If { .. } => Some("if"),
+        InstructionKind::While { .. } => Some("while"),
+        _ => None,
+      };
+      if let Some(kind) = kind {
+        let branch = Branch {
+          line: start.line,
+          kind,
+          taken: [0, 0],
+        };
+        self.branches.insert(start.offset, branch);
+      }
+      match &instruction.kind {
+        InstructionKind::If { instructions, .. }
+        | InstructionKind::Else { instructions }
+        | InstructionKind::While { instructions, .. }
+        | InstructionKind::Scope { instructions } => self.collect(instructions),
+        InstructionKind::Function(function) => {
+          let index = self.functions.len();
+          self
+            .function_indices
+            .insert(function.name_span.start.offset, index);
+          self.functions.push(Function {
+            name: function.name.clone(),
+            line: start.line,
+            calls: 0,
+          });
+          self.collect(&function.instructions);
+        }
+        InstructionKind::Match { arms, .. } => {
+          for arm in arms {
+            self.collect(&arm.instructions);
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+
+  // How often the first instruction on every line ran
+  fn lines(&self) -> BTreeMap<usize, u64> {
+    let mut lines = BTreeMap::new();
+    for (line, hits) in self.statements.values() {
+      lines.entry(*line).or_insert(*hits);
+    }
+    lines
+  }
+
+  pub fn summary(&self) -> String {
+    let statements = self.statements.len();
+    let run = self
+      .statements
+      .values()
+      .filter(|(_, hits)| *hits > 0)
+      .count();
+    let branches = self.branches.len() * 2;
+    let taken: usize = self
+      .branches
+      .values()
+      .map(|branch| branch.taken.iter().filter(|taken| **taken > 0).count())
+      .sum();
+    let mut summary = format!(
+      "Coverage\n  statements {}\n  branches   {}\n",
+      percentage(run, statements),
+      percentage(taken, branches)
+    );
+    let missed: Vec<usize> = self
+      .lines()
+      .into_iter()
+      .filter(|(_, hits)| *hits == 0)
+      .map(|(line, _)| line)
+      .collect();
+    if !missed.is_empty() {
+      let _ = writeln!(summary, "Lines that never ran: {}", line_ranges(&missed));
+    }
+    for branch in self.branches.values() {
+      for (taken, outcome) in branch.taken.iter().zip(["true", "false"]) {
+        if *taken == 0 {
+          let _ = writeln!(
+            summary,
+            "The {} condition on line {} was never {}",
+            branch.kind, branch.line, outcome
+          );
+        }
+      }
+    }
+    summary
+  }
+
+  // The lcov tracefile format, as read by genhtml and most editors
+  pub fn lcov(&self, path: &str) -> String {
+    let mut lcov = format!("TN:\nSF:{}\n", path);
+    for function in &self.functions {
+      let _ = writeln!(lcov, "FN:{},{}", function.line, function.name);
+    }
+    for function in &self.functions {
+      let _ = writeln!(lcov, "FNDA:{},{}", function.calls, function.name);
+    }
+    let called = self
+      .functions
+      .iter()
+      .filter(|function| function.calls > 0)
+      .count();
+    let _ = writeln!(lcov, "FNF:{}\nFNH:{}", self.functions.len(), called);
+    let lines = self.lines();
+    let mut taken_branches = 0;
+    for (block, branch) in self.branches.values().enumerate() {
+      // A branch whose line never ran has no outcome at all
+      let ran = lines.get(&branch.line).is_some_and(|hits| *hits > 0);
+      for (index, taken) in branch.taken.iter().enumerate() {
+        let taken = match ran {
+          true => taken.to_string(),
+          false => "-".to_string(),
+        };
+        let _ = writeln!(lcov, "BRDA:{},{},{},{}", branch.line, block, index, taken);
+      }
+      taken_branches += branch.taken.iter().filter(|taken| **taken > 0).count();
+    }
+    let _ = writeln!(
+      lcov,
+      "BRF:{}\nBRH:{}",
+      self.branches.len() * 2,
+      taken_branches
+    );
+    for (line, hits) in &lines {
+      let _ = writeln!(lcov, "DA:{},{}", line, hits);
+    }
+    let hit = lines.values().filter(|hits| **hits > 0).count();
+    let _ = writeln!(lcov, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit);
+    lcov
+  }
+}
+
+fn percentage(part: usize, total: usize) -> String {
+  match total {
+    0 => "0/0".to_string(),
+    _ => format!(
+      "{}/{} ({:.1}%)",
+      part,
+      total,
+      part as f64 * 100.0 / total as f64
+    ),
+  }
+}
+
+// 1, 2, 3, 5 becomes 1-3, 5
+fn line_ranges(lines: &[usize]) -> String {
+  let mut ranges: Vec<(usize, usize)> = Vec::new();
+  for line in lines {
+    match ranges.last_mut() {
+      Some((_, end)) if *end + 1 == *line => *end = *line,
+      _ => ranges.push((*line, *line)),
+    }
+  }
+  let ranges: Vec<String> = ranges
+    .into_iter()
+    .map(|(start, end)| match start == end {
+      true => start.to_string(),
+      false => format!("{}-{}", start, end),
+    })
+    .collect();
+  ranges.join(", ")
+}
+
+impl Hooks for Coverage {
+  fn before_instruction(
+    &mut self,
+    instruction: &Instruction,
+    _vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    if let Some((_, hits)) = self.statements.get_mut(&instruction.span.start.offset) {
+      *hits += 1;
+    }
+    Ok(())
+  }
+
+  fn scope_enter(&mut self, scope: Scope) {
+    if let Scope::Function(function) = scope {
+      let offset = function.name_span.start.offset;
+      if let Some(index) = self.function_indices.get(&offset) {
+        self.functions[*index].calls += 1;
+      }
+    }
+  }
+
+  fn on_branch(&mut self, instruction: &Instruction, taken: bool) {
+    if let Some(branch) = self.branches.get_mut(&instruction.span.start.offset) {
+      branch.taken[usize::from(!taken)] += 1;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{interpreter, limits::Limits, resolver, samples};
+
+  const CODE: &str = "\
+fn sign(x) {
+  if (x < 0) {
+    return 0 - 1;
+  } else {
+    return 1;
+  };
+}
+fn unused() { return 2; }
+i = 0;
+while (i < 2) {
+  s = sign(i);
+  i += 1;
+}
+";
+
+  fn coverage(code: &str) -> Coverage {
+    let mut instructions = samples::parse(code);
+    resolver::resolve(&mut instructions);
+    let mut coverage = Coverage::new(&instructions);
+    interpreter::interpret_with_hooks(&instructions, Limits::default(), &[], &mut coverage)
+      .expect("The script failed");
+    coverage
+  }
+
+  #[test]
+  fn lcov_has_the_hits_of_every_line_branch_and_function() {
+    // Line 8 counts as run because defining the function is, the while on line 10 runs once and
+    // checks its condition three times
+    assert_eq!(
+      coverage(CODE).lcov("sign.fsh"),
+      "TN:\nSF:sign.fsh\n\
+       FN:1,sign\nFN:8,unused\nFNDA:2,sign\nFNDA:0,unused\nFNF:2\nFNH:1\n\
+       BRDA:2,0,0,0\nBRDA:2,0,1,2\nBRDA:10,1,0,2\nBRDA:10,1,1,1\nBRF:4\nBRH:3\n\
+       DA:1,1\nDA:2,2\nDA:3,0\nDA:4,2\nDA:5,2\nDA:8,1\nDA:9,1\nDA:10,1\nDA:11,2\nDA:12,2\n\
+       LF:10\nLH:9\nend_of_record\n"
+    );
+  }
+
+  #[test]
+  fn the_summary_names_what_never_ran() {
+    assert_eq!(
+      coverage(CODE).summary(),
+      "Coverage\n  statements 9/11 (81.8%)\n  branches   3/4 (75.0%)\n\
+       Lines that never ran: 3\nThe if condition on line 2 was never true\n"
+    );
+  }
+}
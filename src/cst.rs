@@ -0,0 +1,218 @@
+use std::{iter::Peekable, vec};
+
+use crate::tokenizer::{Keyword, Lexeme, Token};
+
+/*
+ The concrete syntax tree keeps every token of the source, comments included as their trivia,
+ grouped just enough to know where statements, brackets and blocks start and end. Unlike the
+ instructions from the parser it can be turned back into the exact code it was built from.
+*/
+#[derive(Debug)]
+pub struct Tree {
+  pub items: Vec<Item>,
+  pub end_of_file: Lexeme,
+}
+
+// A statement, a match arm or an enum variant, `end` is the ; or , after it
+#[derive(Debug)]
+pub struct Item {
+  pub nodes: Vec<Node>,
+  pub end: Option<Lexeme>,
+}
+
+#[derive(Debug)]
+pub enum Node {
+  Token(Lexeme),
+  // ( ) or [ ] and the tokens between them
+  Group {
+    open: Lexeme,
+    nodes: Vec<Node>,
+    close: Option<Lexeme>,
+  },
+  Block {
+    kind: BlockKind,
+    open: Lexeme,
+    items: Vec<Item>,
+    close: Option<Lexeme>,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockKind {
+  Statements,
+  Arms,
+  Variants,
+}
+
+impl Node {
+  pub fn first(&self) -> &Lexeme {
+    match self {
+      Node::Token(lexeme) => lexeme,
+      Node::Group { open, .. } | Node::Block { open, .. } => open,
+    }
+  }
+}
+
+pub fn build(tokens: Vec<Lexeme>) -> Tree {
+  let mut builder = Builder {
+    tokens: tokens.into_iter().peekable(),
+  };
+  let mut items = Vec::new();
+  loop {
+    items.extend(builder.items(BlockKind::Statements));
+    match builder.tokens.next() {
+      Some(lexeme) if lexeme.token == Token::EndOfFile => {
+        return Tree {
+          items,
+          end_of_file: lexeme,
+        }
+      }
+      // A } without a matching {
+      Some(lexeme) => items.push(Item {
+        nodes: vec![Node::Token(lexeme)],
+        end: None,
+      }),
+      None => panic!("Tokens do not end with EndOfFile"),
+    }
+  }
+}
+
+struct Builder {
+  tokens: Peekable<vec::IntoIter<Lexeme>>,
+}
+
+impl Builder {
+  fn peek(&mut self) -> Option<&Token> {
+    self.tokens.peek().map(|lexeme| &lexeme.token)
+  }
+
+  fn next_if_eq(&mut self, token: &Token) -> Option<Lexeme> {
+    self.tokens.next_if(|lexeme| lexeme.token == *token)
+  }
+
+  // Reads items up to the } closing the block they are in, or the end of the file
+  fn items(&mut self, kind: BlockKind) -> Vec<Item> {
+    let mut items = Vec::new();
+    while !matches!(
+      self.peek(),
+      None | Some(Token::ScopeClose | Token::EndOfFile)
+    ) {
+      let mut nodes = match kind {
+        BlockKind::Statements => self.statement(),
+        BlockKind::Arms => self.nodes_through_block(Vec::new(), BlockKind::Statements),
+        BlockKind::Variants => self.nodes_until(|token| *token == Token::Comma),
+      };
+      let end = match kind {
+        BlockKind::Statements => self.next_if_eq(&Token::EndStatement),
+        BlockKind::Arms | BlockKind::Variants => self.next_if_eq(&Token::Comma),
+      };
+      // Code that does not parse can leave a token no item starts with, like a ; between
+      // variants, which is kept as an item of its own
+      if nodes.is_empty() && end.is_none() {
+        nodes.push(Node::Token(self.tokens.next().unwrap()));
+      }
+      items.push(Item { nodes, end });
+    }
+    items
+  }
+
+  // Follows the parser in where a statement without a ; ends
+  fn statement(&mut self) -> Vec<Node> {
+    let first = match self.peek() {
+      Some(Token::EndStatement) => return Vec::new(),
+      Some(Token::ScopeOpen) => return vec![self.node(BlockKind::Statements)],
+      Some(token) => token.clone(),
+      None => return Vec::new(),
+    };
+    let mut nodes = vec![self.node(BlockKind::Statements)];
+    match first {
+      Token::Keyword(
+        Keyword::If | Keyword::While | Keyword::Else | Keyword::Fn | Keyword::Test,
+      ) => self.nodes_through_block(nodes, BlockKind::Statements),
+      Token::Keyword(Keyword::Match) => self.nodes_through_block(nodes, BlockKind::Arms),
+      Token::Keyword(Keyword::Enum) => self.nodes_through_block(nodes, BlockKind::Variants),
+      Token::Keyword(Keyword::Print | Keyword::Input | Keyword::Assert) => {
+        if !matches!(
+          self.peek(),
+          None | Some(Token::EndStatement | Token::ScopeClose | Token::EndOfFile)
+        ) {
+          nodes.push(self.node(BlockKind::Statements));
+        }
+        nodes
+      }
+      Token::Keyword(Keyword::Break) => nodes,
+      _ => {
+        nodes.extend(self.nodes_until(|_| false));
+        nodes
+      }
+    }
+  }
+
+  fn nodes_through_block(&mut self, mut nodes: Vec<Node>, kind: BlockKind) -> Vec<Node> {
+    while !matches!(
+      self.peek(),
+      None | Some(Token::EndStatement | Token::ScopeClose | Token::EndOfFile)
+    ) {
+      let node = self.node(kind);
+      let is_block = matches!(node, Node::Block { .. });
+      nodes.push(node);
+      if is_block {
+        break;
+      }
+    }
+    nodes
+  }
+
+  fn nodes_until(&mut self, stop: impl Fn(&Token) -> bool) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    while let Some(token) = self.peek() {
+      if stop(token)
+        || matches!(
+          token,
+          Token::EndStatement | Token::ScopeClose | Token::EndOfFile
+        )
+      {
+        break;
+      }
+      nodes.push(self.node(BlockKind::Statements));
+    }
+    nodes
+  }
+
+  // A single token, or a whole group or block when it is an opening bracket. `kind` is used
+  // when the node turns out to be a block
+  fn node(&mut self, kind: BlockKind) -> Node {
+    let lexeme = self.tokens.next().expect("No token left to read");
+    let close = match lexeme.token {
+      Token::BracketOpen => Token::BracketClose,
+      Token::SquareBracketOpen => Token::SquareBracketClose,
+      Token::ScopeOpen => {
+        let items = self.items(kind);
+        return Node::Block {
+          kind,
+          open: lexeme,
+          items,
+          close: self.next_if_eq(&Token::ScopeClose),
+        };
+      }
+      _ => return Node::Token(lexeme),
+    };
+    let mut nodes = Vec::new();
+    while let Some(token) = self.peek() {
+      if *token == close
+        || matches!(
+          token,
+          Token::EndStatement | Token::ScopeOpen | Token::ScopeClose | Token::EndOfFile
+        )
+      {
+        break;
+      }
+      nodes.push(self.node(BlockKind::Statements));
+    }
+    Node::Group {
+      open: lexeme,
+      nodes,
+      close: self.next_if_eq(&close),
+    }
+  }
+}
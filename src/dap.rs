@@ -0,0 +1,610 @@
+use std::{
+  fs,
+  io::{self, BufRead, Write},
+  sync::mpsc::{self, Receiver, TryRecvError},
+  thread,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+  debugger::{Breakpoints, Debugger, Frontend, Paused, Request, Resume, Stop},
+  interpreter::{self, InterpreterError},
+  limits::Limits,
+  lsp::{read_message, write_message},
+  parser::{self, Instruction},
+  resolver,
+  tokenizer::{self, Position},
+};
+
+// The script is the only thread
+const THREAD: u64 = 1;
+
+/*
+ A debug adapter speaking the Debug Adapter Protocol over `input` and `output`, stdin and stdout
+ for `fish dap`. It debugs one script, given by the `program` of the launch request, and runs it
+ once the configuration is done.
+
+ Messages are read on a thread of their own, so pause requests and new breakpoints get through
+ while the script is running. What the script prints is sent as output events, and it can not
+ read input because stdin is taken by the protocol.
+*/
+pub fn serve(input: impl BufRead + Send + 'static, output: impl Write) -> io::Result<()> {
+  let (sender, receiver) = mpsc::channel();
+  thread::spawn(move || {
+    let mut input = input;
+    while let Ok(Some(message)) = read_message(&mut input) {
+      let Ok(message) = serde_json::from_slice::<Value>(&message) else {
+        continue;
+      };
+      if sender.send(message).is_err() {
+        break;
+      }
+    }
+  });
+  let mut client = Client {
+    receiver,
+    output,
+    seq: 0,
+    lines_start_at_1: true,
+    columns_start_at_1: true,
+    disconnected: false,
+  };
+  let mut program: Option<Program> = None;
+  let mut breakpoints = Breakpoints::default();
+  let mut stop_on_entry = false;
+  while !client.disconnected {
+    let Ok(request) = client.receiver.recv() else {
+      break;
+    };
+    let arguments = &request["arguments"];
+    match request["command"].as_str().unwrap_or_default() {
+      "initialize" => {
+        client.lines_start_at_1 = arguments["linesStartAt1"].as_bool() != Some(false);
+        client.columns_start_at_1 = arguments["columnsStartAt1"].as_bool() != Some(false);
+        client.respond(
+          &request,
+          Ok(json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsConditionalBreakpoints": true,
+            "supportsTerminateRequest": true,
+          })),
+        )?;
+      }
+      "launch" => {
+        let path = arguments["program"].as_str().unwrap_or_default();
+        let args = arguments["args"]
+          .as_array()
+          .into_iter()
+          .flatten()
+          .filter_map(|arg| Some(arg.as_str()?.to_string()))
+          .collect();
+        match Program::load(path, args) {
+          Ok(loaded) => {
+            stop_on_entry = arguments["stopOnEntry"].as_bool() == Some(true);
+            breakpoints = Breakpoints::new(&loaded.instructions);
+            program = Some(loaded);
+            client.respond(&request, Ok(Value::Null))?;
+            // Breakpoints can only be checked once the script is known, so they get asked for
+            // after the launch
+            client.event("initialized", Value::Null)?;
+          }
+          Err(error) => client.respond(&request, Err(error))?,
+        }
+      }
+      "setBreakpoints" => {
+        let body = client.set_breakpoints(program.as_ref(), &mut breakpoints, arguments);
+        client.respond(&request, Ok(body))?;
+      }
+      "setExceptionBreakpoints" => client.respond(&request, Ok(Value::Null))?,
+      "threads" => client.respond(&request, Ok(threads()))?,
+      "configurationDone" => {
+        client.respond(&request, Ok(Value::Null))?;
+        let Some(program) = program.take() else {
+          client.event("terminated", Value::Null)?;
+          continue;
+        };
+        let breakpoints = std::mem::take(&mut breakpoints);
+        let session = Session {
+          client: &mut client,
+          program: &program,
+          error: None,
+          terminated: false,
+        };
+        let mut debugger = Debugger::new(session, breakpoints, stop_on_entry);
+        let limits = Limits {
+          allow_input: false,
+          ..Limits::default()
+        };
+        let result = interpreter::interpret_with_hooks(
+          &program.instructions,
+          limits,
+          &program.args,
+          &mut debugger,
+        );
+        if let Some(error) = debugger.frontend.error.take() {
+          return Err(error);
+        }
+        let exit_code = match result {
+          Ok(()) => 0,
+          Err(InterpreterError::Stopped) => 1,
+          Err(InterpreterError::Exit(code)) => code,
+          Err(error) => {
+            let message = format!("Error interpreting code: {}\n", error);
+            client.event("output", json!({ "category": "stderr", "output": message }))?;
+            1
+          }
+        };
+        client.event("exited", json!({ "exitCode": exit_code }))?;
+        client.event("terminated", Value::Null)?;
+      }
+      "disconnect" | "terminate" => {
+        client.respond(&request, Ok(Value::Null))?;
+        client.disconnected = true;
+      }
+      command => client.respond(&request, Err(format!("Unknown command '{}'", command)))?,
+    }
+  }
+  Ok(())
+}
+
+struct Program {
+  path: String,
+  instructions: Vec<Instruction>,
+  // The `args` of the launch request
+  args: Vec<String>,
+}
+
+impl Program {
+  fn load(path: &str, args: Vec<String>) -> Result<Self, String> {
+    let code =
+      fs::read_to_string(path).map_err(|error| format!("Error reading '{}': {}", path, error))?;
+    let tokens = tokenizer::tokenize(&code)
+      .map_err(|error| format!("Error tokenizing code at {}:{}", path, error))?;
+    let (mut instructions, diagnostics) = parser::parse(tokens, &Limits::default());
+    if let Some(diagnostic) = diagnostics.first() {
+      return Err(format!("Error parsing code at {}:{}", path, diagnostic));
+    }
+    resolver::resolve(&mut instructions);
+    Ok(Self {
+      path: path.to_string(),
+      instructions,
+      args,
+    })
+  }
+}
+
+struct Client<W: Write> {
+  receiver: Receiver<Value>,
+  output: W,
+  seq: u64,
+  lines_start_at_1: bool,
+  columns_start_at_1: bool,
+  disconnected: bool,
+}
+
+impl<W: Write> Client<W> {
+  fn send(&mut self, mut message: Value) -> io::Result<()> {
+    self.seq += 1;
+    message["seq"] = json!(self.seq);
+    write_message(&mut self.output, &message)
+  }
+
+  fn respond(&mut self, request: &Value, body: Result<Value, String>) -> io::Result<()> {
+    let mut response = json!({
+      "type": "response",
+      "request_seq": request["seq"],
+      "command": request["command"],
+      "success": body.is_ok(),
+    });
+    match body {
+      Ok(Value::Null) => (),
+      Ok(body) => response["body"] = body,
+      Err(message) => response["message"] = json!(message),
+    }
+    self.send(response)
+  }
+
+  fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+    let mut message = json!({ "type": "event", "event": event });
+    if !body.is_null() {
+      message["body"] = body;
+    }
+    self.send(message)
+  }
+
+  // Lines as the client counts them
+  fn line(&self, line: usize) -> usize {
+    if self.lines_start_at_1 {
+      line
+    } else {
+      line - 1
+    }
+  }
+
+  fn column(&self, column: usize) -> usize {
+    if self.columns_start_at_1 {
+      column
+    } else {
+      column - 1
+    }
+  }
+
+  // Replaces all breakpoints, they are only verified when they are in the script being debugged
+  fn set_breakpoints(
+    &self,
+    program: Option<&Program>,
+    breakpoints: &mut Breakpoints,
+    arguments: &Value,
+  ) -> Value {
+    let requested = arguments["breakpoints"]
+      .as_array()
+      .cloned()
+      .unwrap_or_default();
+    let path = arguments["source"]["path"].as_str();
+    let in_program = program.is_some_and(|program| Some(program.path.as_str()) == path);
+    if in_program {
+      breakpoints.clear();
+    }
+    let offset = if self.lines_start_at_1 { 0 } else { 1 };
+    let mut verified = Vec::new();
+    for breakpoint in requested {
+      let line = breakpoint["line"].as_u64().unwrap_or_default() as usize + offset;
+      let result = match in_program {
+        true => breakpoints.set(line, breakpoint["condition"].as_str()),
+        false => Err("The breakpoint is not in the script being debugged".to_string()),
+      };
+      verified.push(match result {
+        Ok(line) => json!({ "verified": true, "line": self.line(line) }),
+        Err(message) => {
+          json!({ "verified": false, "line": self.line(line), "message": message })
+        }
+      });
+    }
+    json!({ "breakpoints": verified })
+  }
+}
+
+fn threads() -> Value {
+  json!({ "threads": [{ "id": THREAD, "name": "main" }] })
+}
+
+// The client while the script runs
+struct Session<'c, W: Write> {
+  client: &'c mut Client<W>,
+  program: &'c Program,
+  // Writing to the client failed, the hooks have no way to return it
+  error: Option<io::Error>,
+  // The client asked to end the script
+  terminated: bool,
+}
+
+impl<W: Write> Session<'_, W> {
+  fn send(&mut self, result: io::Result<()>) {
+    if let Err(error) = result {
+      self.error.get_or_insert(error);
+      self.terminated = true;
+    }
+  }
+
+  // Answers the requests that can be answered whether the script is running or not, returns the
+  // request back otherwise
+  fn handle(&mut self, request: Value, breakpoints: &mut Breakpoints) -> Option<Value> {
+    let result = match request["command"].as_str().unwrap_or_default() {
+      "setBreakpoints" => {
+        let body =
+          self
+            .client
+            .set_breakpoints(Some(self.program), breakpoints, &request["arguments"]);
+        self.client.respond(&request, Ok(body))
+      }
+      "threads" => self.client.respond(&request, Ok(threads())),
+      "setExceptionBreakpoints" => self.client.respond(&request, Ok(Value::Null)),
+      "terminate" => {
+        self.terminated = true;
+        self.client.respond(&request, Ok(Value::Null))
+      }
+      "disconnect" => {
+        self.terminated = true;
+        self.client.disconnected = true;
+        self.client.respond(&request, Ok(Value::Null))
+      }
+      _ => return Some(request),
+    };
+    self.send(result);
+    None
+  }
+
+  fn stack_trace(&self, state: &Paused) -> Value {
+    let name = std::path::Path::new(&self.program.path)
+      .file_name()
+      .map(|name| name.to_string_lossy().into_owned());
+    let frames: Vec<Value> = state
+      .stack()
+      .into_iter()
+      .enumerate()
+      .map(|(id, frame)| {
+        let start = frame.span.map_or(
+          Position {
+            offset: 0,
+            line: 1,
+            column: 1,
+          },
+          |span| span.start,
+        );
+        json!({
+          "id": id,
+          "name": frame.name,
+          "line": self.client.line(start.line),
+          "column": self.client.column(start.column),
+          "source": { "name": name, "path": self.program.path },
+        })
+      })
+      .collect();
+    json!({ "totalFrames": frames.len(), "stackFrames": frames })
+  }
+
+  // Answers a request while paused, returns how to go on when it was one that resumes
+  fn paused_request(&mut self, request: &Value, state: &mut Paused) -> Option<Resume> {
+    let arguments = &request["arguments"];
+    let frame = arguments["frameId"].as_u64().unwrap_or_default() as usize;
+    let (body, resume) = match request["command"].as_str().unwrap_or_default() {
+      "continue" => (
+        Ok(json!({ "allThreadsContinued": true })),
+        Some(Resume::Continue),
+      ),
+      "next" => (Ok(Value::Null), Some(Resume::StepOver)),
+      "stepIn" => (Ok(Value::Null), Some(Resume::StepIn)),
+      "stepOut" => (Ok(Value::Null), Some(Resume::StepOut)),
+      "pause" => (Ok(Value::Null), None),
+      "stackTrace" => (Ok(self.stack_trace(state)), None),
+      // Every stack frame has a single scope, the variable reference is the frame id plus one
+      "scopes" => (
+        Ok(json!({ "scopes": [{
+          "name": "Locals",
+          "variablesReference": frame + 1,
+          "expensive": false,
+        }] })),
+        None,
+      ),
+      "variables" => {
+        let reference = arguments["variablesReference"].as_u64().unwrap_or_default() as usize;
+        let variables: Vec<Value> = match reference.checked_sub(1) {
+          Some(frame) => state
+            .variables(frame)
+            .into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value, "variablesReference": 0 }))
+            .collect(),
+          None => Vec::new(),
+        };
+        (Ok(json!({ "variables": variables })), None)
+      }
+      "evaluate" => {
+        let expression = arguments["expression"].as_str().unwrap_or_default();
+        let body = state
+          .evaluate(frame, expression)
+          .map(|result| json!({ "result": result, "variablesReference": 0 }));
+        (body, None)
+      }
+      command => (Err(format!("Unknown command '{}'", command)), None),
+    };
+    let result = self.client.respond(request, body);
+    self.send(result);
+    resume
+  }
+}
+
+impl<W: Write> Frontend for Session<'_, W> {
+  fn poll(&mut self, breakpoints: &mut Breakpoints) -> Option<Request> {
+    loop {
+      if self.terminated {
+        return Some(Request::Stop);
+      }
+      let request = match self.client.receiver.try_recv() {
+        Ok(request) => request,
+        Err(TryRecvError::Empty) => return None,
+        Err(TryRecvError::Disconnected) => return Some(Request::Stop),
+      };
+      let Some(request) = self.handle(request, breakpoints) else {
+        continue;
+      };
+      if request["command"] == "pause" {
+        let result = self.client.respond(&request, Ok(Value::Null));
+        self.send(result);
+        return Some(Request::Pause);
+      }
+      let result = self
+        .client
+        .respond(&request, Err("The script is running".to_string()));
+      self.send(result);
+    }
+  }
+
+  fn paused(&mut self, stop: Stop, state: &mut Paused) -> Resume {
+    let (reason, text) = match stop {
+      Stop::Entry => ("entry", None),
+      Stop::Breakpoint => ("breakpoint", None),
+      Stop::Step => ("step", None),
+      Stop::Pause => ("pause", None),
+      Stop::ConditionFailed(error) => ("breakpoint", Some(error)),
+      Stop::Error(error) => ("exception", Some(error)),
+    };
+    let mut body = json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true });
+    if let Some(text) = text {
+      body["text"] = json!(text);
+    }
+    let result = self.client.event("stopped", body);
+    self.send(result);
+    loop {
+      if self.terminated {
+        return Resume::Stop;
+      }
+      let Ok(request) = self.client.receiver.recv() else {
+        return Resume::Stop;
+      };
+      let Some(request) = self.handle(request, state.breakpoints) else {
+        continue;
+      };
+      if let Some(resume) = self.paused_request(&request, state) {
+        return resume;
+      }
+    }
+  }
+
+  fn print(&mut self, text: &str) {
+    let output = format!("{}\n", text);
+    let result = self
+      .client
+      .event("output", json!({ "category": "stdout", "output": output }));
+    self.send(result);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::samples;
+  use std::io::{BufReader, PipeReader, PipeWriter};
+
+  const CODE: &str = "\
+fn double(x) {
+  y = x * 2;
+  return y;
+}
+a = 1;
+b = double(a);
+print(b);
+";
+
+  // The editor side, sending requests while the adapter runs on a thread of its own
+  struct Client {
+    input: PipeWriter,
+    output: BufReader<PipeReader>,
+    seq: u64,
+    // Messages that were read while waiting for another one
+    unread: Vec<Value>,
+  }
+
+  impl Client {
+    fn next(&mut self, wanted: impl Fn(&Value) -> bool) -> Value {
+      if let Some(index) = self.unread.iter().position(&wanted) {
+        return self.unread.remove(index);
+      }
+      loop {
+        let message = read_message(&mut self.output)
+          .expect("The adapter sent a broken message")
+          .expect("The adapter stopped sending");
+        let message = serde_json::from_slice(&message).expect("The adapter sent invalid JSON");
+        if wanted(&message) {
+          return message;
+        }
+        self.unread.push(message);
+      }
+    }
+
+    // Sends a request and waits for the response to it
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+      self.seq += 1;
+      let request = json!({
+        "seq": self.seq,
+        "type": "request",
+        "command": command,
+        "arguments": arguments,
+      });
+      write_message(&mut self.input, &request).unwrap();
+      let seq = self.seq;
+      let response = self.next(|message| message["request_seq"] == json!(seq));
+      assert_eq!(response["success"], json!(true), "{}", response);
+      response
+    }
+
+    fn event(&mut self, event: &str) -> Value {
+      self.next(|message| message["event"] == json!(event))
+    }
+
+    // The function and line of every stack frame
+    fn stack(&mut self) -> Vec<String> {
+      let response = self.request("stackTrace", json!({ "threadId": THREAD }));
+      let frames = response["body"]["stackFrames"].as_array().unwrap();
+      frames
+        .iter()
+        .map(|frame| format!("{}:{}", frame["name"].as_str().unwrap(), frame["line"]))
+        .collect()
+    }
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn a_session_from_initialize_to_disconnect() {
+    let dir = samples::temp_dir("dap");
+    let path = dir.join("double.fsh");
+    fs::write(&path, CODE).unwrap();
+    let path = path.to_string_lossy().into_owned();
+    let (input, to_adapter) = io::pipe().unwrap();
+    let (from_adapter, output) = io::pipe().unwrap();
+    let adapter = thread::spawn(move || serve(BufReader::new(input), output));
+    let mut client = Client {
+      input: to_adapter,
+      output: BufReader::new(from_adapter),
+      seq: 0,
+      unread: Vec::new(),
+    };
+
+    let response = client.request("initialize", json!({ "linesStartAt1": true }));
+    assert_eq!(
+      response["body"]["supportsConditionalBreakpoints"],
+      json!(true)
+    );
+    client.request("launch", json!({ "program": path }));
+    client.event("initialized");
+    let response = client.request(
+      "setBreakpoints",
+      json!({
+        "source": { "path": path },
+        "breakpoints": [{ "line": 2, "condition": "x == 1" }, { "line": 4 }],
+      }),
+    );
+    assert_eq!(
+      response["body"]["breakpoints"],
+      json!([{ "verified": true, "line": 2 }, { "verified": true, "line": 5 }])
+    );
+    client.request("configurationDone", Value::Null);
+
+    let stopped = client.event("stopped");
+    assert_eq!(stopped["body"]["reason"], json!("breakpoint"));
+    assert_eq!(client.stack(), ["main:5"]);
+    client.request("continue", json!({ "threadId": THREAD }));
+    client.event("stopped");
+    assert_eq!(client.stack(), ["double:2", "main:6"]);
+    let response = client.request("scopes", json!({ "frameId": 0 }));
+    let reference = response["body"]["scopes"][0]["variablesReference"].clone();
+    let response = client.request("variables", json!({ "variablesReference": reference }));
+    assert_eq!(
+      response["body"]["variables"],
+      json!([{ "name": "x", "value": "1", "variablesReference": 0 }])
+    );
+    let response = client.request("evaluate", json!({ "expression": "x + 10", "frameId": 0 }));
+    assert_eq!(response["body"]["result"], json!("11"));
+
+    client.request("next", json!({ "threadId": THREAD }));
+    assert_eq!(client.event("stopped")["body"]["reason"], json!("step"));
+    assert_eq!(client.stack(), ["double:3", "main:6"]);
+    client.request("stepOut", json!({ "threadId": THREAD }));
+    client.event("stopped");
+    assert_eq!(client.stack(), ["main:7"]);
+    client.request("continue", json!({ "threadId": THREAD }));
+    let output = client.event("output");
+    assert_eq!(
+      output["body"],
+      json!({ "category": "stdout", "output": "2\n" })
+    );
+    assert_eq!(client.event("exited")["body"], json!({ "exitCode": 0 }));
+    client.event("terminated");
+    client.request("disconnect", Value::Null);
+    adapter
+      .join()
+      .unwrap()
+      .expect("The adapter failed to write");
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
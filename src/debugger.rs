@@ -0,0 +1,851 @@
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  io::{self, BufRead, Write},
+  ops::Range,
+  rc::Rc,
+};
+
+use crate::{
+  interpreter::{self, Hooks, Inspector, InterpreterError, Scope},
+  limits::Limits,
+  parser::{self, Instruction, InstructionKind, Pattern, Value},
+  resolver::Slot,
+  tokenizer::{self, Span},
+};
+
+// Why the script paused
+#[derive(Debug, Clone)]
+pub enum Stop {
+  Entry,
+  Breakpoint,
+  Step,
+  Pause,
+  // The condition of a breakpoint could not be evaluated, which counts as a hit
+  ConditionFailed(String),
+  // The script is about to end because of this error
+  Error(String),
+}
+
+// How to go on after a pause
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+  Continue,
+  // Runs until the next line, without stopping inside the functions called on the way
+  StepOver,
+  StepIn,
+  // Runs until the function that is running returns
+  StepOut,
+  Stop,
+}
+
+// What a frontend can ask for while the script runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+  Pause,
+  Stop,
+}
+
+// Where the user is, like a terminal or an editor speaking the Debug Adapter Protocol
+pub trait Frontend {
+  // Called before every instruction, so it has to be quick
+  fn poll(&mut self, _breakpoints: &mut Breakpoints) -> Option<Request> {
+    None
+  }
+  // Waits for the user to decide how to go on, they can look around through `state` meanwhile
+  fn paused(&mut self, stop: Stop, state: &mut Paused) -> Resume;
+  fn print(&mut self, text: &str);
+}
+
+/*
+ Line breakpoints, optionally with a condition that is evaluated in the function that hits it.
+ A breakpoint can only be on a line an instruction starts on, one on an empty line moves down
+ to the next instruction.
+*/
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+  lines: BTreeSet<usize>,
+  set: BTreeMap<usize, Option<Value>>,
+}
+
+impl Breakpoints {
+  pub fn new(instructions: &[Instruction]) -> Self {
+    let mut breakpoints = Self::default();
+    breakpoints.collect_lines(instructions);
+    breakpoints
+  }
+
+  fn collect_lines(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      self.lines.insert(instruction.span.start.line);
+      match &instruction.kind {
+        InstructionKind::If { instructions, .. }
+        | InstructionKind::Else { instructions }
+        | InstructionKind::While { instructions, .. }
+        | InstructionKind::Scope { instructions } => self.collect_lines(instructions),
+        InstructionKind::Function(function) => self.collect_lines(&function.instructions),
+        InstructionKind::Match { arms, .. } => {
+          for arm in arms {
+            self.collect_lines(&arm.instructions);
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+
+  // Returns the line the breakpoint ended up on
+  pub fn set(&mut self, line: usize, condition: Option<&str>) -> Result<usize, String> {
+    let condition = match condition.map(str::trim) {
+      Some(condition) if !condition.is_empty() => Some(parse_expression(condition)?),
+      _ => None,
+    };
+    let line = match self.lines.range(line..).next() {
+      Some(line) => *line,
+      None => return Err(format!("There is no code on or after line {}", line)),
+    };
+    self.set.insert(line, condition);
+    Ok(line)
+  }
+
+  pub fn remove(&mut self, line: usize) -> bool {
+    self.set.remove(&line).is_some()
+  }
+
+  pub fn clear(&mut self) {
+    self.set.clear();
+  }
+
+  pub fn lines(&self) -> impl Iterator<Item = usize> + '_ {
+    self.set.keys().copied()
+  }
+}
+
+// A frame on the stack of the VM, with the names of its slots
+#[derive(Debug)]
+struct Frame {
+  // Set on the frame a function call starts with, and on the frame of the script itself
+  function: Option<String>,
+  names: Rc<Vec<Option<String>>>,
+  // The instruction that is executing in this frame
+  span: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+  Run,
+  Entry,
+  Pause,
+  Stop,
+  // Where the step started, as a line and the amount of function calls running
+  StepIn((usize, usize)),
+  StepOver((usize, usize)),
+  StepOut(usize),
+}
+
+/*
+ The part of a debugger that does not care where the user is. It keeps a stack of frames next
+ to the one of the VM, and decides before every instruction whether to pause there.
+
+ Stepping works on lines, a step never stops twice on the same line of the same call.
+*/
+pub struct Debugger<F: Frontend> {
+  pub frontend: F,
+  breakpoints: Breakpoints,
+  frames: Vec<Frame>,
+  // Frames get pushed for every block that runs, so the names are only looked up once per block
+  names: HashMap<(usize, u8), Rc<Vec<Option<String>>>>,
+  mode: Mode,
+  // The line and amount of calls of the last instruction
+  last: Option<(usize, usize)>,
+}
+
+impl<F: Frontend> Debugger<F> {
+  pub fn new(frontend: F, breakpoints: Breakpoints, stop_on_entry: bool) -> Self {
+    Self {
+      frontend,
+      breakpoints,
+      frames: Vec::new(),
+      names: HashMap::new(),
+      mode: if stop_on_entry {
+        Mode::Entry
+      } else {
+        Mode::Run
+      },
+      last: None,
+    }
+  }
+
+  // The function calls that are running, the script itself does not count
+  fn calls(&self) -> usize {
+    let functions = self
+      .frames
+      .iter()
+      .filter(|frame| frame.function.is_some())
+      .count();
+    functions.saturating_sub(1)
+  }
+
+  fn breakpoint(&mut self, line: usize, vm: &mut Inspector) -> Option<Stop> {
+    let mut condition = match self.breakpoints.set.get(&line)? {
+      Some(condition) => condition.clone(),
+      None => return Some(Stop::Breakpoint),
+    };
+    resolve(
+      &mut condition,
+      &self.frames,
+      function_frames(&self.frames, 0),
+    );
+    match vm.condition(&condition) {
+      Ok(true) => Some(Stop::Breakpoint),
+      Ok(false) => None,
+      Err(error) => Some(Stop::ConditionFailed(error.to_string())),
+    }
+  }
+
+  fn pause(&mut self, stop: Stop, vm: &mut Inspector) -> Resume {
+    let mut state = Paused {
+      frames: &self.frames,
+      breakpoints: &mut self.breakpoints,
+      vm,
+    };
+    self.frontend.paused(stop, &mut state)
+  }
+}
+
+impl<F: Frontend> Hooks for Debugger<F> {
+  fn before_instruction(
+    &mut self,
+    instruction: &Instruction,
+    vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    let line = instruction.span.start.line;
+    let calls = self.calls();
+    let location = (line, calls);
+    if let Some(frame) = self.frames.last_mut() {
+      frame.span = Some(instruction.span);
+    }
+    let previous = self.last.replace(location);
+    match self.frontend.poll(&mut self.breakpoints) {
+      Some(Request::Pause) => self.mode = Mode::Pause,
+      Some(Request::Stop) => self.mode = Mode::Stop,
+      None => (),
+    }
+    let stop = match self.mode {
+      Mode::Run => None,
+      Mode::Entry => Some(Stop::Entry),
+      Mode::Pause => Some(Stop::Pause),
+      Mode::Stop => return Err(InterpreterError::Stopped),
+      Mode::StepIn(from) => (location != from).then_some(Stop::Step),
+      Mode::StepOver(from) => (location != from && calls <= from.1).then_some(Stop::Step),
+      Mode::StepOut(from) => (calls < from).then_some(Stop::Step),
+    };
+    // Only once per line, even when it holds several instructions
+    let stop = match stop {
+      None if previous != Some(location) => self.breakpoint(line, vm),
+      stop => stop,
+    };
+    let Some(stop) = stop else {
+      return Ok(());
+    };
+    self.mode = match self.pause(stop, vm) {
+      Resume::Continue => Mode::Run,
+      Resume::StepIn => Mode::StepIn(location),
+      Resume::StepOver => Mode::StepOver(location),
+      Resume::StepOut => Mode::StepOut(calls),
+      Resume::Stop => return Err(InterpreterError::Stopped),
+    };
+    Ok(())
+  }
+
+  fn on_error(&mut self, error: &InterpreterError, vm: &mut Inspector) {
+    if !matches!(error, InterpreterError::Stopped | InterpreterError::Exit(_)) {
+      self.pause(Stop::Error(error.to_string()), vm);
+    }
+  }
+
+  fn scope_enter(&mut self, scope: Scope) {
+    let (key, function) = match scope {
+      Scope::Block(instructions) => ((instructions.as_ptr() as usize, 0), None),
+      Scope::MatchArm(arm) => ((arm as *const _ as usize, 1), None),
+      Scope::Function(function) => ((function as *const _ as usize, 2), Some(&function.name)),
+    };
+    let names = self
+      .names
+      .entry(key)
+      .or_insert_with(|| Rc::new(slot_names(scope)))
+      .clone();
+    let function = match (function, self.frames.is_empty()) {
+      (Some(name), _) => Some(name.clone()),
+      (None, true) => Some("main".to_string()),
+      (None, false) => None,
+    };
+    self.frames.push(Frame {
+      function,
+      names,
+      span: None,
+    });
+  }
+
+  fn scope_exit(&mut self) {
+    self.frames.pop();
+  }
+
+  fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+    self.frontend.print(text);
+    Ok(())
+  }
+}
+
+// A function call on the stack, or the script itself
+#[derive(Debug)]
+pub struct StackFrame {
+  pub name: String,
+  // The instruction it is at, None before it ran one
+  pub span: Option<Span>,
+}
+
+// What a frontend can look at while the script is paused. Stack frames are counted from the
+// innermost function call, which is 0
+pub struct Paused<'p, 'v, 'a> {
+  frames: &'p [Frame],
+  pub breakpoints: &'p mut Breakpoints,
+  vm: &'p mut Inspector<'v, 'a>,
+}
+
+impl Paused<'_, '_, '_> {
+  pub fn stack(&self) -> Vec<StackFrame> {
+    let mut stack = Vec::new();
+    for index in 0.. {
+      let frames = function_frames(self.frames, index);
+      if frames.is_empty() {
+        break;
+      }
+      let name = self.frames[frames.start]
+        .function
+        .clone()
+        .unwrap_or_default();
+      let span = self.frames[frames]
+        .iter()
+        .rev()
+        .find_map(|frame| frame.span);
+      stack.push(StackFrame { name, span });
+    }
+    stack
+  }
+
+  // The variables that have a value in a stack frame, inner blocks hide the variables of the outer
+  // ones with the same name
+  pub fn variables(&self, frame: usize) -> Vec<(String, String)> {
+    let mut variables: Vec<(String, String)> = Vec::new();
+    for index in function_frames(self.frames, frame).rev() {
+      let depth = self.frames.len() - index - 1;
+      for (slot, name) in self.frames[index].names.iter().enumerate().rev() {
+        let Some(name) = name else {
+          continue;
+        };
+        if variables.iter().any(|(seen, _)| seen == name) {
+          continue;
+        }
+        if let Some(value) = self.vm.value(Slot { depth, index: slot }) {
+          variables.push((name.clone(), value));
+        }
+      }
+    }
+    variables.reverse();
+    variables
+  }
+
+  // Runs an expression as if it was written where the stack frame is, so it can assign to the
+  // variables that are there as well
+  pub fn evaluate(&mut self, frame: usize, expression: &str) -> Result<String, String> {
+    let mut value = parse_expression(expression)?;
+    resolve(&mut value, self.frames, function_frames(self.frames, frame));
+    self.vm.evaluate(&value).map_err(|error| error.to_string())
+  }
+}
+
+// The frames that belong to a stack frame, from where the call started to where the next one did
+fn function_frames(frames: &[Frame], frame: usize) -> Range<usize> {
+  let starts: Vec<usize> = (0..frames.len())
+    .filter(|index| frames[*index].function.is_some())
+    .collect();
+  match starts.len().checked_sub(frame + 1) {
+    Some(start) => starts[start]..starts.get(start + 1).copied().unwrap_or(frames.len()),
+    None => 0..0,
+  }
+}
+
+fn parse_expression(text: &str) -> Result<Value, String> {
+  let tokens = tokenizer::tokenize(text).map_err(|error| error.to_string())?;
+  let (instructions, diagnostics) = parser::parse(tokens, &Limits::default());
+  if let Some(diagnostic) = diagnostics.first() {
+    return Err(diagnostic.to_string());
+  }
+  let mut instructions = instructions.into_iter();
+  match (instructions.next(), instructions.next()) {
+    (
+      Some(Instruction {
+        kind: InstructionKind::Value { value },
+        ..
+      }),
+      None,
+    ) => Ok(value),
+    _ => Err("Expected a single expression".to_string()),
+  }
+}
+
+// Points the variables in the value to the slots of the variables with those names in `range`,
+// the innermost ones first
+fn resolve(value: &mut Value, frames: &[Frame], range: Range<usize>) {
+  match value {
+    Value::Identifier(identifier) => {
+      identifier.slot = range.rev().find_map(|index| {
+        let names = &frames[index].names;
+        let slot = names
+          .iter()
+          .rposition(|name| name.as_deref() == Some(identifier.name.as_str()))?;
+        Some(Slot {
+          depth: frames.len() - index - 1,
+          index: slot,
+        })
+      });
+    }
+    Value::Expression(expression) => {
+      resolve(expression.get_left_mut(), frames, range.clone());
+      if let Some(right) = expression.get_right_mut() {
+        resolve(right, frames, range);
+      }
+    }
+    Value::Call { arguments, .. } | Value::List(arguments) => {
+      for argument in arguments {
+        resolve(argument, frames, range.clone());
+      }
+    }
+    Value::Field { value, .. } => resolve(value, frames, range),
+    Value::Index { value, index, .. } => {
+      resolve(value, frames, range.clone());
+      resolve(index, frames, range);
+    }
+    Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None => (),
+  }
+}
+
+// The name of the variable in every slot of a frame, found through the identifiers the resolver
+// pointed at it
+fn slot_names(scope: Scope) -> Vec<Option<String>> {
+  let mut names = Vec::new();
+  match scope {
+    Scope::Block(instructions) => collect_names(instructions, 0, &mut names),
+    Scope::MatchArm(arm) => {
+      collect_pattern_names(&arm.pattern, 0, &mut names);
+      if let Some(guard) = &arm.guard {
+        collect_value_names(guard, 0, &mut names);
+      }
+      collect_names(&arm.instructions, 0, &mut names);
+    }
+    Scope::Function(function) => {
+      for parameter in &function.parameters {
+        name_slot(&parameter.variable, 0, &mut names);
+      }
+      collect_names(&function.instructions, 0, &mut names);
+    }
+  }
+  names
+}
+
+fn name_slot(identifier: &parser::Identifier, depth: usize, names: &mut Vec<Option<String>>) {
+  if let Some(slot) = identifier.slot.filter(|slot| slot.depth == depth) {
+    if names.len() <= slot.index {
+      names.resize(slot.index + 1, None);
+    }
+    names[slot.index] = Some(identifier.name.clone());
+  }
+}
+
+// Every nested block is a frame deeper, functions have frames of their own
+fn collect_names(instructions: &[Instruction], depth: usize, names: &mut Vec<Option<String>>) {
+  for instruction in instructions {
+    match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      }
+      | InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        collect_value_names(condition, depth, names);
+        collect_names(instructions, depth + 1, names);
+      }
+      InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+        collect_names(instructions, depth + 1, names);
+      }
+      InstructionKind::Value { value } | InstructionKind::Print { message: value } => {
+        collect_value_names(value, depth, names);
+      }
+      InstructionKind::Input { variable } => name_slot(variable, depth, names),
+      InstructionKind::Match { value, arms } => {
+        collect_value_names(value, depth, names);
+        for arm in arms {
+          collect_pattern_names(&arm.pattern, depth + 1, names);
+          if let Some(guard) = &arm.guard {
+            collect_value_names(guard, depth + 1, names);
+          }
+          collect_names(&arm.instructions, depth + 1, names);
+        }
+      }
+      InstructionKind::Let {
+        variable, value, ..
+      } => {
+        collect_value_names(value, depth, names);
+        name_slot(variable, depth, names);
+      }
+      InstructionKind::Return { value: Some(value) } => collect_value_names(value, depth, names),
+      InstructionKind::Assert { condition, message } => {
+        collect_value_names(condition, depth, names);
+        if let Some(message) = message {
+          collect_value_names(message, depth, names);
+        }
+      }
+      InstructionKind::Return { value: None }
+      | InstructionKind::Function(_)
+      | InstructionKind::Test { .. }
+      | InstructionKind::Break
+      | InstructionKind::Enum { .. } => (),
+    }
+  }
+}
+
+fn collect_pattern_names(pattern: &Pattern, depth: usize, names: &mut Vec<Option<String>>) {
+  match pattern {
+    Pattern::Identifier(identifier) => name_slot(identifier, depth, names),
+    Pattern::Variant { fields, .. } => {
+      for field in fields {
+        collect_pattern_names(field, depth, names);
+      }
+    }
+    Pattern::Wildcard | Pattern::Literal(_) => (),
+  }
+}
+
+fn collect_value_names(value: &Value, depth: usize, names: &mut Vec<Option<String>>) {
+  match value {
+    Value::Identifier(identifier) => name_slot(identifier, depth, names),
+    Value::Expression(expression) => {
+      collect_value_names(expression.get_left(), depth, names);
+      if let Some(right) = expression.get_right() {
+        collect_value_names(right, depth, names);
+      }
+    }
+    Value::Call { arguments, .. } | Value::List(arguments) => {
+      for argument in arguments {
+        collect_value_names(argument, depth, names);
+      }
+    }
+    Value::Field { value, .. } => collect_value_names(value, depth, names),
+    Value::Index { value, index, .. } => {
+      collect_value_names(value, depth, names);
+      collect_value_names(index, depth, names);
+    }
+    Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None => (),
+  }
+}
+
+/*
+ `--debug`, a debugger on the terminal. It pauses before the first instruction and then reads
+ commands from stdin, talking on stderr so stdout only has what the script prints.
+*/
+pub fn debug_in_terminal(
+  path: &str,
+  code: &str,
+  instructions: &[Instruction],
+  limits: Limits,
+  args: &[String],
+) -> Result<(), InterpreterError> {
+  let terminal = Terminal {
+    path,
+    lines: code.lines().collect(),
+    detached: false,
+  };
+  let mut debugger = Debugger::new(terminal, Breakpoints::new(instructions), true);
+  eprintln!("Debugging {}, type 'help' for the commands", path);
+  interpreter::interpret_with_hooks(instructions, limits, args, &mut debugger)
+}
+
+const TERMINAL_HELP: &str = "\
+continue, c               run until the next breakpoint
+next, n                   run until the next line, stepping over function calls
+step, s                   run until the next line, stepping into function calls
+out, o                    run until the function returns
+break, b <line> [if <condition>]
+                          pause on a line, only when the condition holds if there is one
+delete, d <line>          remove the breakpoint on a line
+breakpoints               list the breakpoints
+stack, bt                 show the function calls that are running
+vars, v [<frame>]         show the variables of a function call, 0 is the innermost one
+print, p <expression>     evaluate an expression where the script is paused
+quit, q                   stop the script";
+
+struct Terminal<'c> {
+  path: &'c str,
+  lines: Vec<&'c str>,
+  // Set once stdin is closed, after which the script runs to the end
+  detached: bool,
+}
+
+impl Terminal<'_> {
+  fn show(&self, span: Option<Span>) {
+    let Some(span) = span else {
+      return;
+    };
+    let line = span.start.line;
+    eprintln!("  at {}:{}", self.path, span.start);
+    if let Some(source) = self.lines.get(line - 1) {
+      eprintln!("{:>5} | {}", line, source);
+    }
+  }
+
+  // Handles a command that does not resume the script, None when it does
+  fn command(&mut self, input: &str, state: &mut Paused) -> Option<Resume> {
+    let (command, argument) = input.split_once(' ').unwrap_or((input, ""));
+    let argument = argument.trim();
+    match command {
+      "continue" | "c" => return Some(Resume::Continue),
+      "next" | "n" => return Some(Resume::StepOver),
+      "step" | "s" => return Some(Resume::StepIn),
+      "out" | "o" => return Some(Resume::StepOut),
+      "quit" | "q" => return Some(Resume::Stop),
+      "break" | "b" => {
+        let (line, condition) = match argument.split_once(" if ") {
+          Some((line, condition)) => (line, Some(condition)),
+          None => (argument, None),
+        };
+        match line.trim().parse() {
+          Ok(line) => match state.breakpoints.set(line, condition) {
+            Ok(line) => eprintln!("Breakpoint on line {}", line),
+            Err(error) => eprintln!("{}", error),
+          },
+          Err(_) => eprintln!("Usage: break <line> [if <condition>]"),
+        }
+      }
+      "delete" | "d" => match argument.parse() {
+        Ok(line) if state.breakpoints.remove(line) => eprintln!("Removed the breakpoint"),
+        Ok(line) => eprintln!("There is no breakpoint on line {}", line),
+        Err(_) => eprintln!("Usage: delete <line>"),
+      },
+      "breakpoints" => {
+        for line in state.breakpoints.lines() {
+          eprintln!("line {}", line);
+        }
+      }
+      "stack" | "bt" => {
+        for (index, frame) in state.stack().iter().enumerate() {
+          match frame.span {
+            Some(span) => eprintln!("#{} {} at {}:{}", index, frame.name, self.path, span.start),
+            None => eprintln!("#{} {}", index, frame.name),
+          }
+        }
+      }
+      "vars" | "v" => match if argument.is_empty() {
+        Ok(0)
+      } else {
+        argument.parse()
+      } {
+        Ok(frame) => {
+          for (name, value) in state.variables(frame) {
+            eprintln!("{} = {}", name, value);
+          }
+        }
+        Err(_) => eprintln!("Usage: vars [<frame>]"),
+      },
+      "print" | "p" => match state.evaluate(0, argument) {
+        Ok(value) => eprintln!("{}", value),
+        Err(error) => eprintln!("Error: {}", error),
+      },
+      "help" | "h" => eprintln!("{}", TERMINAL_HELP),
+      "" => (),
+      _ => eprintln!(
+        "Unknown command '{}', type 'help' for the commands",
+        command
+      ),
+    }
+    None
+  }
+}
+
+impl Frontend for Terminal<'_> {
+  fn paused(&mut self, stop: Stop, state: &mut Paused) -> Resume {
+    if self.detached {
+      return Resume::Continue;
+    }
+    match stop {
+      Stop::Entry => eprintln!("Paused at the start"),
+      Stop::Breakpoint => eprintln!("Hit a breakpoint"),
+      Stop::Step => (),
+      Stop::Pause => eprintln!("Paused"),
+      Stop::ConditionFailed(error) => eprintln!("The breakpoint condition failed: {}", error),
+      Stop::Error(error) => eprintln!("Error interpreting code: {}", error),
+    }
+    self.show(state.stack().first().and_then(|frame| frame.span));
+    loop {
+      eprint!("(debug) ");
+      let _ = io::stderr().flush();
+      let mut input = String::new();
+      match io::stdin().lock().read_line(&mut input) {
+        Ok(0) | Err(_) => {
+          self.detached = true;
+          return Resume::Continue;
+        }
+        Ok(_) => (),
+      }
+      if let Some(resume) = self.command(input.trim(), state) {
+        return resume;
+      }
+    }
+  }
+
+  fn print(&mut self, text: &str) {
+    println!("{}", text);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::samples;
+
+  const CODE: &str = "\
+fn double(x) {
+  y = x * 2;
+  return y;
+}
+a = 1;
+b = double(a);
+print(b);
+c = b + 1;
+";
+
+  // A user that answers every pause with the next of its resumes, noting down where the script
+  // was and what it could see
+  struct Script {
+    resumes: Vec<Resume>,
+    seen: Vec<String>,
+    printed: Vec<String>,
+  }
+
+  impl Frontend for Script {
+    fn paused(&mut self, stop: Stop, state: &mut Paused) -> Resume {
+      let stack: Vec<String> = state
+        .stack()
+        .iter()
+        .map(|frame| match frame.span {
+          Some(span) => format!("{}:{}", frame.name, span.start.line),
+          None => frame.name.clone(),
+        })
+        .collect();
+      let variables: Vec<String> = state
+        .variables(0)
+        .iter()
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect();
+      self.seen.push(format!(
+        "{:?} at {} with {}",
+        stop,
+        stack.join(" < "),
+        variables.join(", ")
+      ));
+      if !self.resumes.is_empty() {
+        return self.resumes.remove(0);
+      }
+      Resume::Continue
+    }
+
+    fn print(&mut self, text: &str) {
+      self.printed.push(text.to_string());
+    }
+  }
+
+  // Where the script paused, and how it ended
+  fn debug(
+    breakpoints: &[(usize, Option<&str>)],
+    stop_on_entry: bool,
+    resumes: &[Resume],
+  ) -> (Vec<String>, Result<(), InterpreterError>) {
+    let instructions = samples::instructions(CODE);
+    let mut set = Breakpoints::new(&instructions);
+    for (line, condition) in breakpoints {
+      set
+        .set(*line, *condition)
+        .expect("The breakpoint can not be set");
+    }
+    let script = Script {
+      resumes: resumes.to_vec(),
+      seen: Vec::new(),
+      printed: Vec::new(),
+    };
+    let mut debugger = Debugger::new(script, set, stop_on_entry);
+    let result =
+      interpreter::interpret_with_hooks(&instructions, Limits::default(), &[], &mut debugger);
+    assert_eq!(debugger.frontend.printed, ["2"]);
+    (debugger.frontend.seen, result)
+  }
+
+  #[test]
+  fn breakpoints_pause_where_they_are_and_when_their_condition_holds() {
+    let (seen, result) = debug(
+      &[
+        (2, None),
+        (4, Some("b == 2")),
+        (7, Some("b == 3")),
+        (8, Some("b == 2")),
+      ],
+      false,
+      &[],
+    );
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(
+      seen,
+      [
+        // The breakpoint after the function moved to the next line with code, where b is not
+        // assigned yet, which counts as a hit
+        "ConditionFailed(\"Variable 'b' is not defined\") at main:5 with ",
+        "Breakpoint at double:2 < main:6 with x = 1",
+        "Breakpoint at main:8 with a = 1, b = 2",
+      ]
+    );
+    let mut breakpoints = Breakpoints::new(&samples::instructions(CODE));
+    assert_eq!(breakpoints.set(4, None), Ok(5));
+    assert!(breakpoints.set(9, None).is_err());
+    assert!(breakpoints.set(1, Some("x ==")).is_err());
+  }
+
+  #[test]
+  fn steps_go_into_over_and_out_of_calls() {
+    let (seen, result) = debug(
+      &[],
+      true,
+      &[
+        Resume::StepOver,
+        Resume::StepOver,
+        Resume::StepIn,
+        Resume::StepIn,
+        Resume::StepOut,
+        Resume::StepOver,
+        Resume::Stop,
+      ],
+    );
+    assert!(matches!(result, Err(InterpreterError::Stopped)));
+    assert_eq!(
+      seen,
+      [
+        "Entry at main:1 with ",
+        "Step at main:5 with ",
+        "Step at main:6 with a = 1",
+        "Step at double:2 < main:6 with x = 1",
+        "Step at double:3 < main:6 with x = 1, y = 2",
+        "Step at main:7 with a = 1, b = 2",
+        "Step at main:8 with a = 1, b = 2",
+      ]
+    );
+  }
+}
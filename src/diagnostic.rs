@@ -0,0 +1,22 @@
+use std::fmt;
+
+use crate::tokenizer::Span;
+
+// An error and the code it was found at
+#[derive(Debug)]
+pub struct Diagnostic<E> {
+  pub error: E,
+  pub span: Span,
+}
+
+impl<E> Diagnostic<E> {
+  pub fn new(error: E, span: Span) -> Self {
+    Self { error, span }
+  }
+}
+
+impl<E: fmt::Display> fmt::Display for Diagnostic<E> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {}", self.span.start, self.error)
+  }
+}
@@ -0,0 +1,472 @@
+use crate::{
+  cst::{self, BlockKind, Item, Node, Tree},
+  limits::Limits,
+  parser,
+  tokenizer::{self, Keyword, Lexeme, Operator, Token, Trivia, TriviaKind},
+};
+
+const INDENT: &str = "  ";
+
+/*
+ Writes a tree back out in the canonical style: two spaces of indentation, a statement per line
+ ending in a ; unless it ends in a block, spaces around binary operators and after commas, and
+ `} else {` on a single line. Comments stay next to the tokens they were next to, and one blank
+ line is kept wherever the source had at least one. Formatting formatted code changes nothing.
+*/
+pub fn format(tree: &Tree, source: &str) -> String {
+  let mut formatter = Formatter {
+    source,
+    output: String::new(),
+    indent: 0,
+    continuation: false,
+    last_line: 0,
+    needs_newline: false,
+    block_start: true,
+    previous: None,
+    pending: Vec::new(),
+  };
+  formatter.items(&tree.items, BlockKind::Statements);
+  formatter.leading_comments(&tree.end_of_file, true);
+  formatter.flush_trailing_comments();
+  if !formatter.output.is_empty() && !formatter.output.ends_with('\n') {
+    formatter.output.push('\n');
+  }
+  formatter.output
+}
+
+// Only code that parses gets formatted, and the result has to parse to the same tokens. The
+// formatter only adds and removes semicolons where a statement ends anyway and commas right
+// before or after a }, those are left out
+pub fn format_code(code: &str) -> Result<String, Vec<String>> {
+  let limits = Limits::default();
+  let parse = |code: &str| {
+    let tokens = tokenizer::tokenize(code).map_err(|error| vec![error.to_string()])?;
+    let (_, diagnostics) = parser::parse(tokens.clone(), &limits);
+    if !diagnostics.is_empty() {
+      return Err(
+        diagnostics
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<_>>(),
+      );
+    }
+    Ok(tokens)
+  };
+  let significant = |tokens: &[Lexeme]| {
+    let tokens = tokens
+      .iter()
+      .map(|lexeme| lexeme.token.clone())
+      .filter(|token| *token != Token::EndStatement)
+      .collect::<Vec<_>>();
+    let mut significant = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+      let next_to_block = tokens.get(index + 1) == Some(&Token::ScopeClose)
+        || index > 0 && tokens[index - 1] == Token::ScopeClose;
+      if *token != Token::Comma || !next_to_block {
+        significant.push(token.clone());
+      }
+    }
+    significant
+  };
+  let tokens = parse(code)?;
+  let expected = significant(&tokens);
+  let formatted = format(&cst::build(tokens), code);
+  match parse(&formatted) {
+    Ok(tokens) if significant(&tokens) == expected => Ok(formatted),
+    _ => Err(vec![
+      "the formatted code would do something different, leaving it as it is".to_string(),
+    ]),
+  }
+}
+
+struct Formatter<'a> {
+  source: &'a str,
+  output: String,
+  indent: usize,
+  // The current line continues a statement from the line before, so it is indented once more
+  continuation: bool,
+  // The source line the last written token or comment ended on
+  last_line: usize,
+  // A line comment was written, nothing else can go on this line
+  needs_newline: bool,
+  // Nothing was written in the current block yet, so no blank line goes before the next item
+  block_start: bool,
+  // The last token written, which decides the spacing before the next one
+  previous: Option<Token>,
+  // Comments trailing the last token, written once it is known whether a ; or , goes first
+  pending: Vec<Trivia>,
+}
+
+impl<'a> Formatter<'a> {
+  fn items(&mut self, items: &[Item], kind: BlockKind) {
+    let count = items.iter().filter(|item| !item.nodes.is_empty()).count();
+    let mut written = 0;
+    let mut after_block = false;
+    for item in items {
+      if item.nodes.is_empty() {
+        // An empty statement, only its comments are kept
+        if let Some(end) = &item.end {
+          self.leading_comments(end, true);
+          self.pending.extend(end.trailing.iter().cloned());
+        }
+        continue;
+      }
+      written += 1;
+
+      let first = item.nodes[0].first();
+      let joins_else = after_block
+        && first.token == Token::Keyword(Keyword::Else)
+        && first.leading.is_empty()
+        && self.pending.is_empty();
+      self.nodes(&item.nodes, !joins_else);
+
+      let ends_with_block = matches!(item.nodes.last(), Some(Node::Block { .. }));
+      let punctuation = match kind {
+        BlockKind::Statements if !ends_with_block => Some(";"),
+        BlockKind::Statements => None,
+        BlockKind::Arms | BlockKind::Variants if written < count => Some(","),
+        BlockKind::Arms => None,
+        BlockKind::Variants => Some(","),
+      };
+      match (&item.end, punctuation) {
+        (Some(end), Some(punctuation)) if !end.leading.is_empty() => {
+          self.token(end, punctuation, false)
+        }
+        // Goes right after the last token, before the comments trailing it
+        (end, Some(punctuation)) => {
+          self.output.push_str(punctuation);
+          if let Some(end) = end {
+            self.pending.extend(end.trailing.iter().cloned());
+          }
+        }
+        (Some(end), None) => {
+          self.leading_comments(end, true);
+          self.pending.extend(end.trailing.iter().cloned());
+        }
+        (None, None) => (),
+      }
+      after_block = ends_with_block;
+    }
+  }
+
+  fn nodes(&mut self, nodes: &[Node], statement_start: bool) {
+    for (index, node) in nodes.iter().enumerate() {
+      let starts_line = statement_start && index == 0;
+      match node {
+        Node::Token(lexeme) => self.token(lexeme, self.text(lexeme), starts_line),
+        Node::Group { open, nodes, close } => {
+          self.token(open, self.text(open), starts_line);
+          self.nodes(nodes, false);
+          if let Some(close) = close {
+            self.token(close, self.text(close), false);
+          }
+        }
+        Node::Block {
+          kind,
+          open,
+          items,
+          close,
+        } => {
+          self.token(open, "{", starts_line);
+          self.block(*kind, open, items, close.as_ref());
+        }
+      }
+    }
+  }
+
+  fn block(&mut self, kind: BlockKind, open: &Lexeme, items: &[Item], close: Option<&Lexeme>) {
+    let close_comments = close.is_some_and(|close| !close.leading.is_empty());
+    let empty = items
+      .iter()
+      .all(|item| item.nodes.is_empty() && !item.end.as_ref().is_some_and(has_comments));
+    if empty && open.trailing.is_empty() && !close_comments {
+      if let Some(close) = close {
+        self.token(close, "}", false);
+      }
+      return;
+    }
+    if kind == BlockKind::Variants && !close_comments && !items_have_comments(items) {
+      // enum Shape { Circle(r), Rect(w, h) }
+      let count = items.iter().filter(|item| !item.nodes.is_empty()).count();
+      for (index, item) in items
+        .iter()
+        .filter(|item| !item.nodes.is_empty())
+        .enumerate()
+      {
+        self.nodes(&item.nodes, false);
+        if index + 1 < count {
+          self.output.push(',');
+        }
+      }
+      if let Some(close) = close {
+        self.token(close, "}", false);
+      }
+      return;
+    }
+
+    self.indent += 1;
+    self.block_start = true;
+    self.items(items, kind);
+    if let Some(close) = close {
+      self.leading_comments(close, true);
+    }
+    self.indent -= 1;
+    self.block_start = true;
+    self.start_line(0);
+    if let Some(close) = close {
+      self.write("}");
+      self.wrote(close);
+    }
+  }
+
+  // Writes a token with its comments, `starts_line` puts it at the start of a new line
+  fn token(&mut self, lexeme: &Lexeme, text: &str, starts_line: bool) {
+    self.flush_trailing_comments();
+    self.leading_comments(lexeme, starts_line);
+    let has_leading = !lexeme.leading.is_empty();
+    if self.needs_newline
+      || (starts_line && !has_leading)
+      || (has_leading && lexeme.span.start.line > self.last_line)
+    {
+      self.break_line(lexeme.span.start.line, starts_line);
+    } else if has_leading
+      || (!self.at_line_start()
+        && self
+          .previous
+          .as_ref()
+          .is_some_and(|previous| space_between(previous, &lexeme.token)))
+    {
+      self.write(" ");
+    }
+    self.write(text);
+    self.wrote(lexeme);
+  }
+
+  fn wrote(&mut self, lexeme: &Lexeme) {
+    self.last_line = lexeme.span.end.line;
+    self.previous = Some(lexeme.token.clone());
+    self.pending.extend(lexeme.trailing.iter().cloned());
+  }
+
+  // Writes the comments before a token on lines of their own, block comments that shared a
+  // line stay together
+  fn leading_comments(&mut self, lexeme: &Lexeme, statement_level: bool) {
+    for comment in &lexeme.leading {
+      self.flush_trailing_comments();
+      if self.at_line_start() || self.needs_newline || comment.span.start.line > self.last_line {
+        self.break_line(comment.span.start.line, statement_level);
+      } else {
+        self.write(" ");
+      }
+      self.comment(comment);
+    }
+  }
+
+  fn flush_trailing_comments(&mut self) {
+    let mut comments = std::mem::take(&mut self.pending);
+    // Comments trailing different tokens can end up on one line, where nothing can follow a
+    // line comment
+    comments.sort_by_key(|comment| comment.kind == TriviaKind::LineComment);
+    for comment in comments {
+      self.write(" ");
+      self.comment(&comment);
+    }
+  }
+
+  fn comment(&mut self, comment: &Trivia) {
+    self.write(&comment.text);
+    self.last_line = comment.span.end.line;
+    self.needs_newline = comment.kind == TriviaKind::LineComment;
+  }
+
+  // Starts a line for a statement, keeping a blank line before it if the source had one
+  fn start_line(&mut self, source_line: usize) {
+    self.flush_trailing_comments();
+    if !self.output.is_empty() {
+      if !self.at_line_start() {
+        self.output.push('\n');
+      }
+      if !self.block_start && source_line > self.last_line + 1 {
+        self.output.push('\n');
+      }
+    }
+    self.block_start = false;
+    self.continuation = false;
+    self.needs_newline = false;
+    self.previous = None;
+  }
+
+  fn break_line(&mut self, source_line: usize, statement_level: bool) {
+    if statement_level {
+      self.start_line(source_line);
+    } else {
+      self.continue_line();
+    }
+  }
+
+  // Breaks the line in the middle of a statement
+  fn continue_line(&mut self) {
+    if !self.at_line_start() {
+      self.output.push('\n');
+    }
+    self.continuation = true;
+    self.needs_newline = false;
+  }
+
+  fn write(&mut self, text: &str) {
+    if self.at_line_start() {
+      if text == " " {
+        return;
+      }
+      for _ in 0..self.indent + self.continuation as usize {
+        self.output.push_str(INDENT);
+      }
+    }
+    self.output.push_str(text);
+  }
+
+  fn at_line_start(&self) -> bool {
+    self.output.is_empty() || self.output.ends_with('\n')
+  }
+
+  // Tokens are written exactly as they are in the source, so numbers like 1_000 keep their look
+  fn text(&self, lexeme: &Lexeme) -> &'a str {
+    &self.source[lexeme.span.start.offset..lexeme.span.end.offset]
+  }
+}
+
+fn items_have_comments(items: &[Item]) -> bool {
+  items.iter().any(|item| {
+    item.end.as_ref().is_some_and(has_comments) || item.nodes.iter().any(node_has_comments)
+  })
+}
+
+fn node_has_comments(node: &Node) -> bool {
+  match node {
+    Node::Token(lexeme) => has_comments(lexeme),
+    Node::Group { open, nodes, close } => {
+      has_comments(open)
+        || nodes.iter().any(node_has_comments)
+        || close.as_ref().is_some_and(has_comments)
+    }
+    Node::Block {
+      open, items, close, ..
+    } => {
+      has_comments(open) || items_have_comments(items) || close.as_ref().is_some_and(has_comments)
+    }
+  }
+}
+
+fn has_comments(lexeme: &Lexeme) -> bool {
+  !lexeme.leading.is_empty() || !lexeme.trailing.is_empty()
+}
+
+fn space_between(previous: &Token, next: &Token) -> bool {
+  match (previous, next) {
+    (
+      _,
+      Token::Comma
+      | Token::Colon
+      | Token::EndStatement
+      | Token::BracketClose
+      | Token::SquareBracketClose
+      | Token::Dot
+      | Token::OptionalDot,
+    ) => false,
+    (
+      Token::BracketOpen
+      | Token::SquareBracketOpen
+      | Token::Dot
+      | Token::OptionalDot
+      | Token::Operator(Operator::Not),
+      _,
+    ) => false,
+    (Token::ScopeOpen, Token::ScopeClose) => false,
+    // Calls and indexing, print(x) and xs[0]
+    (
+      Token::Identifier(_)
+      | Token::Number(_)
+      | Token::String(_)
+      | Token::Boolean(_)
+      | Token::None
+      | Token::BracketClose
+      | Token::SquareBracketClose
+      | Token::Keyword(Keyword::Print | Keyword::Assert),
+      Token::BracketOpen | Token::SquareBracketOpen,
+    ) => false,
+    _ => true,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{samples, syntax_json};
+  use proptest::{collection::vec, prelude::*, sample::select, sample::Index};
+
+  // What can go between any two tokens without changing what the code does, with a space first so
+  // a comment never runs into an operator like /
+  const INSERTIONS: [&str; 6] = [" ", "\t", "\n", "\n\n\n", " // note\n", " /* note */"];
+
+  fn ast(code: &str) -> serde_json::Value {
+    let mut json = syntax_json::ast_to_json(&samples::parse(code));
+    samples::without_spans(&mut json);
+    json
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn formatting_formatted_samples_changes_nothing() {
+    let samples = samples::all();
+    assert!(samples
+      .iter()
+      .any(|(name, _)| name.starts_with("README.md")));
+    for (name, code) in samples {
+      let once = format_code(&code).unwrap_or_else(|errors| panic!("{}: {:?}", name, errors));
+      let twice = format_code(&once).unwrap_or_else(|errors| panic!("{}: {:?}", name, errors));
+      assert_eq!(once, twice, "Formatting {} again changed it", name);
+    }
+  }
+
+  proptest! {
+    // The samples are few, it takes thousands of insertions to reach the odd places in them
+    #![proptest_config(ProptestConfig::with_cases(2000))]
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn formatting_keeps_the_code_and_its_comments(
+      (name, code) in select(samples::all()),
+      insertions in vec((any::<Index>(), select(&INSERTIONS[..])), 1..20),
+    ) {
+      let tokens = tokenizer::tokenize(&code).unwrap();
+      let mut boundaries = tokens.iter().map(|lexeme| lexeme.span.start.offset).collect::<Vec<_>>();
+      boundaries.push(code.len());
+      // Numbered, so every comment can be looked for in the formatted code
+      let mut insertions = insertions
+        .iter()
+        .enumerate()
+        .map(|(number, (index, text))| {
+          let offset = boundaries[index.index(boundaries.len())];
+          (offset, text.replace("note", &format!("note <{}>", number)))
+        })
+        .collect::<Vec<_>>();
+      insertions.sort_by_key(|(offset, _)| *offset);
+      let mut messy = code.clone();
+      for (offset, text) in insertions.iter().rev() {
+        messy.insert_str(*offset, text);
+      }
+
+      let formatted = format_code(&messy)
+        .unwrap_or_else(|errors| panic!("{}: {:?} in\n{}", name, errors, messy));
+      prop_assert_eq!(ast(&formatted), ast(&code), "{}:\n{}", name, formatted);
+      let again = format_code(&formatted).unwrap();
+      prop_assert_eq!(&again, &formatted, "Formatting {} again changed it", name);
+      for (_, text) in &insertions {
+        if let Some(start) = text.find("note") {
+          let note = &text[start..=text.find('>').unwrap()];
+          prop_assert_eq!(formatted.matches(note).count(), 1, "{} lost {}:\n{}", name, note, formatted);
+        }
+      }
+    }
+  }
+}
@@ -1,44 +1,467 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+  cell::OnceCell,
+  collections::HashMap,
+  fmt,
+  io::{self, Write},
+  rc::Rc,
+  time::Instant,
+};
 
 use crate::{
-  number::Number,
-  parser::{Expression, Instruction, Value},
-  tokenizer::Operator,
+  limits::{Limit, Limits},
+  number::{ArithmeticError, Number},
+  parser::{
+    Condition, Function, Identifier, Instruction, InstructionKind, MatchArm, Pattern, Value,
+  },
+  resolver::Slot,
+  tokenizer::{Operator, Span},
 };
-pub fn interpret(instructions: Vec<Instruction>) -> Result<(), InterpreterError> {
-  let mut vm = VM::new();
+// `args` is what the script sees as the list `args`, the arguments it was started with
+pub fn interpret(
+  instructions: Vec<Instruction>,
+  limits: Limits,
+  args: &[String],
+) -> Result<(), InterpreterError> {
+  let mut vm = VM::new(limits);
+  vm.set_args(args)?;
   vm.execute_new_instructions(&instructions)?;
   Ok(())
 }
-#[derive(Debug, Clone)]
+
+// Runs the instructions like `interpret`, telling the hooks about everything that happens
+pub fn interpret_with_hooks(
+  instructions: &[Instruction],
+  limits: Limits,
+  args: &[String],
+  hooks: &mut dyn Hooks,
+) -> Result<(), InterpreterError> {
+  let mut vm = VM::new(limits);
+  vm.set_args(args)?;
+  vm.hooks = Some(hooks);
+  vm.execute_new_instructions(instructions)?;
+  Ok(())
+}
+
+/*
+ Keeps the variables, functions and enums of a script between the pieces of code that are run in
+ it, for the repl. Every piece has to be resolved by the same `Resolver` as the ones before it.
+ The pieces are kept in a `Code` that lives as long as the session, functions defined in one can
+ be called from the later ones.
+*/
+pub struct Session<'a> {
+  vm: VM<'a>,
+  // The last piece that was run, new ones go after it
+  last: &'a Code,
+}
+
+impl<'a> Session<'a> {
+  pub fn new(code: &'a Code, limits: Limits) -> Self {
+    let mut vm = VM::new(limits);
+    vm.stack.push(StackFrame::default());
+    Self { vm, last: code }
+  }
+
+  // Returns the value of the last statement when it is an expression other than an assignment,
+  // so it can be shown
+  pub fn run(
+    &mut self,
+    instructions: Vec<Instruction>,
+  ) -> Result<Option<String>, InterpreterError> {
+    self.last = self.last.push(instructions);
+    let instructions = self.last.instructions.as_slice();
+    let shown = match instructions.last().map(|last| &last.kind) {
+      Some(InstructionKind::Value { value }) if !is_assignment(value) => Some(value),
+      _ => None,
+    };
+    let Some(value) = shown else {
+      self.vm.run(instructions)?;
+      return Ok(None);
+    };
+    self.vm.run(&instructions[..instructions.len() - 1])?;
+    self.vm.step()?;
+    match self.vm.evaluate_value(value)? {
+      Data::None => Ok(None),
+      data => Ok(Some(data.describe())),
+    }
+  }
+}
+
+// The pieces of code of a session, one after the other. Pieces are only ever added, so the ones
+// that are there can be borrowed while more come in
+#[derive(Default)]
+pub struct Code {
+  instructions: Vec<Instruction>,
+  next: OnceCell<Box<Code>>,
+}
+
+impl Code {
+  // Adds the piece at the end and returns it
+  fn push(&self, instructions: Vec<Instruction>) -> &Code {
+    let mut last = self;
+    while let Some(next) = last.next.get() {
+      last = next;
+    }
+    let piece = Code {
+      instructions,
+      next: OnceCell::new(),
+    };
+    last.next.get_or_init(|| Box::new(piece))
+  }
+}
+
+// Dropping the pieces one by one, a long session would otherwise recurse for every piece
+impl Drop for Code {
+  fn drop(&mut self) {
+    let mut next = self.next.take();
+    while let Some(mut piece) = next {
+      next = piece.next.take();
+    }
+  }
+}
+
+fn is_assignment(value: &Value) -> bool {
+  match value {
+    Value::Expression(expr) => expr.get_operator().is_assignment(),
+    _ => false,
+  }
+}
+
+/*
+ Runs one test block of a script in a VM of its own. The functions and enums of the script are
+ defined first, nothing else outside of the test runs, so every test starts from the same state.
+*/
+pub fn run_test(
+  instructions: &[Instruction],
+  test: &Instruction,
+  limits: Limits,
+  hooks: &mut dyn Hooks,
+) -> Result<(), InterpreterError> {
+  let InstructionKind::Test {
+    instructions: body, ..
+  } = &test.kind
+  else {
+    panic!("Instruction is not a test");
+  };
+  let mut vm = VM::new(limits);
+  vm.hooks = Some(hooks);
+  for instruction in instructions {
+    if matches!(
+      instruction.kind,
+      InstructionKind::Function(_) | InstructionKind::Enum { .. }
+    ) {
+      vm.run(std::slice::from_ref(instruction))?;
+    }
+  }
+  vm.execute_new_instructions(body)?;
+  Ok(())
+}
+
+/*
+ Lets a debugger, profiler, coverage report or trace follow along while a script runs. Every
+ frame the VM pushes is announced by `scope_enter` and `scope_exit`, so the hooks can keep their
+ own stack next to it and know which variables are in which frame. Returning an error from
+ `before_instruction` stops the script.
+
+ The hooks are not called for anything that runs through the `Inspector` they are given.
+*/
+pub trait Hooks {
+  fn before_instruction(
+    &mut self,
+    _instruction: &Instruction,
+    _vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    Ok(())
+  }
+  // Called once, with the frames still as they were when the error happened
+  fn on_error(&mut self, _error: &InterpreterError, _vm: &mut Inspector) {}
+  fn scope_enter(&mut self, _scope: Scope) {}
+  fn scope_exit(&mut self) {}
+  // Which way an if or while went after checking its condition, a while checks it every time
+  // around
+  fn on_branch(&mut self, _instruction: &Instruction, _taken: bool) {}
+  // Every value that gets evaluated, `after_value` is left out when evaluating it failed
+  fn before_value(&mut self, _value: &Value) {}
+  fn after_value(&mut self, _value: &Value, _result: Described) {}
+  // A variable was assigned by let, input or an assignment like = or +=
+  fn on_assign(&mut self, _variable: &Identifier, _value: Described) {}
+  fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+    print_line(text)
+  }
+}
+
+// What print does without hooks that print somewhere else
+fn print_line(text: &str) -> Result<(), InterpreterError> {
+  writeln!(io::stdout(), "{}", text)
+    .map_err(|error| InterpreterError::Io("write output", Rc::new(error)))
+}
+
+// Nothing to tell when there are no hooks
+impl<H: Hooks> Hooks for Option<H> {
+  fn before_instruction(
+    &mut self,
+    instruction: &Instruction,
+    vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    match self {
+      Some(hooks) => hooks.before_instruction(instruction, vm),
+      None => Ok(()),
+    }
+  }
+  fn on_error(&mut self, error: &InterpreterError, vm: &mut Inspector) {
+    if let Some(hooks) = self {
+      hooks.on_error(error, vm);
+    }
+  }
+  fn scope_enter(&mut self, scope: Scope) {
+    if let Some(hooks) = self {
+      hooks.scope_enter(scope);
+    }
+  }
+  fn scope_exit(&mut self) {
+    if let Some(hooks) = self {
+      hooks.scope_exit();
+    }
+  }
+  fn on_branch(&mut self, instruction: &Instruction, taken: bool) {
+    if let Some(hooks) = self {
+      hooks.on_branch(instruction, taken);
+    }
+  }
+  fn before_value(&mut self, value: &Value) {
+    if let Some(hooks) = self {
+      hooks.before_value(value);
+    }
+  }
+  fn after_value(&mut self, value: &Value, result: Described) {
+    if let Some(hooks) = self {
+      hooks.after_value(value, result);
+    }
+  }
+  fn on_assign(&mut self, variable: &Identifier, value: Described) {
+    if let Some(hooks) = self {
+      hooks.on_assign(variable, value);
+    }
+  }
+  fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+    match self {
+      Some(hooks) => hooks.print(text),
+      None => print_line(text),
+    }
+  }
+}
+
+// Both hooks hear about everything, the first one does the printing
+impl<A: Hooks, B: Hooks> Hooks for (A, B) {
+  fn before_instruction(
+    &mut self,
+    instruction: &Instruction,
+    vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    self.0.before_instruction(instruction, vm)?;
+    self.1.before_instruction(instruction, vm)
+  }
+  fn on_error(&mut self, error: &InterpreterError, vm: &mut Inspector) {
+    self.0.on_error(error, vm);
+    self.1.on_error(error, vm);
+  }
+  fn scope_enter(&mut self, scope: Scope) {
+    self.0.scope_enter(scope);
+    self.1.scope_enter(scope);
+  }
+  fn scope_exit(&mut self) {
+    self.0.scope_exit();
+    self.1.scope_exit();
+  }
+  fn on_branch(&mut self, instruction: &Instruction, taken: bool) {
+    self.0.on_branch(instruction, taken);
+    self.1.on_branch(instruction, taken);
+  }
+  fn before_value(&mut self, value: &Value) {
+    self.0.before_value(value);
+    self.1.before_value(value);
+  }
+  fn after_value(&mut self, value: &Value, result: Described) {
+    self.0.after_value(value, result);
+    self.1.after_value(value, result);
+  }
+  fn on_assign(&mut self, variable: &Identifier, value: Described) {
+    self.0.on_assign(variable, value);
+    self.1.on_assign(variable, value);
+  }
+  fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+    self.0.print(text)
+  }
+}
+
+// What a frame on the stack of the VM was pushed for
+#[derive(Debug, Clone, Copy)]
+pub enum Scope<'a> {
+  // The whole script, or the block of an if, else, while or scope
+  Block(&'a [Instruction]),
+  MatchArm(&'a MatchArm),
+  Function(&'a Function),
+}
+
+impl<'a> Scope<'a> {
+  pub fn instructions(&self) -> &'a [Instruction] {
+    match self {
+      Scope::Block(instructions) => instructions,
+      Scope::MatchArm(arm) => &arm.instructions,
+      Scope::Function(function) => &function.instructions,
+    }
+  }
+}
+
+// A value as the hooks get to see it, which shows it like `Data::describe`
+#[derive(Clone, Copy)]
+pub struct Described<'d>(&'d Data);
+
+impl fmt::Display for Described<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.0 {
+      Data::String(string) => write!(f, "{:?}", string),
+      data => write!(f, "{}", data),
+    }
+  }
+}
+
+// Access to the variables of a paused VM. Frames are counted from the top of the stack, the same
+// way slots count them
+pub struct Inspector<'v, 'a> {
+  vm: &'v mut VM<'a>,
+}
+
+impl Inspector<'_, '_> {
+  // The value in a slot, None when nothing was assigned to it yet
+  pub fn value(&self, slot: Slot) -> Option<String> {
+    self.vm.get_variable(slot).map(Data::describe)
+  }
+
+  pub fn evaluate(&mut self, value: &Value) -> Result<String, InterpreterError> {
+    Ok(self.vm.evaluate_value(value)?.describe())
+  }
+
+  pub fn condition(&mut self, value: &Value) -> Result<bool, InterpreterError> {
+    match self.vm.evaluate_value(value)? {
+      Data::Boolean(condition) => Ok(condition),
+      _ => Err(InterpreterError::TypeMismatch(
+        "Expected boolean for condition".to_string(),
+      )),
+    }
+  }
+}
+
+// How execution continues after running a block of instructions
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flow {
+  Normal,
+  // A break was hit, every block up to the nearest while loop stops executing
+  Break,
+  // A return was hit, every block up to the function call stops executing. The value that is
+  // returned waits in `VM::returned`
+  Return,
+}
+
+// The variables of a frame are stored in the slots the resolver assigned to them,
+// a slot is None until the variable is first assigned to
+#[derive(Debug, Clone, Default)]
 struct StackFrame {
-  variables: HashMap<String, Data>,
+  slots: Vec<Option<Data>>,
 }
 
 #[derive(Debug, Clone)]
 pub enum InterpreterError {
   VariableNotDefined(String),
+  FunctionNotDefined(String),
   TypeMismatch(String),
+  ArgumentMismatch(String),
+  NoMatchingArm(String),
+  FieldNotDefined(String),
+  IndexOutOfBounds(i64),
+  // Integer arithmetic with a result that does not fit in 64 bits, with what was being done
+  IntegerOverflow(&'static str),
+  DivisionByZero,
+  LimitExceeded(Limit),
+  InputDisabled,
+  // Reading input or writing output failed, with what was being done
+  Io(&'static str, Rc<io::Error>),
+  // A debugger ended the script
+  Stopped,
+  AssertionFailed(Box<AssertionFailure>),
+  // The script called exit, which is not an error but ends everything the same way
+  Exit(i32),
+}
+
+#[derive(Debug, Clone)]
+pub struct AssertionFailure {
+  // The whole assert statement
+  pub span: Span,
+  pub message: Option<String>,
+  // Both sides of the condition when it is a comparison, like 3 == 4 for add(1, 2) == 4
+  pub comparison: Option<(String, Operator, String)>,
 }
 
-impl StackFrame {
-  pub fn empty() -> Self {
-    Self {
-      variables: HashMap::new(),
+impl fmt::Display for InterpreterError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      InterpreterError::VariableNotDefined(name) => write!(f, "Variable '{}' is not defined", name),
+      InterpreterError::FunctionNotDefined(name) => write!(f, "Function '{}' is not defined", name),
+      InterpreterError::TypeMismatch(message) => write!(f, "Type mismatch: {}", message),
+      InterpreterError::ArgumentMismatch(message) => write!(f, "Argument mismatch: {}", message),
+      InterpreterError::NoMatchingArm(value) => {
+        write!(f, "No match arm matched the value {}", value)
+      }
+      InterpreterError::FieldNotDefined(field) => write!(f, "Field '{}' is not defined", field),
+      InterpreterError::IndexOutOfBounds(index) => write!(f, "Index {} is out of bounds", index),
+      InterpreterError::IntegerOverflow(doing) => write!(f, "Integer overflow when {}", doing),
+      InterpreterError::DivisionByZero => write!(f, "Division by zero"),
+      InterpreterError::LimitExceeded(limit) => write!(f, "Limit exceeded, {}", limit),
+      InterpreterError::InputDisabled => write!(f, "Reading input is not allowed"),
+      InterpreterError::Io(doing, error) => write!(f, "Could not {}: {}", doing, error),
+      InterpreterError::Stopped => write!(f, "The script was stopped"),
+      InterpreterError::AssertionFailed(failure) => {
+        write!(f, "Assertion failed at {}", failure.span.start)?;
+        if let Some(message) = &failure.message {
+          write!(f, ": {}", message)?;
+        }
+        if let Some((left, operator, right)) = &failure.comparison {
+          write!(f, " ({} {} {} is false)", left, operator, right)?;
+        }
+        Ok(())
+      }
+      InterpreterError::Exit(code) => write!(f, "The script exited with code {}", code),
     }
   }
+}
 
-  pub fn execute(
+impl<'a> VM<'a> {
+  // Runs the instructions in the frame on top of the stack, returns None when the guard did not pass
+  fn execute(
     &mut self,
-    instructions: &Vec<Instruction>,
-    vm: &mut VM,
-  ) -> Result<(), InterpreterError> {
-    self.run(&instructions, vm)
+    guard: Option<&Condition>,
+    instructions: &'a [Instruction],
+  ) -> Result<Option<Flow>, InterpreterError> {
+    if let Some(guard) = guard {
+      match self.evaluate_value(guard)? {
+        Data::Boolean(true) => (),
+        Data::Boolean(false) => return Ok(None),
+        _ => {
+          return Err(InterpreterError::TypeMismatch(
+            "Expected boolean for match guard".to_string(),
+          ))
+        }
+      }
+    }
+    Ok(Some(self.run(instructions)?))
   }
 
-  fn run(&mut self, instructions: &Vec<Instruction>, vm: &mut VM) -> Result<(), InterpreterError> {
+  fn run(&mut self, instructions: &'a [Instruction]) -> Result<Flow, InterpreterError> {
     let mut should_execute_else: Option<bool> = None;
     for instruction in instructions {
+      if self.hooks.is_some() {
+        self.before_instruction(instruction)?;
+      }
+      self.step()?;
       if let Some(should_execute) = should_execute_else {
         if !should_execute {
           should_execute_else = None;
@@ -46,19 +469,25 @@ impl StackFrame {
           should_execute_else = Some(false);
         }
       }
-      match instruction {
-        Instruction::Break => break,
-        Instruction::Value { value } => {
-          let _ = self.evaluate_value(&value, vm).unwrap();
+      match &instruction.kind {
+        InstructionKind::Break => return Ok(Flow::Break),
+        InstructionKind::Value { value } => {
+          self.evaluate_value(value)?;
         }
-        Instruction::If {
+        InstructionKind::If {
           condition,
           instructions,
         } => {
-          let condition = self.evaluate_value(&condition, vm)?;
+          let condition = self.evaluate_value(condition)?;
           if let Data::Boolean(condition) = condition {
+            if let Some(hooks) = &mut self.hooks {
+              hooks.on_branch(instruction, condition);
+            }
             if condition {
-              vm.execute_new_instructions(instructions)?;
+              let flow = self.execute_new_instructions(instructions)?;
+              if flow != Flow::Normal {
+                return Ok(flow);
+              }
             } else {
               should_execute_else = Some(true);
             }
@@ -68,467 +497,1417 @@ impl StackFrame {
             ));
           }
         }
-        Instruction::Else { instructions } => {
-          if let Some(_) = should_execute_else {
-            vm.execute_new_instructions(instructions)?;
+        InstructionKind::Else { instructions } => {
+          if should_execute_else.is_some() {
+            let flow = self.execute_new_instructions(instructions)?;
+            if flow != Flow::Normal {
+              return Ok(flow);
+            }
           }
         }
-        Instruction::While {
+        InstructionKind::While {
           condition,
           instructions,
         } => {
           while {
-            let condition = self.evaluate_value(&condition, vm)?;
+            let condition = self.evaluate_value(condition)?;
             if let Data::Boolean(condition) = condition {
+              if let Some(hooks) = &mut self.hooks {
+                hooks.on_branch(instruction, condition);
+              }
               condition
             } else {
               return Err(InterpreterError::TypeMismatch(
-                "Expected boolean for if condition".to_string(),
+                "Expected boolean for while condition".to_string(),
               ));
             }
           } {
-            vm.execute_new_instructions(instructions)?;
+            self.step()?;
+            match self.execute_new_instructions(instructions)? {
+              Flow::Normal => (),
+              Flow::Break => break,
+              Flow::Return => return Ok(Flow::Return),
+            }
           }
         }
-        Instruction::Scope { instructions } => {
-          vm.execute_new_instructions(instructions)?;
+        InstructionKind::Scope { instructions } => {
+          let flow = self.execute_new_instructions(instructions)?;
+          if flow != Flow::Normal {
+            return Ok(flow);
+          }
         }
-        Instruction::Print { message: value } => {
-          let value = self.evaluate_value(&value, vm)?;
+        InstructionKind::Print { message: value } => {
+          let value = self.evaluate_value(value)?;
           let string = value.to_string();
-          println!("{}", string);
+          match &mut self.hooks {
+            Some(hooks) => hooks.print(&string)?,
+            None => print_line(&string)?,
+          }
         }
-        Instruction::Input { variable } => {
+        InstructionKind::Input { variable } => {
+          if !self.limits.allow_input {
+            return Err(InterpreterError::InputDisabled);
+          }
           let mut input = String::new();
-          std::io::stdin().read_line(&mut input).unwrap();
-          let input = input.trim();
-          let input = Data::String(input.to_string());
-          vm.assign_variable(variable, input)?;
-        }
-      }
-    }
-    Ok(())
-  }
-
-  fn evaluate_value(&mut self, value: &Value, vm: &mut VM) -> Result<Data, InterpreterError> {
-    let data = match value {
-      Value::Number(number) => Data::Number(*number),
-      Value::String(string) => Data::String(string.clone()),
-      Value::Boolean(boolean) => Data::Boolean(*boolean),
-      Value::Identifier(identifier) => {
-        let variable = vm.get_variable(identifier);
-        if variable.is_none() {
-          return Err(InterpreterError::VariableNotDefined(identifier.clone()));
+          let read = io::stdin()
+            .read_line(&mut input)
+            .map_err(|error| InterpreterError::Io("read input", Rc::new(error)))?;
+          let input = if read == 0 {
+            Data::None
+          } else {
+            self.check_size(Data::String(input.trim().into()))?
+          };
+          match variable.slot {
+            Some(_) => self.assign_variable(variable, input),
+            None => return Err(InterpreterError::VariableNotDefined(variable.name.clone())),
+          };
         }
-        variable.unwrap().0.clone()
-      }
-      Value::Expression(expr) => match expr.get_operator() {
-        Operator::Add => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator add").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Number(&left + &right),
-            (Data::String(left), Data::String(right)) => Data::String(left + &right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 strings or 2 numbers when adding".to_string(),
-              ))
-            }
+        InstructionKind::Enum { name, variants, .. } => {
+          for variant in variants {
+            self.define_variant(name, &variant.name, &variant.fields)?;
           }
         }
-        Operator::Subtract => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator subtract").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Number(&left - &right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers when subtracting".to_string(),
-              ))
+        InstructionKind::Match { value, arms } => {
+          let value = self.evaluate_value(value)?;
+          let mut flow = None;
+          for arm in arms {
+            let mut bindings = Vec::new();
+            if !self.match_pattern(&arm.pattern, &value, &mut bindings) {
+              continue;
             }
-          }
-        }
-        Operator::Multiply => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator multiply").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Number(&left * &right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers when multiplying".to_string(),
-              ))
+            flow = self.execute_match_arm(bindings, arm)?;
+            if flow.is_some() {
+              break;
             }
           }
-        }
-        Operator::Divide => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator divide").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Number(&left / &right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers when dividing".to_string(),
-              ))
-            }
+          match flow {
+            Some(Flow::Normal) => (),
+            Some(flow) => return Ok(flow),
+            None => return Err(InterpreterError::NoMatchingArm(value.to_string())),
           }
         }
-        Operator::Modulo => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator modulo").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Number(&left % &right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers when taking modulo".to_string(),
-              ))
-            }
-          }
+        InstructionKind::Let {
+          variable, value, ..
+        } => {
+          let value = self.evaluate_value(value)?;
+          self.assign_variable(variable, value);
         }
-        Operator::Equal => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator equal").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Boolean(left == right),
-            (Data::String(left), Data::String(right)) => Data::Boolean(left == right),
-            (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left == right),
-            _ => Data::Boolean(false),
-          }
+        InstructionKind::Function(function) => {
+          self.functions.insert(function.name.clone(), function);
+        }
+        InstructionKind::Return { value } => {
+          let value = match value {
+            Some(value) => self.evaluate_value(value)?,
+            None => Data::None,
+          };
+          self.returned = Some(value);
+          return Ok(Flow::Return);
+        }
+        InstructionKind::Assert { condition, message } => {
+          self.assert(instruction.span, condition, message.as_ref())?;
         }
-        Operator::NotEqual => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator not equal").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Boolean(left != right),
-            (Data::String(left), Data::String(right)) => Data::Boolean(left != right),
-            (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left != right),
-            _ => Data::Boolean(true),
+        // Only `run_test` runs tests
+        InstructionKind::Test { .. } => (),
+      }
+    }
+    Ok(Flow::Normal)
+  }
+
+  // Everything that recurses goes through here, so a script runs out of levels before it runs out
+  // of stack
+  fn nested<T>(
+    &mut self,
+    evaluate: impl FnOnce(&mut Self) -> Result<T, InterpreterError>,
+  ) -> Result<T, InterpreterError> {
+    if let Some(max_recursion) = self.limits.max_recursion() {
+      if self.levels >= max_recursion {
+        return Err(InterpreterError::LimitExceeded(Limit::Recursion(
+          max_recursion,
+        )));
+      }
+    }
+    self.levels += 1;
+    let result = evaluate(self);
+    self.levels -= 1;
+    result
+  }
+
+  fn evaluate_value(&mut self, value: &Value) -> Result<Data, InterpreterError> {
+    self.nested(|vm| {
+      if let Some(hooks) = &mut vm.hooks {
+        hooks.before_value(value);
+      }
+      let data = match value {
+        Value::Number(number) => Data::Number(*number),
+        Value::String(string) => vm.check_size(Data::String(string.clone()))?,
+        Value::Boolean(boolean) => Data::Boolean(*boolean),
+        Value::None => Data::None,
+        Value::List(items) => {
+          let mut list = Vec::with_capacity(items.len());
+          for item in items {
+            list.push(vm.evaluate_value(item)?);
           }
+          vm.check_size(Data::List(Rc::new(list)))?
         }
-        Operator::LessThan => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator less than").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Boolean(left < right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers ".to_string(),
-              ))
+        Value::Field { .. } | Value::Index { .. } => {
+          vm.evaluate_access(value)?.unwrap_or(Data::None)
+        }
+        Value::Identifier(identifier) => {
+          let variable = identifier.slot.and_then(|slot| vm.get_variable(slot));
+          if let Some(data) = variable {
+            data.clone()
+          } else if let Some(Variant { enum_name, fields }) = vm.variants.get(&identifier.name) {
+            if !fields.is_empty() {
+              return Err(InterpreterError::ArgumentMismatch(format!(
+                "Variant '{}' takes {} values",
+                identifier.name,
+                fields.len()
+              )));
             }
+            Data::Enum(Rc::new(EnumValue {
+              enum_name: enum_name.clone(),
+              variant: identifier.name.clone(),
+              fields: vec![],
+            }))
+          } else if identifier.name == ARGS {
+            vm.args.clone()
+          } else {
+            return Err(InterpreterError::VariableNotDefined(
+              identifier.name.clone(),
+            ));
           }
         }
-        Operator::LessThanOrEqual => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator less than or equal").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Boolean(left <= right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers ".to_string(),
-              ))
-            }
+        Value::Call {
+          name, arguments, ..
+        } => {
+          let mut values = Vec::with_capacity(arguments.len());
+          for argument in arguments {
+            values.push(vm.evaluate_value(argument)?);
           }
+          vm.call(name, values)?
         }
-        Operator::GreaterThan => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator greater than").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Boolean(left > right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers ".to_string(),
-              ))
+        Value::Expression(expr) => match expr.get_operator() {
+          Operator::Add
+          | Operator::Subtract
+          | Operator::Multiply
+          | Operator::Divide
+          | Operator::Modulo => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            let right =
+              vm.evaluate_value(expr.get_right().expect("No right for arithmetic operator"))?;
+            vm.check_size(apply_arithmetic(*expr.get_operator(), left, right)?)?
+          }
+          Operator::Equal
+          | Operator::NotEqual
+          | Operator::LessThan
+          | Operator::LessThanOrEqual
+          | Operator::GreaterThan
+          | Operator::GreaterThanOrEqual => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            let right =
+              vm.evaluate_value(expr.get_right().expect("No right for comparison operator"))?;
+            compare(*expr.get_operator(), left, right)?
+          }
+          Operator::And => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            let right = vm.evaluate_value(expr.get_right().expect("No right for operator and"))?;
+            match (left, right) {
+              (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left && right),
+              _ => {
+                return Err(InterpreterError::TypeMismatch(
+                  "Expected 2 booleans ".to_string(),
+                ))
+              }
             }
           }
-        }
-        Operator::GreaterThanOrEqual => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator greater than or equal").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Boolean(left >= right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers ".to_string(),
-              ))
+          Operator::Or => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            let right = vm.evaluate_value(expr.get_right().expect("No right for operator or"))?;
+            match (left, right) {
+              (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left || right),
+              _ => {
+                return Err(InterpreterError::TypeMismatch(
+                  "Expected 2 booleans ".to_string(),
+                ))
+              }
             }
           }
-        }
-        Operator::And => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator and").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left && right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 booleans ".to_string(),
-              ))
+          Operator::Not => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            match left {
+              Data::Boolean(left) => Data::Boolean(!left),
+              _ => {
+                return Err(InterpreterError::TypeMismatch(
+                  "Expected 1 boolean ".to_string(),
+                ))
+              }
             }
           }
-        }
-        Operator::Or => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator or").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left || right),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 2 booleans ".to_string(),
-              ))
+
+          Operator::Exponent => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            let right =
+              vm.evaluate_value(expr.get_right().expect("No right for operator exponent"))?;
+            match (left, right) {
+              (Data::Number(left), Data::Number(right)) => Data::Number(left.pow(&right)),
+              _ => {
+                return Err(InterpreterError::TypeMismatch(
+                  "Expected 2 numbers when taking exponent".to_string(),
+                ))
+              }
             }
           }
-        }
-        Operator::Not => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          match left {
-            Data::Boolean(left) => Data::Boolean(!left),
-            _ => {
-              return Err(InterpreterError::TypeMismatch(
-                "Expected 1 boolean ".to_string(),
-              ))
+          Operator::Coalesce => {
+            let left = vm.evaluate_value(expr.get_left())?;
+            match left {
+              Data::None => {
+                vm.evaluate_value(expr.get_right().expect("No right for operator coalesce"))?
+              }
+              left => left,
             }
           }
-        }
+          Operator::Brackets => vm.evaluate_value(expr.get_left())?,
+          Operator::Assign => {
+            let left = expr.get_left();
+            let right = expr.get_right().expect("No right for assignment");
+            vm.assign_value(left, right)?
+          }
+          Operator::AddAssign => {
+            let left = expr.get_left();
+            let right = expr.get_right().expect("No right for assignment");
+            vm.assign_value_with_operator(left, right, Operator::Add)?
+          }
+          Operator::SubtractAssign => {
+            let left = expr.get_left();
+            let right = expr.get_right().expect("No right for assignment");
+            vm.assign_value_with_operator(left, right, Operator::Subtract)?
+          }
+          Operator::MultiplyAssign => {
+            let left = expr.get_left();
+            let right = expr.get_right().expect("No right for assignment");
+            vm.assign_value_with_operator(left, right, Operator::Multiply)?
+          }
+          Operator::DivideAssign => {
+            let left = expr.get_left();
+            let right = expr.get_right().expect("No right for assignment");
+            vm.assign_value_with_operator(left, right, Operator::Divide)?
+          }
+          Operator::ModuloAssign => {
+            let left = expr.get_left();
+            let right = expr.get_right().expect("No right for assignment");
+            vm.assign_value_with_operator(left, right, Operator::Modulo)?
+          }
+        },
+      };
+      if let Some(hooks) = &mut vm.hooks {
+        hooks.after_value(value, Described(&data));
+      }
+      Ok(data)
+    })
+  }
 
-        Operator::Exponent => {
-          let left = self.evaluate_value(&expr.get_left(), vm)?;
-          let right = self.evaluate_value(
-            &expr
-              .get_right()
-              .expect(format!("No right for operator exponent").as_str()),
-            vm,
-          )?;
-          match (left, right) {
-            (Data::Number(left), Data::Number(right)) => Data::Number(left.pow(&right)),
+  // A failed comparison reports the values on both sides, which are only evaluated once
+  fn assert(
+    &mut self,
+    span: Span,
+    condition: &Condition,
+    message: Option<&Value>,
+  ) -> Result<(), InterpreterError> {
+    let mut comparison = condition;
+    while let Value::Expression(expr) = comparison {
+      if *expr.get_operator() != Operator::Brackets {
+        break;
+      }
+      comparison = expr.get_left();
+    }
+    let (passed, sides) = match comparison {
+      Value::Expression(expr) if expr.get_operator().is_comparison() => {
+        let operator = *expr.get_operator();
+        let left = self.evaluate_value(expr.get_left())?;
+        let right =
+          self.evaluate_value(expr.get_right().expect("No right for comparison operator"))?;
+        let sides = (left.describe(), operator, right.describe());
+        (compare(operator, left, right)?, Some(sides))
+      }
+      _ => (self.evaluate_value(condition)?, None),
+    };
+    match passed {
+      Data::Boolean(true) => Ok(()),
+      Data::Boolean(false) => {
+        let message = match message {
+          Some(message) => Some(self.evaluate_value(message)?.to_string()),
+          None => None,
+        };
+        Err(InterpreterError::AssertionFailed(Box::new(
+          AssertionFailure {
+            span,
+            message,
+            comparison: sides,
+          },
+        )))
+      }
+      _ => Err(InterpreterError::TypeMismatch(
+        "Expected boolean for assert condition".to_string(),
+      )),
+    }
+  }
+
+  // Evaluates a chain of field and index accesses, returning None when an optional access
+  // short-circuited on a none value somewhere along the chain
+  fn evaluate_access(&mut self, value: &Value) -> Result<Option<Data>, InterpreterError> {
+    self.nested(|vm| {
+      let (base, optional) = match value {
+        Value::Field {
+          value, optional, ..
+        }
+        | Value::Index {
+          value, optional, ..
+        } => (value, *optional),
+        _ => return Ok(Some(vm.evaluate_value(value)?)),
+      };
+      let base = match vm.evaluate_access(base)? {
+        Some(Data::None) if optional => return Ok(None),
+        Some(base) => base,
+        None => return Ok(None),
+      };
+      let data = match value {
+        Value::Field { field, .. } => vm.get_field(base, field)?,
+        Value::Index { index, .. } => {
+          let index = match vm.evaluate_value(index)? {
+            Data::Number(Number::Integer(index)) => index,
             _ => {
               return Err(InterpreterError::TypeMismatch(
-                "Expected 2 numbers when taking exponent".to_string(),
+                "Expected integer as index".to_string(),
               ))
             }
-          }
-        }
-        Operator::Brackets => self.evaluate_value(&expr.get_left(), vm)?,
-        Operator::Assign => {
-          let left = expr.get_left();
-          let right = expr.get_right().expect("No right for assignment");
-          self.assign_variable(left, right, vm)?
+          };
+          get_index(base, index)?
         }
-        Operator::AddAssign => {
-          let left = expr.get_left();
-          let right = expr.get_right().expect("No right for assignment");
-          self.assign_variable_with_operator(left, right, Operator::Add, vm)?
-        }
-        Operator::SubtractAssign => {
-          let left = expr.get_left();
-          let right = expr.get_right().expect("No right for assignment");
-          self.assign_variable_with_operator(left, right, Operator::Subtract, vm)?
-        }
-        Operator::MultiplyAssign => {
-          let left = expr.get_left();
-          let right = expr.get_right().expect("No right for assignment");
-          self.assign_variable_with_operator(left, right, Operator::Multiply, vm)?
-        }
-        Operator::DivideAssign => {
-          let left = expr.get_left();
-          let right = expr.get_right().expect("No right for assignment");
-          self.assign_variable_with_operator(left, right, Operator::Divide, vm)?
-        }
-        Operator::ModuloAssign => {
-          let left = expr.get_left();
-          let right = expr.get_right().expect("No right for assignment");
-          self.assign_variable_with_operator(left, right, Operator::Modulo, vm)?
-        }
-      },
-    };
-    Ok(data)
+        _ => unreachable!(),
+      };
+      Ok(Some(data))
+    })
   }
 
-  fn assign_variable(
-    &mut self,
-    left: &Value,
-    right: &Value,
-    vm: &mut VM,
-  ) -> Result<Data, InterpreterError> {
-    if !match left {
-      Value::Identifier(_) => true,
-      _ => false,
-    } {
-      return Err(InterpreterError::TypeMismatch(
-        "Expected identifier on left side of assignment".to_string(),
-      ));
-    }
-    let name = match left {
-      Value::Identifier(name) => name,
-      _ => unreachable!(),
-    };
-    let data = self.evaluate_value(right, vm)?;
-    Ok(vm.assign_variable(name, data)?.clone())
+  fn assign_value(&mut self, left: &Value, right: &Value) -> Result<Data, InterpreterError> {
+    let variable = assignment_variable(left)?;
+    let data = self.evaluate_value(right)?;
+    Ok(self.assign_variable(variable, data).clone())
   }
 
-  fn assign_variable_with_operator(
+  fn assign_value_with_operator(
     &mut self,
     left: &Value,
     right: &Value,
     operator: Operator,
-    vm: &mut VM,
   ) -> Result<Data, InterpreterError> {
-    if !match left {
-      Value::Identifier(_) => true,
-      _ => false,
-    } {
-      return Err(InterpreterError::TypeMismatch(
-        "Expected identifier on left side of assignment".to_string(),
-      ));
+    let variable = assignment_variable(left)?;
+    let current = self.evaluate_value(left)?;
+    let right = self.evaluate_value(right)?;
+    let data = self.check_size(apply_arithmetic(operator, current, right)?)?;
+    Ok(self.assign_variable(variable, data).clone())
+  }
+}
+
+// The variable on the left of an assignment, which has a slot
+fn assignment_variable(left: &Value) -> Result<&Identifier, InterpreterError> {
+  match left {
+    Value::Identifier(identifier) => match identifier.slot {
+      Some(_) => Ok(identifier),
+      None => Err(InterpreterError::VariableNotDefined(
+        identifier.name.clone(),
+      )),
+    },
+    _ => Err(InterpreterError::TypeMismatch(
+      "Expected identifier on left side of assignment".to_string(),
+    )),
+  }
+}
+
+// What the arithmetic of numbers gives, where integers that do not fit or division by zero are an
+// error of the script
+fn checked(
+  number: Result<Number, ArithmeticError>,
+  doing: &'static str,
+) -> Result<Number, InterpreterError> {
+  number.map_err(|error| match error {
+    ArithmeticError::Overflow => InterpreterError::IntegerOverflow(doing),
+    ArithmeticError::DivisionByZero => InterpreterError::DivisionByZero,
+  })
+}
+
+fn apply_arithmetic(operator: Operator, left: Data, right: Data) -> Result<Data, InterpreterError> {
+  let data = match operator {
+    Operator::Add => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => Data::Number(checked(&left + &right, "adding")?),
+      (Data::String(left), Data::String(right)) => {
+        Data::String(format!("{}{}", left, right).into())
+      }
+      (Data::List(left), Data::List(right)) => {
+        let mut list = Vec::with_capacity(left.len() + right.len());
+        list.extend(left.iter().cloned());
+        list.extend(right.iter().cloned());
+        Data::List(Rc::new(list))
+      }
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected 2 strings, 2 numbers or 2 lists when adding".to_string(),
+        ))
+      }
+    },
+    Operator::Subtract => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => {
+        Data::Number(checked(&left - &right, "subtracting")?)
+      }
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected 2 numbers when subtracting".to_string(),
+        ))
+      }
+    },
+    Operator::Multiply => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => {
+        Data::Number(checked(&left * &right, "multiplying")?)
+      }
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected 2 numbers when multiplying".to_string(),
+        ))
+      }
+    },
+    Operator::Divide => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => {
+        Data::Number(checked(&left / &right, "dividing")?)
+      }
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected 2 numbers when dividing".to_string(),
+        ))
+      }
+    },
+    Operator::Modulo => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => {
+        Data::Number(checked(&left % &right, "taking modulo")?)
+      }
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected 2 numbers when taking modulo".to_string(),
+        ))
+      }
+    },
+    _ => panic!("{:?} is not an arithmetic operator", operator),
+  };
+  Ok(data)
+}
+
+/*
+ What an operator gives for literal operands, worked out the same way as when running so the
+ optimizer can replace it with the result. None when an operand is not a literal or running it
+ would fail, which is left to happen when the code runs.
+*/
+pub fn fold(
+  operator: Operator,
+  left: &Value,
+  right: Option<&Value>,
+  limits: &Limits,
+) -> Option<Value> {
+  let left = literal_data(left)?;
+  let right = right.map(literal_data);
+  let data = match (operator, left, right) {
+    (Operator::Not, Data::Boolean(boolean), None) => Data::Boolean(!boolean),
+    (Operator::Brackets, data, None) => data,
+    (Operator::And, Data::Boolean(left), Some(Some(Data::Boolean(right)))) => {
+      Data::Boolean(left && right)
     }
-    let name = match left {
-      Value::Identifier(name) => name,
-      _ => unreachable!(),
-    };
-    let operator = match operator {
-      Operator::AddAssign => Operator::Add,
-      Operator::SubtractAssign => Operator::Subtract,
-      Operator::MultiplyAssign => Operator::Multiply,
-      Operator::DivideAssign => Operator::Divide,
-      Operator::ModuloAssign => Operator::Modulo,
-      _ => operator,
-    };
-    let expression = Expression::new(operator, left.clone(), right.clone());
-    let value = Value::Expression(Box::new(expression));
-    let data = self.evaluate_value(&value, vm)?;
-    Ok(vm.assign_variable(name, data)?.clone())
+    (Operator::Or, Data::Boolean(left), Some(Some(Data::Boolean(right)))) => {
+      Data::Boolean(left || right)
+    }
+    (Operator::Exponent, Data::Number(left), Some(Some(Data::Number(right)))) => {
+      Data::Number(left.pow(&right))
+    }
+    (operator, left, Some(Some(right))) if operator.is_comparison() => {
+      compare(operator, left, right).ok()?
+    }
+    (
+      Operator::Add | Operator::Subtract | Operator::Multiply | Operator::Divide | Operator::Modulo,
+      left,
+      Some(Some(right)),
+    ) => apply_arithmetic(operator, left, right).ok()?,
+    _ => return None,
+  };
+  match data {
+    Data::Number(number) => Some(Value::Number(number)),
+    Data::String(string) => match limits.max_size {
+      Some(max_size) if string.chars().count() > max_size => None,
+      _ => Some(Value::String(string)),
+    },
+    Data::Boolean(boolean) => Some(Value::Boolean(boolean)),
+    Data::None => Some(Value::None),
+    Data::List(_) | Data::Enum(_) => None,
   }
+}
 
-  fn get_frame_variable(&mut self, name: &str) -> Option<&Data> {
-    self.variables.get(name)
+fn literal_data(value: &Value) -> Option<Data> {
+  match value {
+    Value::Number(number) => Some(Data::Number(*number)),
+    Value::String(string) => Some(Data::String(string.clone())),
+    Value::Boolean(boolean) => Some(Data::Boolean(*boolean)),
+    Value::None => Some(Data::None),
+    _ => None,
   }
 }
 
-#[derive(Debug, Clone)]
+fn compare(operator: Operator, left: Data, right: Data) -> Result<Data, InterpreterError> {
+  let data = match operator {
+    Operator::Equal => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => Data::Boolean(left == right),
+      (Data::String(left), Data::String(right)) => Data::Boolean(left == right),
+      (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left == right),
+      (Data::Enum(left), Data::Enum(right)) => Data::Boolean(left == right),
+      (Data::List(left), Data::List(right)) => Data::Boolean(left == right),
+      (Data::None, Data::None) => Data::Boolean(true),
+      _ => Data::Boolean(false),
+    },
+    Operator::NotEqual => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => Data::Boolean(left != right),
+      (Data::String(left), Data::String(right)) => Data::Boolean(left != right),
+      (Data::Boolean(left), Data::Boolean(right)) => Data::Boolean(left != right),
+      (Data::Enum(left), Data::Enum(right)) => Data::Boolean(left != right),
+      (Data::List(left), Data::List(right)) => Data::Boolean(left != right),
+      (Data::None, Data::None) => Data::Boolean(false),
+      _ => Data::Boolean(true),
+    },
+    _ => match (left, right) {
+      (Data::Number(left), Data::Number(right)) => Data::Boolean(match operator {
+        Operator::LessThan => left < right,
+        Operator::LessThanOrEqual => left <= right,
+        Operator::GreaterThan => left > right,
+        Operator::GreaterThanOrEqual => left >= right,
+        _ => panic!("{:?} is not a comparison operator", operator),
+      }),
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected 2 numbers ".to_string(),
+        ))
+      }
+    },
+  };
+  Ok(data)
+}
+
+// Strings, lists and enum values are reference counted, so reading a variable never copies them
+#[derive(Debug, Clone, PartialEq)]
 enum Data {
   Number(Number),
-  String(String),
+  String(Rc<str>),
   Boolean(bool),
+  None,
+  List(Rc<Vec<Data>>),
+  Enum(Rc<EnumValue>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct EnumValue {
+  enum_name: String,
+  variant: String,
+  fields: Vec<Data>,
+}
+
+impl fmt::Display for Data {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Data::Number(number) => write!(f, "{}", number),
+      Data::String(string) => write!(f, "{}", string),
+      Data::Boolean(boolean) => write!(f, "{}", boolean),
+      Data::None => write!(f, "none"),
+      Data::List(items) => {
+        let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+        write!(f, "[{}]", items.join(", "))
+      }
+      Data::Enum(value) => {
+        write!(f, "{}", value.variant)?;
+        if !value.fields.is_empty() {
+          let fields: Vec<String> = value.fields.iter().map(|field| field.to_string()).collect();
+          write!(f, "({})", fields.join(", "))?;
+        }
+        Ok(())
+      }
+    }
+  }
 }
 
 impl Data {
-  fn to_string(&self) -> String {
+  // Like Display, but strings are quoted so they can be told apart from other values
+  fn describe(&self) -> String {
     match self {
-      Data::Number(number) => number.to_string(),
-      Data::String(string) => string.clone(),
-      Data::Boolean(boolean) => boolean.to_string(),
+      Data::String(string) => format!("{:?}", string),
+      _ => self.to_string(),
     }
   }
 }
 
-struct VM {
-  instructions: Vec<Instruction>,
+fn get_index(data: Data, index: i64) -> Result<Data, InterpreterError> {
+  let position = usize::try_from(index).map_err(|_| InterpreterError::IndexOutOfBounds(index))?;
+  let item = match data {
+    Data::List(items) => items.get(position).cloned(),
+    Data::Enum(value) => value.fields.get(position).cloned(),
+    Data::String(string) => string
+      .chars()
+      .nth(position)
+      .map(|c| Data::String(c.to_string().into())),
+    _ => {
+      return Err(InterpreterError::TypeMismatch(
+        "Expected list, string or enum value when indexing".to_string(),
+      ))
+    }
+  };
+  item.ok_or(InterpreterError::IndexOutOfBounds(index))
+}
+
+#[derive(Debug)]
+struct Variant {
+  enum_name: String,
+  fields: Vec<String>,
+}
+
+struct VM<'a> {
   stack: Vec<StackFrame>,
+  // Frames that were popped off the stack, kept around so their slots can be reused
+  free_frames: Vec<StackFrame>,
+  variants: HashMap<String, Variant>,
+  // Functions are known from the moment their definition runs, like enums
+  functions: HashMap<String, &'a Function>,
+  returned: Option<Data>,
+  // Function calls that are running right now
+  calls: usize,
+  // Values, accesses and blocks being evaluated right now, each of them recurses
+  levels: usize,
+  limits: Limits,
+  steps: u64,
+  deadline: Option<Instant>,
+  hooks: Option<&'a mut dyn Hooks>,
+  // Whether the hooks already heard about the error that is going up the stack
+  error_reported: bool,
+  // The list `args`, unless the script has a variable with that name
+  args: Data,
+}
+
+// What the builtins are called, a function, variant or variable with the same name hides them
+pub const ARGS: &str = "args";
+pub const EXIT: &str = "exit";
+
+// exit() and exit(code), the code has to be one a process can exit with, from 0 to 255
+fn exit(arguments: Vec<Data>) -> InterpreterError {
+  match arguments.as_slice() {
+    [] => InterpreterError::Exit(0),
+    [Data::Number(Number::Integer(code @ 0..=255))] => InterpreterError::Exit(*code as i32),
+    [Data::Number(Number::Integer(code))] => {
+      InterpreterError::TypeMismatch(format!("Exit code {} is not between 0 and 255", code))
+    }
+    [_] => InterpreterError::TypeMismatch("Expected integer as exit code".to_string()),
+    _ => InterpreterError::ArgumentMismatch(format!(
+      "Function 'exit' takes 1 argument but {} were given",
+      arguments.len()
+    )),
+  }
 }
 
-impl VM {
-  fn new() -> VM {
+// Checking the clock is slow compared to a step, so it only happens once every this many steps
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+impl<'a> VM<'a> {
+  fn new(limits: Limits) -> Self {
     VM {
-      instructions: vec![],
       stack: vec![],
+      free_frames: vec![],
+      variants: HashMap::new(),
+      functions: HashMap::new(),
+      returned: None,
+      calls: 0,
+      levels: 0,
+      deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+      limits,
+      steps: 0,
+      hooks: None,
+      error_reported: false,
+      args: Data::List(Rc::new(Vec::new())),
+    }
+  }
+
+  // The hooks are taken out while they run, so they can use the VM through the inspector
+  fn before_instruction(&mut self, instruction: &Instruction) -> Result<(), InterpreterError> {
+    let hooks = self.hooks.take().expect("No hooks to call");
+    let result = hooks.before_instruction(instruction, &mut Inspector { vm: self });
+    self.hooks = Some(hooks);
+    result
+  }
+
+  fn report_error(&mut self, error: &InterpreterError) {
+    if self.error_reported {
+      return;
+    }
+    self.error_reported = true;
+    if let Some(hooks) = self.hooks.take() {
+      hooks.on_error(error, &mut Inspector { vm: self });
+      self.hooks = Some(hooks);
     }
   }
-  fn get_variable(&mut self, name: &str) -> Option<(&Data, usize)> {
-    for (i, frame) in self.stack.iter_mut().enumerate().rev() {
-      if let Some(data) = frame.get_frame_variable(name) {
-        return Some((data, i));
+
+  fn step(&mut self) -> Result<(), InterpreterError> {
+    self.steps += 1;
+    if let Some(max_steps) = self.limits.max_steps {
+      if self.steps > max_steps {
+        return Err(InterpreterError::LimitExceeded(Limit::Steps(max_steps)));
+      }
+    }
+    if let (Some(deadline), Some(timeout)) = (self.deadline, self.limits.timeout) {
+      if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && Instant::now() > deadline {
+        return Err(InterpreterError::LimitExceeded(Limit::Timeout(timeout)));
       }
     }
-    None
+    Ok(())
+  }
+
+  fn check_size(&self, data: Data) -> Result<Data, InterpreterError> {
+    let Some(max_size) = self.limits.max_size else {
+      return Ok(data);
+    };
+    let size = match &data {
+      Data::String(string) => string.chars().count(),
+      Data::List(items) => items.len(),
+      _ => return Ok(data),
+    };
+    if size > max_size {
+      return Err(InterpreterError::LimitExceeded(Limit::Size(max_size)));
+    }
+    Ok(data)
   }
 
-  fn assign_variable(&mut self, name: &str, data: Data) -> Result<&Data, InterpreterError> {
-    let existing_variable = self.get_variable(name);
-    if let Some((_, i)) = existing_variable {
-      self.stack[i].variables.insert(name.to_string(), data);
-      return Ok(&self.stack[i].variables[name]);
-    } else {
-      self.stack[0].variables.insert(name.to_string(), data);
-      return Ok(&self.stack[0].variables[name]);
+  // The arguments are held to the size limit like the values the script makes
+  fn set_args(&mut self, args: &[String]) -> Result<(), InterpreterError> {
+    let mut list = Vec::with_capacity(args.len());
+    for arg in args {
+      list.push(self.check_size(Data::String(arg.as_str().into()))?);
     }
+    self.args = self.check_size(Data::List(Rc::new(list)))?;
+    Ok(())
   }
 
-  fn execute_new_instructions(
+  fn get_variable(&self, slot: Slot) -> Option<&Data> {
+    let frame = self.stack.len().checked_sub(slot.depth + 1)?;
+    self.stack[frame].slots.get(slot.index)?.as_ref()
+  }
+
+  // The variable has to be resolved to a slot
+  fn assign_variable(&mut self, variable: &Identifier, data: Data) -> &Data {
+    let slot = variable.slot.expect("Variable was not resolved");
+    let frame = self.stack.len() - slot.depth - 1;
+    let slots = &mut self.stack[frame].slots;
+    if slots.len() <= slot.index {
+      slots.resize(slot.index + 1, None);
+    }
+    let data = slots[slot.index].insert(data);
+    if let Some(hooks) = &mut self.hooks {
+      hooks.on_assign(variable, Described(data));
+    }
+    data
+  }
+
+  fn define_variant(
     &mut self,
-    instructions: &Vec<Instruction>,
+    enum_name: &str,
+    name: &str,
+    fields: &[String],
   ) -> Result<(), InterpreterError> {
-    let new_frame = StackFrame::empty();
-    self.stack.insert(0, new_frame);
-    unsafe {
-      // get the last stack as a mutable reference, then get self as a mutable reference
-      // this is safe because we just inserted a new stack frame
-      let stack = &mut *(&mut self.stack as *mut Vec<StackFrame>);
-      let stack = &mut stack[0];
-      stack.execute(instructions, self)?;
-    }
-    self.stack.remove(0);
+    if let Some(existing) = self.variants.get(name) {
+      if existing.enum_name != enum_name {
+        return Err(InterpreterError::TypeMismatch(format!(
+          "Variant '{}' is already defined by enum '{}'",
+          name, existing.enum_name
+        )));
+      }
+    }
+    self.variants.insert(
+      name.to_string(),
+      Variant {
+        enum_name: enum_name.to_string(),
+        fields: fields.to_vec(),
+      },
+    );
     Ok(())
   }
+
+  fn get_field(&self, data: Data, field: &str) -> Result<Data, InterpreterError> {
+    let value = match data {
+      Data::Enum(value) => value,
+      _ => {
+        return Err(InterpreterError::TypeMismatch(
+          "Expected enum value when accessing a field".to_string(),
+        ))
+      }
+    };
+    let position = self
+      .variants
+      .get(&value.variant)
+      .and_then(|variant| variant.fields.iter().position(|name| name == field));
+    match position.and_then(|position| value.fields.get(position)) {
+      Some(data) => Ok(data.clone()),
+      None => Err(InterpreterError::FieldNotDefined(field.to_string())),
+    }
+  }
+
+  fn call(&mut self, name: &str, arguments: Vec<Data>) -> Result<Data, InterpreterError> {
+    if let Some(function) = self.functions.get(name).copied() {
+      return self.call_function(function, arguments);
+    }
+    let variant = match self.variants.get(name) {
+      Some(variant) => variant,
+      None if name == EXIT => return Err(exit(arguments)),
+      None => return Err(InterpreterError::FunctionNotDefined(name.to_string())),
+    };
+    if variant.fields.len() != arguments.len() {
+      return Err(InterpreterError::ArgumentMismatch(format!(
+        "Variant '{}' takes {} values but {} were given",
+        name,
+        variant.fields.len(),
+        arguments.len()
+      )));
+    }
+    Ok(Data::Enum(Rc::new(EnumValue {
+      enum_name: variant.enum_name.clone(),
+      variant: name.to_string(),
+      fields: arguments,
+    })))
+  }
+
+  // The resolver put the parameters in the first slots of the frame, in order
+  fn call_function(
+    &mut self,
+    function: &'a Function,
+    arguments: Vec<Data>,
+  ) -> Result<Data, InterpreterError> {
+    if function.parameters.len() != arguments.len() {
+      return Err(InterpreterError::ArgumentMismatch(format!(
+        "Function '{}' takes {} arguments but {} were given",
+        function.name,
+        function.parameters.len(),
+        arguments.len()
+      )));
+    }
+    if let Some(max_calls) = self.limits.max_calls {
+      if self.calls >= max_calls {
+        return Err(InterpreterError::LimitExceeded(Limit::Calls(max_calls)));
+      }
+    }
+    let bindings = arguments.into_iter().enumerate().collect();
+    self.calls += 1;
+    let flow = self.execute_in_new_frame(bindings, Scope::Function(function));
+    self.calls -= 1;
+    let flow = flow?;
+    // A break that is not in a loop ends the function as well
+    Ok(match flow {
+      Some(Flow::Return) => self.returned.take().unwrap_or(Data::None),
+      _ => Data::None,
+    })
+  }
+
+  // Checks if the value fits the pattern, collecting the names it binds along the way
+  fn match_pattern(
+    &self,
+    pattern: &Pattern,
+    value: &Data,
+    bindings: &mut Vec<(usize, Data)>,
+  ) -> bool {
+    match pattern {
+      Pattern::Wildcard => true,
+      Pattern::Literal(literal) => {
+        let literal = match literal {
+          Value::Number(number) => Data::Number(*number),
+          Value::String(string) => Data::String(string.clone()),
+          Value::Boolean(boolean) => Data::Boolean(*boolean),
+          Value::None => Data::None,
+          _ => return false,
+        };
+        literal == *value
+      }
+      Pattern::Identifier(identifier) => match identifier.slot {
+        Some(slot) => {
+          bindings.push((slot.index, value.clone()));
+          true
+        }
+        None => matches!(value, Data::Enum(value) if value.variant == identifier.name),
+      },
+      Pattern::Variant { name, fields, .. } => match value {
+        Data::Enum(value) if value.variant == *name && value.fields.len() == fields.len() => fields
+          .iter()
+          .zip(value.fields.iter())
+          .all(|(field, value)| self.match_pattern(field, value, bindings)),
+        _ => false,
+      },
+    }
+  }
+
+  fn execute_new_instructions(
+    &mut self,
+    instructions: &'a [Instruction],
+  ) -> Result<Flow, InterpreterError> {
+    let flow = self.execute_in_new_frame(vec![], Scope::Block(instructions))?;
+    Ok(flow.unwrap_or(Flow::Normal))
+  }
+
+  // Returns None when the guard of the arm did not pass, in which case the next arm should be tried
+  fn execute_match_arm(
+    &mut self,
+    bindings: Vec<(usize, Data)>,
+    arm: &'a MatchArm,
+  ) -> Result<Option<Flow>, InterpreterError> {
+    self.execute_in_new_frame(bindings, Scope::MatchArm(arm))
+  }
+
+  // The frame only holds the variables, the instructions are executed by the VM itself,
+  // so the stack can grow while the frame is in use without anything pointing into it
+  fn execute_in_new_frame(
+    &mut self,
+    bindings: Vec<(usize, Data)>,
+    scope: Scope<'a>,
+  ) -> Result<Option<Flow>, InterpreterError> {
+    self.nested(|vm| vm.execute_in_frame(bindings, scope))
+  }
+
+  fn execute_in_frame(
+    &mut self,
+    bindings: Vec<(usize, Data)>,
+    scope: Scope<'a>,
+  ) -> Result<Option<Flow>, InterpreterError> {
+    if let Some(max_depth) = self.limits.max_depth {
+      if self.stack.len() >= max_depth {
+        return Err(InterpreterError::LimitExceeded(Limit::Depth(max_depth)));
+      }
+    }
+    let mut new_frame = self.free_frames.pop().unwrap_or_default();
+    for (index, data) in bindings {
+      if new_frame.slots.len() <= index {
+        new_frame.slots.resize(index + 1, None);
+      }
+      new_frame.slots[index] = Some(data);
+    }
+    self.stack.push(new_frame);
+    let guard = match scope {
+      Scope::MatchArm(arm) => arm.guard.as_ref(),
+      _ => None,
+    };
+    if let Some(hooks) = &mut self.hooks {
+      hooks.scope_enter(scope);
+    }
+    let flow = self.execute(guard, scope.instructions());
+    if self.hooks.is_some() {
+      if let Err(error) = &flow {
+        self.report_error(error);
+      }
+      if let Some(hooks) = &mut self.hooks {
+        hooks.scope_exit();
+      }
+    }
+    let mut frame = self.stack.pop().expect("Stack frame was popped twice");
+    frame.slots.clear();
+    self.free_frames.push(frame);
+    flow
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{parser, resolver, tokenizer};
+
+  #[derive(Default)]
+  struct Output(Vec<String>);
+
+  impl Hooks for Output {
+    fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+      self.0.push(text.to_string());
+      Ok(())
+    }
+  }
+
+  // What the code printed, and how it ended
+  fn run(code: &str) -> (Vec<String>, Result<(), InterpreterError>) {
+    run_with(code, Limits::default(), &[])
+  }
+
+  fn run_with(
+    code: &str,
+    limits: Limits,
+    args: &[String],
+  ) -> (Vec<String>, Result<(), InterpreterError>) {
+    let tokens = tokenizer::tokenize(code).expect("Code does not tokenize");
+    let (mut instructions, diagnostics) = parser::parse(tokens, &Limits::default());
+    assert!(
+      diagnostics.is_empty(),
+      "Code does not parse: {:?}",
+      diagnostics
+    );
+    resolver::resolve(&mut instructions);
+    let mut output = Output::default();
+    let result = interpret_with_hooks(&instructions, limits, args, &mut output);
+    (output.0, result)
+  }
+
+  fn printed(code: &str) -> Vec<String> {
+    let (output, result) = run(code);
+    if let Err(error) = result {
+      panic!("Code failed with '{}' after printing {:?}", error, output);
+    }
+    output
+  }
+
+  #[test]
+  fn nested_if_and_while_blocks() {
+    let code = "
+      i = 0;
+      while (i < 3) {
+        j = 0;
+        while (j < 2) {
+          if (i == j) {
+            print(\"same\");
+          } else {
+            if (i > j) { print(i - j); } else { print(j - i); };
+          };
+          j += 1;
+        };
+        i += 1;
+      };
+    ";
+    assert_eq!(printed(code), ["same", "1", "1", "same", "2", "1"]);
+  }
+
+  #[test]
+  fn blocks_assign_the_variables_of_the_blocks_around_them() {
+    let code = "
+      x = 1;
+      if (true) {
+        x = 2;
+        while (x < 5) {
+          if (true) { x += 1; };
+        };
+      };
+      print(x);
+    ";
+    assert_eq!(printed(code), ["5"]);
+  }
+
+  #[test]
+  fn variables_of_a_block_end_with_it() {
+    let (output, result) = run("if (true) { inner = 1; print(inner); }; print(inner);");
+    assert_eq!(output, ["1"]);
+    assert!(matches!(result, Err(InterpreterError::VariableNotDefined(name)) if name == "inner"));
+  }
+
+  #[test]
+  fn let_shadows_a_variable_for_the_rest_of_its_block() {
+    let code = "x = 1; if (true) { let x = 5; x += 1; print(x); }; print(x);";
+    assert_eq!(printed(code), ["6", "1"]);
+  }
+
+  #[test]
+  fn frames_are_reused_without_their_old_variables() {
+    // The second time around the frame of the loop body comes from the ones that were freed
+    let code = "
+      i = 0;
+      while (i < 2) {
+        if (i == 1) { print(seen); };
+        seen = i;
+        i += 1;
+      };
+    ";
+    let (output, result) = run(code);
+    assert!(output.is_empty());
+    assert!(matches!(result, Err(InterpreterError::VariableNotDefined(name)) if name == "seen"));
+  }
+
+  #[test]
+  fn functions_only_see_their_own_frames() {
+    let code = "
+      x = 1;
+      fn count(n) {
+        total = 0;
+        while (n > 0) { total += n; n -= 1; };
+        return total;
+      }
+      print(count(4));
+      print(x);
+    ";
+    assert_eq!(printed(code), ["10", "1"]);
+    let (_, result) = run("x = 1; fn f() { return x; } f();");
+    assert!(matches!(result, Err(InterpreterError::VariableNotDefined(name)) if name == "x"));
+  }
+
+  #[test]
+  fn recursive_calls_keep_their_own_variables() {
+    let code = "
+      fn fib(n) {
+        if (n < 2) { return n; };
+        let a = fib(n - 1);
+        let b = fib(n - 2);
+        return a + b;
+      }
+      print(fib(10));
+    ";
+    assert_eq!(printed(code), ["55"]);
+  }
+
+  #[test]
+  fn a_session_keeps_what_earlier_pieces_defined() {
+    let code = Code::default();
+    let mut session = Session::new(&code, Limits::default());
+    let mut resolver = resolver::Resolver::new();
+    let mut run = |piece: &str| {
+      let tokens = tokenizer::tokenize(piece).expect("Code does not tokenize");
+      let (mut instructions, diagnostics) = parser::parse(tokens, &Limits::default());
+      assert!(diagnostics.is_empty(), "{} does not parse", piece);
+      resolver.resolve_next(&mut instructions);
+      session.run(instructions).map_err(|error| error.to_string())
+    };
+    assert_eq!(run("fn double(x) { return x * 2; };"), Ok(None));
+    assert_eq!(run("n = double(4);"), Ok(None));
+    assert_eq!(run("double(n) + 1;"), Ok(Some("17".to_string())));
+    assert_eq!(run("n += 1;"), Ok(None));
+    assert_eq!(run("m;"), Err("Variable 'm' is not defined".to_string()));
+    assert_eq!(run("n;"), Ok(Some("9".to_string())));
+  }
+
+  #[test]
+  fn match_takes_the_first_arm_whose_pattern_and_guard_fit() {
+    let code = "
+      enum Shape { Circle(r), Rect(w, h), Empty };
+      fn area(shape) {
+        match shape {
+          Circle(r) => { return r * r * 3; },
+          Rect(w, h) if w == h => { print(\"square\"); return w * w; },
+          Rect(w, h) => { return w * h; },
+          Empty => { return 0; }
+        };
+      }
+      print(area(Circle(2)));
+      print(area(Rect(3, 3)));
+      print(area(Rect(2, 5)));
+      print(area(Empty));
+      print(Rect(1, 2));
+      print(Circle(1) == Circle(1));
+      match 3 { 1 => { print(\"one\"); }, n if n > 2 => { print(n); } };
+      match \"b\" { \"a\" => { print(\"a\"); }, _ => { print(\"other\"); } };
+    ";
+    assert_eq!(
+      printed(code),
+      ["12", "square", "9", "10", "0", "Rect(1, 2)", "true", "3", "other"]
+    );
+  }
+
+  #[test]
+  fn a_value_no_arm_matches_is_an_error() {
+    let (output, result) = run(
+      "enum Shape { Circle(r), Rect(w, h) };
+      match Rect(1, 2) { Circle(r) => { print(r); }, Rect(w, h) if w > h => { print(w); } };
+      print(\"after\");",
+    );
+    assert!(output.is_empty(), "{:?}", output);
+    assert_eq!(
+      result.unwrap_err().to_string(),
+      "No match arm matched the value Rect(1, 2)"
+    );
+  }
+
+  #[test]
+  fn optional_access_stops_at_none_and_coalesce_replaces_it() {
+    let code = "
+      enum Pair { Pair(first, second) };
+      pair = Pair(none, [1, 2]);
+      empty = none;
+      print(empty);
+      print(empty?.[0]);
+      print(empty?.first ?? \"nothing\");
+      print(pair?.second?.[1]);
+      print(pair.first?.[0] ?? \"no first\");
+      print(0 ?? 1);
+      print(none == none);
+    ";
+    assert_eq!(
+      printed(code),
+      ["none", "none", "nothing", "2", "no first", "0", "true"]
+    );
+  }
+
+  #[test]
+  fn plain_access_on_none_is_an_error() {
+    let (output, result) = run("empty = none; print(empty[0]);");
+    assert!(output.is_empty(), "{:?}", output);
+    assert_eq!(
+      result.unwrap_err().to_string(),
+      "Type mismatch: Expected list, string or enum value when indexing"
+    );
+  }
+
+  #[test]
+  fn long_sums_are_within_the_default_nesting() {
+    let sum = vec!["1"; 251].join(" + ");
+    assert_eq!(printed(&format!("print({});", sum)), ["251"]);
+  }
+
+  #[test]
+  fn integer_overflow_is_an_error() {
+    for (code, doing) in [
+      ("print(9223372036854775807 + 1);", "adding"),
+      ("print((0 - 9223372036854775807) - 2);", "subtracting"),
+      ("print(4611686018427387904 * 2);", "multiplying"),
+      (
+        "x = (0 - 9223372036854775807) - 1; print(x / (0 - 1));",
+        "dividing",
+      ),
+      (
+        "x = (0 - 9223372036854775807) - 1; print(x % (0 - 1));",
+        "taking modulo",
+      ),
+      ("x = 9223372036854775807; x += 1;", "adding"),
+    ] {
+      let (output, result) = run(code);
+      assert!(output.is_empty(), "{} printed {:?}", code, output);
+      match result {
+        Err(InterpreterError::IntegerOverflow(what)) => assert_eq!(what, doing, "{}", code),
+        result => panic!("{} ended with {:?}", code, result),
+      }
+    }
+  }
+
+  #[test]
+  fn integer_division_by_zero_is_an_error() {
+    for code in ["print(5 / 0);", "print(5 % 0);", "x = 5; x /= 0;"] {
+      let (_, result) = run(code);
+      assert!(
+        matches!(result, Err(InterpreterError::DivisionByZero)),
+        "{} ended with {:?}",
+        code,
+        result
+      );
+    }
+    // Floats divide by zero without an error
+    assert_eq!(
+      printed("print(5.0 / 0); print(0 - 5 / 0.0);"),
+      ["inf", "-inf"]
+    );
+  }
+
+  #[test]
+  fn exit_codes_are_the_ones_a_process_can_have() {
+    for (code, expected) in [("exit();", 0), ("exit(0);", 0), ("exit(255);", 255)] {
+      let (_, result) = run(code);
+      assert!(
+        matches!(result, Err(InterpreterError::Exit(found)) if found == expected),
+        "{} ended with {:?}",
+        code,
+        result
+      );
+    }
+    for code in ["exit(256);", "exit(0 - 1);", "exit(4294967296);"] {
+      let (_, result) = run(code);
+      let Err(InterpreterError::TypeMismatch(message)) = &result else {
+        panic!("{} ended with {:?}", code, result);
+      };
+      assert!(message.ends_with("is not between 0 and 255"), "{}", message);
+    }
+  }
+
+  #[test]
+  fn strings_lists_and_args_are_held_to_the_size_limit() {
+    let limits = Limits {
+      max_size: Some(5),
+      ..Limits::default()
+    };
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    for (code, args) in [
+      ("print(\"abcdefgh\");", args(&[])),
+      ("print(\"abc\" + \"def\");", args(&[])),
+      ("x = [1, 2, 3, 4, 5, 6];", args(&[])),
+      ("x = [1, 2, 3] + [4, 5, 6];", args(&[])),
+      ("print(1);", args(&["abcdefgh"])),
+      ("print(1);", args(&["a", "b", "c", "d", "e", "f"])),
+    ] {
+      let (output, result) = run_with(code, limits.clone(), &args);
+      assert!(output.is_empty(), "{} printed {:?}", code, output);
+      assert!(
+        matches!(result, Err(InterpreterError::LimitExceeded(Limit::Size(5)))),
+        "{} with {:?} ended with {:?}",
+        code,
+        args,
+        result
+      );
+    }
+    let code = "print(\"abcde\"); print([1, 2, 3, 4, 5]); print(args);";
+    let (output, result) = run_with(code, limits, &args(&["abcde", "b"]));
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(output, ["abcde", "[1, 2, 3, 4, 5]", "[abcde, b]"]);
+  }
+
+  #[test]
+  fn break_leaves_only_the_innermost_loop() {
+    let code = "
+      i = 0;
+      while (i < 3) {
+        j = 0;
+        while (true) {
+          if (j == i) { break; };
+          j += 1;
+        };
+        print(j);
+        i += 1;
+      };
+    ";
+    assert_eq!(printed(code), ["0", "1", "2"]);
+  }
+
+  // How the code ended, run on a thread with the stack main gives code within the default limits
+  fn run_with_default_stack(code: String) -> Result<Vec<String>, String> {
+    let thread = std::thread::Builder::new().stack_size(Limits::default().stack_size());
+    let run = move || match run(&code) {
+      (output, Ok(())) => Ok(output),
+      (_, Err(error)) => Err(error.to_string()),
+    };
+    thread.spawn(run).unwrap().join().unwrap()
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn code_nested_up_to_the_limit_fits_on_the_stack() {
+    let levels = crate::limits::DEFAULT_MAX_NESTING - 10;
+    let blocks = format!(
+      "x = 1;{}x += 1;{}print(x);",
+      "{".repeat(levels),
+      "}".repeat(levels)
+    );
+    assert_eq!(run_with_default_stack(blocks).unwrap(), ["2"]);
+    let calls = format!(
+      "fn f(x) {{ return x; }}; print({}1{});",
+      "f(".repeat(levels),
+      ")".repeat(levels)
+    );
+    assert_eq!(run_with_default_stack(calls).unwrap(), ["1"]);
+    let brackets = format!("print({}1{});", "(".repeat(levels), ")".repeat(levels));
+    assert_eq!(run_with_default_stack(brackets).unwrap(), ["1"]);
+    let terms = crate::limits::max_chain(Some(crate::limits::DEFAULT_MAX_NESTING)).unwrap() + 1;
+    let sum = format!("print({});", vec!["1"; terms].join(" + "));
+    assert_eq!(run_with_default_stack(sum).unwrap(), [terms.to_string()]);
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn deep_recursion_runs_out_of_levels_before_the_stack() {
+    for depth in [0, 20, 300] {
+      let code = format!(
+        "fn f(n) {{ return {}f(n - 1){}; }}; f(1);",
+        "1 + (".repeat(depth),
+        ")".repeat(depth)
+      );
+      let error = run_with_default_stack(code).unwrap_err();
+      assert!(error.starts_with("Limit exceeded"), "{}", error);
+    }
+  }
 }
@@ -0,0 +1,933 @@
+use std::mem;
+
+use serde_json::json;
+
+use crate::{
+  limits::DEFAULT_MAX_CALLS,
+  number::Number,
+  parser::{
+    Expression, Function, Identifier, Instruction, InstructionKind, MatchArm, Pattern, Value,
+  },
+  resolver::Slot,
+  tokenizer::{Operator, Position},
+};
+
+const RUNTIME: &str = include_str!("js_runtime.js");
+
+// Words a variable can not be called in JavaScript, a fish name that is one gets a _ after it
+const RESERVED: [&str; 50] = [
+  "arguments",
+  "await",
+  "break",
+  "case",
+  "catch",
+  "class",
+  "const",
+  "continue",
+  "debugger",
+  "default",
+  "delete",
+  "do",
+  "else",
+  "enum",
+  "eval",
+  "export",
+  "extends",
+  "false",
+  "finally",
+  "for",
+  "function",
+  "if",
+  "implements",
+  "import",
+  "in",
+  "Infinity",
+  "instanceof",
+  "interface",
+  "let",
+  "NaN",
+  "new",
+  "null",
+  "package",
+  "private",
+  "protected",
+  "public",
+  "return",
+  "static",
+  "super",
+  "switch",
+  "this",
+  "throw",
+  "true",
+  "try",
+  "typeof",
+  "undefined",
+  "var",
+  "void",
+  "while",
+  "with",
+];
+
+/*
+ Turns a script into JavaScript that does what the interpreter would do when running it, so it
+ can run in a browser or in node without the interpreter. The instructions have to be resolved
+ first.
+
+ The code keeps the shape of the script: blocks stay blocks, variables keep their names and every
+ operation is a call into a small runtime, since none of them work quite like the JavaScript
+ operator does. Integers are BigInts so they keep their 64 bits, and errors are thrown with the
+ messages the interpreter gives. Every line that comes from an instruction is in the source map,
+ pointing at where the instruction starts.
+*/
+pub fn transpile(instructions: &[Instruction]) -> Output {
+  let mut generator = Generator::default();
+  let start = generator.open("$.run(() =>");
+  generator.instructions(instructions);
+  generator.close(start, ");");
+
+  let header = format!(
+    "// Compiled from a fish script\n\"use strict\";\nconst MAX_CALLS = {};\n{}",
+    DEFAULT_MAX_CALLS, RUNTIME
+  );
+  let mut code = header.clone();
+  // Lines before the script's own have no mappings
+  let mut mappings = ";".repeat(header.lines().count());
+  let mut encoder = MappingEncoder::default();
+  for (number, line) in generator.lines.iter().enumerate() {
+    if number > 0 {
+      mappings.push(';');
+    }
+    code.push_str(&"  ".repeat(line.indent));
+    code.push_str(&line.text);
+    code.push('\n');
+    if let Some(source) = line.source.filter(|source| source.line > 0) {
+      encoder.segment(&mut mappings, line.indent * 2, source);
+    }
+  }
+  Output { code, mappings }
+}
+
+pub struct Output {
+  pub code: String,
+  // The mappings of the source map, in the format version 3 of source maps uses
+  mappings: String,
+}
+
+impl Output {
+  // A version 3 source map for the code when it is in `file`, the script is at `source` relative
+  // to the map. With its content the map works without the script being around
+  pub fn source_map(&self, file: &str, source: &str, content: Option<&str>) -> String {
+    json!({
+      "version": 3,
+      "file": file,
+      "sources": [source],
+      "sourcesContent": [content],
+      "names": [],
+      "mappings": self.mappings,
+    })
+    .to_string()
+  }
+}
+
+// The comment that points at a source map, which is in the comment itself when there is no file
+// for it
+pub fn source_map_comment(map_file: Option<&str>, map: &str) -> String {
+  match map_file {
+    Some(file) => format!("//# sourceMappingURL={}\n", file),
+    None => format!(
+      "//# sourceMappingURL=data:application/json;base64,{}\n",
+      base64(map.as_bytes())
+    ),
+  }
+}
+
+fn base64(bytes: &[u8]) -> String {
+  const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+  for chunk in bytes.chunks(3) {
+    let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+      group | (*byte as u32) << (16 - 8 * index)
+    });
+    for index in 0..4 {
+      match index <= chunk.len() {
+        true => encoded.push(ALPHABET[(group >> (18 - 6 * index) & 0x3f) as usize] as char),
+        false => encoded.push('='),
+      }
+    }
+  }
+  encoded
+}
+
+// Segments are relative to the one before them, apart from the column which starts over on
+// every line
+#[derive(Default)]
+struct MappingEncoder {
+  line: i64,
+  column: i64,
+}
+
+impl MappingEncoder {
+  fn segment(&mut self, mappings: &mut String, column: usize, source: Position) {
+    let line = source.line as i64 - 1;
+    let source_column = source.column as i64 - 1;
+    for value in [
+      column as i64,
+      0,
+      line - self.line,
+      source_column - self.column,
+    ] {
+      vlq(mappings, value);
+    }
+    self.line = line;
+    self.column = source_column;
+  }
+}
+
+fn vlq(output: &mut String, value: i64) {
+  const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut rest = match value < 0 {
+    true => ((-value) << 1) | 1,
+    false => value << 1,
+  };
+  loop {
+    let mut digit = rest & 0x1f;
+    rest >>= 5;
+    if rest > 0 {
+      digit |= 0x20;
+    }
+    output.push(DIGITS[digit as usize] as char);
+    if rest == 0 {
+      break;
+    }
+  }
+}
+
+struct Line {
+  indent: usize,
+  text: String,
+  source: Option<Position>,
+}
+
+#[derive(Default)]
+struct Frame {
+  // The JavaScript names of the variables in the slots, given out when a slot is first used
+  names: Vec<Option<String>>,
+  // Slots that are assigned by the time the code being generated runs, which can be read
+  // without checking
+  assigned: Vec<bool>,
+  // Parameters are declared by the function, the other variables at the start of the block
+  parameters: usize,
+}
+
+#[derive(Default)]
+struct Generator {
+  lines: Vec<Line>,
+  indent: usize,
+  // The frames of the function being generated
+  frames: Vec<Frame>,
+  // How many loops are around the code in the function being generated
+  loops: usize,
+  function: bool,
+  labels: usize,
+  // Where the instruction being generated starts
+  source: Option<Position>,
+}
+
+fn string(text: &str) -> String {
+  json!(text).to_string()
+}
+
+// Fish names follow UAX #31 like JavaScript ones, only the reserved words need to change. The
+// optimizer's own variables have a # that no fish name can have, it becomes a $ that none can
+// either
+fn mangle(name: &str) -> String {
+  let name = name.replace('#', "$");
+  match RESERVED.contains(&name.as_str()) {
+    true => format!("{}_", name),
+    false => name,
+  }
+}
+
+fn number(number: Number) -> String {
+  match number {
+    Number::Integer(integer) => format!("{}n", integer),
+    Number::Float(float) if float.is_nan() => "NaN".to_string(),
+    Number::Float(float) if float == f64::INFINITY => "Infinity".to_string(),
+    Number::Float(float) if float == f64::NEG_INFINITY => "-Infinity".to_string(),
+    Number::Float(float) => format!("{:?}", float),
+  }
+}
+
+fn runtime_function(operator: Operator) -> &'static str {
+  match operator {
+    Operator::Add | Operator::AddAssign => "add",
+    Operator::Subtract | Operator::SubtractAssign => "subtract",
+    Operator::Multiply | Operator::MultiplyAssign => "multiply",
+    Operator::Divide | Operator::DivideAssign => "divide",
+    Operator::Modulo | Operator::ModuloAssign => "modulo",
+    Operator::Exponent => "power",
+    Operator::Equal => "equal",
+    Operator::NotEqual => "notEqual",
+    Operator::LessThan => "less",
+    Operator::LessThanOrEqual => "lessEqual",
+    Operator::GreaterThan => "greater",
+    Operator::GreaterThanOrEqual => "greaterEqual",
+    Operator::And => "and",
+    Operator::Or => "or",
+    operator => panic!("{:?} is not a binary operator", operator),
+  }
+}
+
+// Whether the instructions never get to their end, so nothing has to come after them
+fn ends_early(instructions: &[Instruction]) -> bool {
+  matches!(
+    instructions.last().map(|instruction| &instruction.kind),
+    Some(InstructionKind::Return { .. } | InstructionKind::Break)
+  )
+}
+
+impl Generator {
+  fn line(&mut self, text: impl Into<String>) {
+    self.lines.push(Line {
+      indent: self.indent,
+      text: text.into(),
+      source: self.source,
+    });
+  }
+
+  // Starts a block with a frame of its own, returns where its code starts
+  fn open(&mut self, head: &str) -> usize {
+    match head {
+      "" => self.line("{"),
+      head => self.line(format!("{} {{", head)),
+    }
+    self.indent += 1;
+    self.frames.push(Frame::default());
+    self.lines.len()
+  }
+
+  // Its variables are declared at the start, once it is known which there are
+  fn close(&mut self, start: usize, tail: &str) {
+    let frame = self.frames.pop().expect("No frame to close");
+    let names: Vec<String> = frame
+      .names
+      .into_iter()
+      .skip(frame.parameters)
+      .flatten()
+      .collect();
+    if !names.is_empty() {
+      let declaration = Line {
+        indent: self.indent,
+        text: format!("let {};", names.join(", ")),
+        source: None,
+      };
+      self.lines.insert(start, declaration);
+    }
+    self.indent -= 1;
+    self.lines.push(Line {
+      indent: self.indent,
+      text: format!("}}{}", tail),
+      source: None,
+    });
+  }
+
+  fn block(&mut self, head: &str, instructions: &[Instruction]) {
+    let start = self.open(head);
+    self.instructions(instructions);
+    self.close(start, "");
+  }
+
+  // The name of the variable in the slot, different from all the others it could be mixed up with
+  fn name(&mut self, slot: Slot, name: &str) -> String {
+    let index = self
+      .frames
+      .len()
+      .checked_sub(slot.depth + 1)
+      .expect("Slot is outside of the function");
+    if let Some(Some(name)) = self.frames[index].names.get(slot.index) {
+      return name.clone();
+    }
+    let base = mangle(name);
+    let mut name = base.clone();
+    let mut count = 1;
+    while self
+      .frames
+      .iter()
+      .flat_map(|frame| frame.names.iter().flatten())
+      .any(|taken| *taken == name)
+    {
+      count += 1;
+      name = format!("{}_{}", base, count);
+    }
+    let frame = &mut self.frames[index];
+    if frame.names.len() <= slot.index {
+      frame.names.resize(slot.index + 1, None);
+      frame.assigned.resize(slot.index + 1, false);
+    }
+    frame.names[slot.index] = Some(name.clone());
+    name
+  }
+
+  // Only what is assigned by a statement of the block itself is sure to be assigned after it
+  fn assigned(&mut self, slot: Slot) {
+    if slot.depth == 0 {
+      if let Some(frame) = self.frames.last_mut() {
+        frame.assigned[slot.index] = true;
+      }
+    }
+  }
+
+  fn is_assigned(&self, slot: Slot) -> bool {
+    let frame = &self.frames[self.frames.len() - 1 - slot.depth];
+    frame.assigned.get(slot.index).copied().unwrap_or(false)
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    let mut index = 0;
+    while index < instructions.len() {
+      let instruction = &instructions[index];
+      index += 1;
+      let outer = self.source.replace(instruction.span.start);
+      match &instruction.kind {
+        InstructionKind::If {
+          condition,
+          instructions: body,
+        } => {
+          let condition = self.value(condition);
+          self.block(
+            &format!("if ($.condition({}, \"if condition\"))", condition),
+            body,
+          );
+          // An else only runs right after an if that did not, one anywhere else never runs
+          if let Some(Instruction {
+            kind: InstructionKind::Else { instructions },
+            ..
+          }) = instructions.get(index)
+          {
+            self.lines.pop();
+            self.block("} else", instructions);
+            index += 1;
+          }
+        }
+        InstructionKind::Else { .. } => (),
+        InstructionKind::While {
+          condition,
+          instructions,
+        } => {
+          let condition = self.value(condition);
+          self.loops += 1;
+          self.block(
+            &format!("while ($.condition({}, \"while condition\"))", condition),
+            instructions,
+          );
+          self.loops -= 1;
+        }
+        InstructionKind::Scope { instructions } => self.block("", instructions),
+        InstructionKind::Value { value } => match value {
+          Value::Expression(expression) if expression.get_operator().is_assignment() => {
+            let assignment = self.assignment(expression);
+            self.line(format!("{};", assignment));
+            if let Value::Identifier(Identifier {
+              slot: Some(slot), ..
+            }) = expression.get_left()
+            {
+              self.assigned(*slot);
+            }
+          }
+          value => {
+            let value = self.value(value);
+            self.line(format!("{};", value));
+          }
+        },
+        // Outside of a loop a break ends the function or the script
+        InstructionKind::Break => match self.loops {
+          0 => self.line("return;"),
+          _ => self.line("break;"),
+        },
+        InstructionKind::Print { message } => {
+          let message = self.value(message);
+          self.line(format!("$.print({});", message));
+        }
+        InstructionKind::Input { variable } => match variable.slot {
+          Some(slot) => {
+            let name = self.name(slot, &variable.name);
+            self.line(format!("{} = $.input();", name));
+            self.assigned(slot);
+          }
+          None => self.line(format!(
+            "$.fail({});",
+            string(&format!("Variable '{}' is not defined", variable.name))
+          )),
+        },
+        InstructionKind::Enum { name, variants, .. } => {
+          for variant in variants {
+            let fields: Vec<String> = variant.fields.iter().map(|field| string(field)).collect();
+            self.line(format!(
+              "$.defineVariant({}, {}, [{}]);",
+              string(name),
+              string(&variant.name),
+              fields.join(", ")
+            ));
+          }
+        }
+        InstructionKind::Match { value, arms } => self.match_arms(value, arms),
+        InstructionKind::Let {
+          variable, value, ..
+        } => {
+          let value = self.value(value);
+          let slot = variable.slot.expect("Let was not resolved");
+          let name = self.name(slot, &variable.name);
+          self.line(format!("{} = {};", name, value));
+          self.assigned(slot);
+        }
+        InstructionKind::Function(function) => self.function(function),
+        InstructionKind::Return { value } => match value {
+          Some(value) if self.function => {
+            let value = self.value(value);
+            self.line(format!("return {};", value));
+          }
+          // The script ends, its value is not used
+          Some(value) => {
+            let value = self.value(value);
+            self.line(format!("{};", value));
+            self.line("return;");
+          }
+          None => self.line("return;"),
+        },
+        InstructionKind::Assert { condition, message } => {
+          self.assert(instruction.span.start, condition, message.as_ref())
+        }
+        // Only the test runner runs tests
+        InstructionKind::Test { .. } => (),
+      }
+      self.source = outer;
+    }
+  }
+
+  // The arms are tried in a labeled block, which the arm that matched breaks out of
+  fn match_arms(&mut self, value: &Value, arms: &[MatchArm]) {
+    self.labels += 1;
+    let label = format!("match{}", self.labels);
+    let value = self.value(value);
+    self.line(format!("{}: {{", label));
+    self.indent += 1;
+    self.line(format!("const $subject = {};", value));
+    for arm in arms {
+      let start = self.open("");
+      let mut conditions = Vec::new();
+      self.pattern(&arm.pattern, "$subject", &mut conditions);
+      if let Some(guard) = &arm.guard {
+        let guard = self.value(guard);
+        conditions.push(format!("$.condition({}, \"match guard\")", guard));
+      }
+      let condition = match conditions.is_empty() {
+        true => "true".to_string(),
+        false => conditions.join(" && "),
+      };
+      self.line(format!("if ({}) {{", condition));
+      self.indent += 1;
+      self.instructions(&arm.instructions);
+      if !ends_early(&arm.instructions) {
+        self.line(format!("break {};", label));
+      }
+      self.indent -= 1;
+      self.line("}");
+      self.close(start, "");
+    }
+    self.line("$.noArm($subject);");
+    self.indent -= 1;
+    self.line("}");
+  }
+
+  // What has to be true for the subject to match, binding variables along the way
+  fn pattern(&mut self, pattern: &Pattern, subject: &str, conditions: &mut Vec<String>) {
+    match pattern {
+      Pattern::Wildcard => (),
+      Pattern::Literal(literal) => {
+        let literal = self.value(literal);
+        conditions.push(format!("$.equal({}, {})", subject, literal));
+      }
+      Pattern::Identifier(identifier) => match identifier.slot {
+        Some(slot) => {
+          let name = self.name(slot, &identifier.name);
+          conditions.push(format!("({} = {}, true)", name, subject));
+          self.assigned(slot);
+        }
+        // A variant without fields
+        None => conditions.push(format!(
+          "$.isVariant({}, {})",
+          subject,
+          string(&identifier.name)
+        )),
+      },
+      Pattern::Variant { name, fields, .. } => {
+        conditions.push(format!(
+          "$.isVariant({}, {}, {})",
+          subject,
+          string(name),
+          fields.len()
+        ));
+        for (index, field) in fields.iter().enumerate() {
+          self.pattern(field, &format!("{}.fields[{}]", subject, index), conditions);
+        }
+      }
+    }
+  }
+
+  // A fish function becomes a JavaScript function, defined when the definition runs
+  fn function(&mut self, function: &Function) {
+    let frames = mem::take(&mut self.frames);
+    let loops = mem::replace(&mut self.loops, 0);
+    let outer = mem::replace(&mut self.function, true);
+    self.frames.push(Frame::default());
+    let parameters: Vec<String> = function
+      .parameters
+      .iter()
+      .enumerate()
+      .map(|(index, parameter)| {
+        let slot = Slot { depth: 0, index };
+        let name = self.name(slot, &parameter.variable.name);
+        self.assigned(slot);
+        name
+      })
+      .collect();
+    self.frames[0].parameters = parameters.len();
+    let frame = self.frames.pop().expect("Function frame is missing");
+    let head = format!(
+      "$.defineFunction({}, {}, function {}({})",
+      string(&function.name),
+      parameters.len(),
+      mangle(&function.name),
+      parameters.join(", ")
+    );
+    // The frame of the parameters becomes the frame of the body
+    let start = self.open(&head);
+    *self.frames.last_mut().expect("Function frame is missing") = frame;
+    self.instructions(&function.instructions);
+    self.close(start, ");");
+    self.frames = frames;
+    self.loops = loops;
+    self.function = outer;
+  }
+
+  fn assert(&mut self, position: Position, condition: &Value, message: Option<&Value>) {
+    let position = string(&position.to_string());
+    let mut compared = condition;
+    while let Value::Expression(expression) = compared {
+      if *expression.get_operator() != Operator::Brackets {
+        break;
+      }
+      compared = expression.get_left();
+    }
+    let call = match compared {
+      Value::Expression(expression) if expression.get_operator().is_comparison() => {
+        let left = self.value(expression.get_left());
+        let right = self.value(expression.get_right().expect("No right for comparison"));
+        format!(
+          "$.assertComparison({}, {}, {}, {}",
+          position,
+          string(&expression.get_operator().to_string()),
+          left,
+          right
+        )
+      }
+      _ => {
+        let condition = self.value(condition);
+        format!("$.assert({}, {}", position, condition)
+      }
+    };
+    match message {
+      Some(message) => {
+        let message = self.value(message);
+        self.line(format!("{}, () => {});", call, message));
+      }
+      None => self.line(format!("{});", call)),
+    }
+  }
+
+  // A JavaScript expression for the value, which evaluates everything in the order the
+  // interpreter does
+  fn value(&mut self, value: &Value) -> String {
+    match value {
+      Value::Number(value) => number(*value),
+      Value::String(value) => string(value),
+      Value::Boolean(boolean) => boolean.to_string(),
+      Value::None => "null".to_string(),
+      Value::List(items) => {
+        let items: Vec<String> = items.iter().map(|item| self.value(item)).collect();
+        format!("[{}]", items.join(", "))
+      }
+      Value::Identifier(identifier) => self.identifier(identifier),
+      Value::Call {
+        name, arguments, ..
+      } => {
+        let mut arguments: Vec<String> = arguments
+          .iter()
+          .map(|argument| self.value(argument))
+          .collect();
+        arguments.insert(0, string(name));
+        format!("$.call({})", arguments.join(", "))
+      }
+      Value::Field { .. } | Value::Index { .. } if value.has_optional() => {
+        format!("$.chain(() => {})", self.access(value))
+      }
+      Value::Field { .. } | Value::Index { .. } => self.access(value),
+      Value::Expression(expression) => self.expression(expression),
+    }
+  }
+
+  // A variable, or when it was not assigned yet a variant without fields or `args`
+  fn identifier(&mut self, identifier: &Identifier) -> String {
+    match identifier.slot {
+      Some(slot) if self.is_assigned(slot) => self.name(slot, &identifier.name),
+      Some(slot) => {
+        let name = self.name(slot, &identifier.name);
+        format!("$.variable({}, {})", name, string(&identifier.name))
+      }
+      None => format!("$.variable(undefined, {})", string(&identifier.name)),
+    }
+  }
+
+  fn access(&mut self, value: &Value) -> String {
+    let (base, optional) = match value {
+      Value::Field {
+        value, optional, ..
+      }
+      | Value::Index {
+        value, optional, ..
+      } => (value, *optional),
+      value => return self.value(value),
+    };
+    let mut base = self.access(base);
+    if optional {
+      base = format!("$.optional({})", base);
+    }
+    match value {
+      Value::Field { field, .. } => format!("$.field({}, {})", base, string(field)),
+      Value::Index { index, .. } => format!("$.index({}, {})", base, self.value(index)),
+      _ => unreachable!(),
+    }
+  }
+
+  fn expression(&mut self, expression: &Expression) -> String {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    match operator {
+      Operator::Brackets => self.value(left),
+      Operator::Not => format!("$.not({})", self.value(left)),
+      operator if operator.is_assignment() => format!("({})", self.assignment(expression)),
+      // The right side is only evaluated when the left is none
+      Operator::Coalesce => {
+        let left = self.value(left);
+        let right = self.value(expression.get_right().expect("No right for coalesce"));
+        format!("$.coalesce({}, () => {})", left, right)
+      }
+      operator => {
+        let left = self.value(left);
+        let right = self.value(expression.get_right().expect("No right for operator"));
+        format!("$.{}({}, {})", runtime_function(operator), left, right)
+      }
+    }
+  }
+
+  // An assignment, without brackets around it
+  fn assignment(&mut self, expression: &Expression) -> String {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    let right = expression.get_right().expect("No right for assignment");
+    let (slot, name) = match left {
+      Value::Identifier(Identifier {
+        slot: Some(slot),
+        name,
+        ..
+      }) => (*slot, name),
+      Value::Identifier(identifier) => {
+        return format!(
+          "$.fail({})",
+          string(&format!("Variable '{}' is not defined", identifier.name))
+        )
+      }
+      _ => {
+        return "$.fail(\"Type mismatch: Expected identifier on left side of assignment\")"
+          .to_string()
+      }
+    };
+    let value = match operator {
+      Operator::Assign => self.value(right),
+      operator => {
+        let current = self.value(left);
+        let right = self.value(right);
+        format!("$.{}({}, {})", runtime_function(operator), current, right)
+      }
+    };
+    format!("{} = {}", self.name(slot, name), value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{limits::Limits, parser, resolver, samples, tokenizer};
+  use std::{env, fs, path::Path};
+
+  // The code generated for every sample, without the runtime in front of it. Where there is no
+  // node to run the programs this is what keeps the backend from changing unnoticed. Running the
+  // tests with FISH_UPDATE_SNAPSHOTS=1 writes it anew
+  const SNAPSHOTS: &str = "src/js_backend_snapshots.js";
+
+  fn script(code: &str) -> &str {
+    let start = code
+      .rfind("\n$.run(")
+      .expect("There is no script after the runtime");
+    &code[start + 1..]
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn programs_do_what_the_interpreter_does() {
+    let dir = samples::temp_dir("js");
+    let mut ran = 0;
+    let samples = samples::all().into_iter().chain(samples::edge_cases());
+    for (index, (name, code)) in samples.enumerate() {
+      let Some(expected) = samples::interpret(&code) else {
+        continue;
+      };
+      let program = dir.join(format!("{}.js", index));
+      fs::write(&program, transpile(&samples::instructions(&code)).code).unwrap();
+      let Some(ended) = samples::run_program("node", &[program.as_os_str()]) else {
+        eprintln!("Skipping the JavaScript programs, there is no node to run them");
+        return;
+      };
+      assert_eq!(ended, expected, "{}", name);
+      ran += 1;
+    }
+    fs::remove_dir_all(dir).unwrap();
+    assert!(ran > 10, "Only {} samples could be run", ran);
+  }
+
+  #[test]
+  fn programs_are_the_ones_in_the_snapshots() {
+    let mut scripts = String::new();
+    for (name, code) in samples::all().into_iter().chain(samples::edge_cases()) {
+      let output = transpile(&samples::instructions(&code));
+      scripts += &format!("// {}\n{}\n", name, script(&output.code));
+    }
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(SNAPSHOTS);
+    if env::var_os("FISH_UPDATE_SNAPSHOTS").is_some() {
+      fs::write(&path, &scripts).unwrap();
+    }
+    let snapshots = fs::read_to_string(&path).expect("Snapshots can not be read");
+    let mut lines = scripts.lines().zip(snapshots.lines()).enumerate();
+    if let Some((number, (line, snapshot))) = lines.find(|(_, (line, snapshot))| line != snapshot) {
+      panic!(
+        "Line {} of {} is `{}` instead of `{}`, check the programs with node and run the tests \
+         with FISH_UPDATE_SNAPSHOTS=1 if the change is right",
+        number + 1,
+        SNAPSHOTS,
+        snapshot,
+        line
+      );
+    }
+    assert_eq!(scripts.lines().count(), snapshots.lines().count());
+  }
+
+  // Undoes `vlq`
+  fn decode_vlq(digits: &mut impl Iterator<Item = u8>) -> i64 {
+    const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut value = 0;
+    let mut shift = 0;
+    for digit in digits.by_ref() {
+      let digit = DIGITS.iter().position(|known| *known == digit).unwrap() as i64;
+      value |= (digit & 0x1f) << shift;
+      shift += 5;
+      if digit & 0x20 == 0 {
+        break;
+      }
+    }
+    match value & 1 {
+      1 => -(value >> 1),
+      _ => value >> 1,
+    }
+  }
+
+  // Where every line of the code points to in the script, counting from 0 like source maps do
+  fn mapped_lines(mappings: &str) -> Vec<Option<(i64, i64, i64)>> {
+    let (mut line, mut column) = (0, 0);
+    let lines = mappings.split(';').map(|segments| {
+      let segment = segments
+        .split(',')
+        .next()
+        .filter(|segment| !segment.is_empty())?;
+      let mut digits = segment.bytes();
+      let code_column = decode_vlq(&mut digits);
+      assert_eq!(decode_vlq(&mut digits), 0, "There is only one source");
+      line += decode_vlq(&mut digits);
+      column += decode_vlq(&mut digits);
+      Some((code_column, line, column))
+    });
+    lines.collect()
+  }
+
+  #[test]
+  fn source_map_points_at_the_instructions() {
+    let code = "x = 1;\nif (x == 1) {\n  print(x);\n};\n\nfn f(a) {\n    return a;\n};\nf(2);\n";
+    let tokens = tokenizer::tokenize(code).expect("Code does not tokenize");
+    let (mut instructions, _) = parser::parse(tokens, &Limits::default());
+    resolver::resolve(&mut instructions);
+    let output = transpile(&instructions);
+    let map: serde_json::Value =
+      serde_json::from_str(&output.source_map("code.js", "code.fsh", Some(code))).unwrap();
+    assert_eq!(map["version"], 3);
+    assert_eq!(map["sources"], json!(["code.fsh"]));
+    assert_eq!(map["sourcesContent"], json!([code]));
+
+    let lines = mapped_lines(map["mappings"].as_str().unwrap());
+    assert_eq!(lines.len(), output.code.lines().count());
+    let script_lines = code.lines().collect::<Vec<_>>();
+    let mut mapped = Vec::new();
+    for (line, mapping) in output.code.lines().zip(lines) {
+      let Some((code_column, script_line, script_column)) = mapping else {
+        continue;
+      };
+      // Both sides point at where the line starts, past the indentation
+      let indent = |line: &str| (line.len() - line.trim_start().len()) as i64;
+      assert_eq!(code_column, indent(line), "{}", line);
+      let script_line = script_lines[script_line as usize];
+      assert_eq!(script_column, indent(script_line), "{}", script_line);
+      mapped.push(script_line.trim());
+    }
+    assert_eq!(
+      mapped,
+      [
+        "x = 1;",
+        "if (x == 1) {",
+        "print(x);",
+        "fn f(a) {",
+        "return a;",
+        "f(2);"
+      ]
+    );
+  }
+
+  #[test]
+  fn source_map_comment_holds_the_map_without_a_file() {
+    assert_eq!(
+      source_map_comment(Some("code.js.map"), "{}"),
+      "//# sourceMappingURL=code.js.map\n"
+    );
+    for (map, encoded) in [
+      ("f", "Zg=="),
+      ("fi", "Zmk="),
+      ("fis", "Zmlz"),
+      ("fish", "ZmlzaA=="),
+    ] {
+      assert_eq!(
+        source_map_comment(None, map),
+        format!(
+          "//# sourceMappingURL=data:application/json;base64,{}\n",
+          encoded
+        )
+      );
+    }
+  }
+}
@@ -0,0 +1,456 @@
+// code.fsh
+$.run(() => {
+  let name;
+  $.print("This is name_checker_1000");
+  $.print("What is your name?");
+  name = $.input();
+  if ($.condition($.equal(name, "Fish"), "if condition")) {
+    $.print("You are the best!");
+  } else {
+    $.print($.add("You are not the best, ", $.add(name, ", but still cool!")));
+  }
+});
+
+// benches/counting.fsh
+$.run(() => {
+  let index, total;
+  index = 0n;
+  total = 0n;
+  while ($.condition($.less(index, 300000n), "while condition")) {
+    total = $.add(total, $.modulo(index, 7n));
+    index = $.add(index, 1n);
+  }
+  $.print(total);
+});
+
+// benches/matching.fsh
+$.run(() => {
+  let index, area;
+  $.defineVariant("Shape", "Circle", ["r"]);
+  $.defineVariant("Shape", "Rect", ["w", "h"]);
+  $.defineVariant("Shape", "Empty", []);
+  index = 0n;
+  area = 0n;
+  while ($.condition($.less(index, 50000n), "while condition")) {
+    let shape;
+    shape = $.call("Rect", $.modulo(index, 5n), 2n);
+    if ($.condition($.equal($.modulo(index, 3n), 0n), "if condition")) {
+      shape = $.call("Circle", $.modulo(index, 4n));
+    }
+    match1: {
+      const $subject = shape;
+      {
+        let r;
+        if ($.isVariant($subject, "Circle", 1) && (r = $subject.fields[0], true)) {
+          area = $.add(area, $.multiply(r, $.multiply(r, 3n)));
+          break match1;
+        }
+      }
+      {
+        let w, h;
+        if ($.isVariant($subject, "Rect", 2) && (w = $subject.fields[0], true) && (h = $subject.fields[1], true) && $.condition($.equal(w, h), "match guard")) {
+          area = $.add(area, 1n);
+          break match1;
+        }
+      }
+      {
+        let w, h;
+        if ($.isVariant($subject, "Rect", 2) && (w = $subject.fields[0], true) && (h = $subject.fields[1], true)) {
+          area = $.add(area, $.multiply(w, h));
+          break match1;
+        }
+      }
+      {
+        if ($.isVariant($subject, "Empty")) {
+          break match1;
+        }
+      }
+      $.noArm($subject);
+    }
+    index = $.add(index, 1n);
+  }
+  $.print(area);
+});
+
+// benches/nested_scopes.fsh
+$.run(() => {
+  let outer, hits;
+  outer = 0n;
+  hits = 0n;
+  while ($.condition($.less(outer, 300n), "while condition")) {
+    let inner, hoisted$0;
+    inner = 0n;
+    hoisted$0 = null;
+    while ($.condition($.less(inner, 300n), "while condition")) {
+      if ($.condition($.equal($.modulo(inner, 2n), 0n), "if condition")) {
+        if ($.condition($.coalesce(hoisted$0, () => (hoisted$0 = $.equal($.modulo(outer, 3n), 0n))), "if condition")) {
+          hits = $.add(hits, 1n);
+        }
+      }
+      inner = $.add(inner, 1n);
+    }
+    outer = $.add(outer, 1n);
+  }
+  $.print(hits);
+});
+
+// benches/strings.fsh
+$.run(() => {
+  let greeting, other, same, index;
+  greeting = "Hello there, this is a reasonably long string value";
+  other = "Hello there, this is a reasonably long string value";
+  same = 0n;
+  index = 0n;
+  while ($.condition($.less(index, 100000n), "while condition")) {
+    let copy;
+    copy = greeting;
+    if ($.condition($.equal(copy, other), "if condition")) {
+      same = $.add(same, 1n);
+    }
+    index = $.add(index, 1n);
+  }
+  $.print(same);
+});
+
+// README.md example 1
+$.run(() => {
+  let name;
+  $.print("This is name_checker_1000");
+  $.print("What is your name?");
+  name = $.input();
+  if ($.condition($.equal(name, "Fish"), "if condition")) {
+    $.print("You are the best!");
+  } else {
+    $.print($.add("You are not the best, ", $.add(name, ", but still cool!")));
+  }
+});
+
+// README.md example 2
+$.run(() => {
+  let index, end;
+  $.print("Hello World");
+  {
+    $.print("1+5 is greater than or equal to 2*2");
+  }
+  index = 0n;
+  end = 10n;
+  while ($.condition($.less((index = $.add(index, 1n)), end), "while condition")) {
+    $.print("Currently at:");
+    $.print(index);
+  }
+});
+
+// README.md example 3
+$.run(() => {
+  let shape;
+  $.defineVariant("Shape", "Circle", ["r"]);
+  $.defineVariant("Shape", "Rect", ["w", "h"]);
+  shape = $.call("Rect", 3n, 3n);
+  match1: {
+    const $subject = shape;
+    {
+      let r;
+      if ($.isVariant($subject, "Circle", 1) && (r = $subject.fields[0], true)) {
+        $.print($.multiply(r, $.multiply(r, 3.14)));
+        break match1;
+      }
+    }
+    {
+      let w, h;
+      if ($.isVariant($subject, "Rect", 2) && (w = $subject.fields[0], true) && (h = $subject.fields[1], true) && $.condition($.equal(w, h), "match guard")) {
+        $.print("A square!");
+        break match1;
+      }
+    }
+    {
+      let w, h;
+      if ($.isVariant($subject, "Rect", 2) && (w = $subject.fields[0], true) && (h = $subject.fields[1], true)) {
+        $.print($.multiply(w, h));
+        break match1;
+      }
+    }
+    {
+      if (true) {
+        $.print("Unknown shape");
+        break match1;
+      }
+    }
+    $.noArm($subject);
+  }
+});
+
+// README.md example 4
+$.run(() => {
+  let name, xs, empty;
+  name = $.input();
+  $.print($.add("Hello ", $.coalesce(name, () => "stranger")));
+  xs = [1n, 2n, 3n];
+  $.print($.index(xs, 0n));
+  empty = null;
+  $.print($.coalesce($.chain(() => $.index($.optional(empty), 0n)), () => "nothing there"));
+});
+
+// README.md example 5
+$.run(() => {
+  let count;
+  $.defineFunction("fib", 1, function fib(n) {
+    if ($.condition($.less(n, 2n), "if condition")) {
+      return n;
+    }
+    return $.add($.call("fib", $.subtract(n, 1n)), $.call("fib", $.subtract(n, 2n)));
+  });
+  count = 10n;
+  $.print($.call("fib", count));
+  $.defineFunction("greet", 1, function greet(name) {
+    $.print($.add("Hello ", name));
+  });
+  $.call("greet", "fish");
+});
+
+// edge case 1
+$.run(() => {
+  $.defineFunction("add", 2, function add(a, b) {
+    return $.add(a, b);
+  });
+  $.print($.call("add", 9223372036854775807n, 1n));
+});
+
+// edge case 2
+$.run(() => {
+  $.defineFunction("sub", 2, function sub(a, b) {
+    return $.subtract(a, b);
+  });
+  $.print($.call("sub", -9223372036854775807n, 2n));
+});
+
+// edge case 3
+$.run(() => {
+  $.defineFunction("mul", 2, function mul(a, b) {
+    return $.multiply(a, b);
+  });
+  $.print($.call("mul", 4611686018427387904n, 2n));
+});
+
+// edge case 4
+$.run(() => {
+  $.defineFunction("div", 2, function div(a, b) {
+    return $.divide(a, b);
+  });
+  $.print($.call("div", 7n, 2n));
+  $.print($.call("div", -7n, 2n));
+  $.print($.call("div", 7n, 0n));
+});
+
+// edge case 5
+$.run(() => {
+  $.defineFunction("rem", 2, function rem(a, b) {
+    return $.modulo(a, b);
+  });
+  $.print($.call("rem", 7n, 2n));
+  $.print($.call("rem", -7n, 2n));
+  $.print($.call("rem", 7n, 0n));
+});
+
+// edge case 6
+$.run(() => {
+  $.defineFunction("div", 2, function div(a, b) {
+    return $.divide(a, b);
+  });
+  $.print($.call("div", -9223372036854775808n, -1n));
+});
+
+// edge case 7
+$.run(() => {
+  $.defineFunction("rem", 2, function rem(a, b) {
+    return $.modulo(a, b);
+  });
+  $.print($.call("rem", -9223372036854775808n, -1n));
+});
+
+// edge case 8
+$.run(() => {
+  $.defineFunction("f", 2, function f(a, b) {
+    $.print($.add(a, b));
+    $.print($.subtract(a, b));
+    $.print($.multiply(a, b));
+    $.print($.divide(a, b));
+    $.print($.less(a, b));
+  });
+  $.call("f", 7n, 2.0);
+  $.call("f", 7.5, 2n);
+  $.call("f", 1.0, 0.0);
+  $.call("f", -1n, 0.0);
+  $.call("f", 0.1, 0.2);
+  $.call("f", 3n, 3.0);
+});
+
+// edge case 9
+$.run(() => {
+  $.defineFunction("f", 2, function f(a, b) {
+    return $.equal(a, b);
+  });
+  $.print($.call("f", 1n, 1.0));
+  $.print($.call("f", 2n, 2n));
+  $.print($.call("f", true, 1n));
+});
+
+// edge case 10
+$.run(() => {
+  $.defineFunction("f", 1, function f(x) {
+    return $.add(x, true);
+  });
+  $.print(1n);
+  $.call("f", 1n);
+  $.print(2n);
+});
+
+// edge case 11
+$.run(() => {
+  $.defineFunction("f", 1, function f(n) {
+    return $.call("f", $.add(n, 1n));
+  });
+  $.call("f", 0n);
+});
+
+// edge case 12
+$.run(() => {
+  $.print(1n);
+  $.call("exit", 3n);
+  $.print(2n);
+});
+
+// edge case 13
+$.run(() => {
+  $.defineFunction("f", 0, function f() {
+    $.call("exit");
+  });
+  $.print(1n);
+  $.call("f");
+  $.print(2n);
+});
+
+// edge case 14
+$.run(() => {
+  $.defineFunction("f", 1, function f(code) {
+    $.call("exit", code);
+  });
+  $.print(1n);
+  $.call("f", 256n);
+  $.print(2n);
+});
+
+// edge case 15
+$.run(() => {
+  $.defineFunction("f", 1, function f(code) {
+    $.call("exit", code);
+  });
+  $.print(1n);
+  $.call("f", -1n);
+  $.print(2n);
+});
+
+// edge case 16
+$.run(() => {
+  let a, b;
+  a = 9223372036854775807n;
+  b = 1n;
+  $.print($.subtract(a, b));
+  $.print($.add(a, b));
+});
+
+// edge case 17
+$.run(() => {
+  let a, b;
+  a = -9223372036854775807n;
+  b = 2n;
+  $.print($.subtract(a, b));
+});
+
+// edge case 18
+$.run(() => {
+  let a, b;
+  a = 4611686018427387904n;
+  b = 2n;
+  $.print($.multiply(a, b));
+});
+
+// edge case 19
+$.run(() => {
+  let a, b;
+  a = -7n;
+  b = 2n;
+  $.print($.divide(a, b));
+  $.print($.modulo(a, b));
+  $.print($.multiply(a, b));
+  b = 0n;
+  $.print($.modulo(a, b));
+});
+
+// edge case 20
+$.run(() => {
+  let a, b;
+  a = -9223372036854775808n;
+  b = -1n;
+  $.print($.divide(a, b));
+});
+
+// edge case 21
+$.run(() => {
+  let a, b;
+  a = -9223372036854775808n;
+  b = -1n;
+  $.print($.modulo(a, b));
+});
+
+// edge case 22
+$.run(() => {
+  let a, b, c, d;
+  a = 7n;
+  b = 2.0;
+  c = 0.0;
+  d = 2n;
+  $.print($.add(a, b));
+  $.print($.subtract(a, b));
+  $.print($.multiply(a, b));
+  $.print($.divide(a, b));
+  $.print($.less(a, b));
+  $.print($.equal(a, 7.0));
+  $.print($.divide(a, c));
+  $.print($.divide(c, c));
+  $.print($.power(a, d));
+  $.print($.power(a, b));
+  $.print($.power(d, -1n));
+});
+
+// edge case 23
+$.run(() => {
+  let a, b;
+  a = true;
+  b = 1n;
+  $.print($.equal(a, b));
+  $.print($.notEqual(a, null));
+  $.print($.add(a, b));
+});
+
+// edge case 24
+$.run(() => {
+  let a;
+  a = 1n;
+  if ($.condition(a, "if condition")) {
+    $.print(a);
+  }
+});
+
+// edge case 25
+$.run(() => {
+  let a;
+  a = 1n;
+  a = $.divide(a, 0n);
+});
+
+// edge case 26
+$.run(() => {
+  $.print(1n);
+  $.print($.variable(undefined, "x"));
+});
+
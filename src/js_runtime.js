@@ -0,0 +1,531 @@
+/*
+ The runtime of a fish script compiled to JavaScript. Values behave the way they do in the
+ interpreter and errors print the same messages: none is null, integers are BigInts that have to
+ fit in 64 bits, floats are numbers, lists are arrays and enum values are FishEnums. It runs in
+ node, where it reads stdin, writes stdout and sets the exit code, and in a browser, where output
+ goes to the console.
+*/
+const $ = (() => {
+  "use strict";
+
+  const MIN = -(2n ** 63n);
+  const MAX = 2n ** 63n - 1n;
+  const node = typeof process !== "undefined" && process.stdout !== undefined;
+
+  // A runtime error, the message is what the interpreter would print after "Error interpreting code: "
+  class FishError extends Error {}
+  class FishExit {
+    constructor(code) {
+      this.code = code;
+    }
+  }
+  // Thrown by an optional access on none, the whole chain it is in is none
+  const SKIP = Symbol("skip");
+
+  class FishEnum {
+    constructor(enumName, variant, fields) {
+      this.enumName = enumName;
+      this.variant = variant;
+      this.fields = fields;
+    }
+  }
+
+  const variants = new Map();
+  const functions = new Map();
+  let calls = 0;
+  const args = node ? process.argv.slice(2) : [];
+
+  function fail(message) {
+    throw new FishError(message);
+  }
+
+  function isNumber(value) {
+    return typeof value === "bigint" || typeof value === "number";
+  }
+
+  /*
+   Floats are written like Rust writes them: the fewest digits that read back as the same float,
+   never with an exponent, and without a fraction when there is none
+  */
+  function float(number) {
+    if (Number.isNaN(number)) {
+      return "NaN";
+    }
+    if (number === Infinity || number === -Infinity) {
+      return number > 0 ? "inf" : "-inf";
+    }
+    if (number === 0) {
+      return Object.is(number, -0) ? "-0" : "0";
+    }
+    const text = String(number);
+    const e = text.indexOf("e");
+    if (e < 0) {
+      return text;
+    }
+    const sign = text[0] === "-" ? "-" : "";
+    const digits = text.slice(sign.length, e).replace(".", "");
+    const point = Number(text.slice(e + 1)) + 1;
+    if (point <= 0) {
+      return sign + "0." + "0".repeat(-point) + digits;
+    }
+    return sign + digits + "0".repeat(Math.max(point - digits.length, 0));
+  }
+
+  function display(value) {
+    switch (typeof value) {
+      case "bigint":
+      case "boolean":
+      case "string":
+        return String(value);
+      case "number":
+        return float(value);
+    }
+    if (value === null) {
+      return "none";
+    }
+    if (Array.isArray(value)) {
+      return "[" + value.map(display).join(", ") + "]";
+    }
+    if (value.fields.length === 0) {
+      return value.variant;
+    }
+    return value.variant + "(" + value.fields.map(display).join(", ") + ")";
+  }
+
+  // Like display, but strings are quoted the way Rust quotes them
+  function describe(value) {
+    if (typeof value !== "string") {
+      return display(value);
+    }
+    const escapes = { '"': '\\"', "\\": "\\\\", "\n": "\\n", "\r": "\\r", "\t": "\\t", "\0": "\\0" };
+    let quoted = '"';
+    for (const character of value) {
+      const code = character.codePointAt(0);
+      if (escapes[character] !== undefined) {
+        quoted += escapes[character];
+      } else if (code < 0x20 || code === 0x7f) {
+        quoted += "\\u{" + code.toString(16) + "}";
+      } else {
+        quoted += character;
+      }
+    }
+    return quoted + '"';
+  }
+
+  function print(value) {
+    const text = display(value);
+    if (node) {
+      process.stdout.write(text + "\n");
+    } else {
+      console.log(text);
+    }
+  }
+
+  // The characters str::trim removes
+  function isSpace(character) {
+    const code = character.codePointAt(0);
+    return (code >= 0x09 && code <= 0x0d) || code === 0x20 || code === 0x85 || code === 0xa0 ||
+      code === 0x1680 || (code >= 0x2000 && code <= 0x200a) || code === 0x2028 ||
+      code === 0x2029 || code === 0x202f || code === 0x205f || code === 0x3000;
+  }
+
+  function trim(text) {
+    const characters = Array.from(text);
+    let start = 0;
+    let end = characters.length;
+    while (start < end && isSpace(characters[start])) {
+      start++;
+    }
+    while (end > start && isSpace(characters[end - 1])) {
+      end--;
+    }
+    return characters.slice(start, end).join("");
+  }
+
+  // A line from stdin without the whitespace around it, none at the end of the input
+  function input() {
+    let line;
+    if (node) {
+      const fs = require("fs");
+      const bytes = [];
+      const byte = Buffer.alloc(1);
+      while (fs.readSync(0, byte, 0, 1, null) === 1) {
+        bytes.push(byte[0]);
+        if (byte[0] === 0x0a) {
+          break;
+        }
+      }
+      if (bytes.length === 0) {
+        return null;
+      }
+      line = Buffer.from(bytes).toString("utf8");
+    } else {
+      line = typeof prompt === "function" ? prompt() : null;
+      if (line === null) {
+        return null;
+      }
+    }
+    return trim(line);
+  }
+
+  function checked(result, what) {
+    if (result < MIN || result > MAX) {
+      fail("Integer overflow when " + what);
+    }
+    return result;
+  }
+
+  // Integers stay integers, anything with a float in it is a float
+  function arithmetic(left, right, integers, floats, what) {
+    if (!isNumber(left) || !isNumber(right)) {
+      fail("Type mismatch: Expected 2 numbers when " + what);
+    }
+    if (typeof left === "bigint" && typeof right === "bigint") {
+      return integers(left, right);
+    }
+    return floats(Number(left), Number(right));
+  }
+
+  function add(left, right) {
+    if (typeof left === "string" && typeof right === "string") {
+      return left + right;
+    }
+    if (Array.isArray(left) && Array.isArray(right)) {
+      return left.concat(right);
+    }
+    if (!isNumber(left) || !isNumber(right)) {
+      fail("Type mismatch: Expected 2 strings, 2 numbers or 2 lists when adding");
+    }
+    return arithmetic(left, right, (a, b) => checked(a + b, "adding"), (a, b) => a + b, "adding");
+  }
+
+  function subtract(left, right) {
+    return arithmetic(left, right, (a, b) => checked(a - b, "subtracting"), (a, b) => a - b,
+      "subtracting");
+  }
+
+  function multiply(left, right) {
+    return arithmetic(left, right, (a, b) => checked(a * b, "multiplying"), (a, b) => a * b,
+      "multiplying");
+  }
+
+  // Integer division rounds toward zero, like BigInt division already does
+  function divide(left, right) {
+    return arithmetic(left, right, (a, b) => {
+      if (b === 0n) {
+        fail("Division by zero");
+      }
+      return checked(a / b, "dividing");
+    }, (a, b) => a / b, "dividing");
+  }
+
+  function modulo(left, right) {
+    return arithmetic(left, right, (a, b) => {
+      if (b === 0n) {
+        fail("Division by zero");
+      }
+      if (a === MIN && b === -1n) {
+        fail("Integer overflow when taking modulo");
+      }
+      return a % b;
+    }, (a, b) => a % b, "taking modulo");
+  }
+
+  // Always a float, even for two integers
+  function power(left, right) {
+    if (!isNumber(left) || !isNumber(right)) {
+      fail("Type mismatch: Expected 2 numbers when taking exponent");
+    }
+    return Math.pow(Number(left), Number(right));
+  }
+
+  // Integers and floats are equal when they are the same number
+  function equal(left, right) {
+    if (isNumber(left) && isNumber(right)) {
+      if (typeof left === "bigint" && typeof right === "bigint") {
+        return left === right;
+      }
+      return Number(left) === Number(right);
+    }
+    if (Array.isArray(left) && Array.isArray(right)) {
+      return left.length === right.length && left.every((item, index) => equal(item, right[index]));
+    }
+    if (left instanceof FishEnum && right instanceof FishEnum) {
+      return left.enumName === right.enumName && left.variant === right.variant &&
+        left.fields.length === right.fields.length &&
+        left.fields.every((field, index) => equal(field, right.fields[index]));
+    }
+    return left === right;
+  }
+
+  function order(left, right, compare) {
+    if (!isNumber(left) || !isNumber(right)) {
+      fail("Type mismatch: Expected 2 numbers ");
+    }
+    if (typeof left === "bigint" && typeof right === "bigint") {
+      return compare(left, right);
+    }
+    return compare(Number(left), Number(right));
+  }
+
+  const comparisons = {
+    "==": (left, right) => equal(left, right),
+    "!=": (left, right) => !equal(left, right),
+    "<": (left, right) => order(left, right, (a, b) => a < b),
+    "<=": (left, right) => order(left, right, (a, b) => a <= b),
+    ">": (left, right) => order(left, right, (a, b) => a > b),
+    ">=": (left, right) => order(left, right, (a, b) => a >= b),
+  };
+
+  // Both sides are evaluated, so && and || only need booleans
+  function and(left, right) {
+    if (typeof left !== "boolean" || typeof right !== "boolean") {
+      fail("Type mismatch: Expected 2 booleans ");
+    }
+    return left && right;
+  }
+
+  function or(left, right) {
+    if (typeof left !== "boolean" || typeof right !== "boolean") {
+      fail("Type mismatch: Expected 2 booleans ");
+    }
+    return left || right;
+  }
+
+  function not(value) {
+    if (typeof value !== "boolean") {
+      fail("Type mismatch: Expected 1 boolean ");
+    }
+    return !value;
+  }
+
+  // The right side is only evaluated when the left is none
+  function coalesce(left, right) {
+    return left === null ? right() : left;
+  }
+
+  // What is the condition of an if, a while or a match guard
+  function condition(value, what) {
+    if (typeof value !== "boolean") {
+      fail("Type mismatch: Expected boolean for " + what);
+    }
+    return value;
+  }
+
+  /*
+   A variable, or when it was not assigned yet a variant without fields, or the list of arguments
+   when the name is args
+  */
+  function variable(value, name) {
+    if (value !== undefined) {
+      return value;
+    }
+    const variant = variants.get(name);
+    if (variant !== undefined) {
+      if (variant.fields.length !== 0) {
+        fail("Argument mismatch: Variant '" + name + "' takes " + variant.fields.length + " values");
+      }
+      return new FishEnum(variant.enumName, name, []);
+    }
+    if (name === "args") {
+      return args;
+    }
+    fail("Variable '" + name + "' is not defined");
+  }
+
+  function index(value, position) {
+    if (typeof position !== "bigint") {
+      fail("Type mismatch: Expected integer as index");
+    }
+    if (position < 0n) {
+      fail("Index " + position + " is out of bounds");
+    }
+    let items;
+    if (Array.isArray(value)) {
+      items = value;
+    } else if (value instanceof FishEnum) {
+      items = value.fields;
+    } else if (typeof value === "string") {
+      // Strings are indexed by character, not by code unit
+      items = Array.from(value);
+    } else {
+      fail("Type mismatch: Expected list, string or enum value when indexing");
+    }
+    if (position >= BigInt(items.length)) {
+      fail("Index " + position + " is out of bounds");
+    }
+    return items[Number(position)];
+  }
+
+  // The field is looked up in the variant as it is defined now, like in the interpreter
+  function field(value, name) {
+    if (!(value instanceof FishEnum)) {
+      fail("Type mismatch: Expected enum value when accessing a field");
+    }
+    const variant = variants.get(value.variant);
+    const position = variant === undefined ? -1 : variant.fields.indexOf(name);
+    if (position < 0 || position >= value.fields.length) {
+      fail("Field '" + name + "' is not defined");
+    }
+    return value.fields[position];
+  }
+
+  function optional(value) {
+    if (value === null) {
+      throw SKIP;
+    }
+    return value;
+  }
+
+  // A chain of accesses with an optional one in it
+  function chain(access) {
+    try {
+      return access();
+    } catch (error) {
+      if (error === SKIP) {
+        return null;
+      }
+      throw error;
+    }
+  }
+
+  function defineVariant(enumName, name, fields) {
+    const existing = variants.get(name);
+    if (existing !== undefined && existing.enumName !== enumName) {
+      fail("Type mismatch: Variant '" + name + "' is already defined by enum '" +
+        existing.enumName + "'");
+    }
+    variants.set(name, { enumName, fields });
+  }
+
+  function isVariant(value, name, count) {
+    return value instanceof FishEnum && value.variant === name &&
+      (count === undefined || value.fields.length === count);
+  }
+
+  function noArm(value) {
+    fail("No match arm matched the value " + display(value));
+  }
+
+  function defineFunction(name, arity, code) {
+    functions.set(name, { arity, code });
+  }
+
+  // A function of the script comes first, then a variant and then the builtin exit
+  function call(name, ...values) {
+    const fn = functions.get(name);
+    if (fn !== undefined) {
+      if (fn.arity !== values.length) {
+        fail("Argument mismatch: Function '" + name + "' takes " + fn.arity +
+          " arguments but " + values.length + " were given");
+      }
+      if (calls >= MAX_CALLS) {
+        fail("Limit exceeded, more than " + MAX_CALLS + " function calls were nested");
+      }
+      calls++;
+      try {
+        // A function that ends without a return gives none
+        const result = fn.code(...values);
+        return result === undefined ? null : result;
+      } finally {
+        calls--;
+      }
+    }
+    const variant = variants.get(name);
+    if (variant !== undefined) {
+      if (variant.fields.length !== values.length) {
+        fail("Argument mismatch: Variant '" + name + "' takes " + variant.fields.length +
+          " values but " + values.length + " were given");
+      }
+      return new FishEnum(variant.enumName, name, values);
+    }
+    if (name === "exit") {
+      exit(values);
+    }
+    fail("Function '" + name + "' is not defined");
+  }
+
+  // exit() and exit(code), the code has to be one a process can exit with, from 0 to 255
+  function exit(values) {
+    if (values.length === 0) {
+      throw new FishExit(0);
+    }
+    if (values.length > 1) {
+      fail("Argument mismatch: Function 'exit' takes 1 argument but " + values.length +
+        " were given");
+    }
+    if (typeof values[0] !== "bigint") {
+      fail("Type mismatch: Expected integer as exit code");
+    }
+    if (values[0] < 0n || values[0] > 255n) {
+      fail("Type mismatch: Exit code " + values[0] + " is not between 0 and 255");
+    }
+    throw new FishExit(Number(values[0]));
+  }
+
+  function failed(position, message, comparison) {
+    let text = "Assertion failed at " + position;
+    if (message !== undefined) {
+      text += ": " + display(message());
+    }
+    if (comparison !== undefined) {
+      text += " (" + comparison + " is false)";
+    }
+    fail(text);
+  }
+
+  // The message is only evaluated when the assertion fails
+  function assert(position, passed, message) {
+    if (typeof passed !== "boolean") {
+      fail("Type mismatch: Expected boolean for assert condition");
+    }
+    if (!passed) {
+      failed(position, message);
+    }
+  }
+
+  // A failed comparison reports both sides, like in the interpreter
+  function assertComparison(position, operator, left, right, message) {
+    if (!comparisons[operator](left, right)) {
+      failed(position, message, describe(left) + " " + operator + " " + describe(right));
+    }
+  }
+
+  function report(message) {
+    const text = "Error interpreting code: " + message;
+    if (node) {
+      process.stderr.write(text + "\n");
+      process.exitCode = 1;
+    } else {
+      console.error(text);
+    }
+  }
+
+  // Runs the script, errors end it the way they end the interpreter
+  function run(script) {
+    try {
+      script();
+    } catch (error) {
+      if (error instanceof FishExit) {
+        if (node) {
+          process.exitCode = error.code;
+        }
+      } else if (error instanceof FishError) {
+        report(error.message);
+      } else {
+        throw error;
+      }
+    }
+  }
+
+  return {
+    print, input, add, subtract, multiply, divide, modulo, power, equal, and, or, not, coalesce,
+    condition, variable, index, field, optional, chain, defineVariant, isVariant, noArm,
+    defineFunction, call, assert, assertComparison, fail, run,
+    notEqual: comparisons["!="],
+    less: comparisons["<"],
+    lessEqual: comparisons["<="],
+    greater: comparisons[">"],
+    greaterEqual: comparisons[">="],
+  };
+})();
@@ -0,0 +1,122 @@
+use std::{fmt, time::Duration};
+
+// Limits for running scripts that can not be trusted, None means there is no limit
+#[derive(Debug, Clone)]
+pub struct Limits {
+  // Instructions executed plus while loop iterations
+  pub max_steps: Option<u64>,
+  pub timeout: Option<Duration>,
+  // Amount of nested blocks that can be executing at the same time
+  pub max_depth: Option<usize>,
+  // Amount of function calls that can be running at the same time
+  pub max_calls: Option<usize>,
+  // Length of strings and lists, in characters and items
+  pub max_size: Option<usize>,
+  // How deep brackets and blocks may be nested in the source
+  pub max_nesting: Option<usize>,
+  pub allow_input: bool,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self {
+      max_steps: None,
+      timeout: None,
+      max_depth: None,
+      // Every call recurses in the interpreter, so without a limit recursion overflows the stack
+      max_calls: Some(DEFAULT_MAX_CALLS),
+      max_size: None,
+      // Each level of nested blocks takes tens of kilobytes of stack in debug builds
+      max_nesting: Some(DEFAULT_MAX_NESTING),
+      allow_input: true,
+    }
+  }
+}
+
+pub const DEFAULT_MAX_NESTING: usize = 1000;
+pub const DEFAULT_MAX_CALLS: usize = 1000;
+
+const CHAIN_PER_NESTING: usize = 4;
+
+/*
+ Operators that may follow each other in an expression, like the terms of a sum, counting those of
+ the expressions around it. The parser reads them in a loop, so they are not nesting, but they nest
+ to the right in the instructions and everything after the parser recurses through them. There
+ can be four of them for every level of nesting allowed.
+*/
+pub fn max_chain(max_nesting: Option<usize>) -> Option<usize> {
+  Some(max_nesting?.saturating_mul(CHAIN_PER_NESTING))
+}
+
+// Levels of recursion the interpreter allows for every call, enough for a call in an expression
+// a few blocks deep in the function before it
+const LEVELS_PER_CALL: usize = 8;
+
+// Stack taken by a level of nesting in the parser and by a level of recursion in the interpreter,
+// the most any of them was measured to take with some room to spare. Debug builds take far more
+const STACK_PER_NESTING: usize = if cfg!(debug_assertions) { 32 } else { 4 } * 1024;
+const STACK_PER_LEVEL: usize = if cfg!(debug_assertions) { 16 } else { 2 } * 1024;
+// For everything that does not recurse
+const STACK_BASE: usize = 4 * 1024 * 1024;
+
+impl Limits {
+  // Values, blocks and calls that can be evaluating at the same time, every one of them is a level
+  // of recursion in the interpreter. None when nesting or calls are not limited
+  pub fn max_recursion(&self) -> Option<usize> {
+    let nesting = self.max_nesting?;
+    let calls = self.max_calls?.saturating_mul(LEVELS_PER_CALL);
+    let chain = nesting.saturating_mul(CHAIN_PER_NESTING);
+    Some(nesting.saturating_add(chain).saturating_add(calls))
+  }
+
+  /*
+   The stack needed to parse and run code within the limits. Without a limit on nesting or calls
+   nothing bounds the recursion, the stack is then as big as it is for the default limits.
+  */
+  pub fn stack_size(&self) -> usize {
+    let nesting = self.max_nesting.unwrap_or(DEFAULT_MAX_NESTING);
+    let calls = self.max_calls.unwrap_or(DEFAULT_MAX_CALLS);
+    let chain = nesting.saturating_mul(CHAIN_PER_NESTING);
+    let recursion = nesting
+      .saturating_add(chain)
+      .saturating_add(calls.saturating_mul(LEVELS_PER_CALL));
+    STACK_BASE
+      .saturating_add(nesting.saturating_mul(STACK_PER_NESTING))
+      .saturating_add(recursion.saturating_mul(STACK_PER_LEVEL))
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+  Steps(u64),
+  Timeout(Duration),
+  Depth(usize),
+  Calls(usize),
+  Size(usize),
+  Nesting(usize),
+  Chain(usize),
+  Recursion(usize),
+}
+
+impl fmt::Display for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Limit::Steps(steps) => write!(f, "more than {} steps were executed", steps),
+      Limit::Timeout(timeout) => write!(f, "the script ran for longer than {:?}", timeout),
+      Limit::Depth(depth) => write!(f, "more than {} blocks were nested", depth),
+      Limit::Calls(calls) => write!(f, "more than {} function calls were nested", calls),
+      Limit::Size(size) => write!(f, "a value grew larger than {} items", size),
+      Limit::Nesting(nesting) => write!(f, "the code is nested more than {} levels", nesting),
+      Limit::Chain(operators) => write!(
+        f,
+        "more than {} operators follow each other in an expression",
+        operators
+      ),
+      Limit::Recursion(levels) => write!(
+        f,
+        "values, blocks and calls were nested more than {} levels while running",
+        levels
+      ),
+    }
+  }
+}
@@ -0,0 +1,558 @@
+use std::{collections::HashSet, fmt};
+
+use crate::{
+  analysis::{self, Named},
+  diagnostic, interpreter,
+  parser::{Identifier, Instruction, InstructionKind, Pattern, Value},
+  tokenizer::{Lexeme, Operator, Span},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rule {
+  UseBeforeAssignment,
+  UnusedVariable,
+  UnreachableCode,
+  ConstantCondition,
+  MismatchedComparison,
+  DanglingElse,
+}
+
+impl Rule {
+  pub const ALL: [Rule; 6] = [
+    Rule::UseBeforeAssignment,
+    Rule::UnusedVariable,
+    Rule::UnreachableCode,
+    Rule::ConstantCondition,
+    Rule::MismatchedComparison,
+    Rule::DanglingElse,
+  ];
+
+  // The name used in the output and in `lint: allow(...)` comments
+  pub fn id(&self) -> &'static str {
+    match self {
+      Rule::UseBeforeAssignment => "use-before-assignment",
+      Rule::UnusedVariable => "unused-variable",
+      Rule::UnreachableCode => "unreachable-code",
+      Rule::ConstantCondition => "constant-condition",
+      Rule::MismatchedComparison => "mismatched-comparison",
+      Rule::DanglingElse => "dangling-else",
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+  pub rule: Rule,
+  pub message: String,
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} [{}]", self.message, self.rule.id())
+  }
+}
+
+pub type Diagnostic = diagnostic::Diagnostic<Warning>;
+
+/*
+ Looks for code that is most likely a mistake, without running it. Scopes are tracked the same
+ way the resolver does it, so a variable counts as declared exactly where the interpreter would
+ find it.
+
+ A comment containing `lint: allow(rule-id, ...)` silences those rules on its own line and the
+ line after it.
+*/
+pub fn lint(instructions: &[Instruction], tokens: &[Lexeme]) -> Vec<Diagnostic> {
+  let mut linter = Linter {
+    scopes: Vec::new(),
+    variants: HashSet::new(),
+    unit_variants: HashSet::new(),
+    diagnostics: Vec::new(),
+  };
+  linter.collect_variants(instructions);
+  linter.scope(instructions);
+
+  let allowed = allowed_rules(tokens);
+  let mut diagnostics = linter.diagnostics;
+  diagnostics.retain(|diagnostic| {
+    let line = diagnostic.span.start.line;
+    !allowed.iter().any(|(start, end, rule)| {
+      *rule == diagnostic.error.rule && (*start..=end + 1).contains(&line)
+    })
+  });
+  diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+  diagnostics
+}
+
+struct Variable {
+  name: String,
+  span: Span,
+  used: bool,
+}
+
+impl Named for Variable {
+  fn name(&self) -> &str {
+    &self.name
+  }
+}
+
+struct Linter {
+  scopes: Vec<Vec<Variable>>,
+  // Every variant name, reading one of them is not reading a variable
+  variants: HashSet<String>,
+  // Variants without fields, a pattern with one of these names compares instead of binding
+  unit_variants: HashSet<String>,
+  diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter {
+  fn collect_variants(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      match &instruction.kind {
+        InstructionKind::Enum { variants, .. } => {
+          for variant in variants {
+            self.variants.insert(variant.name.clone());
+            if variant.fields.is_empty() {
+              self.unit_variants.insert(variant.name.clone());
+            }
+          }
+        }
+        kind => {
+          for block in kind.blocks() {
+            self.collect_variants(block);
+          }
+        }
+      }
+    }
+  }
+
+  fn warn(&mut self, rule: Rule, message: String, span: Span) {
+    self
+      .diagnostics
+      .push(Diagnostic::new(Warning { rule, message }, span));
+  }
+
+  fn scope(&mut self, instructions: &[Instruction]) {
+    self.scopes.push(Vec::new());
+    self.instructions(instructions);
+    self.pop_scope();
+  }
+
+  fn pop_scope(&mut self) {
+    for variable in self.scopes.pop().expect("No scope to leave") {
+      if !variable.used && !variable.name.starts_with('_') {
+        self.warn(
+          Rule::UnusedVariable,
+          format!("Variable '{}' is assigned but never used", variable.name),
+          variable.span,
+        );
+      }
+    }
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    let mut previous: Option<&InstructionKind> = None;
+    for instruction in instructions {
+      match previous {
+        Some(InstructionKind::Break) => self.warn(
+          Rule::UnreachableCode,
+          "Statement is never run, the break before it always leaves the loop".to_string(),
+          instruction.span,
+        ),
+        Some(InstructionKind::Return { .. }) => self.warn(
+          Rule::UnreachableCode,
+          "Statement is never run, the return before it always leaves the function".to_string(),
+          instruction.span,
+        ),
+        Some(InstructionKind::If { .. }) => (),
+        _ if matches!(instruction.kind, InstructionKind::Else { .. }) => self.warn(
+          Rule::DanglingElse,
+          "Else is not right after an if, so it never runs".to_string(),
+          instruction.span,
+        ),
+        _ => (),
+      }
+      self.instruction(instruction);
+      previous = Some(&instruction.kind);
+    }
+  }
+
+  fn instruction(&mut self, instruction: &Instruction) {
+    match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      } => {
+        if let Some(constant) = constant_boolean(condition) {
+          self.warn(
+            Rule::ConstantCondition,
+            format!("If condition is always {}", constant),
+            instruction.span,
+          );
+        }
+        self.value(condition, instruction.span);
+        self.scope(instructions);
+      }
+      InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        match constant_boolean(condition) {
+          Some(true) if !breaks(instructions) => self.warn(
+            Rule::ConstantCondition,
+            "Loop condition is always true and nothing breaks out of it".to_string(),
+            instruction.span,
+          ),
+          Some(false) => self.warn(
+            Rule::ConstantCondition,
+            "Loop condition is always false, so it never runs".to_string(),
+            instruction.span,
+          ),
+          _ => (),
+        }
+        self.value(condition, instruction.span);
+        self.scope(instructions);
+      }
+      InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+        self.scope(instructions);
+      }
+      InstructionKind::Value { value } | InstructionKind::Print { message: value } => {
+        self.value(value, instruction.span);
+      }
+      InstructionKind::Input { variable } => self.assignment(variable),
+      InstructionKind::Match { value, arms } => {
+        self.value(value, instruction.span);
+        for arm in arms {
+          self.scopes.push(Vec::new());
+          self.pattern(&arm.pattern);
+          if let Some(guard) = &arm.guard {
+            self.value(guard, instruction.span);
+          }
+          self.instructions(&arm.instructions);
+          self.pop_scope();
+        }
+      }
+      InstructionKind::Let {
+        variable, value, ..
+      } => {
+        self.value(value, instruction.span);
+        self.declare(variable);
+      }
+      InstructionKind::Function(function) => {
+        let outer = std::mem::take(&mut self.scopes);
+        self.scopes.push(Vec::new());
+        for parameter in &function.parameters {
+          self.declare(&parameter.variable);
+        }
+        self.instructions(&function.instructions);
+        self.pop_scope();
+        self.scopes = outer;
+      }
+      InstructionKind::Return { value } => {
+        if let Some(value) = value {
+          self.value(value, instruction.span);
+        }
+      }
+      InstructionKind::Assert { condition, message } => {
+        self.value(condition, instruction.span);
+        if let Some(message) = message {
+          self.value(message, instruction.span);
+        }
+      }
+      InstructionKind::Test { instructions, .. } => {
+        let outer = std::mem::take(&mut self.scopes);
+        self.scope(instructions);
+        self.scopes = outer;
+      }
+      InstructionKind::Break | InstructionKind::Enum { .. } => (),
+    }
+  }
+
+  fn pattern(&mut self, pattern: &Pattern) {
+    match pattern {
+      Pattern::Identifier(identifier) => {
+        if !self.unit_variants.contains(&identifier.name) {
+          self.declare(identifier);
+        }
+      }
+      Pattern::Variant { fields, .. } => {
+        for field in fields {
+          self.pattern(field);
+        }
+      }
+      Pattern::Wildcard | Pattern::Literal(_) => (),
+    }
+  }
+
+  // Values have no spans of their own apart from identifiers, so warnings about them point at
+  // the statement they are in
+  fn value(&mut self, value: &Value, span: Span) {
+    match value {
+      Value::Identifier(identifier) => self.read(identifier),
+      Value::Expression(expression) => {
+        let left = expression.get_left();
+        let right = expression.get_right();
+        if *expression.get_operator() == Operator::Assign {
+          if let Some(right) = right {
+            self.value(right, span);
+          }
+          match left {
+            Value::Identifier(identifier) => self.assignment(identifier),
+            left => self.value(left, span),
+          }
+          return;
+        }
+        if let (true, Some(right)) = (expression.get_operator().is_comparison(), right) {
+          if let (Some(left_type), Some(right_type)) = (literal_type(left), literal_type(right)) {
+            if left_type != right_type {
+              self.warn(
+                Rule::MismatchedComparison,
+                format!("Comparing {} to {}", left_type, right_type),
+                span,
+              );
+            }
+          }
+        }
+        self.value(left, span);
+        if let Some(right) = right {
+          self.value(right, span);
+        }
+      }
+      Value::Call { arguments, .. } => {
+        for argument in arguments {
+          self.value(argument, span);
+        }
+      }
+      Value::List(items) => {
+        for item in items {
+          self.value(item, span);
+        }
+      }
+      Value::Field { value, .. } => self.value(value, span),
+      Value::Index { value, index, .. } => {
+        self.value(value, span);
+        self.value(index, span);
+      }
+      Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None => (),
+    }
+  }
+
+  fn read(&mut self, identifier: &Identifier) {
+    if let Some(variable) = analysis::lookup(&mut self.scopes, &identifier.name) {
+      variable.used = true;
+    } else if !self.variants.contains(&identifier.name) && identifier.name != interpreter::ARGS {
+      self.warn(
+        Rule::UseBeforeAssignment,
+        format!(
+          "Variable '{}' is used before it is assigned",
+          identifier.name
+        ),
+        identifier.span,
+      );
+    }
+  }
+
+  fn assignment(&mut self, identifier: &Identifier) {
+    if analysis::lookup(&mut self.scopes, &identifier.name).is_none() {
+      self.declare(identifier);
+    }
+  }
+
+  fn declare(&mut self, identifier: &Identifier) {
+    let scope = self.scopes.last_mut().expect("No scope to declare in");
+    scope.push(Variable {
+      name: identifier.name.clone(),
+      span: identifier.span,
+      used: false,
+    });
+  }
+}
+
+// Whether a break or return in these instructions leaves the loop they are the body of, a break
+// inside a nested loop only leaves that one
+fn breaks(instructions: &[Instruction]) -> bool {
+  instructions
+    .iter()
+    .any(|instruction| match &instruction.kind {
+      InstructionKind::Break | InstructionKind::Return { .. } => true,
+      InstructionKind::While { .. } | InstructionKind::Function(_) => false,
+      kind => kind.blocks().into_iter().any(breaks),
+    })
+}
+
+// true, (false) and !true, without anything that could change between runs
+fn constant_boolean(value: &Value) -> Option<bool> {
+  match value {
+    Value::Boolean(boolean) => Some(*boolean),
+    Value::Expression(expression) => match expression.get_operator() {
+      Operator::Brackets => constant_boolean(expression.get_left()),
+      Operator::Not => constant_boolean(expression.get_left()).map(|boolean| !boolean),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+// Comparing with none is how a missing value is checked for, so it is not a literal type here
+fn literal_type(value: &Value) -> Option<&'static str> {
+  match value {
+    Value::Number(_) => Some("a number"),
+    Value::String(_) => Some("a string"),
+    Value::Boolean(_) => Some("a boolean"),
+    Value::List(_) => Some("a list"),
+    Value::Expression(expression) if *expression.get_operator() == Operator::Brackets => {
+      literal_type(expression.get_left())
+    }
+    _ => None,
+  }
+}
+
+// The lines of each `lint: allow(...)` comment and the rules it allows
+fn allowed_rules(tokens: &[Lexeme]) -> Vec<(usize, usize, Rule)> {
+  let mut allowed = Vec::new();
+  let comments = tokens
+    .iter()
+    .flat_map(|lexeme| lexeme.leading.iter().chain(&lexeme.trailing));
+  for comment in comments {
+    let Some(start) = comment.text.find("lint: allow(") else {
+      continue;
+    };
+    let rest = &comment.text[start + "lint: allow(".len()..];
+    let Some(end) = rest.find(')') else {
+      continue;
+    };
+    for id in rest[..end].split(',') {
+      if let Some(rule) = Rule::ALL.iter().find(|rule| rule.id() == id.trim()) {
+        allowed.push((comment.span.start.line, comment.span.end.line, *rule));
+      }
+    }
+  }
+  allowed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{limits::Limits, parser, tokenizer};
+
+  // The warnings for the code, with where they are
+  fn warnings(code: &str) -> Vec<String> {
+    let tokens = tokenizer::tokenize(code).expect("Code does not tokenize");
+    let (instructions, diagnostics) = parser::parse(tokens.clone(), &Limits::default());
+    assert!(
+      diagnostics.is_empty(),
+      "Code does not parse: {:?}",
+      diagnostics
+    );
+    let diagnostics = lint(&instructions, &tokens);
+    diagnostics
+      .iter()
+      .map(|diagnostic| diagnostic.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn use_before_assignment() {
+    assert_eq!(
+      warnings("print(x);\nx = 1;\nprint(x);"),
+      ["1:7: Variable 'x' is used before it is assigned [use-before-assignment]"]
+    );
+    // Variants and args are there without being assigned
+    assert!(warnings("enum E { A };\nprint(A);\nprint(args);").is_empty());
+    // A function only sees its parameters and its own variables
+    assert_eq!(
+      warnings("x = 1;\nfn f() { return x; };\nprint(x);"),
+      ["2:17: Variable 'x' is used before it is assigned [use-before-assignment]"]
+    );
+  }
+
+  #[test]
+  fn unused_variable() {
+    assert_eq!(
+      warnings("x = 1;\nfn f(a, _b) { return 1; };\nprint(f(1, 2));"),
+      [
+        "1:1: Variable 'x' is assigned but never used [unused-variable]",
+        "2:6: Variable 'a' is assigned but never used [unused-variable]",
+      ]
+    );
+    // A variable of a block that is only used after it is a different one
+    assert_eq!(
+      warnings("if (true) { let y = 1; };\ny = 2;\nprint(y);")[1..],
+      ["1:17: Variable 'y' is assigned but never used [unused-variable]"]
+    );
+  }
+
+  #[test]
+  fn unreachable_code() {
+    assert_eq!(
+      warnings("while (true) { break;\nprint(1); };\nfn f() { return 1;\nprint(2); };\nf();"),
+      [
+        "2:1: Statement is never run, the break before it always leaves the loop \
+         [unreachable-code]",
+        "4:1: Statement is never run, the return before it always leaves the function \
+         [unreachable-code]",
+      ]
+    );
+  }
+
+  #[test]
+  fn constant_condition() {
+    assert_eq!(
+      warnings(
+        "if (!(false)) { print(1); };\nwhile (false) { print(2); };\nwhile (true) { print(3); };"
+      ),
+      [
+        "1:1: If condition is always true [constant-condition]",
+        "2:1: Loop condition is always false, so it never runs [constant-condition]",
+        "3:1: Loop condition is always true and nothing breaks out of it [constant-condition]",
+      ]
+    );
+    // Breaking out of the loop is how it ends, but a break in a nested loop only ends that one
+    assert!(warnings("while (true) { if (args == none) { break; }; };").is_empty());
+    assert_eq!(
+      warnings("while (true) { while (args == none) { break; }; };").len(),
+      1
+    );
+  }
+
+  #[test]
+  fn mismatched_comparison() {
+    assert_eq!(
+      warnings("print(1 == \"1\");\nprint((true) < [1]);\nprint(1 == none);"),
+      [
+        "1:1: Comparing a number to a string [mismatched-comparison]",
+        "2:1: Comparing a boolean to a list [mismatched-comparison]",
+      ]
+    );
+  }
+
+  #[test]
+  fn dangling_else() {
+    assert_eq!(
+      warnings(
+        "x = 1;\nif (x == 1) { print(1); } else { print(2); };\nprint(3);\nelse { print(4); };"
+      ),
+      ["4:1: Else is not right after an if, so it never runs [dangling-else]"]
+    );
+  }
+
+  #[test]
+  fn comments_allow_rules_on_their_line_and_the_next() {
+    let code = "// lint: allow(unused-variable, unreachable-code)\n\
+                x = 1; print(v);\n\
+                y = 2; // lint: allow(unused-variable)\n\
+                \n\
+                z = 3;\n\
+                /* lint: allow(use-before-assignment) */\n\
+                \n\
+                print(w);";
+    assert_eq!(
+      warnings(code),
+      [
+        "2:14: Variable 'v' is used before it is assigned [use-before-assignment]",
+        "5:1: Variable 'z' is assigned but never used [unused-variable]",
+        "8:7: Variable 'w' is used before it is assigned [use-before-assignment]",
+      ]
+    );
+  }
+}
@@ -0,0 +1,671 @@
+use std::{
+  collections::HashMap,
+  io::{self, BufRead, Read, Write},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+  analysis::{self, Analysis, SymbolKind},
+  formatter,
+  limits::Limits,
+  lint, parser,
+  tokenizer::{self, Position, Span},
+  typechecker::{self, Type},
+};
+
+const KEYWORDS: [&str; 16] = [
+  "if", "else", "while", "print", "input", "break", "enum", "match", "let", "fn", "return",
+  "assert", "test", "true", "false", "none",
+];
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/*
+ A language server speaking LSP over `input` and `output`, stdin and stdout for `fish lsp`. Open
+ documents are kept in full and analyzed again for every request, scripts are small enough for
+ that. Returns whether the client asked for a shutdown before exiting, the exit code depends on
+ it.
+
+ Positions in LSP count lines from 0 and characters in UTF-16 code units, everything else here
+ counts lines from 1 and characters as chars, so they are converted going in and out.
+*/
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<bool> {
+  let mut server = Server {
+    documents: HashMap::new(),
+    shutdown: false,
+  };
+  while let Some(message) = read_message(&mut input)? {
+    let message: Value = match serde_json::from_slice(&message) {
+      Ok(message) => message,
+      Err(error) => {
+        let response = error_response(Value::Null, PARSE_ERROR, &error.to_string());
+        write_message(&mut output, &response)?;
+        continue;
+      }
+    };
+    let method = message["method"].as_str().unwrap_or_default();
+    if method == "exit" {
+      break;
+    }
+    let params = &message["params"];
+    match message.get("id") {
+      // A request, which gets exactly one response
+      Some(id) if !method.is_empty() => {
+        let response = match server.request(method, params) {
+          Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+          Err((code, error)) => error_response(id.clone(), code, &error),
+        };
+        write_message(&mut output, &response)?;
+      }
+      // A response to something the server never asks for
+      Some(_) => (),
+      None => {
+        for notification in server.notification(method, params) {
+          write_message(&mut output, &notification)?;
+        }
+      }
+    }
+  }
+  Ok(server.shutdown)
+}
+
+// Far more than any document an editor opens, a longer message is taken to be broken
+const MAX_MESSAGE_LENGTH: usize = 64 * 1024 * 1024;
+
+// Content-Length framed messages, None once the input is closed. The Debug Adapter Protocol
+// frames its messages the same way
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+  let mut length = None;
+  loop {
+    let mut header = String::new();
+    if input.read_line(&mut header)? == 0 {
+      return Ok(None);
+    }
+    let header = header.trim_end();
+    if header.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = header.split_once(':') {
+      if name.eq_ignore_ascii_case("content-length") {
+        length = value.trim().parse().ok();
+      }
+    }
+  }
+  let length = length.ok_or_else(|| {
+    io::Error::new(
+      io::ErrorKind::InvalidData,
+      "Message without a Content-Length",
+    )
+  })?;
+  if length > MAX_MESSAGE_LENGTH {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!(
+        "Message of {} bytes, more than the {} a message can have",
+        length, MAX_MESSAGE_LENGTH
+      ),
+    ));
+  }
+  // Grows as the message arrives instead of trusting the length up front
+  let mut message = Vec::new();
+  input.take(length as u64).read_to_end(&mut message)?;
+  if message.len() < length {
+    return Err(io::ErrorKind::UnexpectedEof.into());
+  }
+  Ok(Some(message))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+  let message = message.to_string();
+  write!(
+    output,
+    "Content-Length: {}\r\n\r\n{}",
+    message.len(),
+    message
+  )?;
+  output.flush()
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+  json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+type RequestResult = Result<Value, (i64, String)>;
+
+struct Server {
+  // The text of every open document by uri
+  documents: HashMap<String, String>,
+  shutdown: bool,
+}
+
+impl Server {
+  fn request(&mut self, method: &str, params: &Value) -> RequestResult {
+    if self.shutdown {
+      return Err((INVALID_REQUEST, "The server is shutting down".to_string()));
+    }
+    match method {
+      "initialize" => Ok(json!({
+        "capabilities": {
+          // The whole document is sent on every change
+          "textDocumentSync": 1,
+          "hoverProvider": true,
+          "definitionProvider": true,
+          "referencesProvider": true,
+          "documentSymbolProvider": true,
+          "completionProvider": {},
+          "documentFormattingProvider": true,
+        },
+        "serverInfo": { "name": "fish", "version": env!("CARGO_PKG_VERSION") },
+      })),
+      "shutdown" => {
+        self.shutdown = true;
+        Ok(Value::Null)
+      }
+      "textDocument/hover" => self.with_position(params, hover),
+      "textDocument/definition" => self.with_position(params, definition),
+      "textDocument/references" => {
+        let declaration = params["context"]["includeDeclaration"].as_bool() == Some(true);
+        self.with_position(params, |document, offset| {
+          references(document, offset, declaration)
+        })
+      }
+      "textDocument/documentSymbol" => Ok(document_symbols(&self.document(params)?)),
+      "textDocument/completion" => self.with_position(params, completion),
+      "textDocument/formatting" => Ok(formatting(self.document(params)?.text)),
+      _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+    }
+  }
+
+  // Diagnostics to publish
+  fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+    let text = match method {
+      "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+      "textDocument/didChange" => params["contentChanges"]
+        .as_array()
+        .and_then(|changes| changes.last())
+        .and_then(|change| change["text"].as_str()),
+      "textDocument/didClose" => {
+        self.documents.remove(uri);
+        return vec![publish_diagnostics(uri, Vec::new())];
+      }
+      _ => None,
+    };
+    match text {
+      Some(text) => {
+        self.documents.insert(uri.to_string(), text.to_string());
+        vec![publish_diagnostics(uri, diagnostics(text))]
+      }
+      None => Vec::new(),
+    }
+  }
+
+  fn document(&self, params: &Value) -> Result<Document<'_>, (i64, String)> {
+    let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+    match self.documents.get_key_value(uri) {
+      Some((uri, text)) => Ok(Document::new(uri, text)),
+      None => Err((INVALID_PARAMS, format!("Document '{}' is not open", uri))),
+    }
+  }
+
+  fn with_position(
+    &self,
+    params: &Value,
+    handle: impl FnOnce(&Document, usize) -> Value,
+  ) -> RequestResult {
+    let document = self.document(params)?;
+    let position = &params["position"];
+    match (position["line"].as_u64(), position["character"].as_u64()) {
+      (Some(line), Some(character)) => {
+        let offset = offset_at(document.text, line as usize, character as usize);
+        Ok(handle(&document, offset))
+      }
+      _ => Err((INVALID_PARAMS, "Missing position".to_string())),
+    }
+  }
+}
+
+// An open document with what could be found out about it, which is nothing when it does not parse
+struct Document<'a> {
+  uri: &'a str,
+  text: &'a str,
+  analysis: Analysis,
+  types: Vec<(Span, Type)>,
+}
+
+impl<'a> Document<'a> {
+  fn new(uri: &'a str, text: &'a str) -> Self {
+    let instructions = tokenizer::tokenize(text)
+      .map(|tokens| parser::parse(tokens, &Limits::default()).0)
+      .unwrap_or_default();
+    Self {
+      uri,
+      text,
+      analysis: analysis::analyze(&instructions),
+      types: typechecker::variable_types(&instructions),
+    }
+  }
+
+  fn range(&self, span: Span) -> Value {
+    json!({ "start": lsp_position(self.text, span.start), "end": lsp_position(self.text, span.end) })
+  }
+
+  fn location(&self, span: Span) -> Value {
+    json!({ "uri": self.uri, "range": self.range(span) })
+  }
+}
+
+fn diagnostics(text: &str) -> Vec<Value> {
+  let diagnostic = |span: Span, severity: u8, message: String| {
+    json!({
+      "range": {
+        "start": lsp_position(text, span.start),
+        "end": lsp_position(text, span.end),
+      },
+      "severity": severity,
+      "source": "fish",
+      "message": message,
+    })
+  };
+  let tokens = match tokenizer::tokenize(text) {
+    Ok(tokens) => tokens,
+    Err(error) => return vec![diagnostic(error.span, 1, error.error.to_string())],
+  };
+  let (instructions, errors) = parser::parse(tokens.clone(), &Limits::default());
+  if !errors.is_empty() {
+    return errors
+      .into_iter()
+      .map(|error| diagnostic(error.span, 1, error.error.to_string()))
+      .collect();
+  }
+  lint::lint(&instructions, &tokens)
+    .into_iter()
+    .map(|warning| {
+      let mut diagnostic = diagnostic(warning.span, 2, warning.error.message);
+      diagnostic["code"] = json!(warning.error.rule.id());
+      diagnostic
+    })
+    .collect()
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+  json!({
+    "jsonrpc": "2.0",
+    "method": "textDocument/publishDiagnostics",
+    "params": { "uri": uri, "diagnostics": diagnostics },
+  })
+}
+
+fn hover(document: &Document, offset: usize) -> Value {
+  let analysis = &document.analysis;
+  let Some(index) = analysis.symbol_at(offset) else {
+    return Value::Null;
+  };
+  let symbol = &analysis.symbols[index];
+  // The name under the cursor, which is the declaration or one of the uses
+  let span = analysis
+    .references_to(index)
+    .chain([symbol.span])
+    .find(|span| span.start.offset <= offset && offset <= span.end.offset)
+    .unwrap_or(symbol.span);
+  let text = match symbol.kind {
+    // The type where the name is written
+    SymbolKind::Variable | SymbolKind::Parameter => {
+      match document.types.iter().find(|(typed, _)| *typed == span) {
+        Some((_, found)) => format!("{}: {}", symbol.name, found),
+        None => symbol.name.clone(),
+      }
+    }
+    _ => symbol.detail.clone(),
+  };
+  json!({
+    "contents": { "kind": "markdown", "value": format!("```fish\n{}\n```", text) },
+    "range": document.range(span),
+  })
+}
+
+fn definition(document: &Document, offset: usize) -> Value {
+  match document.analysis.symbol_at(offset) {
+    Some(symbol) => document.location(document.analysis.symbols[symbol].span),
+    None => Value::Null,
+  }
+}
+
+fn references(document: &Document, offset: usize, declaration: bool) -> Value {
+  let Some(symbol) = document.analysis.symbol_at(offset) else {
+    return Value::Null;
+  };
+  let mut spans: Vec<Span> = document.analysis.references_to(symbol).collect();
+  if declaration {
+    spans.push(document.analysis.symbols[symbol].span);
+  }
+  spans.sort();
+  Value::Array(
+    spans
+      .into_iter()
+      .map(|span| document.location(span))
+      .collect(),
+  )
+}
+
+// Functions, enums with their variants, and the variables declared at the top level
+fn document_symbols(document: &Document) -> Value {
+  let analysis = &document.analysis;
+  let symbol = |index: usize, children: Vec<Value>| {
+    let symbol = &analysis.symbols[index];
+    // The LSP SymbolKind numbers
+    let kind = match symbol.kind {
+      SymbolKind::Variable | SymbolKind::Parameter => 13,
+      SymbolKind::Function => 12,
+      SymbolKind::Enum => 10,
+      SymbolKind::Variant => 22,
+    };
+    json!({
+      "name": symbol.name,
+      "detail": symbol.detail,
+      "kind": kind,
+      "range": document.range(symbol.extent),
+      "selectionRange": document.range(symbol.span),
+      "children": children,
+    })
+  };
+  let mut symbols: Vec<(Position, Value)> = Vec::new();
+  for (index, found) in analysis.symbols.iter().enumerate() {
+    let top_level = match found.kind {
+      SymbolKind::Function | SymbolKind::Enum => true,
+      SymbolKind::Variable => found.scope.end.offset == usize::MAX,
+      SymbolKind::Parameter | SymbolKind::Variant => false,
+    };
+    if top_level {
+      let children = analysis
+        .symbols
+        .iter()
+        .enumerate()
+        .filter(|(_, variant)| variant.parent == Some(index))
+        .map(|(variant, _)| symbol(variant, Vec::new()))
+        .collect();
+      symbols.push((found.span.start, symbol(index, children)));
+    }
+  }
+  symbols.sort_by_key(|(start, _)| *start);
+  Value::Array(symbols.into_iter().map(|(_, symbol)| symbol).collect())
+}
+
+fn completion(document: &Document, offset: usize) -> Value {
+  let mut items: Vec<Value> = Vec::new();
+  let mut seen = std::collections::HashSet::new();
+  // The innermost declaration of a name comes last
+  for symbol in document
+    .analysis
+    .visible_at(offset)
+    .collect::<Vec<_>>()
+    .iter()
+    .rev()
+  {
+    if !seen.insert(symbol.name.clone()) {
+      continue;
+    }
+    // The LSP CompletionItemKind numbers
+    let kind = match symbol.kind {
+      SymbolKind::Variable | SymbolKind::Parameter => 6,
+      SymbolKind::Function => 3,
+      SymbolKind::Enum => 13,
+      SymbolKind::Variant => 20,
+    };
+    let mut item = json!({ "label": symbol.name, "kind": kind });
+    if !symbol.detail.is_empty() {
+      item["detail"] = json!(symbol.detail);
+    }
+    items.push(item);
+  }
+  for keyword in KEYWORDS {
+    items.push(json!({ "label": keyword, "kind": 14 }));
+  }
+  Value::Array(items)
+}
+
+// Replaces the whole document, or nothing when it can not be formatted
+fn formatting(text: &str) -> Value {
+  match formatter::format_code(text) {
+    Ok(formatted) if formatted == text => json!([]),
+    Ok(formatted) => json!([{
+      "range": {
+        "start": { "line": 0, "character": 0 },
+        "end": end_position(text),
+      },
+      "newText": formatted,
+    }]),
+    Err(_) => Value::Null,
+  }
+}
+
+fn lsp_position(text: &str, position: Position) -> Value {
+  let offset = position.offset.min(text.len());
+  let line_start = text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+  json!({
+    "line": text[..line_start].matches('\n').count(),
+    "character": text[line_start..offset].encode_utf16().count(),
+  })
+}
+
+fn end_position(text: &str) -> Value {
+  lsp_position(
+    text,
+    Position {
+      offset: text.len(),
+      ..Position::default()
+    },
+  )
+}
+
+// The byte offset of an LSP position, positions past the end of a line are at its end
+fn offset_at(text: &str, line: usize, character: usize) -> usize {
+  let line_start = match line {
+    0 => 0,
+    _ => match text.match_indices('\n').nth(line - 1) {
+      Some((newline, _)) => newline + 1,
+      None => return text.len(),
+    },
+  };
+  let mut units = 0;
+  for (index, char) in text[line_start..].char_indices() {
+    if units >= character || char == '\n' {
+      return line_start + index;
+    }
+    units += char.len_utf16();
+  }
+  text.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const URI: &str = "file:///script.fsh";
+  const CODE: &str = "\
+fn f(n: int) -> int {
+  return n + 1;
+}
+let x = 2;
+print(f(x));
+";
+
+  fn message(message: Value) -> Vec<u8> {
+    let mut framed = Vec::new();
+    write_message(&mut framed, &message).unwrap();
+    framed
+  }
+
+  fn request(id: u64, method: &str, params: Value) -> Vec<u8> {
+    message(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+  }
+
+  fn notification(method: &str, params: Value) -> Vec<u8> {
+    message(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+  }
+
+  fn at(line: u64, character: u64) -> Value {
+    json!({ "textDocument": { "uri": URI }, "position": { "line": line, "character": character } })
+  }
+
+  // Runs the server on the messages a client sends, returning whether it shut down cleanly and
+  // everything it sent back
+  fn serve_transcript(messages: &[Vec<u8>]) -> (bool, Vec<Value>) {
+    let input = messages.concat();
+    let mut output = Vec::new();
+    let clean = serve(input.as_slice(), &mut output).expect("The server failed");
+    let mut output = output.as_slice();
+    let mut sent = Vec::new();
+    while let Some(message) = read_message(&mut output).expect("The server sent a broken message") {
+      sent.push(serde_json::from_slice(&message).expect("The server sent invalid JSON"));
+    }
+    (clean, sent)
+  }
+
+  // The result of the request with the id
+  fn result(sent: &[Value], id: u64) -> &Value {
+    let response = sent
+      .iter()
+      .find(|message| message["id"] == json!(id))
+      .unwrap_or_else(|| panic!("No response to request {}", id));
+    &response["result"]
+  }
+
+  fn range(line: u64, start: u64, end: u64) -> Value {
+    json!({
+      "start": { "line": line, "character": start },
+      "end": { "line": line, "character": end },
+    })
+  }
+
+  #[test]
+  fn messages_are_only_read_as_long_as_they_say_and_can_be() {
+    let read = |input: &str| read_message(&mut input.as_bytes()).map_err(|error| error.to_string());
+    assert_eq!(
+      read("Content-Length: 2\r\ncontent-type: json\r\n\r\n{}{}"),
+      Ok(Some(b"{}".to_vec()))
+    );
+    assert_eq!(read(""), Ok(None));
+    assert_eq!(
+      read("Content-Type: json\r\n\r\n{}"),
+      Err("Message without a Content-Length".to_string())
+    );
+    assert_eq!(
+      read("Content-Length: 99999999999\r\n\r\n{}"),
+      Err("Message of 99999999999 bytes, more than the 67108864 a message can have".to_string())
+    );
+    assert_eq!(
+      read("Content-Length: 10\r\n\r\n{}"),
+      Err("unexpected end of file".to_string())
+    );
+  }
+
+  #[test]
+  fn a_session_from_initialize_to_exit() {
+    let (clean, sent) = serve_transcript(&[
+      request(1, "initialize", json!({ "capabilities": {} })),
+      notification("initialized", json!({})),
+      notification(
+        "textDocument/didOpen",
+        json!({ "textDocument": { "uri": URI, "languageId": "fish", "version": 1, "text": CODE } }),
+      ),
+      request(2, "textDocument/hover", at(4, 6)),
+      request(3, "textDocument/hover", at(4, 8)),
+      request(4, "textDocument/definition", at(4, 6)),
+      request(5, "textDocument/references", {
+        let mut params = at(3, 4);
+        params["context"] = json!({ "includeDeclaration": true });
+        params
+      }),
+      request(
+        6,
+        "textDocument/documentSymbol",
+        json!({ "textDocument": { "uri": URI } }),
+      ),
+      request(7, "textDocument/completion", at(4, 0)),
+      request(
+        8,
+        "textDocument/formatting",
+        json!({ "textDocument": { "uri": URI } }),
+      ),
+      request(9, "shutdown", Value::Null),
+      notification("exit", Value::Null),
+    ]);
+    assert!(clean);
+    assert!(result(&sent, 1)["capabilities"]["hoverProvider"] == json!(true));
+
+    let published = sent
+      .iter()
+      .find(|message| message["method"] == "textDocument/publishDiagnostics")
+      .expect("No diagnostics were published");
+    assert_eq!(published["params"]["diagnostics"], json!([]));
+
+    // The range is the name under the cursor, not the definition
+    let hover = result(&sent, 2);
+    assert_eq!(hover["range"], range(4, 6, 7));
+    assert_eq!(
+      hover["contents"]["value"],
+      json!(format!("```fish\n{}\n```", "fn f(n: int) -> int"))
+    );
+    let hover = result(&sent, 3);
+    assert_eq!(hover["range"], range(4, 8, 9));
+    assert_eq!(hover["contents"]["value"], json!("```fish\nx: int\n```"));
+
+    assert_eq!(
+      result(&sent, 4),
+      &json!({ "uri": URI, "range": range(0, 3, 4) })
+    );
+    assert_eq!(
+      result(&sent, 5),
+      &json!([
+        { "uri": URI, "range": range(3, 4, 5) },
+        { "uri": URI, "range": range(4, 8, 9) },
+      ])
+    );
+    let names: Vec<&Value> = result(&sent, 6)
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|symbol| &symbol["name"])
+      .collect();
+    assert_eq!(names, [&json!("f"), &json!("x")]);
+    let completion = result(&sent, 7).as_array().unwrap();
+    assert!(completion.iter().any(|item| item["label"] == "f"));
+    assert!(completion.iter().any(|item| item["label"] == "while"));
+    assert_eq!(result(&sent, 8), &json!([]));
+  }
+
+  #[test]
+  fn errors_are_published_and_requests_can_fail() {
+    let (clean, sent) = serve_transcript(&[
+      notification(
+        "textDocument/didOpen",
+        json!({ "textDocument": { "uri": URI, "text": "x = 1 +;" } }),
+      ),
+      b"Content-Length: 9\r\n\r\nnot json!".to_vec(),
+      request(1, "textDocument/unknown", json!({})),
+      request(2, "textDocument/hover", at(0, 0)),
+      request(
+        3,
+        "textDocument/hover",
+        json!({ "textDocument": { "uri": "file:///closed.fsh" } }),
+      ),
+      notification("exit", Value::Null),
+    ]);
+    // Exiting without a shutdown is not clean
+    assert!(!clean);
+    let diagnostics = &sent[0]["params"]["diagnostics"];
+    assert_eq!(diagnostics[0]["range"], range(0, 7, 8));
+    assert_eq!(diagnostics[0]["severity"], json!(1));
+    assert_eq!(sent[1]["error"]["code"], json!(PARSE_ERROR));
+    assert_eq!(sent[2]["error"]["code"], json!(METHOD_NOT_FOUND));
+    // Nothing is known about code that does not parse
+    assert_eq!(result(&sent, 2), &Value::Null);
+    assert_eq!(sent[4]["error"]["code"], json!(INVALID_PARAMS));
+  }
+}
@@ -1,47 +1,1257 @@
-use std::env;
+#![forbid(unsafe_code)]
 
+use std::{
+  env,
+  error::Error,
+  fs::{self, File},
+  io::{self, BufWriter, LineWriter, Read, Write},
+  panic,
+  path::{Path, PathBuf},
+  process, thread,
+  time::Duration,
+};
+
+use coverage::Coverage;
+use interpreter::InterpreterError;
+use limits::Limits;
+use parser::Instruction;
+use profiler::Profiler;
+use tracer::{TraceOptions, Tracer};
+
+mod analysis;
+mod c_backend;
+mod compiled;
+mod coverage;
+mod cst;
+mod dap;
+mod debugger;
+mod diagnostic;
+mod formatter;
 mod interpreter;
+mod js_backend;
+mod limits;
+mod lint;
+mod lsp;
 mod number;
+mod optimizer;
 mod parser;
+mod profiler;
+mod resolver;
+#[cfg(test)]
+mod samples;
+mod syntax_json;
+mod test_runner;
 mod tokenizer;
+mod tracer;
+mod typechecker;
+mod unparser;
+mod wasm_backend;
+mod wat;
+mod x86_64_backend;
+
+// Exit codes, so whatever started a script can tell what went wrong. A script that calls
+// exit(code) exits with its own code
+const EXIT_RUNTIME_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_TOKENIZER_ERROR: i32 = 3;
+const EXIT_PARSER_ERROR: i32 = 4;
+const EXIT_TYPE_ERROR: i32 = 5;
+// `lint` found likely mistakes in code that it could read and parse
+const EXIT_LINT_WARNINGS: i32 = 1;
+
+const USAGE: &str = "\
+Usage: {program} <command> [<arguments>]
+
+Commands:
+  run [<options>] <file> [<arg>...]     runs a script, - reads it from stdin
+  run [<options>] -e <code> [<arg>...]  runs the code given on the command line
+  repl                                  runs code as it is typed
+  check <file>...                       looks for syntax and type errors without running
+  fmt [--check] [<file>...]             formats files in place
+  lint [<file>...]                      warns about likely mistakes
+  test [--filter <text>] [--format text|junit|json] [<path>...]
+                                        runs the test blocks in .fsh files
+  tokens [--json] <file>                prints the tokens of a script
+  ast [--json] <file>                   prints the instructions a script parses to
+  compile [--check-types] [--no-spans] [-o <out>] <file>
+                                        writes a compiled program, .fshc, that run and ast take
+  build --target c|js|wasm|wat|x86_64 [--check-types] [--no-opt] [-o <out>] <file>
+                                        writes a program in C, JavaScript, WebAssembly or
+                                        x86-64 assembly that does what the script does
+  lsp                                   a language server on stdin and stdout
+  dap                                   a debug adapter on stdin and stdout
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+`{program} <file>` is short for `{program} run <file>`.
+The arguments after the script are in the list `args` while it runs.
+
+Options for run:
+  --max-steps <steps>  --timeout <milliseconds>  --max-depth <blocks>  --max-calls <calls>
+  --max-size <items>  --max-nesting <levels>  --no-input  --check-types  --debug  --profile
+  --profile-out <file>  --coverage  --coverage-out <file>
+  --ast  the script is JSON from `{program} ast --json` instead of code
+  --no-opt  runs the code without optimizing it first, which --debug, --profile, --coverage and
+            --trace never do
+  --trace  logs every instruction, value and assignment to stderr while running
+    --trace-format text|json  indented text, or a JSON object per line
+    --trace-lines <line>[-<line>]  only what happens on these lines
+    --trace-variable <name>  only reading and assigning this variable
+    --trace-out <file>  writes the log to a file instead
+
+Exit codes: 1 for errors while running, 2 for wrong arguments or files that can not be read,
+3 for tokenizer errors, 4 for parser errors and 5 for type errors. lint exits with 1 when it
+warns and 2 when a file can not be read, tokenized or parsed.
+";
+
+fn main() {
+  if let Err(error) = with_stack(&Limits::default(), run) {
+    if !error.downcast_ref().is_some_and(output_closed) {
+      eprintln!("Error: {}", error);
+    }
+    process::exit(EXIT_RUNTIME_ERROR);
+  }
+}
+
+// Whatever reads the output stopped reading, like `head` does after its lines. There is no one
+// left to tell
+fn output_closed(error: &io::Error) -> bool {
+  error.kind() == io::ErrorKind::BrokenPipe
+}
+
+// Parsing and running code recurse, the stack of the main thread is too small for code within the
+// limits. Only the part of the stack that gets used takes up memory
+fn with_stack<T: Send>(limits: &Limits, run: impl FnOnce() -> T + Send) -> T {
+  let size = limits.stack_size();
+  thread::scope(|scope| {
+    match thread::Builder::new()
+      .stack_size(size)
+      .spawn_scoped(scope, run)
+    {
+      Ok(thread) => thread
+        .join()
+        .unwrap_or_else(|panic| panic::resume_unwind(panic)),
+      Err(error) => {
+        eprintln!(
+          "Error starting a thread with {} MiB of stack for the limits: {}",
+          size.div_ceil(1024 * 1024),
+          error
+        );
+        process::exit(EXIT_USAGE);
+      }
+    }
+  })
+}
+
+fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
   let args: Vec<String> = env::args().collect();
-  let wd = std::env::current_dir()?;
-  if args.len() != 2 {
-    println!("Usage: {} <file>", args[0]);
-    return Ok(());
+  let program = args[0].as_str();
+  let rest = args.get(2..).unwrap_or_default();
+  match args.get(1).map(String::as_str) {
+    Some("run") => run_command(program, rest),
+    Some("repl") => repl_command(program, rest),
+    Some("check") => check_command(program, rest),
+    Some("fmt") => format_command(program, rest),
+    Some("lint") => lint_command(program, rest),
+    Some("test") => test_command(program, rest),
+    Some("tokens") => tokens_command(program, rest),
+    Some("ast") => ast_command(program, rest),
+    Some("compile") => compile_command(program, rest),
+    Some("build") => build_command(program, rest),
+    Some("lsp") => {
+      let stdin = io::stdin();
+      let clean = lsp::serve(stdin.lock(), io::stdout().lock())?;
+      process::exit(if clean { 0 } else { 1 });
+    }
+    Some("dap") => {
+      let stdin = io::BufReader::new(io::stdin());
+      Ok(dap::serve(stdin, io::stdout().lock())?)
+    }
+    Some("help" | "--help" | "-h") => {
+      write!(io::stdout(), "{}", USAGE.replace("{program}", program))?;
+      Ok(())
+    }
+    Some(_) => run_command(program, &args[1..]),
+    None => usage_error(&USAGE.replace("{program}", program)),
   }
-  let path = std::path::Path::new(&args[1]);
-  if !path.exists() {
-    println!("File '{}' does not exist", path.display());
-    return Ok(());
+}
+
+// Wrong arguments, the usage goes to stderr
+fn usage_error(usage: &str) -> ! {
+  eprint!("{}", usage);
+  if !usage.ends_with('\n') {
+    eprintln!();
   }
-  let code = std::fs::read_to_string(path)?;
+  process::exit(EXIT_USAGE);
+}
 
-  // let code = r#"
-  //   if ((1+5) > (1*2)) {
-  //     print("Hello, world!");
-  //   }
-  // "#;
-  let tokens = tokenizer::tokenize(&code);
-  if tokens.is_err() {
-    println!("Error tokenizing code: {:?}", tokens.err().unwrap());
+// A script from a file, or from stdin for `-`. Returns the name to use for it in errors
+fn read_script(file: &str) -> (String, String) {
+  match read_source(file) {
+    (name, Source::Code(code)) => (name, code),
+    (_, Source::Compiled(_)) => {
+      eprintln!(
+        "Error reading '{}': It is a compiled program, not code",
+        file
+      );
+      process::exit(EXIT_USAGE);
+    }
+  }
+}
+
+// A script as it was read, compiled programs are told apart from code by how they start
+enum Source {
+  Code(String),
+  Compiled(Vec<u8>),
+}
+
+fn read_source(file: &str) -> (String, Source) {
+  let (name, read) = match file {
+    "-" => {
+      let mut bytes = Vec::new();
+      let read = io::stdin().read_to_end(&mut bytes).map(|_| bytes);
+      ("<stdin>".to_string(), read)
+    }
+    _ => (file.to_string(), fs::read(file)),
+  };
+  let source = read.and_then(|bytes| match compiled::is_compiled(&bytes) {
+    true => Ok(Source::Compiled(bytes)),
+    false => String::from_utf8(bytes).map(Source::Code).map_err(|_| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+      )
+    }),
+  });
+  match source {
+    Ok(source) => (name, source),
+    Err(error) if file == "-" => {
+      eprintln!("Error reading stdin: {}", error);
+      process::exit(EXIT_USAGE);
+    }
+    Err(error) => {
+      eprintln!("Error reading '{}': {}", file, error);
+      process::exit(EXIT_USAGE);
+    }
+  }
+}
+
+// Tokenizes, parses and optionally checks the types of a script. The errors are printed, and the
+// exit code for them is returned
+fn compile(
+  name: &str,
+  code: &str,
+  limits: &Limits,
+  check_types: bool,
+) -> Result<Vec<Instruction>, i32> {
+  let tokens = match tokenizer::tokenize(code) {
+    Ok(tokens) => tokens,
+    Err(error) => {
+      eprintln!("Error tokenizing code at {}:{}", name, error);
+      return Err(EXIT_TOKENIZER_ERROR);
+    }
+  };
+  let (instructions, diagnostics) = parser::parse(tokens, limits);
+  if !diagnostics.is_empty() {
+    for diagnostic in diagnostics {
+      eprintln!("Error parsing code at {}:{}", name, diagnostic);
+    }
+    return Err(EXIT_PARSER_ERROR);
+  }
+  if check_types {
+    check_types_of(name, &instructions)?;
+  }
+  Ok(instructions)
+}
+
+fn check_types_of(name: &str, instructions: &[Instruction]) -> Result<(), i32> {
+  let errors = typechecker::check(instructions);
+  if errors.is_empty() {
     return Ok(());
   }
-  let tokens = tokens.unwrap();
+  for error in errors {
+    eprintln!("Type error at {}:{}", name, error);
+  }
+  Err(EXIT_TYPE_ERROR)
+}
+
+// fish run [<options>] (<file> | - | -e <code>) [<arg>...]
+fn run_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let usage = format!(
+    "Usage: {} run [<options>] <file> [<arg>...]\n       {} run [<options>] -e <code> [<arg>...]\n\
+     Use `{} help` for the options",
+    program, program, program
+  );
+  let mut limits = Limits::default();
+  let mut check_types = false;
+  let mut debug = false;
+  let mut ast = false;
+  let mut optimize = true;
+  // Where to write the folded stacks and the lcov report, if anywhere
+  let mut profile: Option<Option<String>> = None;
+  let mut coverage: Option<Option<String>> = None;
+  // Any of the --trace options turns tracing on
+  let mut trace: Option<TraceOptions> = None;
+  let mut script = None;
+  let mut options = args.iter();
+  while let Some(option) = options.next() {
+    let parsed = match option.as_str() {
+      "--max-steps" => {
+        parse_option(option, options.next()).map(|steps| limits.max_steps = Some(steps))
+      }
+      "--timeout" => parse_option(option, options.next())
+        .map(|milliseconds| limits.timeout = Some(Duration::from_millis(milliseconds))),
+      "--max-depth" => {
+        parse_option(option, options.next()).map(|depth| limits.max_depth = Some(depth))
+      }
+      "--max-calls" => {
+        parse_option(option, options.next()).map(|calls| limits.max_calls = Some(calls))
+      }
+      "--max-size" => parse_option(option, options.next()).map(|size| limits.max_size = Some(size)),
+      "--max-nesting" => {
+        parse_option(option, options.next()).map(|nesting| limits.max_nesting = Some(nesting))
+      }
+      "--no-input" => {
+        limits.allow_input = false;
+        Some(())
+      }
+      "--check-types" => {
+        check_types = true;
+        Some(())
+      }
+      "--debug" => {
+        debug = true;
+        Some(())
+      }
+      "--ast" => {
+        ast = true;
+        Some(())
+      }
+      "--no-opt" => {
+        optimize = false;
+        Some(())
+      }
+      "--profile" => {
+        profile.get_or_insert(None);
+        Some(())
+      }
+      "--profile-out" => options.next().map(|out| profile = Some(Some(out.clone()))),
+      "--coverage" => {
+        coverage.get_or_insert(None);
+        Some(())
+      }
+      "--coverage-out" => options.next().map(|out| coverage = Some(Some(out.clone()))),
+      "--trace" => {
+        trace.get_or_insert_default();
+        Some(())
+      }
+      "--trace-format" => {
+        let format = options.next();
+        let json = match format.map(String::as_str) {
+          Some("text") => Some(false),
+          Some("json") => Some(true),
+          _ => invalid_value(option, format),
+        };
+        json.map(|json| trace.get_or_insert_default().json = json)
+      }
+      "--trace-lines" => parse_lines(option, options.next())
+        .map(|lines| trace.get_or_insert_default().lines = Some(lines)),
+      "--trace-variable" => options
+        .next()
+        .map(|name| trace.get_or_insert_default().variable = Some(name.clone())),
+      "--trace-out" => options
+        .next()
+        .map(|out| trace.get_or_insert_default().out = Some(out.clone())),
+      "-e" => options
+        .next()
+        .map(|code| script = Some(("<code>".to_string(), Source::Code(code.clone())))),
+      _ if option == "-" || !option.starts_with('-') => {
+        script = Some(read_source(option));
+        Some(())
+      }
+      _ => None,
+    };
+    if parsed.is_none() {
+      usage_error(&usage);
+    }
+    // Everything after the script is for the script
+    if script.is_some() {
+      break;
+    }
+  }
+  let Some((name, source)) = script else {
+    usage_error(&usage);
+  };
+  let script_args: Vec<String> = options.cloned().collect();
+
+  // Higher limits than the default ones need more stack than this thread has
+  let stack_limits = limits.clone();
+  let run = move || {
+    // Without the source there are no lines to show in errors and reports
+    let (compiled, code) = match source {
+      Source::Compiled(bytes) => (
+        load_compiled(&name, &bytes, &limits, check_types),
+        String::new(),
+      ),
+      Source::Code(json) if ast => (load_ast(&name, &json, &limits, check_types), String::new()),
+      Source::Code(code) => (compile(&name, &code, &limits, check_types), code),
+    };
+    let mut instructions = match compiled {
+      Ok(instructions) => instructions,
+      Err(code) => process::exit(code),
+    };
+    // The debugger, profiler, coverage report and trace are about the code as it was written
+    if optimize && !debug && profile.is_none() && coverage.is_none() && trace.is_none() {
+      optimizer::optimize(&mut instructions, &limits);
+    }
+    resolver::resolve(&mut instructions);
+
+    let res = if debug {
+      debugger::debug_in_terminal(&name, &code, &instructions, limits, &script_args)
+    } else if profile.is_some() || coverage.is_some() || trace.is_some() {
+      let tracer = trace.map(|options| {
+        let out: Box<dyn Write> = match &options.out {
+          Some(out) => match File::create(out) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(error) => {
+              eprintln!("Error writing '{}': {}", out, error);
+              process::exit(EXIT_USAGE);
+            }
+          },
+          None => Box::new(LineWriter::new(io::stderr())),
+        };
+        Tracer::new(out, options)
+      });
+      let mut hooks = (
+        (
+          profile.as_ref().map(|_| Profiler::new()),
+          coverage.as_ref().map(|_| Coverage::new(&instructions)),
+        ),
+        tracer,
+      );
+      let res = interpreter::interpret_with_hooks(&instructions, limits, &script_args, &mut hooks);
+      let ((profiler, coverage_report), tracer) = &mut hooks;
+      if let Some(tracer) = tracer {
+        tracer.finish()?;
+      }
+      if let Some(profiler) = profiler {
+        profiler.finish();
+        eprint!("{}", profiler.summary(&code));
+        if let Some(Some(out)) = &profile {
+          write_or_exit(out, profiler.folded());
+        }
+      }
+      if let Some(coverage_report) = coverage_report {
+        eprint!("{}", coverage_report.summary());
+        if let Some(Some(out)) = &coverage {
+          write_or_exit(out, coverage_report.lcov(&name));
+        }
+      }
+      res
+    } else {
+      interpreter::interpret(instructions, limits, &script_args)
+    };
+    match res {
+      Ok(()) => Ok(()),
+      Err(InterpreterError::Exit(code)) => process::exit(code),
+      Err(InterpreterError::Io(_, error)) if output_closed(&error) => {
+        process::exit(EXIT_RUNTIME_ERROR)
+      }
+      Err(error) => {
+        eprintln!("Error interpreting code: {}", error);
+        process::exit(EXIT_RUNTIME_ERROR);
+      }
+    }
+  };
+  match stack_limits.stack_size() > Limits::default().stack_size() {
+    true => with_stack(&stack_limits, run),
+    false => run(),
+  }
+}
+
+// A file that can not be written is reported like one that can not be read
+fn write_or_exit(out: &str, contents: impl AsRef<[u8]>) {
+  if let Err(error) = fs::write(out, contents) {
+    eprintln!("Error writing '{}': {}", out, error);
+    process::exit(EXIT_USAGE);
+  }
+}
+
+// Instructions from a JSON AST, which is checked the same way as code apart from parsing it
+fn load_ast(
+  name: &str,
+  json: &str,
+  limits: &Limits,
+  check_types: bool,
+) -> Result<Vec<Instruction>, i32> {
+  // Reading JSON recurses for every level of it. Every level of nesting in code takes at most two
+  // levels of JSON, every operator in a row one, and the document and spans take a few more
+  if let Some(max_nesting) = limits.max_nesting {
+    let max_chain = limits::max_chain(Some(max_nesting)).unwrap_or(0);
+    let max_depth = max_nesting.saturating_mul(2).saturating_add(max_chain);
+    if syntax_json::json_depth(json) > max_depth.saturating_add(8) {
+      eprintln!(
+        "Error loading the AST at {}: Limit exceeded, {}",
+        name,
+        limits::Limit::Nesting(max_nesting)
+      );
+      return Err(EXIT_PARSER_ERROR);
+    }
+  }
+  let mut deserializer = serde_json::Deserializer::from_str(json);
+  deserializer.disable_recursion_limit();
+  let mut values = deserializer.into_iter::<serde_json::Value>();
+  let instructions = match (values.next(), values.next()) {
+    (Some(Ok(json)), None) => {
+      syntax_json::ast_from_json(&json, limits.max_nesting).map_err(|error| error.to_string())
+    }
+    (Some(Err(error)), _) => Err(error.to_string()),
+    (None, _) => Err("There is no JSON".to_string()),
+    (Some(Ok(_)), Some(_)) => Err("There is more than one JSON value".to_string()),
+  };
+  let instructions = match instructions {
+    Ok(instructions) => instructions,
+    Err(error) => {
+      eprintln!("Error loading the AST at {}: {}", name, error);
+      return Err(EXIT_PARSER_ERROR);
+    }
+  };
+  if check_types {
+    check_types_of(name, &instructions)?;
+  }
+  Ok(instructions)
+}
+
+// Instructions from a compiled program, checked the same way as code apart from parsing it
+fn load_compiled(
+  name: &str,
+  bytes: &[u8],
+  limits: &Limits,
+  check_types: bool,
+) -> Result<Vec<Instruction>, i32> {
+  let instructions = match compiled::read(bytes, limits.max_nesting) {
+    Ok(instructions) => instructions,
+    Err(error) => {
+      eprintln!("Error loading {}: {}", name, error);
+      return Err(EXIT_PARSER_ERROR);
+    }
+  };
+  if check_types {
+    check_types_of(name, &instructions)?;
+  }
+  Ok(instructions)
+}
+
+// Says what is wrong with the value of an option before the usage is shown
+fn invalid_value<T>(option: &str, value: Option<&String>) -> Option<T> {
+  match value {
+    Some(value) => eprintln!("Error: '{}' is not a valid value for {}", value, option),
+    None => eprintln!("Error: {} needs a value", option),
+  }
+  None
+}
+
+fn parse_option<T: std::str::FromStr>(option: &str, value: Option<&String>) -> Option<T> {
+  match value.map(|value| value.parse()) {
+    Some(Ok(parsed)) => Some(parsed),
+    _ => invalid_value(option, value),
+  }
+}
 
-  let instructions = parser::parse(tokens);
-  if instructions.is_err() {
-    println!("Error parsing code: {:?}", instructions.err().unwrap());
+// A line like `12` or lines like `12-20`, both included
+fn parse_lines(option: &str, value: Option<&String>) -> Option<(usize, usize)> {
+  let lines = value.and_then(|value| {
+    let (first, last) = value.split_once('-').unwrap_or((value, value));
+    Some((first.parse().ok()?, last.parse().ok()?))
+  });
+  lines.or_else(|| invalid_value(option, value))
+}
+
+/*
+ fish repl, runs every statement as soon as it is complete, keeping the variables, functions and
+ enums around for the next ones. The value of an expression statement is shown. Prompts go to
+ stderr, so stdout only has what the code prints.
+*/
+fn repl_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  if !args.is_empty() {
+    usage_error(&format!("Usage: {} repl", program));
+  }
+  let limits = Limits::default();
+  let mut resolver = resolver::Resolver::new();
+  let code = interpreter::Code::default();
+  let mut session = interpreter::Session::new(&code, limits.clone());
+  let mut code = String::new();
+  // `:ast <code>` shows what the code parses to instead of running it
+  let mut show_ast = false;
+  loop {
+    eprint!("{}", if code.is_empty() { "> " } else { ". " });
+    io::stderr().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+      if !code.is_empty() {
+        eprintln!();
+      }
+      return Ok(());
+    }
+    if code.is_empty() {
+      if let Some(rest) = line.strip_prefix(":ast") {
+        show_ast = true;
+        line = rest.to_string();
+      }
+    }
+    code.push_str(&line);
+    let tokens = match tokenizer::tokenize(&code) {
+      Ok(tokens) => tokens,
+      Err(error) => {
+        // A string or comment can go on over more lines
+        if !matches!(
+          error.error,
+          tokenizer::TokenizerError::UnterminatedString
+            | tokenizer::TokenizerError::UnterminatedComment
+        ) {
+          eprintln!("Error tokenizing code at {}", error);
+          code.clear();
+          show_ast = false;
+        }
+        continue;
+      }
+    };
+    if is_unfinished(&tokens) {
+      continue;
+    }
+    let (mut instructions, diagnostics) = parser::parse(tokens, &limits);
+    code.clear();
+    if !diagnostics.is_empty() {
+      for diagnostic in diagnostics {
+        eprintln!("Error parsing code at {}", diagnostic);
+      }
+      show_ast = false;
+      continue;
+    }
+    if show_ast {
+      print!("{}", unparser::unparse(&instructions));
+      show_ast = false;
+      continue;
+    }
+    resolver.resolve_next(&mut instructions);
+    match session.run(instructions) {
+      Ok(Some(value)) => println!("{}", value),
+      Ok(None) => (),
+      Err(InterpreterError::Exit(code)) => process::exit(code),
+      Err(error) => eprintln!("Error interpreting code: {}", error),
+    }
+  }
+}
+
+// Whether a bracket or block is still open, the code has to go on on the next line
+fn is_unfinished(tokens: &[tokenizer::Lexeme]) -> bool {
+  let mut depth = 0;
+  for lexeme in tokens {
+    match lexeme.token {
+      tokenizer::Token::ScopeOpen
+      | tokenizer::Token::BracketOpen
+      | tokenizer::Token::SquareBracketOpen => depth += 1,
+      tokenizer::Token::ScopeClose
+      | tokenizer::Token::BracketClose
+      | tokenizer::Token::SquareBracketClose => depth -= 1,
+      _ => (),
+    }
+  }
+  depth > 0
+}
+
+// fish check <file>..., parses and checks the types without running anything
+fn check_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  if args.is_empty() || args.iter().any(|arg| arg != "-" && arg.starts_with('-')) {
+    usage_error(&format!("Usage: {} check <file>...", program));
+  }
+  let limits = Limits::default();
+  let mut exit_code = None;
+  for file in args {
+    let (name, code) = read_script(file);
+    if let Err(code) = compile(&name, &code, &limits, true) {
+      exit_code.get_or_insert(code);
+    }
+  }
+  if let Some(code) = exit_code {
+    process::exit(code);
+  }
+  Ok(())
+}
+
+// fish tokens [--json] <file>, a token per line with where it is
+fn tokens_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let (json, file) = match args {
+    [flag, file] if flag == "--json" => (true, file),
+    [file] if !file.starts_with("--") => (false, file),
+    _ => usage_error(&format!("Usage: {} tokens [--json] <file>", program)),
+  };
+  let (name, code) = read_script(file);
+  let tokens = match tokenizer::tokenize(&code) {
+    Ok(tokens) => tokens,
+    Err(error) => {
+      eprintln!("Error tokenizing code at {}:{}", name, error);
+      process::exit(EXIT_TOKENIZER_ERROR);
+    }
+  };
+  let mut stdout = io::stdout().lock();
+  if json {
+    writeln!(stdout, "{:#}", syntax_json::tokens_to_json(&tokens))?;
     return Ok(());
   }
-  let instructions = instructions.unwrap();
+  for lexeme in tokens {
+    writeln!(
+      stdout,
+      "{}-{} {:?}",
+      lexeme.span.start, lexeme.span.end, lexeme.token
+    )?;
+  }
+  Ok(())
+}
 
-  let res = interpreter::interpret(instructions);
-  if res.is_err() {
-    println!("Error interpreting code: {:?}", res.err().unwrap());
+// fish ast [--json] <file>, the instructions with the slots the resolver gave the variables. The
+// JSON has no slots, it is meant to be read back with `run --ast`, which resolves them again
+fn ast_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let (json, file) = match args {
+    [flag, file] if flag == "--json" => (true, file),
+    [file] if !file.starts_with("--") => (false, file),
+    _ => usage_error(&format!("Usage: {} ast [--json] <file>", program)),
+  };
+  let compiled = match read_source(file) {
+    (name, Source::Compiled(bytes)) => load_compiled(&name, &bytes, &Limits::default(), false),
+    (name, Source::Code(code)) => compile(&name, &code, &Limits::default(), false),
+  };
+  let mut instructions = match compiled {
+    Ok(instructions) => instructions,
+    Err(code) => process::exit(code),
+  };
+  if json {
+    writeln!(
+      io::stdout(),
+      "{:#}",
+      syntax_json::ast_to_json(&instructions)
+    )?;
     return Ok(());
   }
+  resolver::resolve(&mut instructions);
+  writeln!(io::stdout(), "{:#?}", instructions)?;
+  Ok(())
+}
+
+// fish compile [--check-types] [--no-spans] [-o <out>] <file>, writes the parsed script as a
+// compiled program. It is optimized when it is run, like code, so --no-opt still works on it
+fn compile_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let usage = format!(
+    "Usage: {} compile [--check-types] [--no-spans] [-o <out>] <file>",
+    program
+  );
+  let mut check_types = false;
+  let mut spans = true;
+  let mut out = None;
+  let mut file = None;
+  let mut options = args.iter();
+  while let Some(option) = options.next() {
+    match option.as_str() {
+      "--check-types" => check_types = true,
+      "--no-spans" => spans = false,
+      "-o" => {
+        out = Some(
+          options
+            .next()
+            .unwrap_or_else(|| usage_error(&usage))
+            .clone(),
+        )
+      }
+      _ if file.is_none() && (option == "-" || !option.starts_with('-')) => file = Some(option),
+      _ => usage_error(&usage),
+    }
+  }
+  let Some(file) = file else {
+    usage_error(&usage);
+  };
+  // The script's name with .fshc instead of its extension
+  let out = match out {
+    Some(out) => out,
+    None if file == "-" => usage_error(&format!("{}\nStdin needs -o", usage)),
+    None => Path::new(file)
+      .with_extension("fshc")
+      .to_string_lossy()
+      .into_owned(),
+  };
+  let (name, code) = read_script(file);
+  let instructions = match compile(&name, &code, &Limits::default(), check_types) {
+    Ok(instructions) => instructions,
+    Err(code) => process::exit(code),
+  };
+  let bytes = compiled::write(&instructions, spans);
+  let written = match out.as_str() {
+    "-" => io::stdout().write_all(&bytes),
+    _ => fs::write(&out, bytes),
+  };
+  if let Err(error) = written {
+    eprintln!("Error writing '{}': {}", out, error);
+    process::exit(EXIT_USAGE);
+  }
+  Ok(())
+}
+
+// fish build --target c|js|wasm|wat|x86_64 [--check-types] [--no-opt] [-o <out>] <file>, turns a
+// script or a compiled program into C that a C compiler makes an executable of, into JavaScript,
+// into a WebAssembly module, as a binary or as text, or into assembly for x86-64 Linux
+fn build_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let usage = format!(
+    "Usage: {} build --target c|js|wasm|wat|x86_64 [--check-types] [--no-opt] [-o <out>] <file>",
+    program
+  );
+  let mut target = None;
+  let mut check_types = false;
+  let mut optimize = true;
+  let mut out = None;
+  let mut file = None;
+  let mut options = args.iter();
+  while let Some(option) = options.next() {
+    match option.as_str() {
+      "--target" => target = Some(options.next().unwrap_or_else(|| usage_error(&usage))),
+      "--check-types" => check_types = true,
+      "--no-opt" => optimize = false,
+      "-o" => {
+        out = Some(
+          options
+            .next()
+            .unwrap_or_else(|| usage_error(&usage))
+            .clone(),
+        )
+      }
+      _ if file.is_none() && (option == "-" || !option.starts_with('-')) => file = Some(option),
+      _ => usage_error(&usage),
+    }
+  }
+  let target = match target.map(String::as_str) {
+    Some(target @ ("c" | "js" | "wasm" | "wat" | "x86_64")) => target,
+    Some(target) => usage_error(&format!("{}\nUnknown target '{}'", usage, target)),
+    None => usage_error(&usage),
+  };
+  let Some(file) = file else {
+    usage_error(&usage);
+  };
+  let out = match out {
+    Some(out) => out,
+    None if file == "-" => usage_error(&format!("{}\nStdin needs -o", usage)),
+    None => Path::new(file)
+      .with_extension(match target {
+        "x86_64" => "s",
+        target => target,
+      })
+      .to_string_lossy()
+      .into_owned(),
+  };
+  let limits = Limits::default();
+  let (name, source) = read_source(file);
+  let loaded = match &source {
+    Source::Code(code) => compile(&name, code, &limits, check_types),
+    Source::Compiled(bytes) => load_compiled(&name, bytes, &limits, check_types),
+  };
+  let mut instructions = match loaded {
+    Ok(instructions) => instructions,
+    Err(code) => process::exit(code),
+  };
+  if optimize {
+    optimizer::optimize(&mut instructions, &limits);
+  }
+  resolver::resolve(&mut instructions);
+  let written = match target {
+    "js" => {
+      let content = match &source {
+        Source::Code(code) => Some(code.as_str()),
+        Source::Compiled(_) => None,
+      };
+      write_js(&name, content, &instructions, &out)
+    }
+    "wasm" | "wat" => {
+      let text = match wasm_backend::transpile(&instructions) {
+        Ok(text) => text,
+        Err(diagnostic) => {
+          eprintln!("Error building code at {}:{}", name, diagnostic);
+          process::exit(EXIT_USAGE);
+        }
+      };
+      let bytes = match target {
+        "wasm" => match wat::assemble(&text) {
+          Ok(bytes) => bytes,
+          Err(error) => {
+            eprintln!(
+              "Error building code at {}: The module can not be assembled, {}",
+              name, error
+            );
+            process::exit(EXIT_USAGE);
+          }
+        },
+        _ => text.into_bytes(),
+      };
+      match out.as_str() {
+        "-" => io::stdout().write_all(&bytes),
+        _ => fs::write(&out, bytes),
+      }
+    }
+    "x86_64" => {
+      let assembly = match x86_64_backend::transpile(&instructions) {
+        Ok(assembly) => assembly,
+        Err(diagnostic) => {
+          eprintln!("Error building code at {}:{}", name, diagnostic);
+          process::exit(EXIT_USAGE);
+        }
+      };
+      match out.as_str() {
+        "-" => io::stdout().write_all(assembly.as_bytes()),
+        _ => fs::write(&out, assembly),
+      }
+    }
+    _ => {
+      let c = c_backend::transpile(&instructions);
+      match out.as_str() {
+        "-" => io::stdout().write_all(c.as_bytes()),
+        _ => fs::write(&out, c),
+      }
+    }
+  };
+  if let Err(error) = written {
+    eprintln!("Error writing '{}': {}", out, error);
+    process::exit(EXIT_USAGE);
+  }
   Ok(())
 }
+
+// Writes the JavaScript for a build and its source map next to it, or in it for stdout
+fn write_js(
+  name: &str,
+  content: Option<&str>,
+  instructions: &[Instruction],
+  out: &str,
+) -> io::Result<()> {
+  let output = js_backend::transpile(instructions);
+  if out == "-" {
+    let map = output.source_map("", name, content);
+    let comment = js_backend::source_map_comment(None, &map);
+    return io::stdout().write_all((output.code + &comment).as_bytes());
+  }
+  let out_path = Path::new(out);
+  let file = out_path
+    .file_name()
+    .map(|file| file.to_string_lossy().into_owned())
+    .unwrap_or_default();
+  let map_path = format!("{}.map", out);
+  let map = output.source_map(&file, &map_source(name, out_path), content);
+  let comment = js_backend::source_map_comment(Some(&format!("{}.map", file)), &map);
+  fs::write(out, output.code + &comment)?;
+  write_or_exit(&map_path, map);
+  Ok(())
+}
+
+// Where the script is seen from the directory of the output, which is where a source map looks
+fn map_source(name: &str, out: &Path) -> String {
+  if name == "<stdin>" {
+    return name.to_string();
+  }
+  let absolute = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+  let source = absolute(Path::new(name));
+  let out_directory = match out.parent() {
+    Some(parent) if !parent.as_os_str().is_empty() => absolute(parent),
+    _ => absolute(Path::new(".")),
+  };
+  let shared = source
+    .components()
+    .zip(out_directory.components())
+    .take_while(|(source, out)| source == out)
+    .count();
+  // Paths on different drives have nothing in common
+  if shared == 0 {
+    return source.to_string_lossy().into_owned();
+  }
+  let mut relative = PathBuf::new();
+  for _ in out_directory.components().skip(shared) {
+    relative.push("..");
+  }
+  relative.extend(source.components().skip(shared));
+  relative.to_string_lossy().into_owned()
+}
+
+// fish fmt [--check] [<file>...], formats the files in place, or stdin to stdout without files
+fn format_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let mut check = false;
+  let mut files = Vec::new();
+  for arg in args {
+    match arg.as_str() {
+      "--check" => check = true,
+      "-" => files.push(arg.as_str()),
+      _ if arg.starts_with("--") => {
+        usage_error(&format!("Usage: {} fmt [--check] [<file>...]", program))
+      }
+      _ => files.push(arg.as_str()),
+    }
+  }
+  if files.is_empty() {
+    files.push("-");
+  }
+
+  let code = format_files(&files, check, io::stdin().lock(), io::stdout().lock())?;
+  if code != 0 {
+    process::exit(code);
+  }
+  Ok(())
+}
+
+// Formats the files, or what is read from input to output for -, and returns the exit code: 2 when
+// a file could not be read or formatted, 1 when --check found one that is not formatted
+fn format_files(
+  files: &[&str],
+  check: bool,
+  mut input: impl Read,
+  mut output: impl Write,
+) -> io::Result<i32> {
+  let mut failed = false;
+  let mut unformatted = false;
+  for &file in files {
+    let name = if file == "-" { "<stdin>" } else { file };
+    let code = if file == "-" {
+      let mut code = String::new();
+      input.read_to_string(&mut code)?;
+      code
+    } else {
+      match fs::read_to_string(file) {
+        Ok(code) => code,
+        Err(error) => {
+          eprintln!("Error reading '{}': {}", file, error);
+          failed = true;
+          continue;
+        }
+      }
+    };
+    let formatted = match formatter::format_code(&code) {
+      Ok(formatted) => formatted,
+      Err(errors) => {
+        for error in errors {
+          eprintln!("Error formatting {}: {}", name, error);
+        }
+        failed = true;
+        continue;
+      }
+    };
+    if check {
+      if formatted != code {
+        writeln!(output, "{} is not formatted", name)?;
+        unformatted = true;
+      }
+    } else if file == "-" {
+      write!(output, "{}", formatted)?;
+    } else if formatted != code {
+      fs::write(file, formatted)?;
+    }
+  }
+  output.flush()?;
+  Ok(if failed {
+    2
+  } else if unformatted {
+    1
+  } else {
+    0
+  })
+}
+
+// fish lint [<file>...], prints the warnings for the files, or for stdin without files
+fn lint_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  if args.iter().any(|arg| arg.starts_with("--")) {
+    usage_error(&format!("Usage: {} lint [<file>...]", program));
+  }
+  let mut files: Vec<&str> = args.iter().map(String::as_str).collect();
+  if files.is_empty() {
+    files.push("-");
+  }
+
+  let limits = Limits::default();
+  let mut failed = false;
+  let mut warned = false;
+  for file in files {
+    let name = if file == "-" { "<stdin>" } else { file };
+    let code = if file == "-" {
+      let mut code = String::new();
+      std::io::stdin().read_to_string(&mut code)?;
+      code
+    } else {
+      match fs::read_to_string(file) {
+        Ok(code) => code,
+        Err(error) => {
+          eprintln!("Error reading '{}': {}", file, error);
+          failed = true;
+          continue;
+        }
+      }
+    };
+    let tokens = match tokenizer::tokenize(&code) {
+      Ok(tokens) => tokens,
+      Err(error) => {
+        eprintln!("Error tokenizing code at {}:{}", name, error);
+        failed = true;
+        continue;
+      }
+    };
+    let (instructions, diagnostics) = parser::parse(tokens.clone(), &limits);
+    if !diagnostics.is_empty() {
+      for diagnostic in diagnostics {
+        eprintln!("Error parsing code at {}:{}", name, diagnostic);
+      }
+      failed = true;
+      continue;
+    }
+    for warning in lint::lint(&instructions, &tokens) {
+      writeln!(io::stdout(), "Warning at {}:{}", name, warning)?;
+      warned = true;
+    }
+  }
+  if failed {
+    process::exit(EXIT_USAGE);
+  }
+  if warned {
+    process::exit(EXIT_LINT_WARNINGS);
+  }
+  Ok(())
+}
+
+// fish test [--filter <text>] [--format text|junit|json] [<path>...], runs the test blocks in the
+// .fsh files, directories are searched through. Without paths the current directory is searched
+fn test_command(program: &str, args: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let mut filter = None;
+  let mut format = "text";
+  let mut paths = Vec::new();
+  let mut options = args.iter();
+  while let Some(arg) = options.next() {
+    let parsed = match arg.as_str() {
+      "--filter" => options.next().map(|text| filter = Some(text.as_str())),
+      "--format" => match options.next() {
+        Some(name) if matches!(name.as_str(), "text" | "junit" | "json") => {
+          format = name.as_str();
+          Some(())
+        }
+        name => invalid_value(arg, name),
+      },
+      _ if arg.starts_with("--") => None,
+      _ => {
+        paths.push(arg.as_str());
+        Some(())
+      }
+    };
+    if parsed.is_none() {
+      usage_error(&format!(
+        "Usage: {} test [--filter <text>] [--format text|junit|json] [<path>...]",
+        program
+      ));
+    }
+  }
+  if paths.is_empty() {
+    paths.push(".");
+  }
+
+  let mut failed = false;
+  let mut results = Vec::new();
+  for file in test_runner::find_files(&paths)? {
+    let name = file
+      .strip_prefix(".")
+      .unwrap_or(&file)
+      .display()
+      .to_string();
+    let code = match fs::read_to_string(&file) {
+      Ok(code) => code,
+      Err(error) => {
+        eprintln!("Error reading '{}': {}", name, error);
+        failed = true;
+        continue;
+      }
+    };
+    match test_runner::run_tests(&name, &code, filter) {
+      Ok(file_results) => results.extend(file_results),
+      Err(errors) => {
+        eprintln!("{}", errors);
+        failed = true;
+      }
+    }
+  }
+  let report = match format {
+    "junit" => test_runner::junit_report(&results),
+    "json" => test_runner::json_report(&results),
+    _ => test_runner::text_report(&results),
+  };
+  write!(io::stdout(), "{}", report)?;
+  if failed {
+    process::exit(2);
+  }
+  let passed = results
+    .iter()
+    .all(|result| matches!(result.outcome, test_runner::Outcome::Passed));
+  if !passed {
+    process::exit(1);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FORMATTED: &str = "x = [1, 2];\n\nprint(x[0] + 1);\n";
+  const UNFORMATTED: &str = "x=[1,2];\n\n\n  print( x[0]+1 )  ;";
+
+  fn format_stdin(check: bool, code: &str) -> (i32, String) {
+    let mut output = Vec::new();
+    let code = format_files(&["-"], check, code.as_bytes(), &mut output).unwrap();
+    (code, String::from_utf8(output).unwrap())
+  }
+
+  #[test]
+  fn fmt_formats_stdin_to_stdout() {
+    assert_eq!(format_stdin(false, UNFORMATTED), (0, FORMATTED.to_string()));
+    assert_eq!(format_stdin(false, FORMATTED), (0, FORMATTED.to_string()));
+    assert_eq!(format_stdin(false, "x = ;"), (2, String::new()));
+  }
+
+  #[test]
+  fn fmt_check_exits_with_whether_the_code_is_formatted() {
+    assert_eq!(format_stdin(true, FORMATTED), (0, String::new()));
+    let unformatted = "<stdin> is not formatted\n".to_string();
+    assert_eq!(format_stdin(true, UNFORMATTED), (1, unformatted));
+    assert_eq!(format_stdin(true, "x = ;"), (2, String::new()));
+  }
+
+  #[test]
+  fn fmt_formats_files_in_place() {
+    let dir = samples::temp_dir("fmt");
+    let formatted = dir.join("formatted.fsh").to_string_lossy().into_owned();
+    let unformatted = dir.join("unformatted.fsh").to_string_lossy().into_owned();
+    let broken = dir.join("broken.fsh").to_string_lossy().into_owned();
+    let missing = dir.join("missing.fsh").to_string_lossy().into_owned();
+    fs::write(&formatted, FORMATTED).unwrap();
+    fs::write(&unformatted, UNFORMATTED).unwrap();
+    fs::write(&broken, "x = ;").unwrap();
+    let format = |files: &[&str], check| {
+      let mut output = Vec::new();
+      let code = format_files(files, check, io::empty(), &mut output).unwrap();
+      (code, String::from_utf8(output).unwrap())
+    };
+
+    // --check lists the files and leaves them alone
+    let listed = format!("{} is not formatted\n", unformatted);
+    assert_eq!(format(&[&formatted, &unformatted], true), (1, listed));
+    assert_eq!(fs::read_to_string(&unformatted).unwrap(), UNFORMATTED);
+    assert_eq!(format(&[&formatted, &broken], true), (2, String::new()));
+
+    // The files that can be formatted are, even when others can not
+    let files = [&formatted, &unformatted, &broken, &missing];
+    assert_eq!(
+      format(&files.map(String::as_str), false),
+      (2, String::new())
+    );
+    assert_eq!(fs::read_to_string(&unformatted).unwrap(), FORMATTED);
+    assert_eq!(fs::read_to_string(&broken).unwrap(), "x = ;");
+    assert_eq!(
+      format(&[&formatted, &unformatted], true),
+      (0, String::new())
+    );
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,4 +1,4 @@
-use std::ops::Add;
+use std::{fmt, ops::Add};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Number {
@@ -6,13 +6,14 @@ pub enum Number {
   Float(f64),
 }
 
+// Integer arithmetic without a result that fits, which is an error of the script
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArithmeticError {
+  Overflow,
+  DivisionByZero,
+}
+
 impl Number {
-  pub fn to_string(&self) -> String {
-    match self {
-      Number::Integer(a) => a.to_string(),
-      Number::Float(a) => a.to_string(),
-    }
-  }
   pub fn pow(&self, other: &Number) -> Number {
     let one: f64 = self.into();
     let two: f64 = other.into();
@@ -21,68 +22,94 @@ impl Number {
   }
 }
 
+impl fmt::Display for Number {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Number::Integer(a) => write!(f, "{}", a),
+      Number::Float(a) => write!(f, "{}", a),
+    }
+  }
+}
+
 // Impl add for &Number
 impl Add for &Number {
-  type Output = Number;
-  fn add(self, other: &Number) -> Number {
-    match (self, other) {
-      (Number::Integer(a), Number::Integer(b)) => Number::Integer(a + b),
+  type Output = Result<Number, ArithmeticError>;
+  fn add(self, other: &Number) -> Result<Number, ArithmeticError> {
+    let number = match (self, other) {
+      (Number::Integer(a), Number::Integer(b)) => {
+        Number::Integer(a.checked_add(*b).ok_or(ArithmeticError::Overflow)?)
+      }
       (Number::Integer(a), Number::Float(b)) => Number::Float(*a as f64 + b),
       (Number::Float(a), Number::Integer(b)) => Number::Float(a + *b as f64),
       (Number::Float(a), Number::Float(b)) => Number::Float(a + b),
-    }
+    };
+    Ok(number)
   }
 }
 
 // Impl sub for &Number
 impl std::ops::Sub for &Number {
-  type Output = Number;
-  fn sub(self, other: &Number) -> Number {
-    match (self, other) {
-      (Number::Integer(a), Number::Integer(b)) => Number::Integer(a - b),
+  type Output = Result<Number, ArithmeticError>;
+  fn sub(self, other: &Number) -> Result<Number, ArithmeticError> {
+    let number = match (self, other) {
+      (Number::Integer(a), Number::Integer(b)) => {
+        Number::Integer(a.checked_sub(*b).ok_or(ArithmeticError::Overflow)?)
+      }
       (Number::Integer(a), Number::Float(b)) => Number::Float(*a as f64 - b),
       (Number::Float(a), Number::Integer(b)) => Number::Float(a - *b as f64),
       (Number::Float(a), Number::Float(b)) => Number::Float(a - b),
-    }
+    };
+    Ok(number)
   }
 }
 
 // Impl mul for &Number
 impl std::ops::Mul for &Number {
-  type Output = Number;
-  fn mul(self, other: &Number) -> Number {
-    match (self, other) {
-      (Number::Integer(a), Number::Integer(b)) => Number::Integer(a * b),
+  type Output = Result<Number, ArithmeticError>;
+  fn mul(self, other: &Number) -> Result<Number, ArithmeticError> {
+    let number = match (self, other) {
+      (Number::Integer(a), Number::Integer(b)) => {
+        Number::Integer(a.checked_mul(*b).ok_or(ArithmeticError::Overflow)?)
+      }
       (Number::Integer(a), Number::Float(b)) => Number::Float(*a as f64 * b),
       (Number::Float(a), Number::Integer(b)) => Number::Float(a * *b as f64),
       (Number::Float(a), Number::Float(b)) => Number::Float(a * b),
-    }
+    };
+    Ok(number)
   }
 }
 
 // Impl div for &Number
 impl std::ops::Div for &Number {
-  type Output = Number;
-  fn div(self, other: &Number) -> Number {
-    match (self, other) {
-      (Number::Integer(a), Number::Integer(b)) => Number::Integer(a / b),
+  type Output = Result<Number, ArithmeticError>;
+  fn div(self, other: &Number) -> Result<Number, ArithmeticError> {
+    let number = match (self, other) {
+      (Number::Integer(_), Number::Integer(0)) => return Err(ArithmeticError::DivisionByZero),
+      (Number::Integer(a), Number::Integer(b)) => {
+        Number::Integer(a.checked_div(*b).ok_or(ArithmeticError::Overflow)?)
+      }
       (Number::Integer(a), Number::Float(b)) => Number::Float(*a as f64 / b),
       (Number::Float(a), Number::Integer(b)) => Number::Float(a / *b as f64),
       (Number::Float(a), Number::Float(b)) => Number::Float(a / b),
-    }
+    };
+    Ok(number)
   }
 }
 
 // Impl rem for &Number
 impl std::ops::Rem for &Number {
-  type Output = Number;
-  fn rem(self, other: &Number) -> Number {
-    match (self, other) {
-      (Number::Integer(a), Number::Integer(b)) => Number::Integer(a % b),
+  type Output = Result<Number, ArithmeticError>;
+  fn rem(self, other: &Number) -> Result<Number, ArithmeticError> {
+    let number = match (self, other) {
+      (Number::Integer(_), Number::Integer(0)) => return Err(ArithmeticError::DivisionByZero),
+      (Number::Integer(a), Number::Integer(b)) => {
+        Number::Integer(a.checked_rem(*b).ok_or(ArithmeticError::Overflow)?)
+      }
       (Number::Integer(a), Number::Float(b)) => Number::Float(*a as f64 % b),
       (Number::Float(a), Number::Integer(b)) => Number::Float(a % *b as f64),
       (Number::Float(a), Number::Float(b)) => Number::Float(a % b),
-    }
+    };
+    Ok(number)
   }
 }
 
@@ -0,0 +1,666 @@
+use std::collections::HashSet;
+
+use crate::{
+  interpreter,
+  limits::Limits,
+  number::Number,
+  parser::{Expression, Identifier, Instruction, InstructionKind, Pattern, Value},
+  tokenizer::Operator,
+};
+
+/*
+ Rewrites instructions into ones that do the same with less work, after they were checked and
+ before they are resolved. Nothing is changed in a way that could be told apart from the outside:
+ what is printed, which errors happen and where, and how values compare all stay the same.
+
+ - Operators on literals are worked out, the same way the interpreter would, unless that fails.
+ - An if or while with a literal condition is replaced by the block that runs, if any.
+ - Brackets are taken out, the tree already says what they grouped.
+ - x * 1, 1 * x, x / 1 and x - 0 become x when x is a number, and x && true, x || false and
+   !!x become x when it is a boolean. Nothing is done that would turn -0.0 into 0.0 or an
+   integer into a float. !(a == b) becomes a != b.
+ - Values in a loop that come out the same every time around are kept after the first time.
+   They are still worked out where they were, so they fail in the same place if they fail.
+*/
+pub fn optimize(instructions: &mut Vec<Instruction>, limits: &Limits) {
+  let mut optimizer = Optimizer { limits, hoisted: 0 };
+  optimizer.optimize_block(instructions);
+}
+
+struct Optimizer<'a> {
+  limits: &'a Limits,
+  // How many values were hoisted out of loops so far, each gets a variable of its own
+  hoisted: usize,
+}
+
+impl Optimizer<'_> {
+  fn optimize_block(&mut self, instructions: &mut Vec<Instruction>) {
+    let mut optimized = Vec::with_capacity(instructions.len());
+    let mut rest = std::mem::take(instructions).into_iter().peekable();
+    while let Some(mut instruction) = rest.next() {
+      self.optimize_instruction(&mut instruction);
+      let span = instruction.span;
+      let block = match instruction.kind {
+        InstructionKind::If {
+          condition: Value::Boolean(condition),
+          instructions,
+        } => {
+          // The else goes with the if, only the block that runs is kept
+          let otherwise = rest.next_if(|next| matches!(next.kind, InstructionKind::Else { .. }));
+          match (condition, otherwise) {
+            (true, _) => instructions,
+            (
+              false,
+              Some(Instruction {
+                kind: InstructionKind::Else { mut instructions },
+                ..
+              }),
+            ) => {
+              self.optimize_block(&mut instructions);
+              instructions
+            }
+            (false, _) => Vec::new(),
+          }
+        }
+        InstructionKind::While {
+          condition: Value::Boolean(false),
+          ..
+        } => Vec::new(),
+        InstructionKind::While {
+          mut condition,
+          mut instructions,
+        } => {
+          for name in self.hoist(&mut condition, &mut instructions) {
+            optimized.push(Instruction {
+              kind: InstructionKind::Let {
+                variable: Identifier::new(name, span),
+                annotation: None,
+                value: Value::None,
+              },
+              span,
+            });
+          }
+          optimized.push(Instruction {
+            kind: InstructionKind::While {
+              condition,
+              instructions,
+            },
+            span,
+          });
+          continue;
+        }
+        kind => {
+          optimized.push(Instruction { kind, span });
+          continue;
+        }
+      };
+      // An else only runs right after an if, so what is in front of one can not be left out
+      let before_else = rest
+        .peek()
+        .is_some_and(|next| matches!(next.kind, InstructionKind::Else { .. }));
+      if !block.is_empty() || before_else {
+        optimized.push(Instruction {
+          kind: InstructionKind::Scope {
+            instructions: block,
+          },
+          span,
+        });
+      }
+    }
+    *instructions = optimized;
+  }
+
+  fn optimize_instruction(&mut self, instruction: &mut Instruction) {
+    match &mut instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      }
+      | InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        self.optimize_value(condition);
+        self.optimize_block(instructions);
+      }
+      InstructionKind::Else { instructions }
+      | InstructionKind::Scope { instructions }
+      | InstructionKind::Test { instructions, .. } => self.optimize_block(instructions),
+      InstructionKind::Value { value }
+      | InstructionKind::Print { message: value }
+      | InstructionKind::Let { value, .. }
+      | InstructionKind::Return { value: Some(value) } => self.optimize_value(value),
+      InstructionKind::Match { value, arms } => {
+        self.optimize_value(value);
+        for arm in arms {
+          if let Some(guard) = &mut arm.guard {
+            self.optimize_value(guard);
+          }
+          self.optimize_block(&mut arm.instructions);
+        }
+      }
+      InstructionKind::Function(function) => self.optimize_block(&mut function.instructions),
+      InstructionKind::Assert { condition, message } => {
+        // A failed comparison shows both sides, so an assert has to keep the comparison it has and
+        // can not get one it did not have
+        strip_brackets(condition);
+        match condition {
+          Value::Expression(expression) => self.optimize_sides(expression),
+          condition => self.optimize_value(condition),
+        }
+        if let Some(message) = message {
+          self.optimize_value(message);
+        }
+      }
+      InstructionKind::Return { value: None }
+      | InstructionKind::Break
+      | InstructionKind::Input { .. }
+      | InstructionKind::Enum { .. } => (),
+    }
+  }
+
+  fn optimize_value(&mut self, value: &mut Value) {
+    let simplified = match value {
+      Value::List(values)
+      | Value::Call {
+        arguments: values, ..
+      } => {
+        for value in values {
+          self.optimize_value(value);
+        }
+        None
+      }
+      Value::Field { value, .. } => {
+        self.optimize_value(value);
+        None
+      }
+      Value::Index { value, index, .. } => {
+        self.optimize_value(value);
+        self.optimize_value(index);
+        None
+      }
+      Value::Expression(expression) => {
+        self.optimize_sides(expression);
+        self.simplify(expression)
+      }
+      _ => None,
+    };
+    if let Some(simplified) = simplified {
+      *value = simplified;
+    }
+  }
+
+  fn optimize_sides(&mut self, expression: &mut Expression) {
+    // (a) = 1 fails when it runs, without the brackets it would not
+    if !expression.get_operator().is_assignment() {
+      self.optimize_value(expression.get_left_mut());
+    }
+    if let Some(right) = expression.get_right_mut() {
+      self.optimize_value(right);
+    }
+  }
+
+  // What an expression with optimized sides can be replaced with, if anything
+  fn simplify(&self, expression: &mut Expression) -> Option<Value> {
+    let operator = *expression.get_operator();
+    let folded = interpreter::fold(
+      operator,
+      expression.get_left(),
+      expression.get_right(),
+      self.limits,
+    );
+    if folded.is_some() {
+      return folded;
+    }
+    let side = match (operator, expression.get_left(), expression.get_right()) {
+      (Operator::Brackets, _, _) => Side::Left,
+      // The right side is only evaluated when the left is none
+      (Operator::Coalesce, Value::None, _) => Side::Right,
+      (Operator::Coalesce, left, _) if is_literal(left) => Side::Left,
+      (Operator::Multiply | Operator::Divide, left, Some(Value::Number(Number::Integer(1))))
+      | (Operator::Subtract, left, Some(Value::Number(Number::Integer(0))))
+        if is_number(left) =>
+      {
+        Side::Left
+      }
+      (Operator::Multiply, Value::Number(Number::Integer(1)), Some(right)) if is_number(right) => {
+        Side::Right
+      }
+      (Operator::And, left, Some(Value::Boolean(true)))
+      | (Operator::Or, left, Some(Value::Boolean(false)))
+        if is_boolean(left) =>
+      {
+        Side::Left
+      }
+      (Operator::And, Value::Boolean(true), Some(right))
+      | (Operator::Or, Value::Boolean(false), Some(right))
+        if is_boolean(right) =>
+      {
+        Side::Right
+      }
+      (Operator::Not, _, _) => return negate(expression),
+      _ => return None,
+    };
+    match side {
+      Side::Left => Some(take(expression.get_left_mut())),
+      Side::Right => expression.get_right_mut().map(take),
+    }
+  }
+
+  /*
+   Caches the values in a loop that only read literals and variables the loop does not change, in
+   a variable declared in front of it. `a * b` becomes `hoisted#0 ?? (hoisted#0 = a * b)`, so it
+   is still evaluated the first time it is reached and not before, and an error it gives happens
+   where it did. A value that comes out as none is worked out again every time, which is no worse
+   than before. Returns the variables to declare.
+  */
+  fn hoist(&mut self, condition: &mut Value, instructions: &mut [Instruction]) -> Vec<String> {
+    let mut changed = HashSet::new();
+    collect_assigned_in_value(condition, &mut changed);
+    collect_assigned(instructions, &mut changed);
+    let mut hoisted = Vec::new();
+    self.hoist_value(condition, &changed, &mut hoisted);
+    self.hoist_block(instructions, &changed, &mut hoisted);
+    hoisted.into_iter().map(|(_, name)| name).collect()
+  }
+
+  fn hoist_block(
+    &mut self,
+    instructions: &mut [Instruction],
+    changed: &HashSet<String>,
+    hoisted: &mut Vec<(String, String)>,
+  ) {
+    for instruction in instructions {
+      let (values, blocks) = parts(&mut instruction.kind);
+      for value in values {
+        self.hoist_value(value, changed, hoisted);
+      }
+      for block in blocks {
+        self.hoist_block(block, changed, hoisted);
+      }
+    }
+  }
+
+  // `hoisted` has the code of the values hoisted so far with their variables, the same value is
+  // only kept once
+  fn hoist_value(
+    &mut self,
+    value: &mut Value,
+    changed: &HashSet<String>,
+    hoisted: &mut Vec<(String, String)>,
+  ) {
+    if is_worth_hoisting(value) && is_invariant(value, changed) {
+      let code = value.to_string();
+      let name = match hoisted.iter().find(|(hoisted, _)| *hoisted == code) {
+        Some((_, name)) => name.clone(),
+        None => {
+          let name = format!("hoisted#{}", self.hoisted);
+          self.hoisted += 1;
+          hoisted.push((code, name.clone()));
+          name
+        }
+      };
+      let variable = || Value::Identifier(Identifier::new(name.clone(), Default::default()));
+      let assignment = Expression::new(Operator::Assign, variable(), take(value));
+      *value = Value::Expression(Box::new(Expression::new(
+        Operator::Coalesce,
+        variable(),
+        Value::Expression(Box::new(assignment)),
+      )));
+      return;
+    }
+    match value {
+      Value::List(values)
+      | Value::Call {
+        arguments: values, ..
+      } => {
+        for value in values {
+          self.hoist_value(value, changed, hoisted);
+        }
+      }
+      Value::Field { value, .. } => self.hoist_value(value, changed, hoisted),
+      Value::Index { value, index, .. } => {
+        self.hoist_value(value, changed, hoisted);
+        self.hoist_value(index, changed, hoisted);
+      }
+      Value::Expression(expression) => {
+        if !expression.get_operator().is_assignment() {
+          self.hoist_value(expression.get_left_mut(), changed, hoisted);
+        }
+        if let Some(right) = expression.get_right_mut() {
+          self.hoist_value(right, changed, hoisted);
+        }
+      }
+      _ => (),
+    }
+  }
+}
+
+enum Side {
+  Left,
+  Right,
+}
+
+// !!x is x and !(a == b) is a != b, the other comparisons are not turned around since nothing is
+// less than, equal to or greater than NaN
+fn negate(not: &mut Expression) -> Option<Value> {
+  let Value::Expression(inner) = not.get_left_mut() else {
+    return None;
+  };
+  let operator = match inner.get_operator() {
+    Operator::Not if is_boolean(inner.get_left()) => return Some(take(inner.get_left_mut())),
+    Operator::Equal => Operator::NotEqual,
+    Operator::NotEqual => Operator::Equal,
+    _ => return None,
+  };
+  let right = take(inner.get_right_mut()?);
+  Some(Value::Expression(Box::new(Expression::new(
+    operator,
+    take(inner.get_left_mut()),
+    right,
+  ))))
+}
+
+fn strip_brackets(value: &mut Value) {
+  while let Value::Expression(expression) = value {
+    if *expression.get_operator() != Operator::Brackets {
+      return;
+    }
+    let inner = take(expression.get_left_mut());
+    *value = inner;
+  }
+}
+
+fn take(value: &mut Value) -> Value {
+  std::mem::replace(value, Value::None)
+}
+
+fn is_literal(value: &Value) -> bool {
+  matches!(
+    value,
+    Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None
+  )
+}
+
+// Whether a value is a number whenever evaluating it does not fail
+fn is_number(value: &Value) -> bool {
+  match value {
+    Value::Number(_) => true,
+    Value::Expression(expression) => matches!(
+      expression.get_operator(),
+      Operator::Subtract
+        | Operator::Multiply
+        | Operator::Divide
+        | Operator::Modulo
+        | Operator::Exponent
+        | Operator::SubtractAssign
+        | Operator::MultiplyAssign
+        | Operator::DivideAssign
+        | Operator::ModuloAssign
+    ),
+    _ => false,
+  }
+}
+
+// Whether a value is a boolean whenever evaluating it does not fail
+fn is_boolean(value: &Value) -> bool {
+  match value {
+    Value::Boolean(_) => true,
+    Value::Expression(expression) => {
+      let operator = *expression.get_operator();
+      operator.is_comparison()
+        || matches!(operator, Operator::Not | Operator::And | Operator::Or)
+    }
+    _ => false,
+  }
+}
+
+// Reading a variable is about as fast as reading the cache, there has to be more to it than that
+fn is_worth_hoisting(value: &Value) -> bool {
+  match value {
+    Value::List(_) => true,
+    Value::Expression(expression) => {
+      expression.get_right().is_some() || is_worth_hoisting(expression.get_left())
+    }
+    Value::Field { value, .. } => is_worth_hoisting(value),
+    Value::Index { value, index, .. } => is_worth_hoisting(value) || is_worth_hoisting(index),
+    _ => false,
+  }
+}
+
+// Calls can print, the rest only depends on the variables it reads
+fn is_invariant(value: &Value, changed: &HashSet<String>) -> bool {
+  match value {
+    Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None => true,
+    Value::Identifier(identifier) => !changed.contains(&identifier.name),
+    Value::List(items) => items.iter().all(|item| is_invariant(item, changed)),
+    Value::Expression(expression) => {
+      !expression.get_operator().is_assignment()
+        && is_invariant(expression.get_left(), changed)
+        && expression
+          .get_right()
+          .is_none_or(|right| is_invariant(right, changed))
+    }
+    Value::Field { value, .. } => is_invariant(value, changed),
+    Value::Index { value, index, .. } => {
+      is_invariant(value, changed) && is_invariant(index, changed)
+    }
+    Value::Call { .. } => false,
+  }
+}
+
+// The names given a value somewhere in the instructions, functions defined in them do not count
+// since they can not see the variables around them
+fn collect_assigned(instructions: &mut [Instruction], names: &mut HashSet<String>) {
+  for instruction in instructions {
+    match &instruction.kind {
+      InstructionKind::Let { variable, .. } | InstructionKind::Input { variable } => {
+        names.insert(variable.name.clone());
+      }
+      InstructionKind::Enum { variants, .. } => {
+        names.extend(variants.iter().map(|variant| variant.name.clone()));
+      }
+      InstructionKind::Match { arms, .. } => {
+        for arm in arms {
+          collect_bindings(&arm.pattern, names);
+        }
+      }
+      _ => (),
+    }
+    let (values, blocks) = parts(&mut instruction.kind);
+    for value in values {
+      collect_assigned_in_value(value, names);
+    }
+    for block in blocks {
+      collect_assigned(block, names);
+    }
+  }
+}
+
+fn collect_bindings(pattern: &Pattern, names: &mut HashSet<String>) {
+  match pattern {
+    Pattern::Identifier(identifier) => {
+      names.insert(identifier.name.clone());
+    }
+    Pattern::Variant { fields, .. } => {
+      for field in fields {
+        collect_bindings(field, names);
+      }
+    }
+    Pattern::Wildcard | Pattern::Literal(_) => (),
+  }
+}
+
+fn collect_assigned_in_value(value: &Value, names: &mut HashSet<String>) {
+  match value {
+    Value::List(values)
+    | Value::Call {
+      arguments: values, ..
+    } => {
+      for value in values {
+        collect_assigned_in_value(value, names);
+      }
+    }
+    Value::Field { value, .. } => collect_assigned_in_value(value, names),
+    Value::Index { value, index, .. } => {
+      collect_assigned_in_value(value, names);
+      collect_assigned_in_value(index, names);
+    }
+    Value::Expression(expression) => {
+      if let (true, Value::Identifier(identifier)) = (
+        expression.get_operator().is_assignment(),
+        expression.get_left(),
+      ) {
+        names.insert(identifier.name.clone());
+      }
+      collect_assigned_in_value(expression.get_left(), names);
+      if let Some(right) = expression.get_right() {
+        collect_assigned_in_value(right, names);
+      }
+    }
+    _ => (),
+  }
+}
+
+// The values an instruction evaluates and the blocks it runs, leaving out function bodies. For an
+// assert of a comparison these are the sides, the comparison has to stay to be reported
+fn parts(kind: &mut InstructionKind) -> (Vec<&mut Value>, Vec<&mut Vec<Instruction>>) {
+  match kind {
+    InstructionKind::If {
+      condition,
+      instructions,
+    }
+    | InstructionKind::While {
+      condition,
+      instructions,
+    } => (vec![condition], vec![instructions]),
+    InstructionKind::Else { instructions }
+    | InstructionKind::Scope { instructions }
+    | InstructionKind::Test { instructions, .. } => (vec![], vec![instructions]),
+    InstructionKind::Value { value }
+    | InstructionKind::Print { message: value }
+    | InstructionKind::Let { value, .. }
+    | InstructionKind::Return { value: Some(value) } => (vec![value], vec![]),
+    InstructionKind::Match { value, arms } => {
+      let mut values = vec![value];
+      let mut blocks = Vec::new();
+      for arm in arms {
+        if let Some(guard) = &mut arm.guard {
+          values.push(guard);
+        }
+        blocks.push(&mut arm.instructions);
+      }
+      (values, blocks)
+    }
+    InstructionKind::Assert { condition, message } => {
+      let compared = matches!(
+        condition,
+        Value::Expression(expression) if expression.get_operator().is_comparison()
+      );
+      let mut values = match (compared, condition) {
+        (true, Value::Expression(expression)) => {
+          let (left, right) = expression.get_sides_mut();
+          let mut sides = vec![left];
+          sides.extend(right);
+          sides
+        }
+        (_, condition) => vec![condition],
+      };
+      values.extend(message.as_mut());
+      (values, vec![])
+    }
+    InstructionKind::Function(_)
+    | InstructionKind::Return { value: None }
+    | InstructionKind::Break
+    | InstructionKind::Input { .. }
+    | InstructionKind::Enum { .. } => (vec![], vec![]),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{resolver, samples, unparser};
+
+  // Code the optimizer works on, and code it has to leave alone since working it out fails or
+  // would change what it does
+  const CASES: [&str; 10] = [
+    "print(1 + 2 * 3); print(10 - 2 - 3); print(2 ^ 0.5); print(\"a\" + \"b\");",
+    "print(9223372036854775807 + 1);",
+    "print(1); print(7 / 0);",
+    "print(1); print(true + 1);",
+    "if (false) { print(1); } else { print(2); }; if (true) { print(3); } else { print(4); };",
+    "while (false) { print(1); }; if (1 < 2) { print(2); };",
+    "x = 0.0 * (0 - 1); print(x); print(x * 1); print(x - 0); print(x + 0); print(1 * x);",
+    "x = 5; print(x * 1); print(x / 1); print(x - 0); x = \"a\"; print(x * 1);",
+    "b = true; print(b && true); print(b || false); print(!!b); print(!(1 == 2)); b = 1; \
+     print(b && true);",
+    "x = 1; print(!!x);",
+  ];
+
+  // Loops, and whether they keep a value they only need to work out once
+  const LOOPS: [(&str, bool); 6] = [
+    // A loop that never runs must not work out what it would have kept
+    (
+      "a = 1; b = 0; i = 0; while (i < 0) { print(a / b); i += 1; }; print(\"done\");",
+      true,
+    ),
+    // Guarded by an if that is never true
+    (
+      "a = 10; b = 0; i = 0; while (i < 3) { if (b != 0) { print(a / b); }; i += 1; }; print(i);",
+      true,
+    ),
+    // Fails the third time around, after printing the first two
+    (
+      "a = 1; b = 0; i = 0; while (i < 3) { print(i); if (i == 2) { print(a / b); }; i += 1; };",
+      true,
+    ),
+    // Changed in a block inside the loop
+    (
+      "a = 1; i = 0; while (i < 3) { print(a * 2); if (i == 1) { a = 5; }; i += 1; };",
+      false,
+    ),
+    // Kept by the inner loop, which has to work it out again every time around the outer one
+    (
+      "i = 0; while (i < 2) { j = 0; while (j < 2) { print(j + i * 10); j += 1; }; i += 1; };",
+      true,
+    ),
+    // Comes out as none, so it is worked out every time
+    (
+      "i = 0; while (i < 2) { print([none][0] ?? i); i += 1; };",
+      true,
+    ),
+  ];
+
+  fn run(code: &str, optimize: bool) -> Option<samples::Ending> {
+    samples::run(|| {
+      let mut instructions = samples::parse(code);
+      if optimize {
+        super::optimize(&mut instructions, &Limits::default());
+      }
+      resolver::resolve(&mut instructions);
+      instructions
+    })
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn optimized_code_does_what_the_code_does() {
+    let cases = CASES.into_iter().chain(LOOPS.map(|(code, _)| code));
+    let cases = cases.map(|code| (code.to_string(), code.to_string()));
+    let samples = samples::all().into_iter().chain(samples::edge_cases());
+    for (name, code) in samples.chain(cases) {
+      assert_eq!(run(&code, true), run(&code, false), "{}", name);
+    }
+  }
+
+  #[test]
+  fn loops_keep_what_does_not_change() {
+    for (code, kept) in LOOPS {
+      let mut instructions = samples::parse(code);
+      optimize(&mut instructions, &Limits::default());
+      let optimized = unparser::unparse(&instructions);
+      assert_eq!(optimized.contains("hoisted#"), kept, "{}", optimized);
+    }
+  }
+}
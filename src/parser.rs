@@ -1,8 +1,11 @@
 use crate::{
+  diagnostic,
+  limits::{Limit, Limits},
   number::Number,
-  tokenizer::{Keyword, Operator, Token},
+  resolver::Slot,
+  tokenizer::{Keyword, Lexeme, Operator, Position, Span, Token},
 };
-use std::{collections::HashMap, iter::Peekable, ops::Add, sync::Arc};
+use std::{collections::HashMap, fmt, ops::Range, rc::Rc};
 
 /*
  TokenStream:
@@ -24,227 +27,976 @@ use std::{collections::HashMap, iter::Peekable, ops::Add, sync::Arc};
 #[derive(Debug)]
 pub enum ParserError {
   ExpectedToken(Token),
+  ExpectedKind(TokenKind),
   UnexpectedToken(Token),
-  UnknownOperator(String),
   InvalidOperator(Operator),
   UnexpectedEnd,
+  LimitExceeded(Limit),
 }
-pub fn parse(tokens: Vec<Token>) -> Result<Vec<Instruction>, ParserError> {
-  let mut instructions = Vec::new();
-  let mut tokens = tokens.into_iter().peekable();
-  loop {
-    let token = tokens.next();
-    if token == None {
-      break;
+
+impl fmt::Display for ParserError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ParserError::ExpectedToken(token) => write!(f, "Expected {}", token),
+      ParserError::ExpectedKind(kind) => write!(f, "Expected {}", kind),
+      ParserError::UnexpectedToken(token) => write!(f, "Unexpected {}", token),
+      ParserError::InvalidOperator(operator) => write!(f, "Invalid use of operator `{}`", operator),
+      ParserError::UnexpectedEnd => write!(f, "Unexpected end of input"),
+      ParserError::LimitExceeded(limit) => write!(f, "Limit exceeded, {}", limit),
+    }
+  }
+}
+
+// Tokens that are expected for what they are rather than as one exact token
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TokenKind {
+  Identifier,
+  String,
+}
+
+impl fmt::Display for TokenKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TokenKind::Identifier => write!(f, "an identifier"),
+      TokenKind::String => write!(f, "a string"),
+    }
+  }
+}
+
+pub type Diagnostic = diagnostic::Diagnostic<ParserError>;
+
+impl Diagnostic {
+  fn unexpected(lexeme: Lexeme) -> Self {
+    Self::new(ParserError::UnexpectedToken(lexeme.token), lexeme.span)
+  }
+}
+
+/*
+ A broken statement is reported and skipped, after which parsing picks up again at the next
+ statement, so all errors in a script are found in one go. Running into a limit stops parsing
+ instead. The instructions that did parse are returned next to the diagnostics, which are sorted
+ by where they were found.
+*/
+pub fn parse(mut tokens: Vec<Lexeme>, limits: &Limits) -> (Vec<Instruction>, Vec<Diagnostic>) {
+  let end = match tokens.pop() {
+    Some(Lexeme {
+      token: Token::EndOfFile,
+      span,
+      ..
+    }) => span,
+    _ => panic!("Tokens do not end with EndOfFile"),
+  };
+  let mut parser = Parser {
+    depth: 0,
+    max_depth: limits.max_nesting,
+    functions: 0,
+    chain: 0,
+    max_chain: crate::limits::max_chain(limits.max_nesting),
+    diagnostics: Vec::new(),
+  };
+  let code = Code::new(tokens);
+  let tokens = TokenStream::new(&code, 0..code.lexemes.len(), end, None);
+  let instructions = match parser.parse_instructions(tokens) {
+    Ok(instructions) => instructions,
+    Err(diagnostic) => {
+      parser.diagnostics.push(diagnostic);
+      Vec::new()
     }
-    let token = token.unwrap();
+  };
+  parser
+    .diagnostics
+    .sort_by_key(|diagnostic| diagnostic.span.start);
+  (instructions, parser.diagnostics)
+}
+
+// The tokens of the code, with where every bracket is closed and where the commas between
+// arguments are worked out ahead, so no part of the code is searched through again for every
+// level it is nested in
+struct Code {
+  lexemes: Vec<Lexeme>,
+  // For every opening bracket, where the bracket that closes it is. Brackets and square brackets
+  // are never closed after a `;` or a block starts or ends
+  closing: Vec<Option<usize>>,
+  // How many brackets and square brackets are open before every token, and after the last one
+  open_brackets: Vec<isize>,
+  // Where the commas are, by how many brackets are open before them
+  commas: HashMap<isize, Vec<usize>>,
+}
 
-    let instruction = match token {
-      Token::Comment(_) => None,
+impl Code {
+  fn new(lexemes: Vec<Lexeme>) -> Self {
+    let mut closing = vec![None; lexemes.len()];
+    let mut open_brackets = Vec::with_capacity(lexemes.len() + 1);
+    let mut commas: HashMap<isize, Vec<usize>> = HashMap::new();
+    let (mut brackets, mut square_brackets, mut braces) = (Vec::new(), Vec::new(), Vec::new());
+    let mut open = 0;
+    for (index, lexeme) in lexemes.iter().enumerate() {
+      open_brackets.push(open);
+      let (opened, closed) = match lexeme.token {
+        Token::BracketOpen => (Some(&mut brackets), None),
+        Token::SquareBracketOpen => (Some(&mut square_brackets), None),
+        Token::ScopeOpen => (Some(&mut braces), None),
+        Token::BracketClose => (None, brackets.pop()),
+        Token::SquareBracketClose => (None, square_brackets.pop()),
+        Token::ScopeClose => (None, braces.pop()),
+        Token::Comma => {
+          commas.entry(open).or_default().push(index);
+          (None, None)
+        }
+        _ => (None, None),
+      };
+      if let Some(opened) = opened {
+        opened.push(index);
+      }
+      if let Some(opened) = closed {
+        closing[opened] = Some(index);
+      }
+      match lexeme.token {
+        Token::BracketOpen | Token::SquareBracketOpen => open += 1,
+        Token::BracketClose | Token::SquareBracketClose => open -= 1,
+        Token::EndStatement | Token::ScopeOpen | Token::ScopeClose => {
+          brackets.clear();
+          square_brackets.clear();
+        }
+        _ => (),
+      }
+    }
+    open_brackets.push(open);
+    Self {
+      lexemes,
+      closing,
+      open_brackets,
+      commas,
+    }
+  }
+}
+
+// A part of the code, like the inside of a pair of brackets
+struct TokenStream<'a> {
+  code: &'a Code,
+  // The next token and the one after the last token of the part
+  position: usize,
+  limit: usize,
+  // Where the part ends, errors about missing tokens point here
+  end: Span,
+  // The token the part ends at, which is not in it, or None at the end of the code
+  end_token: Option<Token>,
+  // Whether the last token taken finished a statement, in which case there is nothing to skip
+  // when recovering from an error
+  at_boundary: bool,
+  // Where the last token taken ends
+  previous_end: Position,
+}
+
+impl<'a> TokenStream<'a> {
+  fn new(code: &'a Code, tokens: Range<usize>, end: Span, end_token: Option<Token>) -> Self {
+    Self {
+      code,
+      position: tokens.start,
+      limit: tokens.end,
+      end,
+      end_token,
+      at_boundary: true,
+      previous_end: end.start,
+    }
+  }
+
+  fn peek(&self) -> Option<&'a Token> {
+    self.peek_lexeme().map(|lexeme| &lexeme.token)
+  }
+
+  fn peek_lexeme(&self) -> Option<&'a Lexeme> {
+    self.code.lexemes[..self.limit].get(self.position)
+  }
+
+  // The span of the next token, or the end when there are none left
+  fn span(&self) -> Span {
+    self.peek_lexeme().map_or(self.end, |lexeme| lexeme.span)
+  }
+
+  // Takes the next token without copying it if the condition holds for it
+  fn skip_if(&mut self, condition: impl FnOnce(&Token) -> bool) -> bool {
+    match self.peek_lexeme() {
+      Some(lexeme) if condition(&lexeme.token) => {
+        self.skip_to(self.position);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  // Takes the tokens up to and including the one at the index
+  fn skip_to(&mut self, index: usize) {
+    let lexeme = &self.code.lexemes[index];
+    self.at_boundary = matches!(lexeme.token, Token::EndStatement | Token::ScopeClose);
+    self.previous_end = lexeme.span.end;
+    self.position = index + 1;
+  }
+
+  fn next_if(&mut self, condition: impl FnOnce(&Token) -> bool) -> Option<Lexeme> {
+    let lexeme = self.peek_lexeme()?;
+    self.skip_if(condition).then(|| lexeme.clone())
+  }
+
+  fn next_if_eq(&mut self, token: &Token) -> Option<Lexeme> {
+    self.next_if(|next| next == token)
+  }
+
+  // The next token, a part that ran out of them ends at an unexpected token unless it is the end
+  // of the code
+  fn expect(&mut self) -> Result<Lexeme, Diagnostic> {
+    if let Some(lexeme) = self.next() {
+      return Ok(lexeme);
+    }
+    let error = match &self.end_token {
+      Some(token) => ParserError::UnexpectedToken(token.clone()),
+      None => ParserError::UnexpectedEnd,
+    };
+    Err(Diagnostic::new(error, self.end))
+  }
+
+  fn expect_token(&mut self, token: Token) -> Result<Lexeme, Diagnostic> {
+    match self.next_if_eq(&token) {
+      Some(lexeme) => Ok(lexeme),
+      None => Err(Diagnostic::new(
+        ParserError::ExpectedToken(token),
+        self.span(),
+      )),
+    }
+  }
+
+  // Takes tokens up to the first one the condition holds for, which is left in the stream
+  fn take_until(&mut self, condition: impl Fn(&Token) -> bool) -> TokenStream<'a> {
+    let start = self.position;
+    while self.skip_if(|token| !condition(token)) {}
+    self.rest(start)
+  }
+
+  // The tokens taken from the index on, as a part that ends where the tokens left in this stream
+  // start
+  fn rest(&self, start: usize) -> TokenStream<'a> {
+    let tokens = start..self.position;
+    match self.peek_lexeme() {
+      Some(lexeme) => TokenStream::new(self.code, tokens, lexeme.span, Some(lexeme.token.clone())),
+      None => TokenStream::new(self.code, tokens, self.end, self.end_token.clone()),
+    }
+  }
+
+  // Where the bracket just taken is closed, if that is in this part
+  fn closing(&self) -> Option<usize> {
+    let opened = self.position.checked_sub(1)?;
+    self.code.closing[opened].filter(|closed| *closed < self.limit)
+  }
+}
+
+impl Iterator for TokenStream<'_> {
+  type Item = Lexeme;
+
+  fn next(&mut self) -> Option<Lexeme> {
+    self.next_if(|_| true)
+  }
+}
+
+struct Parser {
+  depth: usize,
+  max_depth: Option<usize>,
+  // Function bodies being parsed, `return` is only allowed in one
+  functions: usize,
+  // Operators before the operand being parsed, in its expression and the ones around it
+  chain: usize,
+  max_chain: Option<usize>,
+  diagnostics: Vec<Diagnostic>,
+}
+
+impl Parser {
+  // Every function that can recurse goes through here, so deeply nested code results in an
+  // error instead of overflowing the stack
+  fn nested<T>(
+    &mut self,
+    span: Span,
+    parse: impl FnOnce(&mut Self) -> Result<T, Diagnostic>,
+  ) -> Result<T, Diagnostic> {
+    if let Some(max_depth) = self.max_depth {
+      if self.depth >= max_depth {
+        return Err(Diagnostic::new(
+          ParserError::LimitExceeded(Limit::Nesting(max_depth)),
+          span,
+        ));
+      }
+    }
+    self.depth += 1;
+    let result = parse(self);
+    self.depth -= 1;
+    result
+  }
+
+  fn parse_instructions(
+    &mut self,
+    mut tokens: TokenStream,
+  ) -> Result<Vec<Instruction>, Diagnostic> {
+    self.nested(tokens.span(), |parser| {
+      let mut instructions = Vec::new();
+      while let Some(lexeme) = tokens.next() {
+        match parser.parse_instruction(lexeme, &mut tokens) {
+          Ok(Some(instruction)) => instructions.push(instruction),
+          Ok(None) => (),
+          // The statements after it are as deep, they would run into the limit again
+          Err(diagnostic) if matches!(diagnostic.error, ParserError::LimitExceeded(_)) => {
+            return Err(diagnostic)
+          }
+          Err(diagnostic) => {
+            parser.diagnostics.push(diagnostic);
+            synchronize(&mut tokens);
+          }
+        }
+      }
+      Ok(instructions)
+    })
+  }
+
+  fn parse_instruction(
+    &mut self,
+    lexeme: Lexeme,
+    tokens: &mut TokenStream,
+  ) -> Result<Option<Instruction>, Diagnostic> {
+    let start = lexeme.span.start;
+    let kind = match lexeme.token {
       Token::Keyword(keyword) => match keyword {
         Keyword::If => {
-          let condition_tokens = parse_brackets(&mut tokens)?;
-          let condition = parse_value(condition_tokens)?;
-          let scope = parse_scope(&mut tokens)?;
-          Some(Instruction::If {
-            condition,
-            instructions: scope,
+          let condition = self.parse_condition(tokens);
+          let scope = self.parse_scope(tokens);
+          Some(InstructionKind::If {
+            condition: condition?,
+            instructions: scope?,
           })
         }
         Keyword::Else => {
-          let scope = parse_scope(&mut tokens)?;
-          Some(Instruction::Else {
+          let scope = self.parse_scope(tokens)?;
+          Some(InstructionKind::Else {
             instructions: scope,
           })
         }
         Keyword::While => {
-          let condition_tokens = parse_brackets(&mut tokens)?;
-          let condition = parse_value(condition_tokens)?;
-          let scope = parse_scope(&mut tokens)?;
-          Some(Instruction::While {
-            condition,
-            instructions: scope,
+          let condition = self.parse_condition(tokens);
+          let scope = self.parse_scope(tokens);
+          Some(InstructionKind::While {
+            condition: condition?,
+            instructions: scope?,
           })
         }
         Keyword::Print => {
-          let value_tokens = parse_brackets(&mut tokens)?;
-          let value = parse_value(value_tokens)?;
-          Some(Instruction::Print { message: value })
+          let value = self.parse_value(parse_brackets(tokens)?)?;
+          Some(InstructionKind::Print { message: value })
         }
         Keyword::Input => {
-          if let Some(Token::Identifier(variable)) = tokens.next() {
-            Some(Instruction::Input {
-              variable: variable.to_string(),
-            })
-          } else {
-            return Err(ParserError::ExpectedToken(Token::Identifier(
-              "".to_string(),
-            )));
+          let (name, span) = expect_identifier(tokens)?;
+          Some(InstructionKind::Input {
+            variable: Identifier::new(name, span),
+          })
+        }
+        Keyword::Break => Some(InstructionKind::Break),
+        Keyword::Enum => Some(parse_enum(tokens)?),
+        Keyword::Match => Some(self.parse_match(tokens)?),
+        Keyword::Let => {
+          let (name, span) = expect_identifier(tokens)?;
+          let annotation = parse_annotation(tokens)?;
+          tokens.expect_token(Token::Operator(Operator::Assign))?;
+          Some(InstructionKind::Let {
+            variable: Identifier::new(name, span),
+            annotation,
+            value: self.parse_statement_value(tokens)?,
+          })
+        }
+        Keyword::Fn => Some(InstructionKind::Function(self.parse_function(tokens)?)),
+        Keyword::Return if self.functions == 0 => {
+          return Err(Diagnostic::new(
+            ParserError::UnexpectedToken(Token::Keyword(keyword)),
+            lexeme.span,
+          ))
+        }
+        Keyword::Return => {
+          let value = match tokens.peek() {
+            None | Some(Token::EndStatement) => None,
+            Some(_) => Some(self.parse_statement_value(tokens)?),
+          };
+          Some(InstructionKind::Return { value })
+        }
+        Keyword::Assert => {
+          let mut arguments = split_arguments(parse_brackets(tokens)?).into_iter();
+          let condition = match arguments.next() {
+            Some(condition_tokens) => self.parse_value(condition_tokens)?,
+            None => return Err(Diagnostic::new(ParserError::UnexpectedEnd, lexeme.span)),
+          };
+          let message = match arguments.next() {
+            Some(message_tokens) => Some(self.parse_value(message_tokens)?),
+            None => None,
+          };
+          if let Some(mut extra) = arguments.next() {
+            let span = extra.span();
+            return Err(match extra.next() {
+              Some(lexeme) => Diagnostic::unexpected(lexeme),
+              None => Diagnostic::new(ParserError::UnexpectedEnd, span),
+            });
           }
+          Some(InstructionKind::Assert { condition, message })
+        }
+        // Tests are only allowed at the top of a file, the parser is one level deep there
+        Keyword::Test if self.depth > 1 => {
+          return Err(Diagnostic::new(
+            ParserError::UnexpectedToken(Token::Keyword(keyword)),
+            lexeme.span,
+          ))
+        }
+        Keyword::Test => {
+          let span = tokens.span();
+          let name = match tokens.next() {
+            Some(Lexeme {
+              token: Token::String(name),
+              ..
+            }) => name,
+            _ => {
+              return Err(Diagnostic::new(
+                ParserError::ExpectedKind(TokenKind::String),
+                span,
+              ))
+            }
+          };
+          Some(InstructionKind::Test {
+            name,
+            instructions: self.parse_scope(tokens)?,
+          })
         }
-        Keyword::Break => Some(Instruction::Break),
       },
+      Token::ScopeOpen => Some(InstructionKind::Scope {
+        instructions: self.parse_already_open_scope(tokens)?,
+      }),
       Token::EndStatement => None,
       Token::Number(_)
       | Token::String(_)
       | Token::Boolean(_)
+      | Token::None
       | Token::Identifier(_)
       | Token::Operator(_)
-      | Token::BracketOpen => {
-        let mut value_tokens = vec![token];
-        while let Some(next_token) = tokens.peek() {
-          match next_token {
-            Token::EndStatement => break,
+      | Token::BracketOpen
+      | Token::SquareBracketOpen => {
+        let start = tokens.position - 1;
+        while tokens.skip_if(|token| {
+          matches!(
+            token,
             Token::Number(_)
-            | Token::String(_)
-            | Token::Boolean(_)
-            | Token::Identifier(_)
-            | Token::Operator(_)
-            | Token::BracketOpen
-            | Token::BracketClose => value_tokens.push(tokens.next().unwrap()),
-            _ => return Err(ParserError::UnexpectedToken(next_token.clone())),
+              | Token::String(_)
+              | Token::Boolean(_)
+              | Token::Identifier(_)
+              | Token::Operator(_)
+              | Token::None
+              | Token::BracketOpen
+              | Token::BracketClose
+              | Token::SquareBracketOpen
+              | Token::SquareBracketClose
+              | Token::Dot
+              | Token::OptionalDot
+              | Token::Comma
+          )
+        }) {}
+        if let Some(token) = tokens.peek() {
+          if *token != Token::EndStatement {
+            return Err(Diagnostic::new(
+              ParserError::UnexpectedToken(token.clone()),
+              tokens.span(),
+            ));
           }
         }
-        let value = parse_value(value_tokens)?;
-        Some(Instruction::Value { value })
+        let value = self.parse_value(tokens.rest(start))?;
+        Some(InstructionKind::Value { value })
       }
-      _ => return Err(ParserError::UnexpectedToken(token.clone())),
+      _ => return Err(Diagnostic::unexpected(lexeme)),
     };
+    Ok(kind.map(|kind| Instruction {
+      kind,
+      span: Span {
+        start,
+        end: tokens.previous_end,
+      },
+    }))
+  }
+
+  // A value that makes up the rest of the statement, up to the ;
+  fn parse_statement_value(&mut self, tokens: &mut TokenStream) -> Result<Value, Diagnostic> {
+    let value_tokens = tokens.take_until(|token| {
+      matches!(
+        token,
+        Token::EndStatement | Token::ScopeOpen | Token::ScopeClose
+      )
+    });
+    if let Some(token) = tokens.peek() {
+      if *token != Token::EndStatement {
+        return Err(Diagnostic::new(
+          ParserError::UnexpectedToken(token.clone()),
+          tokens.span(),
+        ));
+      }
+    }
+    self.parse_value(value_tokens)
+  }
 
-    if let Some(instruction) = instruction {
-      instructions.push(instruction);
+  // fn name(a: int, b) -> bool { ... }
+  fn parse_function(&mut self, tokens: &mut TokenStream) -> Result<Function, Diagnostic> {
+    let (name, name_span) = expect_identifier(tokens)?;
+    let mut parameters = Vec::new();
+    for mut parameter_tokens in split_arguments(parse_brackets(tokens)?) {
+      let (name, span) = expect_identifier(&mut parameter_tokens)?;
+      let annotation = parse_annotation(&mut parameter_tokens)?;
+      if let Some(lexeme) = parameter_tokens.next() {
+        return Err(Diagnostic::unexpected(lexeme));
+      }
+      parameters.push(Parameter {
+        variable: Identifier::new(name, span),
+        annotation,
+      });
     }
+    let return_type = match tokens.next_if_eq(&Token::ReturnArrow) {
+      Some(_) => Some(parse_type(tokens)?),
+      None => None,
+    };
+    self.functions += 1;
+    let instructions = self.parse_scope(tokens);
+    self.functions -= 1;
+    Ok(Function {
+      name,
+      name_span,
+      parameters,
+      return_type,
+      instructions: instructions?,
+    })
   }
-  Ok(instructions)
-}
 
-fn parse_brackets<'a>(
-  token_stream: &'a mut Peekable<impl Iterator<Item = Token>>,
-) -> Result<Vec<Token>, ParserError> {
-  if let Some(Token::BracketOpen) = token_stream.peek() {
-    token_stream.next();
-  } else {
-    return Err(ParserError::ExpectedToken(Token::BracketOpen));
+  fn parse_condition(&mut self, tokens: &mut TokenStream) -> Result<Condition, Diagnostic> {
+    self.parse_value(parse_brackets(tokens)?)
   }
-  parse_already_open_brackets(token_stream)
-}
-fn parse_already_open_brackets<'a>(
-  token_stream: &'a mut Peekable<impl Iterator<Item = Token>>,
-) -> Result<Vec<Token>, ParserError> {
-  let mut tokens = Vec::new();
-  let mut bracket_count = 1;
-  while let Some(token) = token_stream.next() {
-    match token {
-      Token::BracketOpen => bracket_count += 1,
-      Token::BracketClose => bracket_count -= 1,
-      _ => (),
-    }
-    if bracket_count == 0 {
-      break;
+
+  fn parse_match(&mut self, tokens: &mut TokenStream) -> Result<InstructionKind, Diagnostic> {
+    let value = self.parse_value(tokens.take_until(|token| {
+      matches!(
+        token,
+        Token::ScopeOpen | Token::ScopeClose | Token::EndStatement
+      )
+    }));
+    // The arms are taken out first, so a broken arm does not leave the rest of them behind
+    let mut body = parse_braces(tokens)?;
+    let value = value?;
+
+    let mut arms = Vec::new();
+    loop {
+      match body.peek() {
+        None => break,
+        Some(Token::Comma) => {
+          body.next();
+          continue;
+        }
+        _ => (),
+      }
+      let pattern = self.parse_pattern(
+        body.take_until(|token| matches!(token, Token::Arrow | Token::Keyword(Keyword::If))),
+      )?;
+      let guard = if body.next_if_eq(&Token::Keyword(Keyword::If)).is_some() {
+        Some(self.parse_value(body.take_until(|token| *token == Token::Arrow))?)
+      } else {
+        None
+      };
+      body.expect_token(Token::Arrow)?;
+      let instructions = self.parse_scope(&mut body)?;
+      arms.push(MatchArm {
+        pattern,
+        guard,
+        instructions,
+      });
     }
-    tokens.push(token);
-  }
-  if bracket_count != 0 {
-    return Err(ParserError::UnexpectedEnd);
+    Ok(InstructionKind::Match { value, arms })
   }
-  Ok(tokens)
-}
 
-fn parse_scope<'a>(
-  token_stream: &'a mut Peekable<impl Iterator<Item = Token>>,
-) -> Result<Vec<Instruction>, ParserError> {
-  if let Some(Token::ScopeOpen) = token_stream.peek() {
-    token_stream.next();
-  } else {
-    return Err(ParserError::ExpectedToken(Token::ScopeOpen));
+  fn parse_pattern(&mut self, mut tokens: TokenStream) -> Result<Pattern, Diagnostic> {
+    self.nested(tokens.span(), |parser| {
+      let lexeme = tokens.expect()?;
+      let pattern = match lexeme.token {
+        Token::Identifier(name) if name == "_" => Pattern::Wildcard,
+        Token::Identifier(name) => {
+          if tokens.next_if_eq(&Token::BracketOpen).is_some() {
+            let mut fields = Vec::new();
+            for field_tokens in split_arguments(parse_already_open_brackets(&mut tokens)?) {
+              fields.push(parser.parse_pattern(field_tokens)?);
+            }
+            Pattern::Variant {
+              name,
+              span: lexeme.span,
+              fields,
+            }
+          } else {
+            Pattern::Identifier(Identifier::new(name, lexeme.span))
+          }
+        }
+        Token::Number(number) => Pattern::Literal(Value::Number(number)),
+        Token::String(string) => Pattern::Literal(Value::String(string.into())),
+        Token::Boolean(boolean) => Pattern::Literal(Value::Boolean(boolean)),
+        Token::None => Pattern::Literal(Value::None),
+        _ => return Err(Diagnostic::unexpected(lexeme)),
+      };
+      if let Some(lexeme) = tokens.next() {
+        return Err(Diagnostic::unexpected(lexeme));
+      }
+      Ok(pattern)
+    })
   }
-  parse_already_open_scope(token_stream)
-}
-fn parse_already_open_scope<'a>(
-  token_stream: &'a mut Peekable<impl Iterator<Item = Token>>,
-) -> Result<Vec<Instruction>, ParserError> {
-  let mut tokens = Vec::new();
-  let mut bracket_count = 1;
-  while let Some(token) = token_stream.next() {
-    match token {
-      Token::ScopeOpen => bracket_count += 1,
-      Token::ScopeClose => bracket_count -= 1,
-      _ => (),
-    }
-    if bracket_count == 0 {
-      break;
-    }
-    tokens.push(token.clone());
+
+  fn parse_scope(&mut self, tokens: &mut TokenStream) -> Result<Vec<Instruction>, Diagnostic> {
+    let scope = parse_braces(tokens)?;
+    self.parse_instructions(scope)
   }
-  if bracket_count != 0 {
-    return Err(ParserError::UnexpectedEnd);
+  fn parse_already_open_scope(
+    &mut self,
+    tokens: &mut TokenStream,
+  ) -> Result<Vec<Instruction>, Diagnostic> {
+    let scope = parse_already_open_braces(tokens)?;
+    self.parse_instructions(scope)
   }
-  parse(tokens)
-}
 
-fn parse_value(tokens: Vec<Token>) -> Result<Value, ParserError> {
-  let mut tokens = tokens.into_iter().peekable();
-  let mut value: Option<Value> = None;
+  fn parse_value(&mut self, mut tokens: TokenStream) -> Result<Value, Diagnostic> {
+    self.nested(tokens.span(), |parser| {
+      let chain = parser.chain;
+      let result = parser.parse_chain(&mut tokens);
+      parser.chain = chain;
+      // Every operator takes everything to the right of it as its right side
+      let (operands, last) = result?;
+      Ok(
+        operands
+          .into_iter()
+          .rev()
+          .fold(last, |right, (left, operator)| {
+            Value::Expression(Box::new(Expression::new(operator, left, right)))
+          }),
+      )
+    })
+  }
 
-  loop {
-    let token = tokens.next();
-    if token.is_none() {
-      break;
-    }
-    let token = token.unwrap();
-    if value.is_some() {
-      match token {
+  // The operands of an expression with the operators after them and the last operand, read in a
+  // loop so a long sum is not nesting
+  fn parse_chain(
+    &mut self,
+    tokens: &mut TokenStream,
+  ) -> Result<(Vec<(Value, Operator)>, Value), Diagnostic> {
+    let mut operands = Vec::new();
+    loop {
+      let operand = tokens.expect()?;
+      let value = self.parse_operand(operand, tokens)?;
+      let Some(lexeme) = tokens.next() else {
+        return Ok((operands, value));
+      };
+      match lexeme.token {
+        Token::Operator(operator @ (Operator::Not | Operator::Brackets)) => {
+          return Err(Diagnostic::new(
+            ParserError::InvalidOperator(operator),
+            lexeme.span,
+          ))
+        }
         Token::Operator(operator) => {
-          if let Some(left_hand) = value {
-            match operator {
-              Operator::Not | Operator::Brackets => {
-                value = Some(Value::Expression(Box::new(Expression::new_not_or_bracket(
-                  operator.clone(),
-                  left_hand,
-                ))))
-              }
-              _ => {
-                value = Some(Value::Expression(Box::new(Expression::new(
-                  operator.clone(),
-                  left_hand,
-                  parse_value(tokens.by_ref().collect())?,
-                ))))
-              }
+          if let Some(max_chain) = self.max_chain {
+            if self.chain >= max_chain {
+              return Err(Diagnostic::new(
+                ParserError::LimitExceeded(Limit::Chain(max_chain)),
+                lexeme.span,
+              ));
             }
-          } else {
-            return Err(ParserError::ExpectedToken(token.clone()));
           }
+          self.chain += 1;
+          operands.push((value, operator));
         }
-        _ => return Err(ParserError::ExpectedToken(token.clone())),
+        _ => return Err(Diagnostic::unexpected(lexeme)),
       }
-    } else {
-      match token {
-        Token::Identifier(identifier) => value = Some(Value::Identifier(identifier.clone())),
-        Token::Number(numb) => value = Some(Value::Number(numb.clone())),
-        Token::String(string) => value = Some(Value::String(string.clone())),
-        Token::Boolean(boolean) => value = Some(Value::Boolean(boolean.clone())),
-        // 2 + (2 + 2) + 1
-        Token::BracketOpen => {
-          if value.is_some() {
-            return Err(ParserError::ExpectedToken(token.clone()));
+    }
+  }
+
+  fn parse_operand(
+    &mut self,
+    lexeme: Lexeme,
+    tokens: &mut TokenStream,
+  ) -> Result<Value, Diagnostic> {
+    // Not nested itself, the values in brackets, calls and lists are a level deeper through
+    // parse_value, so a pair of brackets is one level
+    let value = match lexeme.token {
+      Token::Identifier(identifier) => {
+        if tokens.next_if_eq(&Token::BracketOpen).is_some() {
+          let mut arguments = Vec::new();
+          for argument_tokens in split_arguments(parse_already_open_brackets(tokens)?) {
+            arguments.push(self.parse_value(argument_tokens)?);
           }
-          let bracketed_tokens = parse_already_open_brackets(&mut tokens)?;
-          value = Some(parse_value(bracketed_tokens)?);
+          Value::Call {
+            name: identifier,
+            span: lexeme.span,
+            arguments,
+          }
+        } else {
+          Value::Identifier(Identifier::new(identifier, lexeme.span))
+        }
+      }
+      Token::Number(numb) => Value::Number(numb),
+      Token::String(string) => Value::String(string.into()),
+      Token::Boolean(boolean) => Value::Boolean(boolean),
+      Token::None => Value::None,
+      Token::SquareBracketOpen => {
+        let mut items = Vec::new();
+        for item_tokens in split_arguments(parse_already_open_square_brackets(tokens)?) {
+          items.push(self.parse_value(item_tokens)?);
+        }
+        Value::List(items)
+      }
+      Token::Operator(Operator::Not) => {
+        let operand = tokens.expect()?;
+        let operand = self.nested(lexeme.span, |parser| parser.parse_operand(operand, tokens))?;
+        Value::Expression(Box::new(Expression::new_not_or_bracket(
+          Operator::Not,
+          operand,
+        )))
+      }
+      // 2 + (2 + 2) + 1
+      Token::BracketOpen => {
+        let bracketed_tokens = parse_already_open_brackets(tokens)?;
+        Value::Expression(Box::new(Expression::new_not_or_bracket(
+          Operator::Brackets,
+          self.parse_value(bracketed_tokens)?,
+        )))
+      }
+      _ => {
+        return Err(Diagnostic::new(
+          ParserError::UnexpectedToken(lexeme.token),
+          lexeme.span,
+        ))
+      }
+    };
+    self.parse_access(value, tokens)
+  }
+
+  // Parses the field and index accesses following a value, like a.b, a?.b, a[0] and a?.[0]
+  fn parse_access(&mut self, value: Value, tokens: &mut TokenStream) -> Result<Value, Diagnostic> {
+    let Some(lexeme) = tokens.next_if(|token| {
+      matches!(
+        token,
+        Token::Dot | Token::OptionalDot | Token::SquareBracketOpen
+      )
+    }) else {
+      return Ok(value);
+    };
+    let optional = lexeme.token == Token::OptionalDot;
+    let is_index = match lexeme.token {
+      Token::SquareBracketOpen => true,
+      _ => tokens.next_if_eq(&Token::SquareBracketOpen).is_some(),
+    };
+    let value = if is_index {
+      let index = self.parse_value(parse_already_open_square_brackets(tokens)?)?;
+      Value::Index {
+        value: Box::new(value),
+        index: Box::new(index),
+        optional,
+      }
+    } else {
+      Value::Field {
+        value: Box::new(value),
+        field: expect_identifier(tokens)?.0,
+        optional,
+      }
+    };
+    // Every access wraps the value one level deeper, so a long chain counts as nesting
+    self.nested(lexeme.span, |parser| parser.parse_access(value, tokens))
+  }
+}
+
+// Skips the rest of a broken statement, up to and including the `;` or block that ends it
+fn synchronize(tokens: &mut TokenStream) {
+  if tokens.at_boundary {
+    return;
+  }
+  let mut depth = 0;
+  for lexeme in tokens.by_ref() {
+    match lexeme.token {
+      Token::ScopeOpen => depth += 1,
+      Token::ScopeClose if depth <= 1 => break,
+      Token::ScopeClose => depth -= 1,
+      Token::EndStatement if depth == 0 => break,
+      _ => (),
+    }
+  }
+}
+
+fn parse_enum(tokens: &mut TokenStream) -> Result<InstructionKind, Diagnostic> {
+  let (name, name_span) = expect_identifier(tokens)?;
+  let mut body = parse_braces(tokens)?;
+  let mut variants = Vec::new();
+  while let Some(lexeme) = body.next() {
+    let Token::Identifier(variant) = lexeme.token else {
+      return Err(Diagnostic::unexpected(lexeme));
+    };
+    let mut fields = Vec::new();
+    if body.next_if_eq(&Token::BracketOpen).is_some() {
+      for mut field_tokens in split_arguments(parse_already_open_brackets(&mut body)?) {
+        fields.push(expect_identifier(&mut field_tokens)?.0);
+        if let Some(lexeme) = field_tokens.next() {
+          return Err(Diagnostic::unexpected(lexeme));
         }
-        Token::BracketClose => return Err(ParserError::UnexpectedToken(token.clone())),
-        Token::EndStatement => return Err(ParserError::UnexpectedEnd),
-        _ => return Err(ParserError::UnexpectedToken(token.clone())),
       }
     }
+    variants.push(Variant {
+      name: variant,
+      span: lexeme.span,
+      fields,
+    });
+    if let Some(lexeme) = body.next_if(|token| *token != Token::Comma) {
+      return Err(Diagnostic::unexpected(lexeme));
+    }
+    body.next();
   }
-  if let Some(value) = value {
-    Ok(value)
-  } else {
-    Err(ParserError::UnexpectedEnd)
+  Ok(InstructionKind::Enum {
+    name,
+    name_span,
+    variants,
+  })
+}
+
+// The `: type` after a name, which can be left out
+fn parse_annotation(tokens: &mut TokenStream) -> Result<Option<TypeAnnotation>, Diagnostic> {
+  match tokens.next_if_eq(&Token::Colon) {
+    Some(_) => Ok(Some(parse_type(tokens)?)),
+    None => Ok(None),
   }
 }
+
+fn parse_type(tokens: &mut TokenStream) -> Result<TypeAnnotation, Diagnostic> {
+  let (name, span) = expect_identifier(tokens)?;
+  Ok(TypeAnnotation { name, span })
+}
+
+fn expect_identifier(tokens: &mut TokenStream) -> Result<(String, Span), Diagnostic> {
+  let span = tokens.span();
+  match tokens.next() {
+    Some(Lexeme {
+      token: Token::Identifier(identifier),
+      span,
+      ..
+    }) => Ok((identifier, span)),
+    _ => Err(Diagnostic::new(
+      ParserError::ExpectedKind(TokenKind::Identifier),
+      span,
+    )),
+  }
+}
+
+// Splits the tokens between a pair of brackets on the commas that are not nested in other brackets
+fn split_arguments(tokens: TokenStream<'_>) -> Vec<TokenStream<'_>> {
+  let code = tokens.code;
+  let (start, limit) = (tokens.position, tokens.limit);
+  let commas = code
+    .commas
+    .get(&code.open_brackets[start])
+    .map_or(&[][..], Vec::as_slice);
+  let commas = &commas[commas.partition_point(|comma| *comma < start)..];
+  let commas = &commas[..commas.partition_point(|comma| *comma < limit)];
+  let mut arguments = Vec::new();
+  let mut argument = start;
+  for &comma in commas {
+    let lexeme = &code.lexemes[comma];
+    arguments.push(TokenStream::new(
+      code,
+      argument..comma,
+      lexeme.span,
+      Some(lexeme.token.clone()),
+    ));
+    argument = comma + 1;
+  }
+  if argument < limit || !arguments.is_empty() {
+    arguments.push(TokenStream::new(
+      code,
+      argument..limit,
+      tokens.end,
+      tokens.end_token,
+    ));
+  }
+  arguments
+}
+
+fn parse_brackets<'a>(tokens: &mut TokenStream<'a>) -> Result<TokenStream<'a>, Diagnostic> {
+  tokens.expect_token(Token::BracketOpen)?;
+  parse_already_open_brackets(tokens)
+}
+fn parse_already_open_brackets<'a>(
+  tokens: &mut TokenStream<'a>,
+) -> Result<TokenStream<'a>, Diagnostic> {
+  parse_already_open_group(tokens, Token::BracketClose)
+}
+fn parse_already_open_square_brackets<'a>(
+  tokens: &mut TokenStream<'a>,
+) -> Result<TokenStream<'a>, Diagnostic> {
+  parse_already_open_group(tokens, Token::SquareBracketClose)
+}
+// Brackets never contain a statement, so an unclosed one stops at the end of the statement
+// instead of taking the rest of the code with it
+fn parse_already_open_group<'a>(
+  tokens: &mut TokenStream<'a>,
+  close: Token,
+) -> Result<TokenStream<'a>, Diagnostic> {
+  if let Some(closed) = tokens.closing() {
+    let group = tokens.position..closed;
+    tokens.skip_to(closed);
+    return Ok(TokenStream::new(
+      tokens.code,
+      group,
+      tokens.code.lexemes[closed].span,
+      Some(close),
+    ));
+  }
+  while tokens.skip_if(|token| {
+    !matches!(
+      token,
+      Token::EndStatement | Token::ScopeOpen | Token::ScopeClose
+    )
+  }) {}
+  Err(Diagnostic::new(
+    ParserError::ExpectedToken(close),
+    tokens.span(),
+  ))
+}
+
+fn parse_braces<'a>(tokens: &mut TokenStream<'a>) -> Result<TokenStream<'a>, Diagnostic> {
+  tokens.expect_token(Token::ScopeOpen)?;
+  parse_already_open_braces(tokens)
+}
+fn parse_already_open_braces<'a>(
+  tokens: &mut TokenStream<'a>,
+) -> Result<TokenStream<'a>, Diagnostic> {
+  if let Some(closed) = tokens.closing() {
+    let scope = tokens.position..closed;
+    tokens.skip_to(closed);
+    return Ok(TokenStream::new(
+      tokens.code,
+      scope,
+      tokens.code.lexemes[closed].span,
+      Some(Token::ScopeClose),
+    ));
+  }
+  if tokens.position < tokens.limit {
+    tokens.skip_to(tokens.limit - 1);
+  }
+  Err(Diagnostic::new(
+    ParserError::ExpectedToken(Token::ScopeClose),
+    tokens.end,
+  ))
+}
+
 #[derive(Debug)]
-pub enum Instruction {
+pub struct Instruction {
+  pub kind: InstructionKind,
+  // From the first token of the statement up to its last, without the ;
+  pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum InstructionKind {
   If {
     condition: Condition,
     instructions: Vec<Instruction>,
@@ -267,22 +1019,172 @@ pub enum Instruction {
     message: Value,
   },
   Input {
-    variable: String,
+    variable: Identifier,
+  },
+  Enum {
+    name: String,
+    name_span: Span,
+    variants: Vec<Variant>,
   },
+  Match {
+    value: Value,
+    arms: Vec<MatchArm>,
+  },
+  // Declares a new variable in the current block, even when a block around it has one with the
+  // same name
+  Let {
+    variable: Identifier,
+    annotation: Option<TypeAnnotation>,
+    value: Value,
+  },
+  Function(Function),
+  Return {
+    value: Option<Value>,
+  },
+  // assert(condition, message), the message can be left out
+  Assert {
+    condition: Condition,
+    message: Option<Value>,
+  },
+  // test "name" { ... }, only at the top of a file. A normal run skips them, `fish test` runs
+  // each of them on its own
+  Test {
+    name: String,
+    instructions: Vec<Instruction>,
+  },
+}
+impl InstructionKind {
+  // The blocks of instructions directly inside an instruction
+  pub fn blocks(&self) -> Vec<&[Instruction]> {
+    match self {
+      InstructionKind::If { instructions, .. }
+      | InstructionKind::Else { instructions }
+      | InstructionKind::While { instructions, .. }
+      | InstructionKind::Scope { instructions }
+      | InstructionKind::Test { instructions, .. } => vec![instructions],
+      InstructionKind::Function(function) => vec![&function.instructions],
+      InstructionKind::Match { arms, .. } => {
+        arms.iter().map(|arm| arm.instructions.as_slice()).collect()
+      }
+      _ => Vec::new(),
+    }
+  }
 }
 
-// TODO: Change this so it does some fancy checks like type checking for booleans
-type Condition = Value;
+#[derive(Debug)]
+pub struct Function {
+  pub name: String,
+  pub name_span: Span,
+  pub parameters: Vec<Parameter>,
+  pub return_type: Option<TypeAnnotation>,
+  pub instructions: Vec<Instruction>,
+}
+
+#[derive(Debug)]
+pub struct Parameter {
+  pub variable: Identifier,
+  pub annotation: Option<TypeAnnotation>,
+}
+
+// A type name as it is written in the code, only the type checker knows what it means
+#[derive(Debug)]
+pub struct TypeAnnotation {
+  pub name: String,
+  pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+  pub name: String,
+  pub span: Span,
+  pub fields: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct MatchArm {
+  pub pattern: Pattern,
+  pub guard: Option<Condition>,
+  pub instructions: Vec<Instruction>,
+}
+
+#[derive(Debug)]
+pub enum Pattern {
+  // _
+  Wildcard,
+  // 1, "text", true
+  Literal(Value),
+  // Either a variant without fields or a name the matched value gets bound to
+  Identifier(Identifier),
+  // Circle(r), Rect(1, _)
+  Variant {
+    name: String,
+    span: Span,
+    fields: Vec<Pattern>,
+  },
+}
+
+// Has to be a boolean, which the type checker can find out before running
+pub type Condition = Value;
 
 // pub type Scope = Vec<Instruction>;
 #[derive(Debug, Clone)]
 pub enum Value {
   Number(Number),
-  String(String),
+  String(Rc<str>),
   Boolean(bool),
-  Identifier(String),
+  None,
+  List(Vec<Value>),
+  Identifier(Identifier),
   Expression(Box<Expression>),
+  Call {
+    name: String,
+    // Where the name is
+    span: Span,
+    arguments: Vec<Value>,
+  },
+  Field {
+    value: Box<Value>,
+    field: String,
+    optional: bool,
+  },
+  Index {
+    value: Box<Value>,
+    index: Box<Value>,
+    optional: bool,
+  },
+}
+impl Value {
+  // Whether an access in this chain of field and index accesses is optional, `a?.b.c` stops at a
+  // none before any of the rest is accessed
+  pub fn has_optional(&self) -> bool {
+    match self {
+      Value::Field {
+        value, optional, ..
+      }
+      | Value::Index {
+        value, optional, ..
+      } => *optional || value.has_optional(),
+      _ => false,
+    }
+  }
+}
+#[derive(Debug, Clone)]
+pub struct Identifier {
+  pub name: String,
+  // Filled in by the resolver, stays None when the name does not refer to a variable
+  pub slot: Option<Slot>,
+  pub span: Span,
 }
+impl Identifier {
+  pub fn new(name: String, span: Span) -> Self {
+    Self {
+      name,
+      slot: None,
+      span,
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct Expression {
   operator: Operator,
@@ -317,12 +1219,197 @@ impl Expression {
     &self.left
   }
   pub fn get_right(&self) -> Option<&Value> {
-    self.right.as_ref().map(|v| &**v)
+    self.right.as_deref()
+  }
+  pub fn get_left_mut(&mut self) -> &mut Value {
+    &mut self.left
+  }
+  pub fn get_right_mut(&mut self) -> Option<&mut Value> {
+    self.right.as_deref_mut()
+  }
+  pub fn get_sides_mut(&mut self) -> (&mut Value, Option<&mut Value>) {
+    (&mut self.left, self.right.as_deref_mut())
   }
 }
 fn is_operator_single(operator: &Operator) -> bool {
-  match operator {
-    Operator::Not | Operator::Brackets => true,
-    _ => false,
+  matches!(operator, Operator::Not | Operator::Brackets)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tokenizer;
+
+  // Every error in the code with the line and column it is at
+  fn errors(code: &str) -> Vec<(String, usize, usize)> {
+    let tokens = tokenizer::tokenize(code).expect("Code does not tokenize");
+    let (_, diagnostics) = parse(tokens, &Limits::default());
+    diagnostics
+      .into_iter()
+      .map(|diagnostic| {
+        let start = diagnostic.span.start;
+        (diagnostic.error.to_string(), start.line, start.column)
+      })
+      .collect()
+  }
+
+  fn error(message: &str, line: usize, column: usize) -> Vec<(String, usize, usize)> {
+    vec![(message.to_string(), line, column)]
+  }
+
+  #[test]
+  fn missing_operand_is_the_token_after_it() {
+    assert_eq!(
+      errors("x = 1 +;"),
+      error("Unexpected `;`", 1, 8)
+    );
+    assert_eq!(
+      errors("print(f(1 +));"),
+      error("Unexpected `)`", 1, 12)
+    );
+    assert_eq!(
+      errors("print([1, 2 * ]);"),
+      error("Unexpected `]`", 1, 15)
+    );
+    assert_eq!(errors("f(1 +, 2);"), error("Unexpected `,`", 1, 6));
+  }
+
+  #[test]
+  fn errors_name_the_tokens_and_kinds_they_are_about() {
+    assert_eq!(errors("test 5 { }"), error("Expected a string", 1, 6));
+    assert_eq!(errors("fn 3() { }"), error("Expected an identifier", 1, 4));
+    assert_eq!(errors("x = 1 2;"), error("Unexpected number `2`", 1, 7));
+    assert_eq!(
+      errors("print(\"a\" \"b\");"),
+      error("Unexpected string \"b\"", 1, 11)
+    );
+    assert_eq!(
+      errors("while (true) { print(1);"),
+      error("Expected `}`", 1, 25)
+    );
+  }
+
+  #[test]
+  fn return_is_only_allowed_in_functions() {
+    assert_eq!(
+      errors("print(0);\nreturn 5;\nprint(1);"),
+      error("Unexpected `return`", 2, 1)
+    );
+    assert_eq!(
+      errors("while (true) { return; }"),
+      error("Unexpected `return`", 1, 16)
+    );
+    assert!(errors("fn f() { if (true) { return 1; } return 2; }").is_empty());
+    // The function ends where its body does
+    assert_eq!(
+      errors("fn f() { return 1; }\nreturn 2;"),
+      error("Unexpected `return`", 2, 1)
+    );
+  }
+
+  #[test]
+  fn missing_operand_at_the_end_of_the_code() {
+    assert_eq!(errors("x = 1 +"), error("Unexpected end of input", 1, 8));
+  }
+
+  #[test]
+  fn every_broken_statement_is_reported() {
+    let code = "x = 1 +;\nprint(2);\ny = * 3;\n";
+    assert_eq!(
+      errors(code),
+      [
+        ("Unexpected `;`".to_string(), 1, 8),
+        ("Unexpected `*`".to_string(), 3, 5),
+      ]
+    );
+  }
+
+  // The diagnostics for the code, parsed on a thread with the stack main gives code within the
+  // default limits
+  fn errors_with_default_stack(code: &str) -> Vec<ParserError> {
+    let tokens = tokenizer::tokenize(code).expect("Code does not tokenize");
+    let parser = std::thread::Builder::new().stack_size(Limits::default().stack_size());
+    parser
+      .spawn(move || {
+        let (_, diagnostics) = parse(tokens, &Limits::default());
+        diagnostics
+          .into_iter()
+          .map(|diagnostic| diagnostic.error)
+          .collect::<Vec<_>>()
+      })
+      .unwrap()
+      .join()
+      .unwrap()
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn deep_nesting_fails_fast() {
+    let depth = 100_000;
+    let brackets = format!("x = {}1{};", "(".repeat(depth), ")".repeat(depth));
+    let indexes = format!("x = [1]; print(x{});", "[0]".repeat(depth));
+    let fields = format!("x = 1; print(x{});", ".a".repeat(depth));
+    let blocks = format!("{}x = 1; y = 2;{}", "{".repeat(depth), "}".repeat(depth));
+    for code in [brackets, indexes, fields, blocks] {
+      let started = std::time::Instant::now();
+      let errors = errors_with_default_stack(&code);
+      assert!(started.elapsed() < std::time::Duration::from_secs(2));
+      // Parsing stops there, the statements after it would only run into the limit again
+      assert!(
+        matches!(
+          errors[..],
+          [ParserError::LimitExceeded(Limit::Nesting(
+            crate::limits::DEFAULT_MAX_NESTING
+          ))]
+        ),
+        "{:?}",
+        errors
+      );
+    }
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn brackets_blocks_and_calls_are_a_level_each() {
+    // The top level and the statement's value are the other two levels
+    let levels = crate::limits::DEFAULT_MAX_NESTING - 2;
+    let brackets = |depth: usize| format!("print({}1{});", "(".repeat(depth), ")".repeat(depth));
+    let blocks = |depth: usize| format!("{}print(1);{}", "{".repeat(depth), "}".repeat(depth));
+    let calls = |depth: usize| format!("print({}1{});", "f(".repeat(depth), ")".repeat(depth));
+    for code in [brackets, blocks, calls] {
+      assert!(errors_with_default_stack(&code(levels)).is_empty());
+      let errors = errors_with_default_stack(&code(levels + 1));
+      assert!(
+        matches!(
+          errors[..],
+          [ParserError::LimitExceeded(Limit::Nesting(
+            crate::limits::DEFAULT_MAX_NESTING
+          ))]
+        ),
+        "{:?}",
+        errors
+      );
+    }
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn long_expressions_are_not_nesting() {
+    let max_chain = crate::limits::max_chain(Some(crate::limits::DEFAULT_MAX_NESTING)).unwrap();
+    let sum = |terms: usize| format!("print({});", vec!["1"; terms].join(" + "));
+    assert!(errors_with_default_stack(&sum(max_chain + 1)).is_empty());
+    // Operators in brackets add to the ones around them
+    let half = vec!["1"; max_chain / 2].join(" - ");
+    let nested = format!("print({} - ({} - 1));", half, half);
+    assert!(errors_with_default_stack(&nested).is_empty());
+    let errors = errors_with_default_stack(&sum(max_chain + 2));
+    assert!(
+      matches!(
+        errors[..],
+        [ParserError::LimitExceeded(Limit::Chain(limit))] if limit == max_chain
+      ),
+      "{:?}",
+      errors
+    );
   }
 }
@@ -0,0 +1,280 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt::Write,
+  time::{Duration, Instant},
+};
+
+use crate::{
+  interpreter::{Hooks, Inspector, InterpreterError, Scope},
+  parser::Instruction,
+};
+
+#[derive(Debug, Default)]
+struct LineStats {
+  hits: u64,
+  // Spent on the instructions that start on the line, not counting the blocks and calls in them
+  time: Duration,
+}
+
+#[derive(Debug, Default)]
+struct FunctionStats {
+  calls: u64,
+  // From the outermost call starting to it returning, so recursion is only counted once
+  time: Duration,
+  running: usize,
+  started: Option<Instant>,
+}
+
+/*
+ `--profile`, counts how often every line and function runs and measures where the time goes.
+ The time between two things happening is added to the line of the instruction that was running,
+ and to the stack of function calls at that moment for the folded stacks.
+
+ Measuring takes time as well, which makes cheap instructions look slower than they are.
+*/
+pub struct Profiler {
+  started: Instant,
+  finished: Option<Instant>,
+  lines: BTreeMap<usize, LineStats>,
+  functions: HashMap<String, FunctionStats>,
+  // Function names for the frames on the stack of the VM, None for blocks
+  frames: Vec<Option<String>>,
+  // The stack of function calls as in the folded format, like <script>;fact;fact
+  stack: String,
+  // Time spent with exactly this stack of calls
+  folded: HashMap<String, Duration>,
+  // The line that is running and since when
+  running: Option<(usize, Instant)>,
+}
+
+impl Default for Profiler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Profiler {
+  pub fn new() -> Self {
+    Self {
+      started: Instant::now(),
+      finished: None,
+      lines: BTreeMap::new(),
+      functions: HashMap::new(),
+      frames: Vec::new(),
+      stack: String::new(),
+      folded: HashMap::new(),
+      running: None,
+    }
+  }
+
+  // Gives the time since the last event to what was running, and starts counting again
+  fn lap(&mut self, line: Option<usize>) {
+    let now = Instant::now();
+    if let Some((line, since)) = self.running {
+      let time = now - since;
+      self.lines.entry(line).or_default().time += time;
+      match self.folded.get_mut(&self.stack) {
+        Some(total) => *total += time,
+        None => {
+          self.folded.insert(self.stack.clone(), time);
+        }
+      }
+    }
+    self.running = line
+      .or(self.running.map(|(line, _)| line))
+      .map(|line| (line, now));
+  }
+
+  pub fn finish(&mut self) {
+    self.lap(None);
+    self.running = None;
+    self.finished = Some(Instant::now());
+  }
+
+  fn total(&self) -> Duration {
+    self.finished.unwrap_or_else(Instant::now) - self.started
+  }
+
+  // Functions by time, then lines in order, each with the code on it
+  pub fn summary(&self, code: &str) -> String {
+    let lines: Vec<&str> = code.lines().collect();
+    let mut summary = format!("Profile, {:.3?} in total\n", self.total());
+    if !self.functions.is_empty() {
+      let mut functions: Vec<_> = self.functions.iter().collect();
+      functions.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+      summary.push_str("\n     calls        time  function\n");
+      for (name, stats) in functions {
+        let time = format!("{:.3?}", stats.time);
+        let _ = writeln!(summary, "{:>10}  {:>10}  {}", stats.calls, time, name);
+      }
+    }
+    summary.push_str("\n      hits   self time  line\n");
+    for (line, stats) in &self.lines {
+      let source = lines.get(line - 1).map_or("", |source| source.trim());
+      let time = format!("{:.3?}", stats.time);
+      let _ = writeln!(
+        summary,
+        "{:>10}  {:>10}  {:>4} | {}",
+        stats.hits, time, line, source
+      );
+    }
+    summary
+  }
+
+  // One line per stack of calls with the microseconds spent in it, for flamegraph tools
+  pub fn folded(&self) -> String {
+    let mut stacks: Vec<_> = self.folded.iter().collect();
+    stacks.sort();
+    let mut folded = String::new();
+    for (stack, time) in stacks {
+      let _ = writeln!(folded, "{} {}", stack, time.as_micros());
+    }
+    folded
+  }
+}
+
+impl Hooks for Profiler {
+  fn before_instruction(
+    &mut self,
+    instruction: &Instruction,
+    _vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    let line = instruction.span.start.line;
+    self.lap(Some(line));
+    self.lines.entry(line).or_default().hits += 1;
+    Ok(())
+  }
+
+  fn scope_enter(&mut self, scope: Scope) {
+    let name = match scope {
+      Scope::Function(function) => function.name.clone(),
+      _ if self.frames.is_empty() => "<script>".to_string(),
+      _ => {
+        self.frames.push(None);
+        return;
+      }
+    };
+    self.lap(None);
+    if !self.stack.is_empty() {
+      self.stack.push(';');
+    }
+    self.stack.push_str(&name);
+    let stats = self.functions.entry(name.clone()).or_default();
+    stats.calls += 1;
+    stats.running += 1;
+    if stats.running == 1 {
+      stats.started = Some(Instant::now());
+    }
+    self.frames.push(Some(name));
+  }
+
+  fn scope_exit(&mut self) {
+    let Some(Some(name)) = self.frames.pop() else {
+      return;
+    };
+    self.lap(None);
+    let length = self.stack.len() - name.len();
+    self.stack.truncate(length.saturating_sub(1));
+    if self.frames.is_empty() {
+      self.running = None;
+    }
+    let stats = self
+      .functions
+      .get_mut(&name)
+      .expect("Function was not entered");
+    stats.running -= 1;
+    if stats.running == 0 {
+      if let Some(started) = stats.started.take() {
+        stats.time += started.elapsed();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{interpreter, limits::Limits, resolver, samples};
+
+  const CODE: &str = "\
+fn fact(n) {
+  if (n < 2) { return 1; };
+  return n * fact(n - 1);
+}
+fn twice(n) { return fact(n) + fact(n); }
+x = twice(3);
+";
+
+  fn profile(code: &str) -> Profiler {
+    let mut instructions = samples::parse(code);
+    resolver::resolve(&mut instructions);
+    let mut profiler = Profiler::new();
+    interpreter::interpret_with_hooks(&instructions, Limits::default(), &[], &mut profiler)
+      .expect("The script failed");
+    profiler.finish();
+    profiler
+  }
+
+  #[test]
+  fn stacks_are_folded_with_their_microseconds() {
+    let profiler = profile(CODE);
+    let folded = profiler.folded();
+    let stacks: Vec<&str> = folded
+      .lines()
+      .map(|line| {
+        let (stack, time) = line.rsplit_once(' ').expect("A line without a time");
+        time
+          .parse::<u64>()
+          .expect("The time is not in microseconds");
+        stack
+      })
+      .collect();
+    assert_eq!(
+      stacks,
+      [
+        "<script>",
+        "<script>;twice",
+        "<script>;twice;fact",
+        "<script>;twice;fact;fact",
+        "<script>;twice;fact;fact;fact",
+      ]
+    );
+  }
+
+  #[test]
+  fn the_summary_counts_calls_and_hits() {
+    let summary = profile(CODE).summary(CODE);
+    // Times differ between runs, the rest of every row does not
+    let mut rows = summary
+      .lines()
+      .skip(1)
+      .map(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        match columns.len() {
+          0 | 1 => line.to_string(),
+          _ => format!("{} {}", columns[0], columns[2..].join(" ")),
+        }
+      })
+      .collect::<Vec<_>>();
+    // Functions are ordered by time, which is nearly the same for all three here
+    rows[2..5].sort();
+    // A line is hit by every instruction that starts on it, like the if and the return on line 2
+    assert_eq!(
+      rows,
+      [
+        "",
+        "calls function",
+        "1 <script>",
+        "1 twice",
+        "6 fact",
+        "",
+        "hits time line",
+        "1 1 | fn fact(n) {",
+        "8 2 | if (n < 2) { return 1; };",
+        "4 3 | return n * fact(n - 1);",
+        "2 5 | fn twice(n) { return fact(n) + fact(n); }",
+        "1 6 | x = twice(3);",
+      ]
+    );
+  }
+}
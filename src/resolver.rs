@@ -0,0 +1,246 @@
+use std::collections::HashSet;
+
+use crate::{
+  parser::{Identifier, Instruction, InstructionKind, Pattern, Value},
+  tokenizer::Operator,
+};
+
+// Where a variable lives at runtime: `depth` frames up from the frame that is executing,
+// at position `index` in that frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slot {
+  pub depth: usize,
+  pub index: usize,
+}
+
+/*
+ Every block of instructions gets its own frame at runtime, so the resolver keeps a scope per
+ block. A variable is declared in the innermost scope the first time it is assigned to, unless
+ one of the scopes around it already declared it, in which case the assignment writes there.
+ A `let` always declares in the innermost scope.
+
+ A function body only sees its parameters and its own variables, whatever is around the
+ definition is not in scope, so it resolves the same no matter where it is called from. A test
+ block does not see the variables of the script either, it runs without the rest of it.
+*/
+pub fn resolve(instructions: &mut [Instruction]) {
+  let mut resolver = Resolver {
+    scopes: Vec::new(),
+    unit_variants: HashSet::new(),
+  };
+  resolver.collect_unit_variants(instructions);
+  resolver.resolve_scope(instructions);
+}
+
+// Resolves code that comes in pieces, which all run in the same frame one after the other, like
+// the lines typed into the repl. Variables declared by a piece stay in scope for the next ones
+pub struct Resolver {
+  scopes: Vec<Vec<String>>,
+  // Variants without fields, a pattern with one of these names compares instead of binding
+  unit_variants: HashSet<String>,
+}
+
+impl Default for Resolver {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Resolver {
+  pub fn new() -> Self {
+    Self {
+      scopes: vec![Vec::new()],
+      unit_variants: HashSet::new(),
+    }
+  }
+
+  pub fn resolve_next(&mut self, instructions: &mut [Instruction]) {
+    self.collect_unit_variants(instructions);
+    self.resolve_instructions(instructions);
+  }
+
+  fn collect_unit_variants(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      match &instruction.kind {
+        InstructionKind::Enum { variants, .. } => {
+          for variant in variants {
+            if variant.fields.is_empty() {
+              self.unit_variants.insert(variant.name.clone());
+            }
+          }
+        }
+        InstructionKind::If { instructions, .. }
+        | InstructionKind::Else { instructions }
+        | InstructionKind::While { instructions, .. }
+        | InstructionKind::Scope { instructions }
+        | InstructionKind::Test { instructions, .. } => self.collect_unit_variants(instructions),
+        InstructionKind::Function(function) => self.collect_unit_variants(&function.instructions),
+        InstructionKind::Match { arms, .. } => {
+          for arm in arms {
+            self.collect_unit_variants(&arm.instructions);
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+
+  fn resolve_scope(&mut self, instructions: &mut [Instruction]) {
+    self.scopes.push(Vec::new());
+    self.resolve_instructions(instructions);
+    self.scopes.pop();
+  }
+
+  fn resolve_instructions(&mut self, instructions: &mut [Instruction]) {
+    for instruction in instructions {
+      match &mut instruction.kind {
+        InstructionKind::If {
+          condition,
+          instructions,
+        }
+        | InstructionKind::While {
+          condition,
+          instructions,
+        } => {
+          self.resolve_value(condition);
+          self.resolve_scope(instructions);
+        }
+        InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+          self.resolve_scope(instructions);
+        }
+        InstructionKind::Value { value } | InstructionKind::Print { message: value } => {
+          self.resolve_value(value);
+        }
+        InstructionKind::Input { variable } => self.resolve_assignment(variable),
+        InstructionKind::Match { value, arms } => {
+          self.resolve_value(value);
+          for arm in arms {
+            // The bindings, guard and instructions of an arm all share a single frame
+            self.scopes.push(Vec::new());
+            self.resolve_pattern(&mut arm.pattern);
+            if let Some(guard) = &mut arm.guard {
+              self.resolve_value(guard);
+            }
+            self.resolve_instructions(&mut arm.instructions);
+            self.scopes.pop();
+          }
+        }
+        InstructionKind::Let {
+          variable, value, ..
+        } => {
+          self.resolve_value(value);
+          variable.slot = Some(self.declare(&variable.name));
+        }
+        InstructionKind::Function(function) => {
+          // The parameters come first in the frame of the call, the body shares it
+          let outer = std::mem::take(&mut self.scopes);
+          self.scopes.push(Vec::new());
+          for parameter in &mut function.parameters {
+            parameter.variable.slot = Some(self.declare(&parameter.variable.name));
+          }
+          self.resolve_instructions(&mut function.instructions);
+          self.scopes = outer;
+        }
+        InstructionKind::Return { value } => {
+          if let Some(value) = value {
+            self.resolve_value(value);
+          }
+        }
+        InstructionKind::Assert { condition, message } => {
+          self.resolve_value(condition);
+          if let Some(message) = message {
+            self.resolve_value(message);
+          }
+        }
+        InstructionKind::Test { instructions, .. } => {
+          let outer = std::mem::take(&mut self.scopes);
+          self.resolve_scope(instructions);
+          self.scopes = outer;
+        }
+        InstructionKind::Break | InstructionKind::Enum { .. } => (),
+      }
+    }
+  }
+
+  fn resolve_pattern(&mut self, pattern: &mut Pattern) {
+    match pattern {
+      Pattern::Identifier(identifier) => {
+        if !self.unit_variants.contains(&identifier.name) {
+          identifier.slot = Some(self.declare(&identifier.name));
+        }
+      }
+      Pattern::Variant { fields, .. } => {
+        for field in fields {
+          self.resolve_pattern(field);
+        }
+      }
+      Pattern::Wildcard | Pattern::Literal(_) => (),
+    }
+  }
+
+  // Resolves in the same order the interpreter evaluates, so a variable is only in scope after
+  // the assignment that declares it
+  fn resolve_value(&mut self, value: &mut Value) {
+    match value {
+      Value::Identifier(identifier) => identifier.slot = self.lookup(&identifier.name),
+      Value::Expression(expr) => {
+        if *expr.get_operator() == Operator::Assign {
+          if let Some(right) = expr.get_right_mut() {
+            self.resolve_value(right);
+          }
+          match expr.get_left_mut() {
+            Value::Identifier(identifier) => self.resolve_assignment(identifier),
+            left => self.resolve_value(left),
+          }
+        } else {
+          self.resolve_value(expr.get_left_mut());
+          if let Some(right) = expr.get_right_mut() {
+            self.resolve_value(right);
+          }
+        }
+      }
+      Value::Call { arguments, .. } => {
+        for argument in arguments {
+          self.resolve_value(argument);
+        }
+      }
+      Value::List(items) => {
+        for item in items {
+          self.resolve_value(item);
+        }
+      }
+      Value::Field { value, .. } => self.resolve_value(value),
+      Value::Index { value, index, .. } => {
+        self.resolve_value(value);
+        self.resolve_value(index);
+      }
+      Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None => (),
+    }
+  }
+
+  fn resolve_assignment(&mut self, identifier: &mut Identifier) {
+    let slot = match self.lookup(&identifier.name) {
+      Some(slot) => slot,
+      None => self.declare(&identifier.name),
+    };
+    identifier.slot = Some(slot);
+  }
+
+  fn lookup(&self, name: &str) -> Option<Slot> {
+    for (depth, scope) in self.scopes.iter().rev().enumerate() {
+      if let Some(index) = scope.iter().rposition(|variable| variable == name) {
+        return Some(Slot { depth, index });
+      }
+    }
+    None
+  }
+
+  fn declare(&mut self, name: &str) -> Slot {
+    let scope = self.scopes.last_mut().expect("No scope to declare in");
+    scope.push(name.to_string());
+    Slot {
+      depth: 0,
+      index: scope.len() - 1,
+    }
+  }
+}
@@ -0,0 +1,213 @@
+use std::{
+  env,
+  ffi::OsStr,
+  fs,
+  path::{Path, PathBuf},
+  process::{self, Command, Stdio},
+  thread,
+  time::Duration,
+};
+
+use serde_json::Value as Json;
+
+use crate::{
+  interpreter::{self, Hooks, InterpreterError},
+  limits::Limits,
+  optimizer,
+  parser::{self, Instruction},
+  resolver, tokenizer,
+};
+
+// The scripts that come with fish, for tests that check something holds for real code: code.fsh,
+// the benchmarks and the examples in the README, which are the blocks in plain ``` fences
+pub fn all() -> Vec<(String, String)> {
+  let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+  let mut samples = vec![read(&root.join("code.fsh"))];
+  let mut benches = fs::read_dir(root.join("benches"))
+    .expect("Benchmarks can not be read")
+    .map(|entry| entry.expect("Benchmarks can not be read").path())
+    .filter(|path| path.extension().is_some_and(|extension| extension == "fsh"))
+    .collect::<Vec<_>>();
+  benches.sort();
+  samples.extend(benches.iter().map(|path| read(path)));
+  let (_, readme) = read(&root.join("README.md"));
+  let mut example = None;
+  let mut examples = 0;
+  for line in readme.lines() {
+    match (&mut example, line) {
+      (None, "```") => example = Some(String::new()),
+      (Some(_), "```") => {
+        examples += 1;
+        let name = format!("README.md example {}", examples);
+        samples.push((name, example.take().unwrap()));
+      }
+      (Some(code), line) => {
+        code.push_str(line);
+        code.push('\n');
+      }
+      (None, _) => (),
+    }
+  }
+  samples
+}
+
+// Named by where they are in the repository, so the names are the same on every machine
+fn read(path: &Path) -> (String, String) {
+  let code = fs::read_to_string(path).expect("Sample can not be read");
+  let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+  let name = path.strip_prefix(root).unwrap_or(path);
+  (name.display().to_string(), code)
+}
+
+// Small scripts for where the backends are most likely to differ from the interpreter: arithmetic
+// at the edges of integers, mixing integers and floats, errors and exit codes
+pub fn edge_cases() -> Vec<(String, String)> {
+  let cases = [
+    "fn add(a, b) { return a + b; }; print(add(9223372036854775807, 1));",
+    "fn sub(a, b) { return a - b; }; print(sub(0 - 9223372036854775807, 2));",
+    "fn mul(a, b) { return a * b; }; print(mul(4611686018427387904, 2));",
+    "fn div(a, b) { return a / b; }; print(div(7, 2)); print(div(0 - 7, 2)); print(div(7, 0));",
+    "fn rem(a, b) { return a % b; }; print(rem(7, 2)); print(rem(0 - 7, 2)); print(rem(7, 0));",
+    "fn div(a, b) { return a / b; }; print(div((0 - 9223372036854775807) - 1, 0 - 1));",
+    "fn rem(a, b) { return a % b; }; print(rem((0 - 9223372036854775807) - 1, 0 - 1));",
+    "fn f(a, b) { print(a + b); print(a - b); print(a * b); print(a / b); print(a < b); };
+     f(7, 2.0); f(7.5, 2); f(1.0, 0.0); f(0 - 1, 0.0); f(0.1, 0.2); f(3, 3.0);",
+    "fn f(a, b) { return a == b; }; print(f(1, 1.0)); print(f(2, 2)); print(f(true, 1));",
+    "fn f(x) { return x + true; }; print(1); f(1); print(2);",
+    "fn f(n) { return f(n + 1); }; f(0);",
+    "print(1); exit(3); print(2);",
+    "fn f() { exit(); }; print(1); f(); print(2);",
+    "fn f(code) { exit(code); }; print(1); f(256); print(2);",
+    "fn f(code) { exit(code); }; print(1); f(0 - 1); print(2);",
+    // The same without functions, which the x86_64 target does not have
+    "a = 9223372036854775807; b = 1; print(a - b); print(a + b);",
+    "a = 0 - 9223372036854775807; b = 2; print(a - b);",
+    "a = 4611686018427387904; b = 2; print(a * b);",
+    "a = 0 - 7; b = 2; print(a / b); print(a % b); print(a * b); b = 0; print(a % b);",
+    "a = (0 - 9223372036854775807) - 1; b = 0 - 1; print(a / b);",
+    "a = (0 - 9223372036854775807) - 1; b = 0 - 1; print(a % b);",
+    "a = 7; b = 2.0; c = 0.0; d = 2;
+     print(a + b); print(a - b); print(a * b); print(a / b); print(a < b); print(a == 7.0);
+     print(a / c); print(c / c); print(a ^ d); print(a ^ b); print(d ^ (0 - 1));",
+    "a = true; b = 1; print(a == b); print(a != none); print(a + b);",
+    "a = 1; if (a) { print(a); };",
+    "a = 1; a /= 0;",
+    "print(1); print(x);",
+  ];
+  let cases = cases.iter().enumerate();
+  cases
+    .map(|(index, code)| (format!("edge case {}", index + 1), code.to_string()))
+    .collect()
+}
+
+// What running a script did as seen from outside, to compare the backends with the interpreter
+#[derive(Debug, PartialEq)]
+pub struct Ending {
+  pub output: String,
+  pub error: String,
+  pub code: i32,
+}
+
+// The instructions of a script as they were parsed
+pub fn parse(code: &str) -> Vec<Instruction> {
+  let tokens = tokenizer::tokenize(code).expect("Sample does not tokenize");
+  let (instructions, diagnostics) = parser::parse(tokens, &Limits::default());
+  assert!(
+    diagnostics.is_empty(),
+    "Sample does not parse: {:?}",
+    diagnostics
+  );
+  instructions
+}
+
+// Leaves out where the nodes are in the code, to compare syntax trees of different code
+pub fn without_spans(json: &mut Json) {
+  match json {
+    Json::Object(fields) => {
+      fields.remove("span");
+      fields.remove("name_span");
+      fields.values_mut().for_each(without_spans);
+    }
+    Json::Array(items) => items.iter_mut().for_each(without_spans),
+    _ => (),
+  }
+}
+
+// The instructions `fish build` turns into a program
+pub fn instructions(code: &str) -> Vec<Instruction> {
+  let mut instructions = parse(code);
+  optimizer::optimize(&mut instructions, &Limits::default());
+  resolver::resolve(&mut instructions);
+  instructions
+}
+
+// Hooks that keep what the script prints
+#[derive(Default)]
+pub struct Output(pub String);
+
+impl Hooks for Output {
+  fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+    self.0.push_str(text);
+    self.0.push('\n');
+    Ok(())
+  }
+}
+
+// How `fish run` ends for the script, None when it reads input
+pub fn interpret(code: &str) -> Option<Ending> {
+  run(|| instructions(code))
+}
+
+// How running the resolved instructions ends, on a thread with the stack `fish run` has, which is
+// where they are made as well
+pub fn run(instructions: impl FnOnce() -> Vec<Instruction> + Send) -> Option<Ending> {
+  // A script that no longer ends, like a loop that was optimized wrong, fails instead of hanging
+  let limits = Limits {
+    allow_input: false,
+    timeout: Some(Duration::from_secs(60)),
+    ..Limits::default()
+  };
+  let stack_size = limits.stack_size();
+  let run = || {
+    let mut output = Output::default();
+    let result = interpreter::interpret_with_hooks(&instructions(), limits, &[], &mut output);
+    let (error, code) = match result {
+      Ok(()) => (String::new(), 0),
+      Err(InterpreterError::InputDisabled) => return None,
+      Err(InterpreterError::Exit(code)) => (String::new(), code),
+      Err(error) => (format!("Error interpreting code: {}\n", error), 1),
+    };
+    Some(Ending {
+      output: output.0,
+      error,
+      code,
+    })
+  };
+  thread::scope(|scope| {
+    let thread = thread::Builder::new().stack_size(stack_size);
+    thread.spawn_scoped(scope, run).unwrap().join().unwrap()
+  })
+}
+
+// A directory of its own for a test to write programs into, emptied first
+pub fn temp_dir(name: &str) -> PathBuf {
+  let dir = env::temp_dir().join(format!("fish-{}-{}", name, process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).expect("Temporary directory can not be created");
+  dir
+}
+
+// How a program that was built from a sample ends without any input, None when it can not be
+// started, like a compiler that is not installed
+pub fn run_program(program: impl AsRef<OsStr>, args: &[&OsStr]) -> Option<Ending> {
+  let output = Command::new(program)
+    .args(args)
+    .stdin(Stdio::null())
+    .output()
+    .ok()?;
+  Some(Ending {
+    output: String::from_utf8_lossy(&output.stdout).into_owned(),
+    error: String::from_utf8_lossy(&output.stderr).into_owned(),
+    code: output.status.code().expect("The program was killed"),
+  })
+}
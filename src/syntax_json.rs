@@ -0,0 +1,860 @@
+use std::fmt;
+
+use serde_json::{json, Map, Value as Json};
+
+use crate::{
+  limits::{self, Limit},
+  number::Number,
+  parser::{
+    Expression, Function, Identifier, Instruction, InstructionKind, MatchArm, Parameter, Pattern,
+    TypeAnnotation, Value, Variant,
+  },
+  tokenizer::{Lexeme, Operator, Position, Span, Token, Trivia, TriviaKind, OPERATORS},
+};
+
+/*
+ Tokens and instructions as JSON, for tools that want to work with fish code without tokenizing
+ or parsing it themselves. Every document has the version of the format it was written in, which
+ goes up whenever a change would make an older reader misread it. Adding fields does not count.
+
+ Every node is an object with a `kind`, positions are objects with an offset in bytes and a line
+ and column counted from 1, the same as in error messages.
+*/
+pub const VERSION: u64 = 1;
+
+pub fn tokens_to_json(tokens: &[Lexeme]) -> Json {
+  let tokens: Vec<Json> = tokens.iter().map(token_to_json).collect();
+  json!({ "version": VERSION, "kind": "tokens", "tokens": tokens })
+}
+
+pub fn ast_to_json(instructions: &[Instruction]) -> Json {
+  json!({ "version": VERSION, "kind": "ast", "instructions": instructions_to_json(instructions) })
+}
+
+fn token_to_json(lexeme: &Lexeme) -> Json {
+  let (kind, value) = match &lexeme.token {
+    Token::EndStatement => ("end_statement", Json::Null),
+    Token::Identifier(name) => ("identifier", json!(name)),
+    Token::Number(number) => ("number", number_to_json(*number)),
+    Token::String(string) => ("string", json!(string)),
+    Token::Operator(operator) => ("operator", json!(operator.to_string())),
+    Token::Keyword(keyword) => ("keyword", json!(keyword.to_string())),
+    Token::ScopeOpen => ("scope_open", Json::Null),
+    Token::ScopeClose => ("scope_close", Json::Null),
+    Token::BracketOpen => ("bracket_open", Json::Null),
+    Token::BracketClose => ("bracket_close", Json::Null),
+    Token::Boolean(boolean) => ("boolean", json!(boolean)),
+    Token::None => ("none", Json::Null),
+    Token::SquareBracketOpen => ("square_bracket_open", Json::Null),
+    Token::SquareBracketClose => ("square_bracket_close", Json::Null),
+    Token::Comma => ("comma", Json::Null),
+    Token::Dot => ("dot", Json::Null),
+    Token::OptionalDot => ("optional_dot", Json::Null),
+    Token::Arrow => ("arrow", Json::Null),
+    Token::ReturnArrow => ("return_arrow", Json::Null),
+    Token::Colon => ("colon", Json::Null),
+    Token::EndOfFile => ("end_of_file", Json::Null),
+  };
+  let mut token = json!({
+    "kind": kind,
+    "span": span_to_json(lexeme.span),
+    "leading": lexeme.leading.iter().map(trivia_to_json).collect::<Vec<_>>(),
+    "trailing": lexeme.trailing.iter().map(trivia_to_json).collect::<Vec<_>>(),
+  });
+  if !value.is_null() {
+    token["value"] = value;
+  }
+  token
+}
+
+fn trivia_to_json(trivia: &Trivia) -> Json {
+  let kind = match trivia.kind {
+    TriviaKind::LineComment => "line_comment",
+    TriviaKind::BlockComment => "block_comment",
+  };
+  json!({ "kind": kind, "text": trivia.text, "span": span_to_json(trivia.span) })
+}
+
+fn span_to_json(span: Span) -> Json {
+  json!({ "start": position_to_json(span.start), "end": position_to_json(span.end) })
+}
+
+fn position_to_json(position: Position) -> Json {
+  json!({ "offset": position.offset, "line": position.line, "column": position.column })
+}
+
+// Integers stay integers and floats always have a fraction, 1.0 instead of 1, so they can be told
+// apart when reading them back
+fn number_to_json(number: Number) -> Json {
+  match number {
+    Number::Integer(integer) => json!(integer),
+    Number::Float(float) => json!(float),
+  }
+}
+
+fn instructions_to_json(instructions: &[Instruction]) -> Vec<Json> {
+  instructions.iter().map(instruction_to_json).collect()
+}
+
+fn instruction_to_json(instruction: &Instruction) -> Json {
+  let mut node = match &instruction.kind {
+    InstructionKind::If {
+      condition,
+      instructions,
+    } => json!({
+      "kind": "if",
+      "condition": value_to_json(condition),
+      "body": instructions_to_json(instructions),
+    }),
+    InstructionKind::Else { instructions } => {
+      json!({ "kind": "else", "body": instructions_to_json(instructions) })
+    }
+    InstructionKind::While {
+      condition,
+      instructions,
+    } => json!({
+      "kind": "while",
+      "condition": value_to_json(condition),
+      "body": instructions_to_json(instructions),
+    }),
+    InstructionKind::Scope { instructions } => {
+      json!({ "kind": "scope", "body": instructions_to_json(instructions) })
+    }
+    InstructionKind::Value { value } => json!({ "kind": "value", "value": value_to_json(value) }),
+    InstructionKind::Break => json!({ "kind": "break" }),
+    InstructionKind::Print { message } => {
+      json!({ "kind": "print", "value": value_to_json(message) })
+    }
+    InstructionKind::Input { variable } => {
+      json!({ "kind": "input", "variable": identifier_to_json(variable) })
+    }
+    InstructionKind::Enum {
+      name,
+      name_span,
+      variants,
+    } => {
+      let variants: Vec<Json> = variants
+        .iter()
+        .map(|variant| {
+          json!({
+            "name": variant.name,
+            "span": span_to_json(variant.span),
+            "fields": variant.fields,
+          })
+        })
+        .collect();
+      json!({
+        "kind": "enum",
+        "name": name,
+        "name_span": span_to_json(*name_span),
+        "variants": variants,
+      })
+    }
+    InstructionKind::Match { value, arms } => {
+      let arms: Vec<Json> = arms
+        .iter()
+        .map(|arm| {
+          json!({
+            "pattern": pattern_to_json(&arm.pattern),
+            "guard": arm.guard.as_ref().map(value_to_json),
+            "body": instructions_to_json(&arm.instructions),
+          })
+        })
+        .collect();
+      json!({ "kind": "match", "value": value_to_json(value), "arms": arms })
+    }
+    InstructionKind::Let {
+      variable,
+      annotation,
+      value,
+    } => json!({
+      "kind": "let",
+      "variable": identifier_to_json(variable),
+      "annotation": annotation.as_ref().map(annotation_to_json),
+      "value": value_to_json(value),
+    }),
+    InstructionKind::Function(function) => {
+      let parameters: Vec<Json> = function
+        .parameters
+        .iter()
+        .map(|parameter| {
+          json!({
+            "variable": identifier_to_json(&parameter.variable),
+            "annotation": parameter.annotation.as_ref().map(annotation_to_json),
+          })
+        })
+        .collect();
+      json!({
+        "kind": "function",
+        "name": function.name,
+        "name_span": span_to_json(function.name_span),
+        "parameters": parameters,
+        "return_type": function.return_type.as_ref().map(annotation_to_json),
+        "body": instructions_to_json(&function.instructions),
+      })
+    }
+    InstructionKind::Return { value } => {
+      json!({ "kind": "return", "value": value.as_ref().map(value_to_json) })
+    }
+    InstructionKind::Assert { condition, message } => json!({
+      "kind": "assert",
+      "condition": value_to_json(condition),
+      "message": message.as_ref().map(value_to_json),
+    }),
+    InstructionKind::Test { name, instructions } => json!({
+      "kind": "test",
+      "name": name,
+      "body": instructions_to_json(instructions),
+    }),
+  };
+  node["span"] = span_to_json(instruction.span);
+  node
+}
+
+fn identifier_to_json(identifier: &Identifier) -> Json {
+  json!({ "name": identifier.name, "span": span_to_json(identifier.span) })
+}
+
+fn annotation_to_json(annotation: &TypeAnnotation) -> Json {
+  json!({ "name": annotation.name, "span": span_to_json(annotation.span) })
+}
+
+fn value_to_json(value: &Value) -> Json {
+  match value {
+    Value::Number(number) => json!({ "kind": "number", "value": number_to_json(*number) }),
+    Value::String(string) => json!({ "kind": "string", "value": string.as_ref() }),
+    Value::Boolean(boolean) => json!({ "kind": "boolean", "value": boolean }),
+    Value::None => json!({ "kind": "none" }),
+    Value::List(items) => {
+      let items: Vec<Json> = items.iter().map(value_to_json).collect();
+      json!({ "kind": "list", "items": items })
+    }
+    Value::Identifier(identifier) => json!({
+      "kind": "identifier",
+      "name": identifier.name,
+      "span": span_to_json(identifier.span),
+    }),
+    Value::Expression(expression) => json!({
+      "kind": "expression",
+      "operator": expression.get_operator().to_string(),
+      "left": value_to_json(expression.get_left()),
+      "right": expression.get_right().map(value_to_json),
+    }),
+    Value::Call {
+      name,
+      span,
+      arguments,
+    } => {
+      let arguments: Vec<Json> = arguments.iter().map(value_to_json).collect();
+      json!({
+        "kind": "call",
+        "name": name,
+        "span": span_to_json(*span),
+        "arguments": arguments,
+      })
+    }
+    Value::Field {
+      value,
+      field,
+      optional,
+    } => json!({
+      "kind": "field",
+      "value": value_to_json(value),
+      "field": field,
+      "optional": optional,
+    }),
+    Value::Index {
+      value,
+      index,
+      optional,
+    } => json!({
+      "kind": "index",
+      "value": value_to_json(value),
+      "index": value_to_json(index),
+      "optional": optional,
+    }),
+  }
+}
+
+fn pattern_to_json(pattern: &Pattern) -> Json {
+  match pattern {
+    Pattern::Wildcard => json!({ "kind": "wildcard" }),
+    Pattern::Literal(value) => json!({ "kind": "literal", "value": value_to_json(value) }),
+    Pattern::Identifier(identifier) => json!({
+      "kind": "identifier",
+      "name": identifier.name,
+      "span": span_to_json(identifier.span),
+    }),
+    Pattern::Variant { name, span, fields } => {
+      let fields: Vec<Json> = fields.iter().map(pattern_to_json).collect();
+      json!({
+        "kind": "variant",
+        "name": name,
+        "span": span_to_json(*span),
+        "fields": fields,
+      })
+    }
+  }
+}
+
+// What is wrong with a JSON AST and where, like `instructions[2].condition.left: Missing "kind"`
+#[derive(Debug)]
+pub struct FormatError {
+  pub path: String,
+  pub message: String,
+}
+
+impl fmt::Display for FormatError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.path.is_empty() {
+      true => write!(f, "{}", self.message),
+      false => write!(f, "{}: {}", self.path, self.message),
+    }
+  }
+}
+
+/*
+ Reads instructions back from what `ast_to_json` wrote, so generated code can be run without
+ going through the source. Spans can be left out, code without them runs the same but errors
+ can not point at where they happened. Blocks, values and patterns can be nested as deep as the
+ parser allows in code. Variables still have to be resolved afterwards.
+*/
+pub fn ast_from_json(
+  json: &Json,
+  max_nesting: Option<usize>,
+) -> Result<Vec<Instruction>, FormatError> {
+  let document = Node {
+    json,
+    path: String::new(),
+    depth: 0,
+    max_depth: max_nesting,
+    chain: 0,
+    max_chain: limits::max_chain(max_nesting),
+  };
+  let version = document.field("version")?.integer()?;
+  if version != VERSION as i64 {
+    return Err(document.error(format!(
+      "Version {} is not supported, only {} is",
+      version, VERSION
+    )));
+  }
+  let kind = document.field("kind")?.string()?;
+  if kind != "ast" {
+    return Err(document.error(format!("Expected an ast, found {}", kind)));
+  }
+  document.field("instructions")?.instructions()
+}
+
+// How deep arrays and objects are nested in JSON text, without parsing it
+pub fn json_depth(json: &str) -> usize {
+  let (mut depth, mut max_depth) = (0usize, 0);
+  let (mut in_string, mut escaped) = (false, false);
+  for byte in json.bytes() {
+    match byte {
+      _ if escaped => escaped = false,
+      b'\\' if in_string => escaped = true,
+      b'"' => in_string = !in_string,
+      _ if in_string => (),
+      b'[' | b'{' => {
+        depth += 1;
+        max_depth = max_depth.max(depth);
+      }
+      b']' | b'}' => depth = depth.saturating_sub(1),
+      _ => (),
+    }
+  }
+  max_depth
+}
+
+// A part of the document together with how to get there from the top, for errors
+struct Node<'a> {
+  json: &'a Json,
+  path: String,
+  // Blocks, values and patterns around it, which are nested as deep as the parser allows in code
+  depth: usize,
+  max_depth: Option<usize>,
+  // Operators to the left of it in its expression and the ones around it
+  chain: usize,
+  max_chain: Option<usize>,
+}
+
+impl<'a> Node<'a> {
+  fn error(&self, message: impl Into<String>) -> FormatError {
+    FormatError {
+      path: self.path.clone(),
+      message: message.into(),
+    }
+  }
+
+  // The same node one level deeper, for a block, value or pattern
+  fn nested(&self) -> Result<Node<'a>, FormatError> {
+    if let Some(max_depth) = self.max_depth {
+      if self.depth >= max_depth {
+        return Err(self.error(format!("Limit exceeded, {}", Limit::Nesting(max_depth))));
+      }
+    }
+    Ok(Node {
+      path: self.path.clone(),
+      depth: self.depth + 1,
+      ..*self
+    })
+  }
+
+  // The right side of an operator, which is as deep as the operator like it is in the parser
+  fn chained(&self) -> Result<Node<'a>, FormatError> {
+    if let Some(max_chain) = self.max_chain {
+      if self.chain >= max_chain {
+        return Err(self.error(format!("Limit exceeded, {}", Limit::Chain(max_chain))));
+      }
+    }
+    Ok(Node {
+      path: self.path.clone(),
+      depth: self.depth - 1,
+      chain: self.chain + 1,
+      ..*self
+    })
+  }
+
+  fn object(&self) -> Result<&'a Map<String, Json>, FormatError> {
+    self
+      .json
+      .as_object()
+      .ok_or_else(|| self.error("Expected an object"))
+  }
+
+  fn path_to(&self, name: &str) -> String {
+    match self.path.is_empty() {
+      true => name.to_string(),
+      false => format!("{}.{}", self.path, name),
+    }
+  }
+
+  fn field(&self, name: &str) -> Result<Node<'a>, FormatError> {
+    match self.object()?.get(name) {
+      Some(json) => Ok(Node {
+        json,
+        path: self.path_to(name),
+        ..*self
+      }),
+      None => Err(self.error(format!("Missing \"{}\"", name))),
+    }
+  }
+
+  // A field that can be left out or null
+  fn optional(&self, name: &str) -> Result<Option<Node<'a>>, FormatError> {
+    match self.object()?.get(name) {
+      None | Some(Json::Null) => Ok(None),
+      Some(json) => Ok(Some(Node {
+        json,
+        path: self.path_to(name),
+        ..*self
+      })),
+    }
+  }
+
+  fn items(&self) -> Result<Vec<Node<'a>>, FormatError> {
+    let items = self
+      .json
+      .as_array()
+      .ok_or_else(|| self.error("Expected an array"))?;
+    Ok(
+      items
+        .iter()
+        .enumerate()
+        .map(|(index, json)| Node {
+          json,
+          path: format!("{}[{}]", self.path, index),
+          ..*self
+        })
+        .collect(),
+    )
+  }
+
+  fn string(&self) -> Result<&'a str, FormatError> {
+    self
+      .json
+      .as_str()
+      .ok_or_else(|| self.error("Expected a string"))
+  }
+
+  fn boolean(&self) -> Result<bool, FormatError> {
+    self
+      .json
+      .as_bool()
+      .ok_or_else(|| self.error("Expected a boolean"))
+  }
+
+  fn integer(&self) -> Result<i64, FormatError> {
+    self
+      .json
+      .as_i64()
+      .ok_or_else(|| self.error("Expected an integer"))
+  }
+
+  fn unsigned(&self) -> Result<usize, FormatError> {
+    self
+      .json
+      .as_u64()
+      .and_then(|number| usize::try_from(number).ok())
+      .ok_or_else(|| self.error("Expected a positive integer"))
+  }
+
+  fn number(&self) -> Result<Number, FormatError> {
+    match self.json {
+      Json::Number(number) if number.is_i64() => Ok(Number::Integer(number.as_i64().unwrap())),
+      Json::Number(number) if number.is_f64() => Ok(Number::Float(number.as_f64().unwrap())),
+      Json::Number(_) => Err(self.error("Expected a number that fits in 64 bits")),
+      _ => Err(self.error("Expected a number")),
+    }
+  }
+
+  fn kind(&self) -> Result<&'a str, FormatError> {
+    self.field("kind")?.string()
+  }
+
+  fn span(&self) -> Result<Span, FormatError> {
+    self.span_field("span")
+  }
+
+  fn span_field(&self, name: &str) -> Result<Span, FormatError> {
+    let Some(span) = self.optional(name)? else {
+      return Ok(Span::default());
+    };
+    Ok(Span {
+      start: span.field("start")?.position()?,
+      end: span.field("end")?.position()?,
+    })
+  }
+
+  fn position(&self) -> Result<Position, FormatError> {
+    Ok(Position {
+      offset: self.field("offset")?.unsigned()?,
+      line: self.field("line")?.unsigned()?,
+      column: self.field("column")?.unsigned()?,
+    })
+  }
+
+  fn name(&self) -> Result<String, FormatError> {
+    Ok(self.field("name")?.string()?.to_string())
+  }
+
+  fn identifier(&self) -> Result<Identifier, FormatError> {
+    Ok(Identifier::new(self.name()?, self.span()?))
+  }
+
+  fn annotation(&self) -> Result<TypeAnnotation, FormatError> {
+    Ok(TypeAnnotation {
+      name: self.name()?,
+      span: self.span()?,
+    })
+  }
+
+  fn instructions(&self) -> Result<Vec<Instruction>, FormatError> {
+    let node = self.nested()?;
+    node.items()?.iter().map(Node::instruction).collect()
+  }
+
+  fn body(&self) -> Result<Vec<Instruction>, FormatError> {
+    self.field("body")?.instructions()
+  }
+
+  fn values(&self) -> Result<Vec<Value>, FormatError> {
+    self.items()?.iter().map(Node::value).collect()
+  }
+
+  fn optional_value(&self, name: &str) -> Result<Option<Value>, FormatError> {
+    self.optional(name)?.map(|value| value.value()).transpose()
+  }
+
+  fn instruction(&self) -> Result<Instruction, FormatError> {
+    let kind = match self.kind()? {
+      "if" => InstructionKind::If {
+        condition: self.field("condition")?.value()?,
+        instructions: self.body()?,
+      },
+      "else" => InstructionKind::Else {
+        instructions: self.body()?,
+      },
+      "while" => InstructionKind::While {
+        condition: self.field("condition")?.value()?,
+        instructions: self.body()?,
+      },
+      "scope" => InstructionKind::Scope {
+        instructions: self.body()?,
+      },
+      "value" => InstructionKind::Value {
+        value: self.field("value")?.value()?,
+      },
+      "break" => InstructionKind::Break,
+      "print" => InstructionKind::Print {
+        message: self.field("value")?.value()?,
+      },
+      "input" => InstructionKind::Input {
+        variable: self.field("variable")?.identifier()?,
+      },
+      "enum" => {
+        let mut variants = Vec::new();
+        for variant in self.field("variants")?.items()? {
+          let mut fields = Vec::new();
+          for field in variant.field("fields")?.items()? {
+            fields.push(field.string()?.to_string());
+          }
+          variants.push(Variant {
+            name: variant.name()?,
+            span: variant.span()?,
+            fields,
+          });
+        }
+        InstructionKind::Enum {
+          name: self.name()?,
+          name_span: self.span_field("name_span")?,
+          variants,
+        }
+      }
+      "match" => {
+        let mut arms = Vec::new();
+        for arm in self.field("arms")?.items()? {
+          arms.push(MatchArm {
+            pattern: arm.field("pattern")?.pattern()?,
+            guard: arm.optional_value("guard")?,
+            instructions: arm.body()?,
+          });
+        }
+        InstructionKind::Match {
+          value: self.field("value")?.value()?,
+          arms,
+        }
+      }
+      "let" => InstructionKind::Let {
+        variable: self.field("variable")?.identifier()?,
+        annotation: self
+          .optional("annotation")?
+          .map(|annotation| annotation.annotation())
+          .transpose()?,
+        value: self.field("value")?.value()?,
+      },
+      "function" => {
+        let mut parameters = Vec::new();
+        for parameter in self.field("parameters")?.items()? {
+          parameters.push(Parameter {
+            variable: parameter.field("variable")?.identifier()?,
+            annotation: parameter
+              .optional("annotation")?
+              .map(|annotation| annotation.annotation())
+              .transpose()?,
+          });
+        }
+        InstructionKind::Function(Function {
+          name: self.name()?,
+          name_span: self.span_field("name_span")?,
+          parameters,
+          return_type: self
+            .optional("return_type")?
+            .map(|annotation| annotation.annotation())
+            .transpose()?,
+          instructions: self.body()?,
+        })
+      }
+      "return" => InstructionKind::Return {
+        value: self.optional_value("value")?,
+      },
+      "assert" => InstructionKind::Assert {
+        condition: self.field("condition")?.value()?,
+        message: self.optional_value("message")?,
+      },
+      "test" => InstructionKind::Test {
+        name: self.name()?,
+        instructions: self.body()?,
+      },
+      kind => return Err(self.error(format!("Unknown instruction \"{}\"", kind))),
+    };
+    Ok(Instruction {
+      kind,
+      span: self.span()?,
+    })
+  }
+
+  fn value(&self) -> Result<Value, FormatError> {
+    let node = self.nested()?;
+    let value = match node.kind()? {
+      "number" => Value::Number(node.field("value")?.number()?),
+      "string" => Value::String(node.field("value")?.string()?.into()),
+      "boolean" => Value::Boolean(node.field("value")?.boolean()?),
+      "none" => Value::None,
+      "list" => Value::List(node.field("items")?.values()?),
+      "identifier" => Value::Identifier(node.identifier()?),
+      "expression" => {
+        let operator_node = node.field("operator")?;
+        let symbol = operator_node.string()?;
+        let operator = OPERATORS
+          .into_iter()
+          .find(|operator| operator.to_string() == symbol)
+          .ok_or_else(|| operator_node.error(format!("Unknown operator \"{}\"", symbol)))?;
+        let left = node.field("left")?.value()?;
+        let right = node.optional("right")?;
+        let right = right.map(|right| right.chained()?.value()).transpose()?;
+        let expression = match (operator, right) {
+          (Operator::Not | Operator::Brackets, None) => {
+            Expression::new_not_or_bracket(operator, left)
+          }
+          (Operator::Not | Operator::Brackets, Some(_)) => {
+            return Err(node.error(format!("Operator \"{}\" takes no right side", symbol)))
+          }
+          (_, Some(right)) => Expression::new(operator, left, right),
+          (_, None) => return Err(node.error("Missing \"right\"")),
+        };
+        Value::Expression(Box::new(expression))
+      }
+      "call" => Value::Call {
+        name: node.name()?,
+        span: node.span()?,
+        arguments: node.field("arguments")?.values()?,
+      },
+      "field" => Value::Field {
+        value: Box::new(node.field("value")?.value()?),
+        field: node.field("field")?.string()?.to_string(),
+        optional: node.field("optional")?.boolean()?,
+      },
+      "index" => Value::Index {
+        value: Box::new(node.field("value")?.value()?),
+        index: Box::new(node.field("index")?.value()?),
+        optional: node.field("optional")?.boolean()?,
+      },
+      kind => return Err(node.error(format!("Unknown value \"{}\"", kind))),
+    };
+    Ok(value)
+  }
+
+  fn pattern(&self) -> Result<Pattern, FormatError> {
+    let node = self.nested()?;
+    let pattern = match node.kind()? {
+      "wildcard" => Pattern::Wildcard,
+      "literal" => {
+        let value = node.field("value")?;
+        match value.value()? {
+          literal @ (Value::Number(_) | Value::String(_) | Value::Boolean(_) | Value::None) => {
+            Pattern::Literal(literal)
+          }
+          _ => return Err(value.error("Expected a number, string, boolean or none")),
+        }
+      }
+      "identifier" => Pattern::Identifier(node.identifier()?),
+      "variant" => {
+        let fields = node.field("fields")?.items()?;
+        Pattern::Variant {
+          name: node.name()?,
+          span: node.span()?,
+          fields: fields.iter().map(Node::pattern).collect::<Result<_, _>>()?,
+        }
+      }
+      kind => return Err(node.error(format!("Unknown pattern \"{}\"", kind))),
+    };
+    Ok(pattern)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::samples::{self, parse, without_spans};
+
+  const PRINT: &str = r#"{"version": 1, "kind": "ast", "instructions": [{"kind": "print",
+    "value": {"kind": "expression", "operator": "+", "left": {"kind": "number", "value": 1},
+    "right": {"kind": "number", "value": 2}}}]}"#;
+
+  fn read(json: &str, max_nesting: Option<usize>) -> Result<Vec<Instruction>, String> {
+    let json: Json = serde_json::from_str(json).expect("The test JSON is not JSON");
+    ast_from_json(&json, max_nesting).map_err(|error| error.to_string())
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn the_ast_reads_back_as_it_was_written() {
+    let mut samples = samples::all();
+    samples.extend(samples::edge_cases());
+    for (name, code) in samples {
+      let json = ast_to_json(&parse(&code));
+      let text = serde_json::to_string(&json).unwrap();
+      let read_back =
+        read(&text, None).unwrap_or_else(|error| panic!("{} does not read back: {}", name, error));
+      assert_eq!(ast_to_json(&read_back), json, "{}", name);
+
+      // Without spans the nodes read back with default spans and nothing else changes
+      let mut bare = json;
+      without_spans(&mut bare);
+      let read_back = read(&serde_json::to_string(&bare).unwrap(), None)
+        .unwrap_or_else(|error| panic!("{} does not read back without spans: {}", name, error));
+      let mut read_back = ast_to_json(&read_back);
+      without_spans(&mut read_back);
+      assert_eq!(read_back, bare, "{}", name);
+    }
+  }
+
+  #[test]
+  fn malformed_asts_are_rejected_with_where_they_are_wrong() {
+    assert!(read(PRINT, None).is_ok());
+    let cases = [
+      (
+        PRINT.replace("\"version\": 1", "\"version\": 2"),
+        "Version 2 is not supported, only 1 is",
+      ),
+      (
+        PRINT.replace("\"ast\"", "\"tokens\""),
+        "Expected an ast, found tokens",
+      ),
+      (
+        PRINT.replace("\"instructions\"", "\"statements\""),
+        "Missing \"instructions\"",
+      ),
+      (
+        PRINT.replace("\"print\"", "\"shout\""),
+        "instructions[0]: Unknown instruction \"shout\"",
+      ),
+      (
+        PRINT.replace("\"+\"", "\"**\""),
+        "instructions[0].value.operator: Unknown operator \"**\"",
+      ),
+      (
+        PRINT.replace("\"value\": 2", "\"value\": \"2\""),
+        "instructions[0].value.right.value: Expected a number",
+      ),
+      (
+        PRINT.replace("\"left\"", "\"first\""),
+        "instructions[0].value: Missing \"left\"",
+      ),
+      (
+        PRINT.replace("[{", "[[{").replace("}]}", "}]]}"),
+        "instructions[0]: Expected an object",
+      ),
+      (
+        PRINT.replace(
+          "\"print\",",
+          "\"print\", \"span\": {\"start\": {\"line\": 1}, \"end\": 3},",
+        ),
+        "instructions[0].span.start: Missing \"offset\"",
+      ),
+    ];
+    for (json, error) in cases {
+      assert_eq!(read(&json, None).unwrap_err(), error, "{}", json);
+    }
+    assert_eq!(
+      read(PRINT, Some(1)).unwrap_err(),
+      "instructions[0].value: Limit exceeded, the code is nested more than 1 levels"
+    );
+    assert_eq!(
+      read(
+        &PRINT.replace("\"value\": 1", "\"value\": 18446744073709551615"),
+        None
+      )
+      .unwrap_err(),
+      "instructions[0].value.left.value: Expected a number that fits in 64 bits"
+    );
+  }
+
+  #[test]
+  fn depth_counts_brackets_outside_of_strings() {
+    assert_eq!(json_depth(PRINT), 5);
+    assert_eq!(json_depth(r#"[{"a": "[[[{{{\"]]]"}]"#), 2);
+    assert_eq!(json_depth("1"), 0);
+  }
+}
@@ -0,0 +1,448 @@
+use std::{
+  fmt::Write,
+  fs, io,
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
+};
+
+use crate::{
+  interpreter::{self, Hooks, InterpreterError},
+  limits::Limits,
+  parser::{self, InstructionKind},
+  resolver, tokenizer,
+};
+
+#[derive(Debug)]
+pub enum Outcome {
+  Passed,
+  // An assert did not hold, with the statement and what went wrong
+  Failed(String),
+  // The test stopped on any other error
+  Error(String),
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+  pub file: String,
+  pub name: String,
+  pub outcome: Outcome,
+  pub time: Duration,
+  // What the test printed
+  pub output: String,
+}
+
+// Everything a test prints is kept for the report instead of going to stdout
+#[derive(Default)]
+struct Output(String);
+
+impl Hooks for Output {
+  fn print(&mut self, text: &str) -> Result<(), InterpreterError> {
+    self.0.push_str(text);
+    self.0.push('\n');
+    Ok(())
+  }
+}
+
+// The .fsh files in the paths, directories are searched through except for hidden ones and target
+pub fn find_files(paths: &[&str]) -> io::Result<Vec<PathBuf>> {
+  let mut files = Vec::new();
+  for path in paths {
+    let path = Path::new(path);
+    if path.is_dir() {
+      collect_files(path, &mut files)?;
+    } else {
+      files.push(path.to_path_buf());
+    }
+  }
+  Ok(files)
+}
+
+fn collect_files(directory: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+  let mut entries: Vec<PathBuf> = fs::read_dir(directory)?
+    .map(|entry| entry.map(|entry| entry.path()))
+    .collect::<io::Result<_>>()?;
+  entries.sort();
+  for path in entries {
+    let name = path
+      .file_name()
+      .map_or(String::new(), |name| name.to_string_lossy().to_string());
+    if path.is_dir() {
+      if !name.starts_with('.') && name != "target" {
+        collect_files(&path, files)?;
+      }
+    } else if path.extension().is_some_and(|extension| extension == "fsh") {
+      files.push(path);
+    }
+  }
+  Ok(())
+}
+
+/*
+ Runs the tests in a script whose names contain the filter, each in a VM of its own with reading
+ input turned off. A script that does not parse has no tests to run, the errors are returned.
+*/
+pub fn run_tests(file: &str, code: &str, filter: Option<&str>) -> Result<Vec<TestResult>, String> {
+  let tokens = tokenizer::tokenize(code)
+    .map_err(|error| format!("Error tokenizing code at {}:{}", file, error))?;
+  let limits = Limits {
+    allow_input: false,
+    ..Limits::default()
+  };
+  let (mut instructions, diagnostics) = parser::parse(tokens, &limits);
+  if !diagnostics.is_empty() {
+    let errors: Vec<String> = diagnostics
+      .iter()
+      .map(|diagnostic| format!("Error parsing code at {}:{}", file, diagnostic))
+      .collect();
+    return Err(errors.join("\n"));
+  }
+  resolver::resolve(&mut instructions);
+
+  let mut results = Vec::new();
+  for test in &instructions {
+    let InstructionKind::Test { name, .. } = &test.kind else {
+      continue;
+    };
+    if filter.is_some_and(|filter| !name.contains(filter)) {
+      continue;
+    }
+    let mut output = Output::default();
+    let started = Instant::now();
+    let result = interpreter::run_test(&instructions, test, limits.clone(), &mut output);
+    let time = started.elapsed();
+    let outcome = match result {
+      Ok(()) => Outcome::Passed,
+      Err(InterpreterError::AssertionFailed(failure)) => {
+        let statement = &code[failure.span.start.offset..failure.span.end.offset];
+        let error = InterpreterError::AssertionFailed(failure);
+        Outcome::Failed(format!("{}\n{}", statement, error))
+      }
+      Err(error) => Outcome::Error(format!("Error interpreting code: {}", error)),
+    };
+    results.push(TestResult {
+      file: file.to_string(),
+      name: name.clone(),
+      outcome,
+      time,
+      output: output.0,
+    });
+  }
+  Ok(results)
+}
+
+// A line per test, then what went wrong in the ones that did not pass
+pub fn text_report(results: &[TestResult]) -> String {
+  let mut report = String::new();
+  for result in results {
+    let status = match result.outcome {
+      Outcome::Passed => "ok",
+      Outcome::Failed(_) => "FAILED",
+      Outcome::Error(_) => "ERROR",
+    };
+    let _ = writeln!(
+      report,
+      "test {}: {} ... {}",
+      result.file, result.name, status
+    );
+  }
+  for result in results {
+    let message = match &result.outcome {
+      Outcome::Passed => continue,
+      Outcome::Failed(message) | Outcome::Error(message) => message,
+    };
+    let _ = writeln!(report, "\n---- {}: {} ----", result.file, result.name);
+    for line in message.lines() {
+      let _ = writeln!(report, "  {}", line);
+    }
+    if !result.output.is_empty() {
+      let _ = writeln!(report, "  output:");
+      for line in result.output.lines() {
+        let _ = writeln!(report, "    {}", line);
+      }
+    }
+  }
+  let (passed, failed, errors) = counts(results);
+  let _ = writeln!(
+    report,
+    "\n{} passed, {} failed, {} errors",
+    passed, failed, errors
+  );
+  report
+}
+
+// A test suite per file, failed asserts are failures and other errors are errors
+pub fn junit_report(results: &[TestResult]) -> String {
+  let (passed, failed, errors) = counts(results);
+  let total: Duration = results.iter().map(|result| result.time).sum();
+  let mut report = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  let _ = writeln!(
+    report,
+    "<testsuites name=\"fish\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.6}\">",
+    passed + failed + errors,
+    failed,
+    errors,
+    total.as_secs_f64()
+  );
+  let mut index = 0;
+  while index < results.len() {
+    let file = &results[index].file;
+    let suite: Vec<&TestResult> = results[index..]
+      .iter()
+      .take_while(|result| result.file == *file)
+      .collect();
+    index += suite.len();
+    let (_, failed, errors) = counts(suite.iter().copied());
+    let time: Duration = suite.iter().map(|result| result.time).sum();
+    let _ = writeln!(
+      report,
+      "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.6}\">",
+      escape_xml(file),
+      suite.len(),
+      failed,
+      errors,
+      time.as_secs_f64()
+    );
+    for result in suite {
+      let _ = write!(
+        report,
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.6}\"",
+        escape_xml(&result.name),
+        escape_xml(file),
+        result.time.as_secs_f64()
+      );
+      let problem = match &result.outcome {
+        Outcome::Passed => None,
+        Outcome::Failed(message) => Some(("failure", "AssertionFailed", message)),
+        Outcome::Error(message) => Some(("error", "InterpreterError", message)),
+      };
+      if problem.is_none() && result.output.is_empty() {
+        report.push_str("/>\n");
+        continue;
+      }
+      report.push_str(">\n");
+      if let Some((tag, kind, message)) = problem {
+        let summary = message.lines().last().unwrap_or_default();
+        let _ = writeln!(
+          report,
+          "      <{} message=\"{}\" type=\"{}\">{}</{}>",
+          tag,
+          escape_xml(summary),
+          kind,
+          escape_xml(message),
+          tag
+        );
+      }
+      if !result.output.is_empty() {
+        let _ = writeln!(
+          report,
+          "      <system-out>{}</system-out>",
+          escape_xml(&result.output)
+        );
+      }
+      report.push_str("    </testcase>\n");
+    }
+    report.push_str("  </testsuite>\n");
+  }
+  report.push_str("</testsuites>\n");
+  report
+}
+
+pub fn json_report(results: &[TestResult]) -> String {
+  let (passed, failed, errors) = counts(results);
+  let tests: Vec<serde_json::Value> = results
+    .iter()
+    .map(|result| {
+      let (outcome, message) = match &result.outcome {
+        Outcome::Passed => ("passed", None),
+        Outcome::Failed(message) => ("failed", Some(message)),
+        Outcome::Error(message) => ("error", Some(message)),
+      };
+      serde_json::json!({
+        "file": result.file,
+        "name": result.name,
+        "outcome": outcome,
+        "message": message,
+        "time": result.time.as_secs_f64(),
+        "output": result.output,
+      })
+    })
+    .collect();
+  let report = serde_json::json!({
+    "tests": tests,
+    "passed": passed,
+    "failed": failed,
+    "errors": errors,
+  });
+  format!("{:#}\n", report)
+}
+
+// Passed, failed and errors
+fn counts<'a>(results: impl IntoIterator<Item = &'a TestResult>) -> (usize, usize, usize) {
+  let mut counts = (0, 0, 0);
+  for result in results {
+    match result.outcome {
+      Outcome::Passed => counts.0 += 1,
+      Outcome::Failed(_) => counts.1 += 1,
+      Outcome::Error(_) => counts.2 += 1,
+    }
+  }
+  counts
+}
+
+fn escape_xml(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CODE: &str = "\
+fn add(a, b) { return a + b; }
+total = add(1, 2);
+test \"adds\" { assert(add(1, 2) == 3); }
+test \"fails <&>\" { print(\"before\"); assert(add(1, 1) == 3, \"one and one\"); }
+test \"sees no variables\" { print(total); }
+";
+
+  // The results with no time taken, so the reports are the same on every run
+  fn results_of(file: &str, code: &str, filter: Option<&str>) -> Vec<TestResult> {
+    let mut results = run_tests(file, code, filter).expect("The tests could not run");
+    for result in &mut results {
+      result.time = Duration::ZERO;
+    }
+    results
+  }
+
+  #[test]
+  fn tests_pass_fail_and_error_on_their_own() {
+    let results = results_of("add.fsh", CODE, None);
+    assert_eq!(counts(&results), (1, 1, 1));
+    let outcomes: Vec<String> = results
+      .iter()
+      .map(|result| format!("{} {:?} {:?}", result.name, result.outcome, result.output))
+      .collect();
+    assert_eq!(
+      outcomes,
+      [
+        "adds Passed \"\"",
+        "fails <&> Failed(\"assert(add(1, 1) == 3, \\\"one and one\\\")\\nAssertion failed at 4:37: \
+         one and one (2 == 3 is false)\") \"before\\n\"",
+        "sees no variables Error(\"Error interpreting code: Variable 'total' is not defined\") \"\"",
+      ]
+    );
+    let filtered = results_of("add.fsh", CODE, Some("fail"));
+    assert_eq!(counts(&filtered), (0, 1, 0));
+    assert!(run_tests("broken.fsh", "test \"x\" {", None).is_err());
+  }
+
+  #[test]
+  fn the_text_report_lists_the_tests_then_what_went_wrong() {
+    assert_eq!(
+      text_report(&results_of("add.fsh", CODE, None)),
+      "\
+test add.fsh: adds ... ok
+test add.fsh: fails <&> ... FAILED
+test add.fsh: sees no variables ... ERROR
+
+---- add.fsh: fails <&> ----
+  assert(add(1, 1) == 3, \"one and one\")
+  Assertion failed at 4:37: one and one (2 == 3 is false)
+  output:
+    before
+
+---- add.fsh: sees no variables ----
+  Error interpreting code: Variable 'total' is not defined
+
+1 passed, 1 failed, 1 errors
+"
+    );
+  }
+
+  #[test]
+  fn the_junit_report_has_a_suite_per_file() {
+    let mut results = results_of("add.fsh", CODE, None);
+    results.extend(results_of(
+      "other.fsh",
+      "test \"other\" { assert(true); }",
+      None,
+    ));
+    assert_eq!(
+      junit_report(&results),
+      "\
+<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<testsuites name=\"fish\" tests=\"4\" failures=\"1\" errors=\"1\" time=\"0.000000\">
+  <testsuite name=\"add.fsh\" tests=\"3\" failures=\"1\" errors=\"1\" time=\"0.000000\">
+    <testcase name=\"adds\" classname=\"add.fsh\" time=\"0.000000\"/>
+    <testcase name=\"fails &lt;&amp;&gt;\" classname=\"add.fsh\" time=\"0.000000\">
+      <failure message=\"Assertion failed at 4:37: one and one (2 == 3 is false)\" \
+type=\"AssertionFailed\">assert(add(1, 1) == 3, &quot;one and one&quot;)
+Assertion failed at 4:37: one and one (2 == 3 is false)</failure>
+      <system-out>before
+</system-out>
+    </testcase>
+    <testcase name=\"sees no variables\" classname=\"add.fsh\" time=\"0.000000\">
+      <error message=\"Error interpreting code: Variable &apos;total&apos; is not defined\" \
+type=\"InterpreterError\">Error interpreting code: Variable &apos;total&apos; is not defined</error>
+    </testcase>
+  </testsuite>
+  <testsuite name=\"other.fsh\" tests=\"1\" failures=\"0\" errors=\"0\" time=\"0.000000\">
+    <testcase name=\"other\" classname=\"other.fsh\" time=\"0.000000\"/>
+  </testsuite>
+</testsuites>
+"
+    );
+  }
+
+  #[test]
+  fn the_json_report_has_every_test_and_the_counts() {
+    let report: serde_json::Value =
+      serde_json::from_str(&json_report(&results_of("add.fsh", CODE, None))).unwrap();
+    assert_eq!(
+      report,
+      serde_json::json!({
+        "passed": 1,
+        "failed": 1,
+        "errors": 1,
+        "tests": [
+          {
+            "file": "add.fsh",
+            "name": "adds",
+            "outcome": "passed",
+            "message": null,
+            "time": 0.0,
+            "output": "",
+          },
+          {
+            "file": "add.fsh",
+            "name": "fails <&>",
+            "outcome": "failed",
+            "message": "assert(add(1, 1) == 3, \"one and one\")\n\
+                        Assertion failed at 4:37: one and one (2 == 3 is false)",
+            "time": 0.0,
+            "output": "before\n",
+          },
+          {
+            "file": "add.fsh",
+            "name": "sees no variables",
+            "outcome": "error",
+            "message": "Error interpreting code: Variable 'total' is not defined",
+            "time": 0.0,
+            "output": "",
+          },
+        ],
+      })
+    );
+  }
+}
@@ -1,148 +1,455 @@
-use std::ops::Add;
+use std::fmt;
 
-use crate::number::Number;
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::{diagnostic, number::Number};
 
 #[derive(Debug)]
 pub enum TokenizerError {
   UnknownOperator(String),
+  UnexpectedCharacter(char),
+  UnterminatedString,
+  UnterminatedComment,
+  InvalidNumber(String),
 }
 
-pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenizerError> {
-  let mut tokens = Vec::new();
-  let mut chars = input.chars().peekable();
-  while let Some(c) = chars.next() {
-    match c {
+impl fmt::Display for TokenizerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TokenizerError::UnknownOperator(operator) => {
+        write!(f, "Unknown operator '{}'", operator)?;
+        // Catches swapped characters, like =! instead of !=
+        let reversed: String = operator.chars().rev().collect();
+        match operator_token(&reversed) {
+          Some(Token::Operator(_)) => write!(f, ", did you mean '{}'?", reversed),
+          _ => Ok(()),
+        }
+      }
+      TokenizerError::UnexpectedCharacter(c) => {
+        write!(f, "Unexpected character '{}'", c.escape_debug())
+      }
+      TokenizerError::UnterminatedString => write!(f, "Unterminated string, missing a closing \""),
+      TokenizerError::UnterminatedComment => {
+        write!(f, "Unterminated comment, missing a closing */")
+      }
+      TokenizerError::InvalidNumber(number) => write!(f, "Invalid number '{}'", number),
+    }
+  }
+}
+
+pub type Diagnostic = diagnostic::Diagnostic<TokenizerError>;
+
+/*
+ Comments are not tokens, they are kept as trivia on the tokens around them. A comment on the
+ same line as the token before it trails that token, any other comment leads the next token.
+ The last token is always EndOfFile, which holds the comments at the end of the file.
+*/
+pub fn tokenize(input: &str) -> Result<Vec<Lexeme>, Diagnostic> {
+  let mut tokens: Vec<Lexeme> = Vec::new();
+  let mut leading = Vec::new();
+  let mut chars = Cursor::new(input);
+  while let Some(c) = chars.peek() {
+    let start = chars.position();
+    chars.next();
+    let error = |error, chars: &Cursor| {
+      Diagnostic::new(
+        error,
+        Span {
+          start,
+          end: chars.position(),
+        },
+      )
+    };
+    let token = match c {
       ' ' | '\t' | '\r' | '\n' => continue,
-      ';' => {
-        tokens.push(Token::EndStatement);
+      '/' | '#' if c == '#' || matches!(chars.peek(), Some('/' | '*')) => {
+        let kind = if c == '/' && chars.next() == Some('*') {
+          let mut depth = 1;
+          while depth > 0 {
+            match chars.next() {
+              Some('*') if chars.peek() == Some('/') => {
+                chars.next();
+                depth -= 1;
+              }
+              Some('/') if chars.peek() == Some('*') => {
+                chars.next();
+                depth += 1;
+              }
+              Some(_) => (),
+              None => return Err(error(TokenizerError::UnterminatedComment, &chars)),
+            }
+          }
+          TriviaKind::BlockComment
+        } else {
+          while chars.peek().is_some_and(|c| c != '\n') {
+            chars.next();
+          }
+          TriviaKind::LineComment
+        };
+        let end = chars.position();
+        let trivia = Trivia {
+          kind,
+          text: input[start.offset..end.offset].to_string(),
+          span: Span { start, end },
+        };
+        match tokens.last_mut() {
+          Some(last) if last.span.end.line == start.line => last.trailing.push(trivia),
+          _ => leading.push(trivia),
+        }
+        continue;
       }
+      ';' => Token::EndStatement,
       '0'..='9' => {
         let mut number = String::new();
         number.push(c);
-        while let Some(&('0'..='9') | &'.' | &',' | &'_') = chars.peek() {
+        while let Some('0'..='9' | '.' | '_') = chars.peek() {
           number.push(chars.next().unwrap());
         }
-        let number = number.replace("_", "");
-        let number = number.replace(",", "");
+        let digits = number.replace('_', "");
 
-        // TODO: Improved number parsing. You know this had to be done.
-        if number.contains('.') {
-          tokens.push(Token::Number(Number::Float(number.parse().unwrap())));
+        let parsed = if digits.contains('.') {
+          digits.parse().map(Number::Float).ok()
         } else {
-          tokens.push(Token::Number(Number::Integer(number.parse().unwrap())));
+          digits.parse().map(Number::Integer).ok()
+        };
+        match parsed {
+          Some(number) => Token::Number(number),
+          None => return Err(error(TokenizerError::InvalidNumber(number), &chars)),
         }
       }
-      'a'..='z' | 'A'..='Z' | '_' => {
+      // Identifiers follow UAX #31, with _ allowed at the start as well
+      c if c == '_' || is_xid_start(c) => {
         let mut identifier = String::new();
         identifier.push(c);
-        while let Some(&('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) = chars.peek() {
-          identifier.push(chars.next().unwrap());
+        while let Some(c) = chars.peek().filter(|&c| is_xid_continue(c)) {
+          identifier.push(c);
+          chars.next();
         }
         match identifier.as_str() {
-          "if" => tokens.push(Token::Keyword(Keyword::If)),
-          "else" => tokens.push(Token::Keyword(Keyword::Else)),
-          "while" => tokens.push(Token::Keyword(Keyword::While)),
-          "print" => tokens.push(Token::Keyword(Keyword::Print)),
-          "input" => tokens.push(Token::Keyword(Keyword::Input)),
-          "break" => tokens.push(Token::Keyword(Keyword::Break)),
-
-          "true" => tokens.push(Token::Boolean(true)),
-          "false" => tokens.push(Token::Boolean(false)),
-          _ => tokens.push(Token::Identifier(identifier)),
+          "if" => Token::Keyword(Keyword::If),
+          "else" => Token::Keyword(Keyword::Else),
+          "while" => Token::Keyword(Keyword::While),
+          "print" => Token::Keyword(Keyword::Print),
+          "input" => Token::Keyword(Keyword::Input),
+          "break" => Token::Keyword(Keyword::Break),
+          "enum" => Token::Keyword(Keyword::Enum),
+          "match" => Token::Keyword(Keyword::Match),
+          "let" => Token::Keyword(Keyword::Let),
+          "fn" => Token::Keyword(Keyword::Fn),
+          "return" => Token::Keyword(Keyword::Return),
+          "assert" => Token::Keyword(Keyword::Assert),
+          "test" => Token::Keyword(Keyword::Test),
+
+          "true" => Token::Boolean(true),
+          "false" => Token::Boolean(false),
+          "none" => Token::None,
+          _ => Token::Identifier(identifier),
         }
       }
-      '+' | '-' | '*' | '/' | '%' | '=' | '!' | '<' | '>' | '^' => {
-        let mut operator = String::new();
-        operator.push(c);
-        while let Some(&('=' | '=')) = chars.peek() {
-          operator.push(chars.next().unwrap());
+      '?' => match chars.peek() {
+        Some('?') => {
+          chars.next();
+          Token::Operator(Operator::Coalesce)
         }
-        let operator = match operator.as_str() {
-          "+" => Ok(Operator::Add),
-          "-" => Ok(Operator::Subtract),
-          "*" => Ok(Operator::Multiply),
-          "/" => Ok(Operator::Divide),
-          "%" => Ok(Operator::Modulo),
-          "^" => Ok(Operator::Exponent),
-
-          "==" => Ok(Operator::Equal),
-          "!=" => Ok(Operator::NotEqual),
-          "<" => Ok(Operator::LessThan),
-          ">" => Ok(Operator::GreaterThan),
-          "<=" => Ok(Operator::LessThanOrEqual),
-          ">=" => Ok(Operator::GreaterThanOrEqual),
-
-          "&&" => Ok(Operator::And),
-          "||" => Ok(Operator::Or),
-          "!" => Ok(Operator::Not),
-
-          "=" => Ok(Operator::Assign),
-          "+=" => Ok(Operator::AddAssign),
-          "-=" => Ok(Operator::SubtractAssign),
-          "*=" => Ok(Operator::MultiplyAssign),
-          "/=" => Ok(Operator::DivideAssign),
-          "%=" => Ok(Operator::ModuloAssign),
-
-          _ => Err(TokenizerError::UnknownOperator(operator)),
-        }?;
-        tokens.push(Token::Operator(operator));
-      }
-      '&' | '|' => {
+        Some('.') => {
+          chars.next();
+          Token::OptionalDot
+        }
+        _ => {
+          return Err(error(
+            TokenizerError::UnknownOperator("?".to_string()),
+            &chars,
+          ))
+        }
+      },
+      c if is_operator_character(c) => {
         let mut operator = String::new();
         operator.push(c);
-        if let Some(&c2) = chars.peek() {
-          if c2 == c {
-            operator.push(chars.next().unwrap());
+        while let Some(c) = chars.peek() {
+          if !is_operator_character(c) || chars.at_comment() {
+            break;
           }
+          operator.push(c);
+          chars.next();
         }
-        // TODO: Throw error if operator is not valid
-      }
-      '"' => {
-        let mut string = String::new();
-        while let Some(&c) = chars.peek() {
-          if c == '"' {
-            chars.next();
-            break;
+        if let Some(token) = operator_token(&operator) {
+          token
+        } else {
+          // A binary operator can be directly followed by nots, like a==!b, but =! is much more
+          // likely to be a typo for != than an assignment of a negation
+          let binary = operator.trim_end_matches('!');
+          let binary = if binary.is_empty() { "!" } else { binary };
+          match operator_token(binary) {
+            Some(token) if binary != "=" => {
+              let span = Span {
+                start,
+                end: start.advanced(binary.len()),
+              };
+              tokens.push(Lexeme::new(token, span, &mut leading));
+              for index in binary.len()..operator.len() {
+                let span = Span {
+                  start: start.advanced(index),
+                  end: start.advanced(index + 1),
+                };
+                tokens.push(Lexeme::new(
+                  Token::Operator(Operator::Not),
+                  span,
+                  &mut leading,
+                ));
+              }
+              continue;
+            }
+            _ => return Err(error(TokenizerError::UnknownOperator(operator), &chars)),
           }
-          string.push(chars.next().unwrap());
         }
-        tokens.push(Token::String(string));
       }
-      '#' => {
-        let mut comment = String::new();
-        while let Some(&c) = chars.peek() {
-          if c == '#' {
-            chars.next();
-            break;
+      '"' => {
+        let mut string = String::new();
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            // \" and \\ are the only escapes, a \ before anything else is kept as it is
+            Some('\\') if matches!(chars.peek(), Some('"' | '\\')) => {
+              string.extend(chars.next());
+            }
+            Some(c) => string.push(c),
+            None => return Err(error(TokenizerError::UnterminatedString, &chars)),
           }
-          comment.push(chars.next().unwrap());
         }
-        tokens.push(Token::Comment(comment));
+        Token::String(string)
       }
-      '{' => tokens.push(Token::ScopeOpen),
-      '}' => tokens.push(Token::ScopeClose),
-      '(' => tokens.push(Token::BracketOpen),
-      ')' => tokens.push(Token::BracketClose),
-      _ => panic!("Unexpected character: {}", c),
-    }
+      '{' => Token::ScopeOpen,
+      '}' => Token::ScopeClose,
+      '(' => Token::BracketOpen,
+      ')' => Token::BracketClose,
+      '[' => Token::SquareBracketOpen,
+      ']' => Token::SquareBracketClose,
+      ',' => Token::Comma,
+      '.' => Token::Dot,
+      ':' => Token::Colon,
+      _ => return Err(error(TokenizerError::UnexpectedCharacter(c), &chars)),
+    };
+    let span = Span {
+      start,
+      end: chars.position(),
+    };
+    tokens.push(Lexeme::new(token, span, &mut leading));
   }
+  let end = chars.position();
+  tokens.push(Lexeme::new(
+    Token::EndOfFile,
+    Span { start: end, end },
+    &mut leading,
+  ));
   Ok(tokens)
 }
 
+fn is_operator_character(c: char) -> bool {
+  matches!(
+    c,
+    '+' | '-' | '*' | '/' | '%' | '=' | '!' | '<' | '>' | '^' | '&' | '|'
+  )
+}
+
+fn operator_token(operator: &str) -> Option<Token> {
+  let operator = match operator {
+    "=>" => return Some(Token::Arrow),
+    "->" => return Some(Token::ReturnArrow),
+
+    "+" => Operator::Add,
+    "-" => Operator::Subtract,
+    "*" => Operator::Multiply,
+    "/" => Operator::Divide,
+    "%" => Operator::Modulo,
+    "^" => Operator::Exponent,
+
+    "==" => Operator::Equal,
+    "!=" => Operator::NotEqual,
+    "<" => Operator::LessThan,
+    ">" => Operator::GreaterThan,
+    "<=" => Operator::LessThanOrEqual,
+    ">=" => Operator::GreaterThanOrEqual,
+
+    "&&" => Operator::And,
+    "||" => Operator::Or,
+    "!" => Operator::Not,
+
+    "=" => Operator::Assign,
+    "+=" => Operator::AddAssign,
+    "-=" => Operator::SubtractAssign,
+    "*=" => Operator::MultiplyAssign,
+    "/=" => Operator::DivideAssign,
+    "%=" => Operator::ModuloAssign,
+
+    _ => return None,
+  };
+  Some(Token::Operator(operator))
+}
+
+// A position in the source, lines and columns start at 1 and columns count characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Position {
+  pub offset: usize,
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Position {
+  // Moves along the line, only valid for ASCII text without line breaks
+  fn advanced(self, characters: usize) -> Self {
+    Self {
+      offset: self.offset + characters,
+      line: self.line,
+      column: self.column + characters,
+    }
+  }
+}
+
+impl fmt::Display for Position {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.column)
+  }
+}
+
+// The source a token was read from, `end` is the position just after its last character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Span {
+  pub start: Position,
+  pub end: Position,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lexeme {
+  pub token: Token,
+  pub span: Span,
+  pub leading: Vec<Trivia>,
+  pub trailing: Vec<Trivia>,
+}
+
+impl Lexeme {
+  fn new(token: Token, span: Span, leading: &mut Vec<Trivia>) -> Self {
+    Self {
+      token,
+      span,
+      leading: std::mem::take(leading),
+      trailing: Vec::new(),
+    }
+  }
+}
+
+// A comment, `text` is exactly what was in the source including the comment markers
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trivia {
+  pub kind: TriviaKind,
+  pub text: String,
+  pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TriviaKind {
+  LineComment,  // // or #
+  BlockComment, // /* */, can be nested
+}
+
+struct Cursor<'a> {
+  chars: std::str::Chars<'a>,
+  position: Position,
+}
+
+impl<'a> Cursor<'a> {
+  fn new(input: &'a str) -> Self {
+    Self {
+      chars: input.chars(),
+      position: Position {
+        offset: 0,
+        line: 1,
+        column: 1,
+      },
+    }
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.chars.clone().next()
+  }
+
+  fn at_comment(&self) -> bool {
+    let mut chars = self.chars.clone();
+    chars.next() == Some('/') && matches!(chars.next(), Some('/' | '*'))
+  }
+
+  fn position(&self) -> Position {
+    self.position
+  }
+}
+
+impl Iterator for Cursor<'_> {
+  type Item = char;
+
+  fn next(&mut self) -> Option<char> {
+    let c = self.chars.next()?;
+    self.position.offset += c.len_utf8();
+    if c == '\n' {
+      self.position.line += 1;
+      self.position.column = 1;
+    } else {
+      self.position.column += 1;
+    }
+    Some(c)
+  }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
   EndStatement,       // ;
-  Identifier(String), // [a-zA-Z_][a-zA-Z0-9_]*
+  Identifier(String), // XID_Start XID_Continue*
   Number(Number),     // [0-9]+
   String(String),     // ".*"
   Operator(Operator), // + - * / % = == != < > <= >=
-  Keyword(Keyword),   // if else while
-  Comment(String),    // #/.*#
+  Keyword(Keyword),   // if else while enum match let fn return assert test
   ScopeOpen,          // {
   ScopeClose,         // }
   BracketOpen,        // (
   BracketClose,       // )
   Boolean(bool),      // true false
+  None,               // none
+  SquareBracketOpen,  // [
+  SquareBracketClose, // ]
+  Comma,              // ,
+  Dot,                // .
+  OptionalDot,        // ?.
+  Arrow,              // =>
+  ReturnArrow,        // ->
+  Colon,              // :
+  EndOfFile,
+}
+
+// How a token is named in messages, like "identifier `x`" or "`;`"
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Token::EndStatement => write!(f, "`;`"),
+      Token::Identifier(identifier) => write!(f, "identifier `{}`", identifier),
+      Token::Number(number) => write!(f, "number `{}`", number),
+      Token::String(string) => write!(f, "string \"{}\"", string.escape_debug()),
+      Token::Operator(operator) => write!(f, "`{}`", operator),
+      Token::Keyword(keyword) => write!(f, "`{}`", keyword),
+      Token::ScopeOpen => write!(f, "`{{`"),
+      Token::ScopeClose => write!(f, "`}}`"),
+      Token::BracketOpen => write!(f, "`(`"),
+      Token::BracketClose => write!(f, "`)`"),
+      Token::Boolean(boolean) => write!(f, "`{}`", boolean),
+      Token::None => write!(f, "`none`"),
+      Token::SquareBracketOpen => write!(f, "`[`"),
+      Token::SquareBracketClose => write!(f, "`]`"),
+      Token::Comma => write!(f, "`,`"),
+      Token::Dot => write!(f, "`.`"),
+      Token::OptionalDot => write!(f, "`?.`"),
+      Token::Arrow => write!(f, "`=>`"),
+      Token::ReturnArrow => write!(f, "`->`"),
+      Token::Colon => write!(f, "`:`"),
+      Token::EndOfFile => write!(f, "end of file"),
+    }
+  }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -153,6 +460,34 @@ pub enum Keyword {
   Print,
   Input,
   Break,
+  Enum,
+  Match,
+  Let,
+  Fn,
+  Return,
+  Assert,
+  Test,
+}
+
+impl fmt::Display for Keyword {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let keyword = match self {
+      Keyword::If => "if",
+      Keyword::Else => "else",
+      Keyword::While => "while",
+      Keyword::Print => "print",
+      Keyword::Input => "input",
+      Keyword::Break => "break",
+      Keyword::Enum => "enum",
+      Keyword::Match => "match",
+      Keyword::Let => "let",
+      Keyword::Fn => "fn",
+      Keyword::Return => "return",
+      Keyword::Assert => "assert",
+      Keyword::Test => "test",
+    };
+    write!(f, "{}", keyword)
+  }
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -175,6 +510,7 @@ pub enum Operator {
   And,
   Or,
   Not,
+  Coalesce,
   // TODO: Fix this jank, there are bracket tokens and a bracket operator
   Brackets,
 
@@ -185,3 +521,222 @@ pub enum Operator {
   DivideAssign,
   ModuloAssign,
 }
+
+// Every operator, in the order the compiled format numbers them
+pub const OPERATORS: [Operator; 23] = [
+  Operator::Add,
+  Operator::Subtract,
+  Operator::Multiply,
+  Operator::Divide,
+  Operator::Modulo,
+  Operator::Exponent,
+  Operator::Equal,
+  Operator::NotEqual,
+  Operator::LessThan,
+  Operator::GreaterThan,
+  Operator::LessThanOrEqual,
+  Operator::GreaterThanOrEqual,
+  Operator::And,
+  Operator::Or,
+  Operator::Not,
+  Operator::Coalesce,
+  Operator::Brackets,
+  Operator::Assign,
+  Operator::AddAssign,
+  Operator::SubtractAssign,
+  Operator::MultiplyAssign,
+  Operator::DivideAssign,
+  Operator::ModuloAssign,
+];
+
+impl Operator {
+  pub fn is_comparison(self) -> bool {
+    matches!(
+      self,
+      Operator::Equal
+        | Operator::NotEqual
+        | Operator::LessThan
+        | Operator::LessThanOrEqual
+        | Operator::GreaterThan
+        | Operator::GreaterThanOrEqual
+    )
+  }
+
+  pub fn is_assignment(self) -> bool {
+    matches!(
+      self,
+      Operator::Assign
+        | Operator::AddAssign
+        | Operator::SubtractAssign
+        | Operator::MultiplyAssign
+        | Operator::DivideAssign
+        | Operator::ModuloAssign
+    )
+  }
+}
+
+impl fmt::Display for Operator {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let symbol = match self {
+      Operator::Add => "+",
+      Operator::Subtract => "-",
+      Operator::Multiply => "*",
+      Operator::Divide => "/",
+      Operator::Modulo => "%",
+      Operator::Exponent => "^",
+      Operator::Equal => "==",
+      Operator::NotEqual => "!=",
+      Operator::LessThan => "<",
+      Operator::GreaterThan => ">",
+      Operator::LessThanOrEqual => "<=",
+      Operator::GreaterThanOrEqual => ">=",
+      Operator::And => "&&",
+      Operator::Or => "||",
+      Operator::Not => "!",
+      Operator::Coalesce => "??",
+      Operator::Brackets => "()",
+      Operator::Assign => "=",
+      Operator::AddAssign => "+=",
+      Operator::SubtractAssign => "-=",
+      Operator::MultiplyAssign => "*=",
+      Operator::DivideAssign => "/=",
+      Operator::ModuloAssign => "%=",
+    };
+    write!(f, "{}", symbol)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Where an error is and what it says, like `1:5-1:7: Unknown operator '=!'`
+  fn error(code: &str) -> String {
+    let diagnostic = tokenize(code).expect_err("The code tokenizes");
+    format!(
+      "{}-{}: {}",
+      diagnostic.span.start, diagnostic.span.end, diagnostic.error
+    )
+  }
+
+  fn tokens(code: &str) -> Vec<String> {
+    tokenize(code)
+      .expect("The code does not tokenize")
+      .iter()
+      .map(|lexeme| {
+        format!(
+          "{}-{} {:?}",
+          lexeme.span.start, lexeme.span.end, lexeme.token
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn errors_say_what_is_wrong_and_where() {
+    let cases = [
+      (
+        "x = \"abc;\nprint(x);",
+        "1:5-2:10: Unterminated string, missing a closing \"",
+      ),
+      (
+        "x = 1; /* a /* nested */ comment",
+        "1:8-1:33: Unterminated comment, missing a closing */",
+      ),
+      (
+        "x = \"a \\\" b\\\";",
+        "1:5-1:15: Unterminated string, missing a closing \"",
+      ),
+      ("x = 1 @ 2;", "1:7-1:8: Unexpected character '@'"),
+      (
+        "if (x =! 1) {}",
+        "1:7-1:9: Unknown operator '=!', did you mean '!='?",
+      ),
+      ("x <<= 1;", "1:3-1:6: Unknown operator '<<='"),
+      ("x = a ? b;", "1:7-1:8: Unknown operator '?'"),
+      ("x = 1.2.3;", "1:5-1:10: Invalid number '1.2.3'"),
+      (
+        "x = 99999999999999999999;",
+        "1:5-1:25: Invalid number '99999999999999999999'",
+      ),
+    ];
+    for (code, expected) in cases {
+      assert_eq!(error(code), expected, "{}", code);
+    }
+  }
+
+  #[test]
+  fn identifiers_can_be_in_any_script() {
+    // Columns count characters, so a combining accent takes a column of its own
+    assert_eq!(
+      tokens("café = _x1 + 名前 + e\u{301};"),
+      [
+        "1:1-1:5 Identifier(\"café\")",
+        "1:6-1:7 Operator(Assign)",
+        "1:8-1:11 Identifier(\"_x1\")",
+        "1:12-1:13 Operator(Add)",
+        "1:14-1:16 Identifier(\"名前\")",
+        "1:17-1:18 Operator(Add)",
+        "1:19-1:21 Identifier(\"e\\u{301}\")",
+        "1:21-1:22 EndStatement",
+        "1:22-1:22 EndOfFile",
+      ]
+    );
+    assert_eq!(error("x = 🐟;"), "1:5-1:6: Unexpected character '🐟'");
+    // Only ASCII spaces separate tokens
+    assert_eq!(
+      error("x = 1\u{2003}+ 1;"),
+      "1:6-1:7: Unexpected character '\\u{2003}'"
+    );
+  }
+
+  #[test]
+  fn operators_are_split_into_nots_after_a_binary_operator() {
+    assert_eq!(
+      tokens("a==!b != !!c"),
+      [
+        "1:1-1:2 Identifier(\"a\")",
+        "1:2-1:4 Operator(Equal)",
+        "1:4-1:5 Operator(Not)",
+        "1:5-1:6 Identifier(\"b\")",
+        "1:7-1:9 Operator(NotEqual)",
+        "1:10-1:11 Operator(Not)",
+        "1:11-1:12 Operator(Not)",
+        "1:12-1:13 Identifier(\"c\")",
+        "1:13-1:13 EndOfFile",
+      ]
+    );
+  }
+
+  #[test]
+  fn comments_are_kept_on_the_tokens_around_them() {
+    let code = "\
+// leading
+x = 1; # trailing
+/* block /* nested */
+   over lines */ print(x); /* after */
+// at the end
+";
+    let mut trivia = Vec::new();
+    for lexeme in tokenize(code).unwrap() {
+      for (side, comments) in [("leads", &lexeme.leading), ("trails", &lexeme.trailing)] {
+        for comment in comments {
+          trivia.push(format!(
+            "{}-{} {:?} {:?} {} {:?}",
+            comment.span.start, comment.span.end, comment.kind, comment.text, side, lexeme.token
+          ));
+        }
+      }
+    }
+    assert_eq!(
+      trivia,
+      [
+        "1:1-1:11 LineComment \"// leading\" leads Identifier(\"x\")",
+        "2:8-2:18 LineComment \"# trailing\" trails EndStatement",
+        "3:1-4:17 BlockComment \"/* block /* nested */\\n   over lines */\" leads Keyword(Print)",
+        "4:28-4:39 BlockComment \"/* after */\" trails EndStatement",
+        "5:1-5:14 LineComment \"// at the end\" leads EndOfFile",
+      ]
+    );
+  }
+}
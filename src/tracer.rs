@@ -0,0 +1,326 @@
+use std::io::{self, Write};
+
+use serde_json::json;
+
+use crate::{
+  interpreter::{Described, Hooks, Inspector, InterpreterError, Scope},
+  parser::{Identifier, Instruction, Value},
+  tokenizer::Position,
+};
+
+// What `--trace` and the options next to it ask for
+#[derive(Debug, Default)]
+pub struct TraceOptions {
+  pub json: bool,
+  // Only what happens on these lines, both included
+  pub lines: Option<(usize, usize)>,
+  // Only reading and assigning the variable with this name
+  pub variable: Option<String>,
+  pub out: Option<String>,
+}
+
+/*
+ `--trace`, writes down every instruction that runs, every value that gets evaluated with what it
+ came out as, and every variable that gets assigned. Each of them has a depth, which goes up for
+ every block and function call they are in and for every value they are part of, and the
+ position in the source they start at. A value without a position of its own, like `a + 1`, is
+ written without one, and is on the lines of the instruction it is part of when narrowing them.
+
+ Values are written once they are known, so the parts of a value come before it. As text every
+ line is indented by its depth, as JSON every line is an object of its own.
+*/
+pub struct Tracer {
+  out: Box<dyn Write>,
+  options: TraceOptions,
+  depth: usize,
+  // Where the instruction that runs in every frame of the VM starts
+  positions: Vec<Position>,
+  // Writing stops at the first error, which `finish` returns
+  error: Option<io::Error>,
+}
+
+impl Tracer {
+  pub fn new(out: Box<dyn Write>, options: TraceOptions) -> Self {
+    Self {
+      out,
+      options,
+      depth: 0,
+      positions: Vec::new(),
+      error: None,
+    }
+  }
+
+  pub fn finish(&mut self) -> io::Result<()> {
+    match self.error.take() {
+      Some(error) => Err(error),
+      None => self.out.flush(),
+    }
+  }
+
+  fn shows_line(&self, position: Position) -> bool {
+    match self.options.lines {
+      Some((first, last)) => (first..=last).contains(&position.line),
+      None => true,
+    }
+  }
+
+  // Instructions and values are one level less deep than the VM is while they run, so the values
+  // of an instruction line up under it like the instructions of a block. An assignment is one
+  // level deeper than what does it
+  fn write(
+    &mut self,
+    event: &str,
+    depth: usize,
+    position: Option<Position>,
+    code: &str,
+    value: Option<Described>,
+  ) {
+    if self.error.is_some() {
+      return;
+    }
+    let indent = "  ".repeat(depth);
+    let at = match position {
+      Some(position) => format!("{}{} ", indent, position),
+      None => indent,
+    };
+    let line = match (self.options.json, value) {
+      (true, value) => {
+        let mut json = json!({
+          "event": event,
+          "depth": depth,
+          "code": code,
+        });
+        if let Some(position) = position {
+          json["line"] = json!(position.line);
+          json["column"] = json!(position.column);
+        }
+        if let Some(value) = value {
+          json["value"] = json!(value.to_string());
+        }
+        json.to_string()
+      }
+      (false, Some(value)) if event == "assign" => {
+        format!("{}set {} = {}", at, code, value)
+      }
+      (false, Some(value)) => format!("{}{} => {}", at, code, value),
+      (false, None) => format!("{}{}", at, code),
+    };
+    if let Err(error) = writeln!(self.out, "{}", line) {
+      self.error = Some(error);
+    }
+  }
+}
+
+// The first line of an instruction, without the brace that opens its block
+fn heading(instruction: &Instruction) -> String {
+  let code = instruction.to_string();
+  let line = code.lines().next().unwrap_or_default();
+  match line.strip_suffix('{') {
+    Some(heading) if !heading.is_empty() => heading.trim_end().to_string(),
+    _ => line.to_string(),
+  }
+}
+
+impl Hooks for Tracer {
+  fn before_instruction(
+    &mut self,
+    instruction: &Instruction,
+    _vm: &mut Inspector,
+  ) -> Result<(), InterpreterError> {
+    let position = instruction.span.start;
+    if let Some(running) = self.positions.last_mut() {
+      *running = position;
+    }
+    if self.options.variable.is_none() && self.shows_line(position) {
+      let depth = self.depth.saturating_sub(1);
+      self.write("instruction", depth, Some(position), &heading(instruction), None);
+    }
+    Ok(())
+  }
+
+  fn scope_enter(&mut self, _scope: Scope) {
+    self.depth += 1;
+    let running = self.positions.last().copied().unwrap_or_default();
+    self.positions.push(running);
+  }
+
+  fn scope_exit(&mut self) {
+    self.depth -= 1;
+    self.positions.pop();
+  }
+
+  fn before_value(&mut self, _value: &Value) {
+    self.depth += 1;
+  }
+
+  fn after_value(&mut self, value: &Value, result: Described) {
+    let position = match value {
+      Value::Identifier(identifier) => Some(identifier.span.start),
+      Value::Call { span, .. } => Some(span.start),
+      _ => None,
+    };
+    let line = position.unwrap_or_else(|| self.positions.last().copied().unwrap_or_default());
+    let variable = match (&self.options.variable, value) {
+      (None, _) => true,
+      (Some(name), Value::Identifier(identifier)) => identifier.name == *name,
+      (Some(_), _) => false,
+    };
+    if variable && self.shows_line(line) {
+      let depth = self.depth - 1;
+      self.write("value", depth, position, &value.to_string(), Some(result));
+    }
+    self.depth -= 1;
+  }
+
+  fn on_assign(&mut self, variable: &Identifier, value: Described) {
+    let position = variable.span.start;
+    let shown = self
+      .options
+      .variable
+      .as_ref()
+      .is_none_or(|name| variable.name == *name);
+    if shown && self.shows_line(position) {
+      self.write("assign", self.depth, Some(position), &variable.name, Some(value));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::RefCell, rc::Rc};
+
+  use serde_json::Value as Json;
+
+  use super::*;
+  use crate::{interpreter, limits::Limits, resolver, samples};
+
+  const CODE: &str = "\
+fn add(a, b) {
+  return a + b;
+}
+x = add(1, 2);
+print(x * 3);
+";
+
+  // Output the test can still read after the tracer took it
+  #[derive(Clone, Default)]
+  struct Shared(Rc<RefCell<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  // Runs the code with the tracer, what it prints is kept instead of going to stdout
+  fn run(tracer: Tracer) -> Tracer {
+    let mut instructions = samples::parse(CODE);
+    resolver::resolve(&mut instructions);
+    let mut hooks = (samples::Output::default(), tracer);
+    interpreter::interpret_with_hooks(&instructions, Limits::default(), &[], &mut hooks)
+      .expect("The script failed");
+    assert_eq!(hooks.0 .0, "9\n");
+    hooks.1
+  }
+
+  fn trace(options: TraceOptions) -> String {
+    let out = Shared::default();
+    let mut tracer = run(Tracer::new(Box::new(out.clone()), options));
+    tracer.finish().unwrap();
+    String::from_utf8(out.0.take()).unwrap()
+  }
+
+  #[test]
+  fn every_instruction_value_and_assignment_is_traced() {
+    // Literals and operators have no position of their own
+    assert_eq!(
+      trace(TraceOptions::default()),
+      "\
+1:1 fn add(a, b)
+4:1 x = add(1, 2);
+      1 => 1
+      2 => 2
+      2:3 return a + b;
+          2:10 a => 1
+          2:14 b => 2
+        a + b => 3
+    4:5 add(1, 2) => 3
+    4:1 set x = 3
+  x = add(1, 2) => 3
+5:1 print(x * 3);
+    5:7 x => 3
+    3 => 3
+  x * 3 => 9
+"
+    );
+  }
+
+  #[test]
+  fn lines_and_variables_narrow_the_trace() {
+    let lines = trace(TraceOptions {
+      lines: Some((2, 3)),
+      ..Default::default()
+    });
+    assert_eq!(
+      lines,
+      "      2:3 return a + b;\n          2:10 a => 1\n          2:14 b => 2\n        a + b => 3\n"
+    );
+    let variable = trace(TraceOptions {
+      variable: Some("x".to_string()),
+      ..Default::default()
+    });
+    assert_eq!(variable, "    4:1 set x = 3\n    5:7 x => 3\n");
+  }
+
+  #[test]
+  fn json_traces_have_an_object_on_every_line() {
+    let json = trace(TraceOptions {
+      json: true,
+      lines: Some((4, 4)),
+      ..Default::default()
+    });
+    let events: Vec<Json> = json
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    let value = |depth, code, value| json!({"event": "value", "depth": depth, "code": code,
+      "value": value});
+    assert_eq!(
+      events,
+      [
+        json!({"event": "instruction", "depth": 0, "line": 4, "column": 1,
+          "code": "x = add(1, 2);"}),
+        value(3, "1", "1"),
+        value(3, "2", "2"),
+        json!({"event": "value", "depth": 2, "line": 4, "column": 5, "code": "add(1, 2)",
+          "value": "3"}),
+        json!({"event": "assign", "depth": 2, "line": 4, "column": 1, "code": "x", "value": "3"}),
+        value(1, "x = add(1, 2)", "3"),
+      ]
+    );
+  }
+
+  #[test]
+  fn writing_stops_at_the_first_error() {
+    struct Closed;
+
+    impl Write for Closed {
+      fn write(&mut self, _bytes: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::BrokenPipe.into())
+      }
+
+      fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+      }
+    }
+
+    let mut tracer = run(Tracer::new(Box::new(Closed), TraceOptions::default()));
+    let error = tracer.finish().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    assert!(tracer.finish().is_ok());
+  }
+}
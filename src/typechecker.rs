@@ -0,0 +1,787 @@
+use std::{
+  collections::{BTreeSet, HashMap, HashSet},
+  fmt,
+};
+
+use crate::{
+  analysis::{self, Named},
+  diagnostic, interpreter,
+  number::Number,
+  parser::{Function, Identifier, Instruction, InstructionKind, Pattern, TypeAnnotation, Value},
+  tokenizer::{Operator, Span},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+  Int,
+  Float,
+  String,
+  Bool,
+  None,
+  List,
+  Enum(String),
+  // Not known before running, anything goes
+  Any,
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Type::Int => write!(f, "int"),
+      Type::Float => write!(f, "float"),
+      Type::String => write!(f, "string"),
+      Type::Bool => write!(f, "bool"),
+      Type::None => write!(f, "none"),
+      Type::List => write!(f, "list"),
+      Type::Enum(name) => write!(f, "{}", name),
+      Type::Any => write!(f, "any"),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum TypeError {
+  // `subject` is what had the wrong type, like "The if condition"
+  Mismatch {
+    subject: String,
+    expected: Type,
+    found: Type,
+  },
+  InvalidOperands {
+    operator: Operator,
+    left: Type,
+    right: Option<Type>,
+  },
+  ArgumentCount {
+    function: String,
+    expected: usize,
+    found: usize,
+  },
+  UnknownType(String),
+}
+
+impl fmt::Display for TypeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      TypeError::Mismatch {
+        subject,
+        expected,
+        found,
+      } => write!(f, "{} has to be {}, found {}", subject, expected, found),
+      TypeError::InvalidOperands {
+        operator,
+        left,
+        right: Some(right),
+      } => write!(
+        f,
+        "Operator '{}' can not be used on {} and {}",
+        operator, left, right
+      ),
+      TypeError::InvalidOperands { operator, left, .. } => {
+        write!(f, "Operator '{}' can not be used on {}", operator, left)
+      }
+      TypeError::ArgumentCount {
+        function,
+        expected,
+        found,
+      } => write!(
+        f,
+        "Function '{}' takes {} arguments but {} were given",
+        function, expected, found
+      ),
+      TypeError::UnknownType(name) => write!(f, "Unknown type '{}'", name),
+    }
+  }
+}
+
+pub type Diagnostic = diagnostic::Diagnostic<TypeError>;
+
+/*
+ Finds type errors without running the code. The checking is gradual: a variable or parameter
+ without an annotation, and anything else that can not be known up front, has the type any,
+ which fits everywhere. Literals, operators, annotated variables and the results of annotated
+ functions do have a type, and are checked wherever they are used. A `let` without an annotation
+ that is never assigned to again has the type of its value.
+
+ Annotations are the names int, float, string, bool, none, list and any, or the name of an enum.
+ An int fits where a float is expected.
+*/
+pub fn check(instructions: &[Instruction]) -> Vec<Diagnostic> {
+  let mut diagnostics = run(instructions).diagnostics;
+  diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+  diagnostics
+}
+
+/*
+ The type of every variable where its name is written, for showing in an editor. A variable
+ without an annotation gets the type of the values assigned to it so far, as long as they all
+ had the same type. That is only a guess, which is why it is never used for checking.
+*/
+pub fn variable_types(instructions: &[Instruction]) -> Vec<(Span, Type)> {
+  run(instructions).types
+}
+
+// Which variables of a `let` are assigned to again is only known after going through all of the
+// code, so it is gone through twice
+fn run(instructions: &[Instruction]) -> Checker {
+  let first = run_knowing(instructions, None);
+  run_knowing(instructions, Some(first.assigned))
+}
+
+fn run_knowing(instructions: &[Instruction], reassigned: Option<BTreeSet<Span>>) -> Checker {
+  let mut checker = Checker {
+    scopes: Vec::new(),
+    enums: HashSet::new(),
+    variants: HashMap::new(),
+    functions: HashMap::new(),
+    function: None,
+    diagnostics: Vec::new(),
+    types: Vec::new(),
+    reassigned,
+    assigned: BTreeSet::new(),
+  };
+  checker.collect_enums(instructions);
+  checker.collect_functions(instructions);
+  checker.scope(instructions);
+  checker
+}
+
+struct Signature {
+  parameters: Vec<(String, Type)>,
+  returns: Type,
+}
+
+struct Variable {
+  name: String,
+  // any without an annotation
+  declared: Type,
+  inferred: Type,
+  // Where the name is in a `let` without an annotation
+  unannotated_let: Option<Span>,
+}
+
+impl Named for Variable {
+  fn name(&self) -> &str {
+    &self.name
+  }
+}
+
+impl Variable {
+  fn shown(&self) -> Type {
+    match self.declared {
+      Type::Any => self.inferred.clone(),
+      _ => self.declared.clone(),
+    }
+  }
+}
+
+struct Checker {
+  // Tracked the same way the resolver does it
+  scopes: Vec<Vec<Variable>>,
+  enums: HashSet<String>,
+  // The enum each variant belongs to
+  variants: HashMap<String, String>,
+  functions: HashMap<String, Signature>,
+  // The name and return type of the function whose body is being checked
+  function: Option<(String, Type)>,
+  diagnostics: Vec<Diagnostic>,
+  types: Vec<(Span, Type)>,
+  // The variables of a `let` without an annotation that are assigned to after it, by where their
+  // name is in the `let`. None until the code has been gone through once
+  reassigned: Option<BTreeSet<Span>>,
+  assigned: BTreeSet<Span>,
+}
+
+impl Checker {
+  fn collect_enums(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      match &instruction.kind {
+        InstructionKind::Enum { name, variants, .. } => {
+          self.enums.insert(name.clone());
+          for variant in variants {
+            self.variants.insert(variant.name.clone(), name.clone());
+          }
+        }
+        kind => {
+          for block in kind.blocks() {
+            self.collect_enums(block);
+          }
+        }
+      }
+    }
+  }
+
+  // Functions can be called before their definition, so all signatures are known up front
+  fn collect_functions(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      if let InstructionKind::Function(function) = &instruction.kind {
+        let parameters = function
+          .parameters
+          .iter()
+          .map(|parameter| {
+            let annotation = parameter.annotation.as_ref();
+            (
+              parameter.variable.name.clone(),
+              self.resolve_annotation(annotation),
+            )
+          })
+          .collect();
+        let returns = self.resolve_annotation(function.return_type.as_ref());
+        self.functions.insert(
+          function.name.clone(),
+          Signature {
+            parameters,
+            returns,
+          },
+        );
+      }
+      for block in instruction.kind.blocks() {
+        self.collect_functions(block);
+      }
+    }
+  }
+
+  fn resolve_annotation(&self, annotation: Option<&TypeAnnotation>) -> Type {
+    let Some(annotation) = annotation else {
+      return Type::Any;
+    };
+    match annotation.name.as_str() {
+      "int" => Type::Int,
+      "float" => Type::Float,
+      "string" => Type::String,
+      "bool" => Type::Bool,
+      "none" => Type::None,
+      "list" => Type::List,
+      "any" => Type::Any,
+      name if self.enums.contains(name) => Type::Enum(name.to_string()),
+      _ => Type::Any,
+    }
+  }
+
+  // Like resolve_annotation, but reports names that are not a type
+  fn annotation(&mut self, annotation: Option<&TypeAnnotation>) -> Type {
+    let resolved = self.resolve_annotation(annotation);
+    if let Some(annotation) = annotation {
+      if resolved == Type::Any && annotation.name != "any" {
+        self.error(
+          TypeError::UnknownType(annotation.name.clone()),
+          annotation.span,
+        );
+      }
+    }
+    resolved
+  }
+
+  fn error(&mut self, error: TypeError, span: Span) {
+    self.diagnostics.push(Diagnostic::new(error, span));
+  }
+
+  fn expect(&mut self, subject: impl Into<String>, expected: &Type, found: Type, span: Span) {
+    if !fits(expected, &found) {
+      self.error(
+        TypeError::Mismatch {
+          subject: subject.into(),
+          expected: expected.clone(),
+          found,
+        },
+        span,
+      );
+    }
+  }
+
+  fn scope(&mut self, instructions: &[Instruction]) {
+    self.scopes.push(Vec::new());
+    self.instructions(instructions);
+    self.scopes.pop();
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    for instruction in instructions {
+      self.instruction(instruction);
+    }
+  }
+
+  // Values have no spans of their own, so errors about them point at the statement they are in
+  fn instruction(&mut self, instruction: &Instruction) {
+    let span = instruction.span;
+    match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      } => {
+        let condition = self.value(condition, span);
+        self.expect("The if condition", &Type::Bool, condition, span);
+        self.scope(instructions);
+      }
+      InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        let condition = self.value(condition, span);
+        self.expect("The while condition", &Type::Bool, condition, span);
+        self.scope(instructions);
+      }
+      InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+        self.scope(instructions);
+      }
+      InstructionKind::Value { value } | InstructionKind::Print { message: value } => {
+        self.value(value, span);
+      }
+      InstructionKind::Input { variable } => self.assignment(variable, Type::String, span),
+      InstructionKind::Match { value, arms } => {
+        self.value(value, span);
+        for arm in arms {
+          self.scopes.push(Vec::new());
+          self.pattern(&arm.pattern);
+          if let Some(guard) = &arm.guard {
+            let guard = self.value(guard, span);
+            self.expect("The match guard", &Type::Bool, guard, span);
+          }
+          self.instructions(&arm.instructions);
+          self.scopes.pop();
+        }
+      }
+      InstructionKind::Let {
+        variable,
+        annotation,
+        value,
+      } => {
+        let found = self.value(value, span);
+        let mut declared = self.annotation(annotation.as_ref());
+        let fixed = match &self.reassigned {
+          Some(reassigned) => !reassigned.contains(&variable.span),
+          None => false,
+        };
+        if annotation.is_none() && fixed {
+          declared = found.clone();
+        }
+        self.expect(
+          format!("Variable '{}'", variable.name),
+          &declared,
+          found.clone(),
+          span,
+        );
+        self.declare(variable, declared, found).unannotated_let =
+          annotation.is_none().then_some(variable.span);
+      }
+      InstructionKind::Function(function) => self.function(function, span),
+      InstructionKind::Return { value } => {
+        let found = match value {
+          Some(value) => self.value(value, span),
+          None => Type::None,
+        };
+        if let Some((name, returns)) = self.function.clone() {
+          self.expect(
+            format!("The return value of '{}'", name),
+            &returns,
+            found,
+            span,
+          );
+        }
+      }
+      InstructionKind::Assert { condition, message } => {
+        let condition = self.value(condition, span);
+        self.expect("The assert condition", &Type::Bool, condition, span);
+        if let Some(message) = message {
+          self.value(message, span);
+        }
+      }
+      InstructionKind::Test { instructions, .. } => {
+        let outer_scopes = std::mem::take(&mut self.scopes);
+        let outer_function = self.function.take();
+        self.scope(instructions);
+        self.scopes = outer_scopes;
+        self.function = outer_function;
+      }
+      InstructionKind::Break | InstructionKind::Enum { .. } => (),
+    }
+  }
+
+  fn function(&mut self, function: &Function, span: Span) {
+    let mut parameters = Vec::new();
+    for parameter in &function.parameters {
+      let declared = self.annotation(parameter.annotation.as_ref());
+      parameters.push((&parameter.variable, declared));
+    }
+    let returns = self.annotation(function.return_type.as_ref());
+    // Falling off the end of a function returns none
+    if !matches!(returns, Type::None | Type::Any) && !returns_always(&function.instructions) {
+      self.error(
+        TypeError::Mismatch {
+          subject: format!("The return value of '{}'", function.name),
+          expected: returns.clone(),
+          found: Type::None,
+        },
+        span,
+      );
+    }
+
+    let outer_scopes = std::mem::replace(&mut self.scopes, vec![Vec::new()]);
+    for (parameter, declared) in parameters {
+      self.declare(parameter, declared.clone(), declared);
+    }
+    let outer_function = self.function.replace((function.name.clone(), returns));
+    self.instructions(&function.instructions);
+    self.scopes = outer_scopes;
+    self.function = outer_function;
+  }
+
+  fn pattern(&mut self, pattern: &Pattern) {
+    match pattern {
+      Pattern::Identifier(identifier) => {
+        if !self.variants.contains_key(&identifier.name) {
+          self.declare(identifier, Type::Any, Type::Any);
+        }
+      }
+      Pattern::Variant { fields, .. } => {
+        for field in fields {
+          self.pattern(field);
+        }
+      }
+      Pattern::Wildcard | Pattern::Literal(_) => (),
+    }
+  }
+
+  fn value(&mut self, value: &Value, span: Span) -> Type {
+    match value {
+      Value::Number(Number::Integer(_)) => Type::Int,
+      Value::Number(Number::Float(_)) => Type::Float,
+      Value::String(_) => Type::String,
+      Value::Boolean(_) => Type::Bool,
+      Value::None => Type::None,
+      Value::List(items) => {
+        for item in items {
+          self.value(item, span);
+        }
+        Type::List
+      }
+      Value::Identifier(identifier) => {
+        match analysis::lookup(&mut self.scopes, &identifier.name) {
+          Some(variable) => {
+            let (declared, shown) = (variable.declared.clone(), variable.shown());
+            self.types.push((identifier.span, shown));
+            declared
+          }
+          None => match self.variants.get(&identifier.name) {
+            Some(name) => Type::Enum(name.clone()),
+            None if identifier.name == interpreter::ARGS => Type::List,
+            None => Type::Any,
+          },
+        }
+      }
+      Value::Expression(expression) => {
+        let operator = *expression.get_operator();
+        let left = expression.get_left();
+        let right = expression.get_right();
+        match (operator, right) {
+          (Operator::Brackets, _) => self.value(left, span),
+          (Operator::Not, _) => {
+            let operand = self.value(left, span);
+            self.operation(operator, operand, None, span)
+          }
+          (Operator::Assign, Some(right)) => {
+            let found = self.value(right, span);
+            match left {
+              Value::Identifier(identifier) => self.assignment(identifier, found.clone(), span),
+              left => {
+                self.value(left, span);
+              }
+            }
+            found
+          }
+          (_, Some(right)) if compound_operator(operator).is_some() => {
+            let current = self.value(left, span);
+            let right = self.value(right, span);
+            let result = self.operation(
+              compound_operator(operator).unwrap(),
+              current.clone(),
+              Some(right),
+              span,
+            );
+            if let Value::Identifier(identifier) = left {
+              self.mark_assigned(&identifier.name);
+              self.expect(
+                format!("Variable '{}'", identifier.name),
+                &current,
+                result.clone(),
+                span,
+              );
+            }
+            result
+          }
+          (_, Some(right)) => {
+            let left = self.value(left, span);
+            let right = self.value(right, span);
+            self.operation(operator, left, Some(right), span)
+          }
+          (_, None) => self.value(left, span),
+        }
+      }
+      Value::Call {
+        name, arguments, ..
+      } => {
+        let arguments: Vec<Type> = arguments
+          .iter()
+          .map(|argument| self.value(argument, span))
+          .collect();
+        if let Some(signature) = self.functions.get(name) {
+          let returns = signature.returns.clone();
+          if signature.parameters.len() != arguments.len() {
+            let expected = signature.parameters.len();
+            self.error(
+              TypeError::ArgumentCount {
+                function: name.clone(),
+                expected,
+                found: arguments.len(),
+              },
+              span,
+            );
+            return returns;
+          }
+          let parameters = signature.parameters.clone();
+          for ((parameter, expected), found) in parameters.iter().zip(arguments) {
+            self.expect(
+              format!("Argument '{}' of '{}'", parameter, name),
+              expected,
+              found,
+              span,
+            );
+          }
+          returns
+        } else if let Some(enum_name) = self.variants.get(name) {
+          Type::Enum(enum_name.clone())
+        } else {
+          Type::Any
+        }
+      }
+      Value::Field { value, .. } => {
+        self.value(value, span);
+        Type::Any
+      }
+      Value::Index {
+        value,
+        index,
+        optional,
+      } => {
+        let indexed = self.value(value, span);
+        let index = self.value(index, span);
+        self.expect("The index", &Type::Int, index, span);
+        match indexed {
+          Type::String if !optional => Type::String,
+          _ => Type::Any,
+        }
+      }
+    }
+  }
+
+  // The type of what an operator results in, any when the operands do not fit it
+  fn operation(&mut self, operator: Operator, left: Type, right: Option<Type>, span: Span) -> Type {
+    use Type::{Any, Bool, Float, Int, List, String};
+    let result = match (operator, &left, &right) {
+      (Operator::Not, Bool | Any, _) => Some(Bool),
+      (Operator::Equal | Operator::NotEqual, _, _) => Some(Bool),
+      (Operator::Coalesce, Type::None, Some(right)) => Some(right.clone()),
+      (Operator::Coalesce, Any, _) => Some(Any),
+      (Operator::Coalesce, left, _) => Some(left.clone()),
+      (Operator::And | Operator::Or, Bool | Any, Some(Bool | Any)) => Some(Bool),
+      (
+        Operator::LessThan
+        | Operator::GreaterThan
+        | Operator::LessThanOrEqual
+        | Operator::GreaterThanOrEqual,
+        Int | Float | Any,
+        Some(Int | Float | Any),
+      ) => Some(Bool),
+      (Operator::Exponent, Int | Float | Any, Some(Int | Float | Any)) => Some(Float),
+      (Operator::Add, String, Some(String | Any)) | (Operator::Add, Any, Some(String)) => {
+        Some(String)
+      }
+      (Operator::Add, List, Some(List | Any)) | (Operator::Add, Any, Some(List)) => Some(List),
+      (
+        Operator::Add
+        | Operator::Subtract
+        | Operator::Multiply
+        | Operator::Divide
+        | Operator::Modulo,
+        left,
+        Some(right),
+      ) => match (left, right) {
+        (Int, Int) => Some(Int),
+        (Int | Float, Int | Float) | (Float, Any) | (Any, Float) => Some(Float),
+        (Int | Any, Int | Any) => Some(Any),
+        _ => None,
+      },
+      _ => None,
+    };
+    result.unwrap_or_else(|| {
+      self.error(
+        TypeError::InvalidOperands {
+          operator,
+          left,
+          right,
+        },
+        span,
+      );
+      Any
+    })
+  }
+
+  fn assignment(&mut self, identifier: &Identifier, found: Type, span: Span) {
+    self.mark_assigned(&identifier.name);
+    let Some(variable) = analysis::lookup(&mut self.scopes, &identifier.name) else {
+      self.declare(identifier, Type::Any, found);
+      return;
+    };
+    if variable.inferred != found {
+      variable.inferred = Type::Any;
+    }
+    let (declared, shown) = (variable.declared.clone(), variable.shown());
+    self.types.push((identifier.span, shown));
+    self.expect(
+      format!("Variable '{}'", identifier.name),
+      &declared,
+      found,
+      span,
+    );
+  }
+
+  fn mark_assigned(&mut self, name: &str) {
+    if let Some(span) =
+      analysis::lookup(&mut self.scopes, name).and_then(|variable| variable.unannotated_let)
+    {
+      self.assigned.insert(span);
+    }
+  }
+
+  fn declare(&mut self, identifier: &Identifier, declared: Type, inferred: Type) -> &mut Variable {
+    let variable = Variable {
+      name: identifier.name.clone(),
+      declared,
+      inferred,
+      unannotated_let: None,
+    };
+    self.types.push((identifier.span, variable.shown()));
+    let scope = self.scopes.last_mut().expect("No scope to declare in");
+    scope.push(variable);
+    scope.last_mut().unwrap()
+  }
+}
+
+fn fits(expected: &Type, found: &Type) -> bool {
+  expected == found || matches!((expected, found), (Type::Any, _) | (_, Type::Any))
+}
+
+// += and the like, as the operator they apply
+fn compound_operator(operator: Operator) -> Option<Operator> {
+  match operator {
+    Operator::AddAssign => Some(Operator::Add),
+    Operator::SubtractAssign => Some(Operator::Subtract),
+    Operator::MultiplyAssign => Some(Operator::Multiply),
+    Operator::DivideAssign => Some(Operator::Divide),
+    Operator::ModuloAssign => Some(Operator::Modulo),
+    _ => None,
+  }
+}
+
+// Whether running the instructions always ends in a return, an endless loop counts as well
+fn returns_always(instructions: &[Instruction]) -> bool {
+  instructions
+    .iter()
+    .enumerate()
+    .any(|(index, instruction)| match &instruction.kind {
+      InstructionKind::Return { .. } => true,
+      InstructionKind::Scope { instructions: block } => returns_always(block),
+      // An if only returns in every case together with the else right after it
+      InstructionKind::If {
+        instructions: block,
+        ..
+      } => {
+        let next = instructions.get(index + 1).map(|next| &next.kind);
+        returns_always(block)
+          && matches!(next, Some(InstructionKind::Else { instructions: block }) if returns_always(block))
+      }
+      InstructionKind::While {
+        condition: Value::Boolean(true),
+        instructions: block,
+      } => !has_break(block),
+      _ => false,
+    })
+}
+
+// Whether a break leaves the loop these instructions are the body of
+fn has_break(instructions: &[Instruction]) -> bool {
+  instructions
+    .iter()
+    .any(|instruction| match &instruction.kind {
+      InstructionKind::Break => true,
+      InstructionKind::While { .. } | InstructionKind::Function(_) => false,
+      kind => kind.blocks().into_iter().any(has_break),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{limits::Limits, parser, tokenizer};
+
+  // Every type error in the code
+  fn errors(code: &str) -> Vec<String> {
+    let tokens = tokenizer::tokenize(code).expect("Code does not tokenize");
+    let (instructions, diagnostics) = parser::parse(tokens, &Limits::default());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    check(&instructions)
+      .into_iter()
+      .map(|diagnostic| diagnostic.error.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn let_without_annotation_has_the_type_of_its_value() {
+    assert_eq!(
+      errors("let y = 1; while (y) {}"),
+      ["The while condition has to be bool, found int"]
+    );
+    assert_eq!(
+      errors("let s = \"a\"; { print(s - 1); }"),
+      ["Operator '-' can not be used on string and int"]
+    );
+  }
+
+  #[test]
+  fn let_that_is_assigned_to_again_can_be_anything() {
+    for code in [
+      "let y = 1; while (y < 3) { y = y + 0.5; } if (y) {}",
+      "let y = 1; y += 0.5; if (y) {}",
+      "let y = \"a\"; input y; if (y) {}",
+      "let y = 1; { y = true; } if (y) {}",
+    ] {
+      assert_eq!(errors(code), Vec::<String>::new(), "{}", code);
+    }
+  }
+
+  #[test]
+  fn assigning_to_a_shadowing_variable_leaves_the_outer_one_alone() {
+    assert_eq!(
+      errors("let y = 1; { let y = 2; y = true; } if (y) {}"),
+      ["The if condition has to be bool, found int"]
+    );
+    // Functions do not see the variables around them
+    assert_eq!(
+      errors("let y = 1; fn f() { y = true; } if (y) {}"),
+      ["The if condition has to be bool, found int"]
+    );
+  }
+
+  #[test]
+  fn an_int_is_not_a_float() {
+    // Nothing converts it, half(1) would divide integers
+    assert_eq!(
+      errors("fn half(x: float) -> float { return x / 2; } print(half(1)); print(half(1.0));"),
+      ["Argument 'x' of 'half' has to be float, found int"]
+    );
+    assert_eq!(
+      errors("let x: float = 1; let y: float = 2.0 * 3; x = y;"),
+      ["Variable 'x' has to be float, found int"]
+    );
+  }
+}
@@ -0,0 +1,647 @@
+use std::fmt::{self, Write};
+
+use crate::{
+  number::Number,
+  parser::{Expression, Instruction, InstructionKind, MatchArm, Pattern, TypeAnnotation, Value},
+  tokenizer::Operator,
+};
+
+/*
+ Turns instructions back into fish code. Unlike the formatter, which works on the source and keeps
+ its comments and brackets, this only has the AST to go on, so it is meant for code that was
+ generated or changed after parsing and for showing parts of it in messages.
+
+ All operators bind equally and group from the right, so `a - b - c` is `a - (b - c)`. Brackets
+ are only written where the AST would otherwise come back different: around an operation on the
+ left of another one, after a `!` and in front of a `.` or `[`. The brackets parsed code keeps as
+ operators of their own are left out, so `(a) + (b - c)` is written as `a + b - c`, and printing
+ and parsing code again only keeps the brackets that are needed.
+*/
+pub fn unparse(instructions: &[Instruction]) -> String {
+  let mut code = String::new();
+  // Writing to a String can not fail
+  let _ = write_instructions(&mut code, instructions, 0);
+  code
+}
+
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write_instruction(f, self, 0)
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Value::Number(number) => write_number(f, *number),
+      Value::String(string) => write_string(f, string),
+      Value::Boolean(boolean) => write!(f, "{}", boolean),
+      Value::None => write!(f, "none"),
+      Value::List(items) => {
+        write!(f, "[")?;
+        write_separated(f, items)?;
+        write!(f, "]")
+      }
+      Value::Identifier(identifier) => write!(f, "{}", identifier.name),
+      Value::Expression(expression) => write!(f, "{}", expression),
+      Value::Call {
+        name, arguments, ..
+      } => {
+        write!(f, "{}(", name)?;
+        write_separated(f, arguments)?;
+        write!(f, ")")
+      }
+      Value::Field {
+        value,
+        field,
+        optional,
+      } => {
+        let dot = if *optional { "?." } else { "." };
+        write_operand(f, value, dot)?;
+        write!(f, "{}{}", dot, field)
+      }
+      Value::Index {
+        value,
+        index,
+        optional,
+      } => {
+        let open = if *optional { "?.[" } else { "[" };
+        write_operand(f, value, open)?;
+        write!(f, "{}{}]", open, index)
+      }
+    }
+  }
+}
+
+impl fmt::Display for Expression {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (self.get_operator(), self.get_right()) {
+      (Operator::Brackets, _) => write!(f, "{}", self.get_left()),
+      (Operator::Not, _) => {
+        write!(f, "!")?;
+        write_operand(f, self.get_left(), "")
+      }
+      (operator, right) => {
+        write_operand(f, self.get_left(), "")?;
+        write!(f, " {} ", operator)?;
+        match right {
+          Some(right) => write!(f, "{}", right),
+          None => Ok(()),
+        }
+      }
+    }
+  }
+}
+
+// A value where the parser only takes a single operand, with brackets when it is more than that.
+// `then` is the access that follows it, if any. A `!` only needs them in front of an access, since
+// `!a.b` is `!(a.b)`, and a number only in front of a `.`, which the tokenizer would read as part
+// of `1.a`
+fn write_operand(f: &mut impl Write, value: &Value, then: &str) -> fmt::Result {
+  let value = without_brackets(value);
+  let bracketed = match value {
+    Value::Expression(expression) => match expression.get_operator() {
+      Operator::Not => !then.is_empty(),
+      _ => true,
+    },
+    // -1 is written as (0 - 1), there are no negative number literals
+    Value::Number(number) => then == "." || is_negative(*number),
+    _ => false,
+  };
+  match bracketed {
+    true => write!(f, "({})", value),
+    false => write!(f, "{}", value),
+  }
+}
+
+// The value inside any brackets around it, which are written again only where they are needed
+fn without_brackets(mut value: &Value) -> &Value {
+  while let Value::Expression(expression) = value {
+    match expression.get_operator() {
+      Operator::Brackets => value = expression.get_left(),
+      _ => break,
+    }
+  }
+  value
+}
+
+fn is_negative(number: Number) -> bool {
+  match number {
+    Number::Integer(integer) => integer < 0,
+    Number::Float(float) => float.is_sign_negative() && float != 0.0,
+  }
+}
+
+// Floats always get a fraction, otherwise they would be read back as integers
+fn write_number(f: &mut impl Write, number: Number) -> fmt::Result {
+  match number {
+    Number::Integer(integer) if integer < 0 => write!(f, "0 - {}", integer.unsigned_abs()),
+    Number::Integer(integer) => write!(f, "{}", integer),
+    Number::Float(float) if float.is_finite() => {
+      if is_negative(number) {
+        write!(f, "0 - ")?;
+      }
+      let digits = float.abs().to_string();
+      match digits.contains('.') {
+        true => write!(f, "{}", digits),
+        false => write!(f, "{}.0", digits),
+      }
+    }
+    Number::Float(float) => write!(f, "{}", float),
+  }
+}
+
+// Only " and \ are escaped, the tokenizer reads any other character as it is
+fn write_string(f: &mut impl Write, string: &str) -> fmt::Result {
+  write!(f, "\"")?;
+  for c in string.chars() {
+    if matches!(c, '"' | '\\') {
+      write!(f, "\\")?;
+    }
+    write!(f, "{}", c)?;
+  }
+  write!(f, "\"")
+}
+
+fn write_separated(f: &mut impl Write, values: &[Value]) -> fmt::Result {
+  for (index, value) in values.iter().enumerate() {
+    if index > 0 {
+      write!(f, ", ")?;
+    }
+    write!(f, "{}", value)?;
+  }
+  Ok(())
+}
+
+// A line per instruction, apart from an else which goes after the } of the if before it
+fn write_instructions(
+  f: &mut impl Write,
+  instructions: &[Instruction],
+  indent: usize,
+) -> fmt::Result {
+  for (index, instruction) in instructions.iter().enumerate() {
+    let is_else = matches!(instruction.kind, InstructionKind::Else { .. });
+    let follows_block = index > 0
+      && matches!(
+        instructions[index - 1].kind,
+        InstructionKind::If { .. } | InstructionKind::Else { .. }
+      );
+    if is_else && follows_block {
+      write!(f, " ")?;
+    } else {
+      if index > 0 {
+        writeln!(f)?;
+      }
+      write!(f, "{:1$}", "", indent * 2)?;
+    }
+    write_instruction(f, instruction, indent)?;
+  }
+  if !instructions.is_empty() {
+    writeln!(f)?;
+  }
+  Ok(())
+}
+
+// The instruction without the indentation of its first line
+fn write_instruction(f: &mut impl Write, instruction: &Instruction, indent: usize) -> fmt::Result {
+  match &instruction.kind {
+    InstructionKind::If {
+      condition,
+      instructions,
+    } => {
+      write!(f, "if ({}) ", condition)?;
+      write_block(f, instructions, indent)
+    }
+    InstructionKind::Else { instructions } => {
+      write!(f, "else ")?;
+      write_block(f, instructions, indent)
+    }
+    InstructionKind::While {
+      condition,
+      instructions,
+    } => {
+      write!(f, "while ({}) ", condition)?;
+      write_block(f, instructions, indent)
+    }
+    InstructionKind::Scope { instructions } => write_block(f, instructions, indent),
+    InstructionKind::Value { value } => write!(f, "{};", value),
+    InstructionKind::Break => write!(f, "break;"),
+    InstructionKind::Print { message } => write!(f, "print({});", message),
+    InstructionKind::Input { variable } => write!(f, "input {};", variable.name),
+    InstructionKind::Enum { name, variants, .. } => {
+      write!(f, "enum {} {{", name)?;
+      for (index, variant) in variants.iter().enumerate() {
+        write!(f, "{} {}", if index > 0 { "," } else { "" }, variant.name)?;
+        if !variant.fields.is_empty() {
+          write!(f, "({})", variant.fields.join(", "))?;
+        }
+      }
+      write!(f, " }}")
+    }
+    InstructionKind::Match { value, arms } if arms.is_empty() => write!(f, "match {} {{}}", value),
+    InstructionKind::Match { value, arms } => {
+      writeln!(f, "match {} {{", value)?;
+      for (index, arm) in arms.iter().enumerate() {
+        if index > 0 {
+          writeln!(f, ",")?;
+        }
+        write!(f, "{:1$}", "", (indent + 1) * 2)?;
+        write_arm(f, arm, indent + 1)?;
+      }
+      writeln!(f)?;
+      write!(f, "{:1$}}}", "", indent * 2)
+    }
+    InstructionKind::Let {
+      variable,
+      annotation,
+      value,
+    } => {
+      write!(f, "let {}", variable.name)?;
+      write_annotation(f, annotation.as_ref())?;
+      write!(f, " = {};", value)
+    }
+    InstructionKind::Function(function) => {
+      write!(f, "fn {}(", function.name)?;
+      for (index, parameter) in function.parameters.iter().enumerate() {
+        if index > 0 {
+          write!(f, ", ")?;
+        }
+        write!(f, "{}", parameter.variable.name)?;
+        write_annotation(f, parameter.annotation.as_ref())?;
+      }
+      write!(f, ") ")?;
+      if let Some(return_type) = &function.return_type {
+        write!(f, "-> {} ", return_type.name)?;
+      }
+      write_block(f, &function.instructions, indent)
+    }
+    InstructionKind::Return { value: Some(value) } => write!(f, "return {};", value),
+    InstructionKind::Return { value: None } => write!(f, "return;"),
+    InstructionKind::Assert {
+      condition,
+      message: Some(message),
+    } => write!(f, "assert({}, {});", condition, message),
+    InstructionKind::Assert {
+      condition,
+      message: None,
+    } => write!(f, "assert({});", condition),
+    InstructionKind::Test { name, instructions } => {
+      write!(f, "test ")?;
+      write_string(f, name)?;
+      write!(f, " ")?;
+      write_block(f, instructions, indent)
+    }
+  }
+}
+
+fn write_block(f: &mut impl Write, instructions: &[Instruction], indent: usize) -> fmt::Result {
+  if instructions.is_empty() {
+    return write!(f, "{{}}");
+  }
+  writeln!(f, "{{")?;
+  write_instructions(f, instructions, indent + 1)?;
+  write!(f, "{:1$}}}", "", indent * 2)
+}
+
+fn write_arm(f: &mut impl Write, arm: &MatchArm, indent: usize) -> fmt::Result {
+  write_pattern(f, &arm.pattern)?;
+  if let Some(guard) = &arm.guard {
+    write!(f, " if {}", guard)?;
+  }
+  write!(f, " => ")?;
+  write_block(f, &arm.instructions, indent)
+}
+
+fn write_pattern(f: &mut impl Write, pattern: &Pattern) -> fmt::Result {
+  match pattern {
+    Pattern::Wildcard => write!(f, "_"),
+    Pattern::Literal(value) => write!(f, "{}", value),
+    Pattern::Identifier(identifier) => write!(f, "{}", identifier.name),
+    Pattern::Variant { name, fields, .. } => {
+      write!(f, "{}(", name)?;
+      for (index, field) in fields.iter().enumerate() {
+        if index > 0 {
+          write!(f, ", ")?;
+        }
+        write_pattern(f, field)?;
+      }
+      write!(f, ")")
+    }
+  }
+}
+
+fn write_annotation(f: &mut impl Write, annotation: Option<&TypeAnnotation>) -> fmt::Result {
+  match annotation {
+    Some(annotation) => write!(f, ": {}", annotation.name),
+    None => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    limits::Limits,
+    parser::{self, Function, Identifier, Parameter, Variant},
+    syntax_json, tokenizer,
+    tokenizer::{Keyword, Span, Token, OPERATORS},
+  };
+  use proptest::{collection::vec, option, prelude::*, sample::select, strategy::LazyJust};
+  use serde_json::Value as Json;
+
+  const NAMES: [&str; 6] = ["a", "b", "count", "x_1", "_tmp", "größe"];
+  const TYPES: [&str; 4] = ["int", "float", "string", "Shape"];
+  // With " and \ more often than anything else, which have to be escaped
+  const STRING: &str = "[\"\\\\]{0,2}[ -~é\n]{0,4}[\"\\\\]{0,2}";
+
+  fn name() -> impl Strategy<Value = String> {
+    select(&NAMES[..]).prop_map(String::from)
+  }
+
+  fn identifier() -> impl Strategy<Value = Identifier> {
+    name().prop_map(|name| Identifier::new(name, Span::default()))
+  }
+
+  fn annotation() -> impl Strategy<Value = Option<TypeAnnotation>> {
+    option::of(select(&TYPES[..]).prop_map(|name| TypeAnnotation {
+      name: name.to_string(),
+      span: Span::default(),
+    }))
+  }
+
+  // Only what the tokenizer can read, so no negative numbers
+  fn literal() -> impl Strategy<Value = Value> {
+    prop_oneof![
+      (0..=i64::MAX).prop_map(|integer| Value::Number(Number::Integer(integer))),
+      any::<f64>()
+        .prop_filter("Only finite floats are literals", |float| float.is_finite())
+        .prop_map(|float| Value::Number(Number::Float(float.abs()))),
+      STRING.prop_map(|string| Value::String(string.into())),
+      any::<bool>().prop_map(Value::Boolean),
+      Just(Value::None),
+    ]
+  }
+
+  fn value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![literal(), identifier().prop_map(Value::Identifier)];
+    leaf.prop_recursive(4, 48, 3, |value| {
+      let binary = OPERATORS
+        .into_iter()
+        .filter(|operator| !matches!(operator, Operator::Not | Operator::Brackets))
+        .collect::<Vec<_>>();
+      let single = select(vec![Operator::Not, Operator::Brackets]);
+      prop_oneof![
+        (select(binary), value.clone(), value.clone()).prop_map(|(operator, left, right)| {
+          Value::Expression(Box::new(Expression::new(operator, left, right)))
+        }),
+        (single, value.clone()).prop_map(|(operator, value)| {
+          Value::Expression(Box::new(Expression::new_not_or_bracket(operator, value)))
+        }),
+        vec(value.clone(), 0..3).prop_map(Value::List),
+        (name(), vec(value.clone(), 0..3)).prop_map(|(name, arguments)| Value::Call {
+          name,
+          span: Span::default(),
+          arguments,
+        }),
+        (value.clone(), name(), any::<bool>()).prop_map(|(value, field, optional)| {
+          Value::Field {
+            value: Box::new(value),
+            field,
+            optional,
+          }
+        }),
+        (value.clone(), value, any::<bool>()).prop_map(|(value, index, optional)| {
+          Value::Index {
+            value: Box::new(value),
+            index: Box::new(index),
+            optional,
+          }
+        }),
+      ]
+    })
+  }
+
+  fn pattern() -> impl Strategy<Value = Pattern> {
+    let leaf = prop_oneof![
+      LazyJust::new(|| Pattern::Wildcard),
+      literal().prop_map(Pattern::Literal),
+      identifier().prop_map(Pattern::Identifier),
+    ];
+    leaf.prop_recursive(3, 12, 3, |pattern| {
+      (name(), vec(pattern, 0..3)).prop_map(|(name, fields)| Pattern::Variant {
+        name,
+        span: Span::default(),
+        fields,
+      })
+    })
+  }
+
+  fn instruction(kind: InstructionKind) -> Instruction {
+    Instruction {
+      kind,
+      span: Span::default(),
+    }
+  }
+
+  fn instructions() -> impl Strategy<Value = Vec<Instruction>> {
+    let variant = (name(), vec(name(), 0..3)).prop_map(|(name, fields)| Variant {
+      name,
+      span: Span::default(),
+      fields,
+    });
+    let simple = prop_oneof![
+      value().prop_map(|value| InstructionKind::Value { value }),
+      LazyJust::new(|| InstructionKind::Break),
+      value().prop_map(|message| InstructionKind::Print { message }),
+      identifier().prop_map(|variable| InstructionKind::Input { variable }),
+      (name(), vec(variant, 0..3)).prop_map(|(name, variants)| InstructionKind::Enum {
+        name,
+        name_span: Span::default(),
+        variants,
+      }),
+      (identifier(), annotation(), value()).prop_map(|(variable, annotation, value)| {
+        InstructionKind::Let {
+          variable,
+          annotation,
+          value,
+        }
+      }),
+      (value(), option::of(value()))
+        .prop_map(|(condition, message)| InstructionKind::Assert { condition, message }),
+    ];
+    let nested = simple
+      .prop_map(instruction)
+      .prop_recursive(3, 24, 3, |inner| {
+        let block = || vec(inner.clone(), 0..3);
+        let arm =
+          (pattern(), option::of(value()), block()).prop_map(|(pattern, guard, block)| MatchArm {
+            pattern,
+            guard,
+            instructions: block,
+          });
+        let parameter = (identifier(), annotation()).prop_map(|(variable, annotation)| Parameter {
+          variable,
+          annotation,
+        });
+        prop_oneof![
+          (value(), block()).prop_map(|(condition, instructions)| InstructionKind::If {
+            condition,
+            instructions
+          }),
+          block().prop_map(|instructions| InstructionKind::Else { instructions }),
+          (value(), block()).prop_map(|(condition, instructions)| InstructionKind::While {
+            condition,
+            instructions
+          }),
+          block().prop_map(|instructions| InstructionKind::Scope { instructions }),
+          (value(), vec(arm, 0..3))
+            .prop_map(|(value, arms)| InstructionKind::Match { value, arms }),
+          // Returns are only allowed in functions, one may end the body
+          (
+            name(),
+            vec(parameter, 0..3),
+            annotation(),
+            block(),
+            option::of(option::of(value()))
+          )
+            .prop_map(|(name, parameters, return_type, mut instructions, returned)| {
+              if let Some(value) = returned {
+                instructions.push(instruction(InstructionKind::Return { value }));
+              }
+              InstructionKind::Function(Function {
+                name,
+                name_span: Span::default(),
+                parameters,
+                return_type,
+                instructions,
+              })
+            }),
+        ]
+        .prop_map(instruction)
+      });
+    // Tests are only allowed at the top
+    let test = (STRING, vec(nested.clone(), 0..3))
+      .prop_map(|(name, instructions)| instruction(InstructionKind::Test { name, instructions }));
+    vec(prop_oneof![4 => nested, 1 => test], 0..4)
+  }
+
+  // The AST without what code can not keep: where everything is, and brackets, which only group
+  // what the tree already groups
+  fn shape(json: &mut Json) {
+    if json["kind"] == "expression" && json["operator"] == "()" {
+      *json = json["left"].take();
+      return shape(json);
+    }
+    match json {
+      Json::Object(fields) => {
+        fields.remove("span");
+        fields.remove("name_span");
+        fields.values_mut().for_each(shape);
+      }
+      Json::Array(items) => items.iter_mut().for_each(shape),
+      _ => (),
+    }
+  }
+
+  // The code with each pair of brackets that only groups taken out in turn, leaving the ones of
+  // calls, conditions and patterns
+  fn without_each_bracket(code: &str) -> Vec<String> {
+    let tokens = tokenizer::tokenize(code).unwrap();
+    let (mut open, mut pairs) = (Vec::new(), Vec::new());
+    for (index, lexeme) in tokens.iter().enumerate() {
+      match lexeme.token {
+        Token::BracketOpen => {
+          let before = index.checked_sub(1).map(|before| &tokens[before].token);
+          let grouping = !matches!(
+            before,
+            Some(
+              Token::Identifier(_)
+                | Token::Keyword(Keyword::If | Keyword::While | Keyword::Print | Keyword::Assert)
+            )
+          );
+          open.push((lexeme.span.start.offset, grouping));
+        }
+        Token::BracketClose => {
+          if let Some((start, true)) = open.pop() {
+            pairs.push((start, lexeme.span.start.offset));
+          }
+        }
+        _ => (),
+      }
+    }
+    pairs
+      .into_iter()
+      .map(|(open, close)| {
+        format!(
+          "{}{}{}",
+          &code[..open],
+          &code[open + 1..close],
+          &code[close + 1..]
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn brackets_are_only_written_where_they_are_needed() {
+    let cases = [
+      ("1 + (2 * 3) - 4;", "1 + (2 * 3) - 4;"),
+      ("(1 + 2) * 3;", "(1 + 2) * 3;"),
+      ("1 + (2 * 3);", "1 + 2 * 3;"),
+      ("((a)) + ((b - c));", "a + b - c;"),
+      ("!(a) && (!b);", "!a && !b;"),
+      ("!(a.b) + (!a).b;", "!a.b + (!a).b;"),
+      ("(1).a + (1)[0] + (1.5)?.a;", "(1).a + 1[0] + 1.5?.a;"),
+      ("x = (0 - 1) - (0 - 1);", "x = (0 - 1) - 0 - 1;"),
+      ("print((f(a)));", "print(f(a));"),
+    ];
+    for (code, expected) in cases {
+      let tokens = tokenizer::tokenize(code).unwrap();
+      let (parsed, _) = parser::parse(tokens, &Limits::default());
+      assert_eq!(unparse(&parsed), format!("{}\n", expected), "{}", code);
+    }
+  }
+
+  #[test]
+  fn strings_escape_quotes_and_backslashes() {
+    let code = r#"test "a \"test\"" { print("C:\\dir\\" + "\n"); }"#;
+    let tokens = tokenizer::tokenize(code).unwrap();
+    let (parsed, _) = parser::parse(tokens, &Limits::default());
+    // The \n is a backslash and an n, which gets escaped like any other backslash
+    let expected = r#"test "a \"test\"" {
+  print("C:\\dir\\" + "\\n");
+}
+"#;
+    assert_eq!(unparse(&parsed), expected);
+  }
+
+  proptest! {
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn printed_code_parses_back_to_the_same_ast(instructions in instructions()) {
+      let code = unparse(&instructions);
+      let tokens = tokenizer::tokenize(&code)
+        .unwrap_or_else(|error| panic!("{:?} in\n{}", error, code));
+      let (parsed, diagnostics) = parser::parse(tokens, &Limits::default());
+      prop_assert!(diagnostics.is_empty(), "{:?} in\n{}", diagnostics, code);
+      let mut expected = syntax_json::ast_to_json(&instructions);
+      let mut printed = syntax_json::ast_to_json(&parsed);
+      shape(&mut expected);
+      shape(&mut printed);
+      prop_assert_eq!(&printed, &expected, "{}", code);
+      // The brackets are in the parsed AST, so it prints the same again
+      prop_assert_eq!(unparse(&parsed), code.clone());
+      for without in without_each_bracket(&code) {
+        let parsed = tokenizer::tokenize(&without)
+          .ok()
+          .map(|tokens| parser::parse(tokens, &Limits::default()))
+          .filter(|(_, diagnostics)| diagnostics.is_empty());
+        if let Some((parsed, _)) = parsed {
+          let mut printed = syntax_json::ast_to_json(&parsed);
+          shape(&mut printed);
+          prop_assert_ne!(&printed, &expected, "Brackets are not needed in\n{}", code);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,1131 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  fmt, mem,
+};
+
+use crate::{
+  diagnostic::Diagnostic,
+  interpreter::{ARGS, EXIT},
+  limits::DEFAULT_MAX_CALLS,
+  number::Number,
+  parser::{
+    Expression, Function, Identifier, Instruction, InstructionKind, MatchArm, Pattern, Value,
+  },
+  resolver::Slot,
+  tokenizer::{Operator, Position, Span},
+};
+
+const RUNTIME: &str = include_str!("wasm_runtime.wat");
+
+// Strings start after the first 8 bytes, so that no address is 0
+const DATA_START: u64 = 8;
+
+// What a script uses that a WebAssembly module can not do yet
+#[derive(Debug)]
+pub enum Unsupported {
+  Lists,
+  Enums,
+  Args,
+}
+
+impl fmt::Display for Unsupported {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Unsupported::Lists => write!(f, "Lists are not supported by the wasm target yet"),
+      Unsupported::Enums => write!(f, "Enums are not supported by the wasm target yet"),
+      Unsupported::Args => write!(f, "The list args is not supported by the wasm target yet"),
+    }
+  }
+}
+
+/*
+ Turns a script into a WebAssembly module in the text format, which does what the interpreter
+ would do when running it. The instructions have to be resolved first. Numbers, booleans, none,
+ strings and functions work, a script with lists or enums gives an error instead.
+
+ Every value is a tag and a 64 bit payload, variables are a local for each and the operations are
+ calls into the runtime in wasm_runtime.wat. Strings live in linear memory, the ones in the script
+ are in a data segment. Output, input, exiting and writing floats are functions the host imports
+ into the module, the script runs when the host calls the exported `run`.
+*/
+pub fn transpile(instructions: &[Instruction]) -> Result<String, Diagnostic<Unsupported>> {
+  check(instructions)?;
+  let mut generator = Generator::default();
+  generator.open(false);
+  generator.instructions(instructions);
+  generator.close();
+  let body = mem::take(&mut generator.body);
+  let script = body.finish("$script (export \"run\")");
+
+  let runtime = generator.runtime();
+  let calls = mem::take(&mut generator.calls);
+  let dispatchers: Vec<String> = calls
+    .iter()
+    .map(|(name, count)| generator.dispatcher(name, *count))
+    .collect();
+
+  let heap = (DATA_START + generator.data.len() as u64 + 7) & !7;
+  let mut module = String::from("(module\n");
+  for import in [
+    "\"print\" (func $host_print (param i32 i32))",
+    "\"error\" (func $host_error (param i32 i32))",
+    "\"input\" (func $host_input (result i32 i32))",
+    "\"exit\" (func $host_exit (param i32))",
+    "\"float\" (func $host_float (param f64 i32) (result i32))",
+    "\"pow\" (func $host_pow (param f64 f64) (result f64))",
+  ] {
+    module.push_str(&format!("  (import \"fish\" {})\n", import));
+  }
+  module.push_str(&format!(
+    "  (memory (export \"memory\") {})\n",
+    heap / 65536 + 1
+  ));
+  module.push_str(&format!(
+    "  (global $heap (mut i32) (i32.const {}))\n",
+    heap
+  ));
+  module.push_str("  (global $calls (mut i32) (i32.const 0))\n");
+  module.push_str(&format!(
+    "  (global $max_calls i32 (i32.const {}))\n",
+    DEFAULT_MAX_CALLS
+  ));
+  // Which of the definitions of a function ran last, 0 while none did
+  let mut defined: Vec<&String> = generator.definitions.keys().collect();
+  defined.sort();
+  for name in defined {
+    module.push_str(&format!(
+      "  (global $function.{} (mut i32) (i32.const 0))\n",
+      mangle(name)
+    ));
+  }
+  module.push('\n');
+  module.push_str(&runtime);
+  for function in [script]
+    .iter()
+    .chain(&generator.functions)
+    .chain(&dispatchers)
+  {
+    module.push('\n');
+    module.push_str(function);
+  }
+  module.push_str("\n  (data (i32.const 8)");
+  for chunk in generator.data.chunks(64) {
+    module.push_str(&format!("\n    \"{}\"", escape(chunk)));
+  }
+  module.push_str("))\n");
+  Ok(format!(";; Compiled from a fish script\n{}", module))
+}
+
+fn check(instructions: &[Instruction]) -> Result<(), Diagnostic<Unsupported>> {
+  for instruction in instructions {
+    let span = instruction.span;
+    match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      }
+      | InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        check_value(condition, span)?;
+        check(instructions)?;
+      }
+      InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+        check(instructions)?
+      }
+      InstructionKind::Value { value }
+      | InstructionKind::Print { message: value }
+      | InstructionKind::Let { value, .. }
+      | InstructionKind::Return { value: Some(value) } => check_value(value, span)?,
+      InstructionKind::Enum { .. } => return Err(Diagnostic::new(Unsupported::Enums, span)),
+      InstructionKind::Match { value, arms } => {
+        check_value(value, span)?;
+        for arm in arms {
+          if let Pattern::Literal(literal) = &arm.pattern {
+            check_value(literal, span)?;
+          }
+          if let Some(guard) = &arm.guard {
+            check_value(guard, span)?;
+          }
+          check(&arm.instructions)?;
+        }
+      }
+      InstructionKind::Function(function) => check(&function.instructions)?,
+      InstructionKind::Assert { condition, message } => {
+        check_value(condition, span)?;
+        if let Some(message) = message {
+          check_value(message, span)?;
+        }
+      }
+      InstructionKind::Break
+      | InstructionKind::Input { .. }
+      | InstructionKind::Return { value: None }
+      | InstructionKind::Test { .. } => (),
+    }
+  }
+  Ok(())
+}
+
+fn check_value(value: &Value, span: Span) -> Result<(), Diagnostic<Unsupported>> {
+  match value {
+    Value::List(_) => Err(Diagnostic::new(Unsupported::Lists, span)),
+    Value::Identifier(Identifier {
+      name,
+      slot: None,
+      span,
+    }) if name == ARGS => Err(Diagnostic::new(Unsupported::Args, *span)),
+    Value::Call { arguments, .. } => arguments
+      .iter()
+      .try_for_each(|argument| check_value(argument, span)),
+    Value::Field { value, .. } => check_value(value, span),
+    Value::Index { value, index, .. } => {
+      check_value(value, span)?;
+      check_value(index, span)
+    }
+    Value::Expression(expression) => {
+      check_value(expression.get_left(), span)?;
+      match expression.get_right() {
+        Some(right) => check_value(right, span),
+        None => Ok(()),
+      }
+    }
+    _ => Ok(()),
+  }
+}
+
+// Fish names are ASCII apart from the letters of other scripts, which get written as their code
+// point between a % and a ., neither of which a fish name can have
+fn mangle(name: &str) -> String {
+  name
+    .chars()
+    .map(|character| match character.is_ascii() {
+      true => character.to_string(),
+      false => format!("%{:x}.", character as u32),
+    })
+    .collect()
+}
+
+fn escape(bytes: &[u8]) -> String {
+  bytes
+    .iter()
+    .map(|byte| match byte {
+      b'"' | b'\\' => format!("\\{}", *byte as char),
+      0x20..=0x7e => (*byte as char).to_string(),
+      byte => format!("\\{:02x}", byte),
+    })
+    .collect()
+}
+
+fn runtime_function(operator: Operator) -> &'static str {
+  match operator {
+    Operator::Add | Operator::AddAssign => "add",
+    Operator::Subtract | Operator::SubtractAssign => "subtract",
+    Operator::Multiply | Operator::MultiplyAssign => "multiply",
+    Operator::Divide | Operator::DivideAssign => "divide",
+    Operator::Modulo | Operator::ModuloAssign => "modulo",
+    Operator::Exponent => "power",
+    Operator::And => "and",
+    Operator::Or => "or",
+    operator => panic!("{:?} is not a binary operator", operator),
+  }
+}
+
+// The number $compare knows a comparison by
+fn comparison(operator: Operator) -> Option<u8> {
+  match operator {
+    Operator::Equal => Some(0),
+    Operator::NotEqual => Some(1),
+    Operator::LessThan => Some(2),
+    Operator::LessThanOrEqual => Some(3),
+    Operator::GreaterThan => Some(4),
+    Operator::GreaterThanOrEqual => Some(5),
+    _ => None,
+  }
+}
+
+// Without enums nothing is a variant, so these patterns never match
+fn never_matches(pattern: &Pattern) -> bool {
+  matches!(
+    pattern,
+    Pattern::Variant { .. } | Pattern::Identifier(Identifier { slot: None, .. })
+  )
+}
+
+struct Frame {
+  // The names of the locals of the variables in the slots, given out when a slot is first used
+  names: Vec<Option<String>>,
+  // Where the code of the block starts, its variables are unassigned again there
+  start: usize,
+  indent: usize,
+  reset: bool,
+}
+
+// A wasm function being generated
+struct Body {
+  lines: Vec<String>,
+  indent: usize,
+  frames: Vec<Frame>,
+  // The names of all variables in the function, each has a local for its tag and one for its
+  // payload. The parameters come first
+  names: Vec<String>,
+  parameters: usize,
+  // Locals for values that are needed more than once, used like a stack
+  temporaries: usize,
+  most_temporaries: usize,
+  // The labels of the loops around the code being generated
+  loops: Vec<usize>,
+  function: bool,
+}
+
+impl Body {
+  fn new(function: bool) -> Self {
+    Self {
+      lines: Vec::new(),
+      indent: 2,
+      frames: Vec::new(),
+      names: Vec::new(),
+      parameters: 0,
+      temporaries: 0,
+      most_temporaries: 0,
+      loops: Vec::new(),
+      function,
+    }
+  }
+
+  fn finish(self, head: &str) -> String {
+    let mut text = format!("  (func {}", head);
+    for name in &self.names[..self.parameters] {
+      text.push_str(&format!(
+        " (param {}.tag i32) (param {}.value i64)",
+        name, name
+      ));
+    }
+    if self.function {
+      text.push_str(" (result i32 i64)");
+    }
+    text.push('\n');
+    let temporaries = (1..=self.most_temporaries).map(|index| format!("$t.{}", index));
+    for name in self.names[self.parameters..]
+      .iter()
+      .cloned()
+      .chain(temporaries)
+    {
+      text.push_str(&format!(
+        "    (local {}.tag i32) (local {}.value i64)\n",
+        name, name
+      ));
+    }
+    for line in &self.lines {
+      text.push_str(line);
+      text.push('\n');
+    }
+    text.push_str("  )\n");
+    text
+  }
+}
+
+impl Default for Body {
+  fn default() -> Self {
+    Self::new(false)
+  }
+}
+
+#[derive(Default)]
+struct Generator {
+  body: Body,
+  functions: Vec<String>,
+  // The arities of the definitions of every function, the first definition is number 1
+  definitions: HashMap<String, Vec<usize>>,
+  // The functions called and with how many arguments, each gets a function that finds the
+  // definition to call
+  calls: BTreeSet<(String, usize)>,
+  data: Vec<u8>,
+  strings: HashMap<String, u64>,
+  labels: usize,
+}
+
+impl Generator {
+  fn line(&mut self, text: impl Into<String>) {
+    let line = format!("{}{}", "  ".repeat(self.body.indent), text.into());
+    self.body.lines.push(line);
+  }
+
+  fn label(&mut self) -> usize {
+    self.labels += 1;
+    self.labels
+  }
+
+  // The payload of a string with the text, which is in the data segment once
+  fn string(&mut self, text: &str) -> u64 {
+    if let Some(payload) = self.strings.get(text) {
+      return *payload;
+    }
+    let address = DATA_START + self.data.len() as u64;
+    self.data.extend_from_slice(text.as_bytes());
+    let payload = address | (text.len() as u64) << 32;
+    self.strings.insert(text.to_string(), payload);
+    payload
+  }
+
+  fn string_line(&mut self, text: &str) {
+    let payload = self.string(text);
+    self.line(format!("i64.const {} ;; {:?}", payload, text));
+  }
+
+  // The runtime with the payloads of its strings filled in, comments stay as they are
+  fn runtime(&mut self) -> String {
+    let mut runtime = String::new();
+    for line in RUNTIME.lines() {
+      let mut rest = line;
+      if !line.trim_start().starts_with(";;") {
+        while let Some(start) = rest.find("(string \"") {
+          let text_start = start + "(string \"".len();
+          let length = rest[text_start..]
+            .find("\")")
+            .expect("String in the runtime is not closed");
+          runtime.push_str(&rest[..start]);
+          let payload = self.string(&rest[text_start..text_start + length]);
+          runtime.push_str(&format!("(i64.const {})", payload));
+          rest = &rest[text_start + length + 2..];
+        }
+      }
+      runtime.push_str(rest);
+      runtime.push('\n');
+    }
+    runtime
+  }
+
+  fn open(&mut self, reset: bool) {
+    self.body.frames.push(Frame {
+      names: Vec::new(),
+      start: self.body.lines.len(),
+      indent: self.body.indent,
+      reset,
+    });
+  }
+
+  // A block starts with its variables unassigned, which is only known once its code is there
+  fn close(&mut self) {
+    let frame = self.body.frames.pop().expect("No frame to close");
+    if !frame.reset {
+      return;
+    }
+    let indent = "  ".repeat(frame.indent);
+    let resets: Vec<String> = frame
+      .names
+      .iter()
+      .flatten()
+      .flat_map(|name| {
+        [
+          format!("{}i32.const 0", indent),
+          format!("{}local.set {}.tag", indent, name),
+        ]
+      })
+      .collect();
+    self.body.lines.splice(frame.start..frame.start, resets);
+  }
+
+  // The instructions in a frame of their own
+  fn frame(&mut self, instructions: &[Instruction]) {
+    self.open(true);
+    self.instructions(instructions);
+    self.close();
+  }
+
+  fn block(&mut self, instructions: &[Instruction]) {
+    self.body.indent += 1;
+    self.frame(instructions);
+    self.body.indent -= 1;
+  }
+
+  // The name of the variable in the slot, different from all other variables of the function
+  fn name(&mut self, slot: Slot, name: &str) -> String {
+    let frames = &self.body.frames;
+    let index = frames
+      .len()
+      .checked_sub(slot.depth + 1)
+      .expect("Slot is outside of the function");
+    if let Some(Some(name)) = frames[index].names.get(slot.index) {
+      return name.clone();
+    }
+    let base = format!("${}", mangle(name));
+    let mut name = base.clone();
+    let mut count = 1;
+    while self.body.names.contains(&name) {
+      count += 1;
+      name = format!("{}_{}", base, count);
+    }
+    self.body.names.push(name.clone());
+    let frame = &mut self.body.frames[index];
+    if frame.names.len() <= slot.index {
+      frame.names.resize(slot.index + 1, None);
+    }
+    frame.names[slot.index] = Some(name.clone());
+    name
+  }
+
+  fn temporary(&mut self) -> String {
+    self.body.temporaries += 1;
+    self.body.most_temporaries = self.body.most_temporaries.max(self.body.temporaries);
+    format!("$t.{}", self.body.temporaries)
+  }
+
+  fn release(&mut self) {
+    self.body.temporaries -= 1;
+  }
+
+  fn get(&mut self, local: &str) {
+    self.line(format!("local.get {}.tag", local));
+    self.line(format!("local.get {}.value", local));
+  }
+
+  fn set(&mut self, local: &str) {
+    self.line(format!("local.set {}.value", local));
+    self.line(format!("local.set {}.tag", local));
+  }
+
+  fn none(&mut self) {
+    self.line("i32.const 1");
+    self.line("i64.const 0");
+  }
+
+  fn fail(&mut self, message: &str) {
+    self.string_line(message);
+    self.line("call $fail");
+    self.line("unreachable");
+  }
+
+  fn undefined_variable(&mut self, name: &str) {
+    self.string_line(name);
+    self.line("call $undefined_variable");
+    self.line("unreachable");
+  }
+
+  // Turns the value on the stack into the i32 that if and br_if take
+  fn condition(&mut self, what: &str) {
+    self.string_line(what);
+    self.line("call $condition");
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    let mut index = 0;
+    while index < instructions.len() {
+      let instruction = &instructions[index];
+      index += 1;
+      match &instruction.kind {
+        InstructionKind::If {
+          condition,
+          instructions: body,
+        } => {
+          self.value(condition);
+          self.condition("if condition");
+          self.line("if");
+          self.block(body);
+          // An else only runs right after an if that did not, one anywhere else never runs
+          if let Some(Instruction {
+            kind: InstructionKind::Else { instructions },
+            ..
+          }) = instructions.get(index)
+          {
+            self.line("else");
+            self.block(instructions);
+            index += 1;
+          }
+          self.line("end");
+        }
+        InstructionKind::Else { .. } => (),
+        InstructionKind::While {
+          condition,
+          instructions,
+        } => {
+          let label = self.label();
+          self.line(format!("block $loop.{}.end", label));
+          self.body.indent += 1;
+          self.line(format!("loop $loop.{}", label));
+          self.body.indent += 1;
+          self.value(condition);
+          self.condition("while condition");
+          self.line("i32.eqz");
+          self.line(format!("br_if $loop.{}.end", label));
+          self.body.loops.push(label);
+          self.frame(instructions);
+          self.body.loops.pop();
+          self.line(format!("br $loop.{}", label));
+          self.body.indent -= 1;
+          self.line("end");
+          self.body.indent -= 1;
+          self.line("end");
+        }
+        InstructionKind::Scope { instructions } => {
+          self.line("block");
+          self.block(instructions);
+          self.line("end");
+        }
+        InstructionKind::Value { value } => match value {
+          Value::Expression(expression) if expression.get_operator().is_assignment() => {
+            self.assignment(expression, false)
+          }
+          value => {
+            self.value(value);
+            self.line("drop");
+            self.line("drop");
+          }
+        },
+        // Outside of a loop a break ends the function or the script
+        InstructionKind::Break => match self.body.loops.last() {
+          Some(label) => self.line(format!("br $loop.{}.end", label)),
+          None => self.end(None),
+        },
+        InstructionKind::Print { message } => {
+          self.value(message);
+          self.line("call $print");
+        }
+        InstructionKind::Input { variable } => match variable.slot {
+          Some(slot) => {
+            let name = self.name(slot, &variable.name);
+            self.line("call $input");
+            self.set(&name);
+          }
+          None => self.undefined_variable(&variable.name),
+        },
+        InstructionKind::Match { value, arms } => self.match_arms(value, arms),
+        InstructionKind::Let {
+          variable, value, ..
+        } => {
+          self.value(value);
+          let slot = variable.slot.expect("Let was not resolved");
+          let name = self.name(slot, &variable.name);
+          self.set(&name);
+        }
+        InstructionKind::Function(function) => self.function(function),
+        InstructionKind::Return { value } => self.end(value.as_ref()),
+        InstructionKind::Assert { condition, message } => {
+          self.assert(instruction.span.start, condition, message.as_ref())
+        }
+        // There are no enums by now, and only the test runner runs tests
+        InstructionKind::Enum { .. } | InstructionKind::Test { .. } => (),
+      }
+    }
+  }
+
+  // Returns from the function, or ends the script without using the value
+  fn end(&mut self, value: Option<&Value>) {
+    match value {
+      Some(value) => self.value(value),
+      None => self.none(),
+    }
+    if !self.body.function {
+      self.line("drop");
+      self.line("drop");
+    }
+    self.line("return");
+  }
+
+  // The arms are tried in blocks of their own, which an arm that does not match breaks out of
+  fn match_arms(&mut self, value: &Value, arms: &[MatchArm]) {
+    let label = self.label();
+    self.value(value);
+    let subject = self.temporary();
+    self.set(&subject);
+    self.line(format!("block $match.{}", label));
+    self.body.indent += 1;
+    for (number, arm) in arms.iter().enumerate() {
+      if never_matches(&arm.pattern) {
+        continue;
+      }
+      let arm_label = format!("$arm.{}.{}", label, number + 1);
+      self.line(format!("block {}", arm_label));
+      self.body.indent += 1;
+      self.open(true);
+      match &arm.pattern {
+        Pattern::Literal(literal) => {
+          self.get(&subject);
+          self.value(literal);
+          self.line("call $equal");
+          self.line("i32.eqz");
+          self.line(format!("br_if {}", arm_label));
+        }
+        Pattern::Identifier(identifier) => {
+          let slot = identifier.slot.expect("Pattern was not resolved");
+          let name = self.name(slot, &identifier.name);
+          self.get(&subject);
+          self.set(&name);
+        }
+        _ => (),
+      }
+      if let Some(guard) = &arm.guard {
+        self.value(guard);
+        self.condition("match guard");
+        self.line("i32.eqz");
+        self.line(format!("br_if {}", arm_label));
+      }
+      self.instructions(&arm.instructions);
+      self.line(format!("br $match.{}", label));
+      self.close();
+      self.body.indent -= 1;
+      self.line("end");
+    }
+    self.get(&subject);
+    self.line("call $no_arm");
+    self.body.indent -= 1;
+    self.line("end");
+    self.release();
+  }
+
+  // A fish function becomes a wasm function, which is the one calls go to once the definition
+  // runs
+  fn function(&mut self, function: &Function) {
+    let definitions = self.definitions.entry(function.name.clone()).or_default();
+    definitions.push(function.parameters.len());
+    let number = definitions.len();
+    let mangled = mangle(&function.name);
+    self.line(format!("i32.const {}", number));
+    self.line(format!("global.set $function.{}", mangled));
+
+    let outer = mem::replace(&mut self.body, Body::new(true));
+    // The frame of the parameters is the frame of the body, and they start out assigned
+    self.open(false);
+    for (index, parameter) in function.parameters.iter().enumerate() {
+      self.name(Slot { depth: 0, index }, &parameter.variable.name);
+    }
+    self.body.parameters = function.parameters.len();
+    self.instructions(&function.instructions);
+    self.none();
+    self.close();
+    let body = mem::replace(&mut self.body, outer);
+    let head = format!("${}.{}", mangled, number);
+    self.functions.push(body.finish(&head));
+  }
+
+  /*
+   Finds the definition of the function that ran last and calls it when it takes that many
+   arguments. Without one the name can still be the builtin exit
+  */
+  fn dispatcher(&mut self, name: &str, count: usize) -> String {
+    let mangled = mangle(name);
+    let mut text = format!("  (func $call.{}.{}", mangled, count);
+    for _ in 0..count {
+      text.push_str(" (param i32 i64)");
+    }
+    text.push_str(" (result i32 i64)\n");
+    let arguments: String = (0..count * 2)
+      .map(|index| format!(" (local.get {})", index))
+      .collect();
+    let definitions = self.definitions.get(name).cloned().unwrap_or_default();
+    for (index, arity) in definitions.iter().enumerate() {
+      text.push_str(&format!(
+        "    (if (i32.eq (global.get $function.{}) (i32.const {}))\n",
+        mangled,
+        index + 1
+      ));
+      match *arity == count {
+        true => {
+          text.push_str("      (then\n        (call $enter)\n");
+          text.push_str(&format!(
+            "        (call ${}.{}{})\n",
+            mangled,
+            index + 1,
+            arguments
+          ));
+          text.push_str("        (call $leave)\n        (return)))\n");
+        }
+        false => {
+          let message = format!(
+            "Argument mismatch: Function '{}' takes {} arguments but {} were given",
+            name, arity, count
+          );
+          let payload = self.string(&message);
+          text.push_str(&format!(
+            "      (then (call $fail (i64.const {})))) ;; {:?}\n",
+            payload, message
+          ));
+        }
+      }
+    }
+    let fallback = match (name == EXIT, count) {
+      (true, 0) => "(call $host_exit (i32.const 0))".to_string(),
+      (true, 1) => "(call $exit (local.get 0) (local.get 1))".to_string(),
+      (true, count) => {
+        let message = format!(
+          "Argument mismatch: Function 'exit' takes 1 argument but {} were given",
+          count
+        );
+        format!(
+          "(call $fail (i64.const {})) ;; {:?}",
+          self.string(&message),
+          message
+        )
+      }
+      (false, _) => {
+        let message = format!("Function '{}' is not defined", name);
+        format!(
+          "(call $fail (i64.const {})) ;; {:?}",
+          self.string(&message),
+          message
+        )
+      }
+    };
+    text.push_str(&format!("    {}\n    (unreachable))\n", fallback));
+    text
+  }
+
+  fn assert(&mut self, position: Position, condition: &Value, message: Option<&Value>) {
+    let position = position.to_string();
+    let mut compared = condition;
+    while let Value::Expression(expression) = compared {
+      if *expression.get_operator() != Operator::Brackets {
+        break;
+      }
+      compared = expression.get_left();
+    }
+    match compared {
+      Value::Expression(expression) if comparison(*expression.get_operator()).is_some() => {
+        let operator = *expression.get_operator();
+        self.value(expression.get_left());
+        let left = self.temporary();
+        self.set(&left);
+        self.value(expression.get_right().expect("No right for comparison"));
+        let right = self.temporary();
+        self.set(&right);
+        self.line(format!(
+          "i32.const {}",
+          comparison(operator).unwrap_or_default()
+        ));
+        self.get(&left);
+        self.get(&right);
+        self.line("call $compare");
+        self.line("i32.eqz");
+        self.line("if");
+        self.body.indent += 1;
+        self.string_line(&position);
+        self.message(message);
+        self.get(&left);
+        self.string_line(&operator.to_string());
+        self.get(&right);
+        self.line("call $assert_failed");
+        self.body.indent -= 1;
+        self.line("end");
+        self.release();
+        self.release();
+      }
+      _ => {
+        self.value(condition);
+        self.line("call $assert_condition");
+        self.line("i32.eqz");
+        self.line("if");
+        self.body.indent += 1;
+        self.string_line(&position);
+        self.message(message);
+        // The sides of a comparison, there are none
+        self.line("i32.const 0");
+        self.line("i64.const 0");
+        self.line("i64.const 0");
+        self.line("i32.const 0");
+        self.line("i64.const 0");
+        self.line("call $assert_failed");
+        self.body.indent -= 1;
+        self.line("end");
+      }
+    }
+  }
+
+  // The message of a failed assert, which is only evaluated once it failed
+  fn message(&mut self, message: Option<&Value>) {
+    match message {
+      Some(message) => self.value(message),
+      None => {
+        self.line("i32.const 0");
+        self.line("i64.const 0");
+      }
+    }
+  }
+
+  // Code that puts the tag and the payload of the value on the stack, evaluating everything in
+  // the order the interpreter does
+  fn value(&mut self, value: &Value) {
+    match value {
+      Value::Number(Number::Integer(integer)) => {
+        self.line("i32.const 3");
+        self.line(format!("i64.const {}", integer));
+      }
+      Value::Number(Number::Float(float)) => {
+        self.line("i32.const 4");
+        self.line(format!(
+          "i64.const {} ;; {:?}",
+          float.to_bits() as i64,
+          float
+        ));
+      }
+      Value::String(text) => {
+        self.line("i32.const 5");
+        self.string_line(text);
+      }
+      Value::Boolean(boolean) => {
+        self.line("i32.const 2");
+        self.line(format!("i64.const {}", *boolean as u8));
+      }
+      Value::None => self.none(),
+      Value::List(_) => unreachable!("Lists are turned down before generating"),
+      Value::Identifier(identifier) => match identifier.slot {
+        Some(slot) => {
+          let name = self.name(slot, &identifier.name);
+          self.get(&name);
+          self.string_line(&identifier.name);
+          self.line("call $variable");
+        }
+        None => self.undefined_variable(&identifier.name),
+      },
+      Value::Call {
+        name, arguments, ..
+      } => {
+        for argument in arguments {
+          self.value(argument);
+        }
+        self.calls.insert((name.clone(), arguments.len()));
+        self.line(format!("call $call.{}.{}", mangle(name), arguments.len()));
+      }
+      // A none in an optional access breaks out of the whole chain with none
+      Value::Field { .. } | Value::Index { .. } if value.has_optional() => {
+        let label = self.label();
+        self.line(format!("block $access.{} (result i32 i64)", label));
+        self.body.indent += 1;
+        self.access(value, label);
+        self.body.indent -= 1;
+        self.line("end");
+      }
+      Value::Field { .. } | Value::Index { .. } => self.access(value, 0),
+      Value::Expression(expression) => self.expression(expression),
+    }
+  }
+
+  fn access(&mut self, value: &Value, label: usize) {
+    let (base, optional) = match value {
+      Value::Field {
+        value, optional, ..
+      }
+      | Value::Index {
+        value, optional, ..
+      } => (value, *optional),
+      value => return self.value(value),
+    };
+    self.access(base, label);
+    if optional {
+      let base = self.temporary();
+      self.set(&base);
+      self.none();
+      self.line(format!("local.get {}.tag", base));
+      self.line("i32.const 1");
+      self.line("i32.eq");
+      self.line(format!("br_if $access.{}", label));
+      self.line("drop");
+      self.line("drop");
+      self.get(&base);
+      self.release();
+    }
+    match value {
+      Value::Field { .. } => self.line("call $field"),
+      Value::Index { index, .. } => {
+        self.value(index);
+        self.line("call $index");
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  fn expression(&mut self, expression: &Expression) {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    match operator {
+      Operator::Brackets => self.value(left),
+      Operator::Not => {
+        self.value(left);
+        self.line("call $not");
+      }
+      operator if operator.is_assignment() => self.assignment(expression, true),
+      // The right side is only evaluated when the left is none
+      Operator::Coalesce => {
+        self.value(left);
+        let value = self.temporary();
+        self.set(&value);
+        self.line(format!("local.get {}.tag", value));
+        self.line("i32.const 1");
+        self.line("i32.eq");
+        self.line("if (result i32 i64)");
+        self.body.indent += 1;
+        self.value(expression.get_right().expect("No right for coalesce"));
+        self.body.indent -= 1;
+        self.line("else");
+        self.body.indent += 1;
+        self.get(&value);
+        self.body.indent -= 1;
+        self.line("end");
+        self.release();
+      }
+      operator => {
+        let right = expression.get_right().expect("No right for operator");
+        match comparison(operator) {
+          Some(code) => {
+            self.line(format!("i32.const {}", code));
+            self.value(left);
+            self.value(right);
+            self.line("call $comparison");
+          }
+          None => {
+            self.value(left);
+            self.value(right);
+            self.line(format!("call ${}", runtime_function(operator)));
+          }
+        }
+      }
+    }
+  }
+
+  // An assignment, which leaves the value on the stack when it is used
+  fn assignment(&mut self, expression: &Expression, used: bool) {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    let right = expression.get_right().expect("No right for assignment");
+    let (slot, name) = match left {
+      Value::Identifier(Identifier {
+        slot: Some(slot),
+        name,
+        ..
+      }) => (*slot, name),
+      Value::Identifier(identifier) => return self.undefined_variable(&identifier.name),
+      _ => return self.fail("Type mismatch: Expected identifier on left side of assignment"),
+    };
+    match operator {
+      Operator::Assign => self.value(right),
+      operator => {
+        self.value(left);
+        self.value(right);
+        self.line(format!("call ${}", runtime_function(operator)));
+      }
+    }
+    let name = self.name(slot, name);
+    self.set(&name);
+    if used {
+      self.get(&name);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{samples, wat};
+  use wasmi::{Caller, Config, Engine, Extern, Linker, Module, StackLimits, Store};
+
+  // What the module wrote through the host
+  #[derive(Default)]
+  struct Host {
+    output: String,
+    error: String,
+  }
+
+  fn text(caller: &Caller<Host>, address: i32, length: i32) -> String {
+    let memory = caller
+      .get_export("memory")
+      .and_then(Extern::into_memory)
+      .expect("The module has no memory");
+    let bytes = &memory.data(caller)[address as usize..][..length as usize];
+    String::from_utf8(bytes.to_vec()).expect("The module wrote text that is not UTF-8")
+  }
+
+  // Runs the module like a host that follows the README would, without any input
+  fn run(module: &[u8]) -> samples::Ending {
+    // Every nested call takes up to three frames, the runtime a few more
+    let stack = StackLimits {
+      maximum_recursion_depth: 3 * DEFAULT_MAX_CALLS + 100,
+      ..StackLimits::default()
+    };
+    let mut config = Config::default();
+    config.set_stack_limits(stack);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, module).expect("The module is not valid");
+    let mut store = Store::new(&engine, Host::default());
+    let mut linker = Linker::<Host>::new(&engine);
+    linker
+      .func_wrap(
+        "fish",
+        "print",
+        |mut caller: Caller<Host>, address, length| {
+          let line = text(&caller, address, length);
+          caller.data_mut().output += &format!("{}\n", line);
+        },
+      )
+      .unwrap()
+      .func_wrap(
+        "fish",
+        "error",
+        |mut caller: Caller<Host>, address, length| {
+          let line = text(&caller, address, length);
+          caller.data_mut().error += &format!("{}\n", line);
+        },
+      )
+      .unwrap()
+      .func_wrap("fish", "input", || -> (i32, i32) { (0, -1) })
+      .unwrap()
+      .func_wrap("fish", "exit", |code: i32| -> Result<(), wasmi::Error> {
+        Err(wasmi::Error::i32_exit(code))
+      })
+      .unwrap()
+      .func_wrap(
+        "fish",
+        "float",
+        |mut caller: Caller<Host>, number: f64, address: i32| {
+          let text = number.to_string();
+          let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .expect("The module has no memory");
+          memory
+            .write(&mut caller, address as usize, text.as_bytes())
+            .expect("The float does not fit in memory");
+          text.len() as i32
+        },
+      )
+      .unwrap()
+      .func_wrap("fish", "pow", |a: f64, b: f64| a.powf(b))
+      .unwrap();
+    let instance = linker
+      .instantiate(&mut store, &module)
+      .and_then(|instance| instance.start(&mut store))
+      .expect("The module can not be instantiated");
+    let run = instance
+      .get_typed_func::<(), ()>(&store, "run")
+      .expect("The module does not export run");
+    let code = match run.call(&mut store, ()) {
+      Ok(()) => 0,
+      Err(error) => error
+        .i32_exit_status()
+        .unwrap_or_else(|| panic!("The module trapped: {}", error)),
+    };
+    let Host { output, error } = store.into_data();
+    samples::Ending {
+      output,
+      error,
+      code,
+    }
+  }
+
+  #[test]
+  #[cfg_attr(miri, ignore)]
+  fn modules_do_what_the_interpreter_does() {
+    let mut built = 0;
+    for (name, code) in samples::all().into_iter().chain(samples::edge_cases()) {
+      let Some(expected) = samples::interpret(&code) else {
+        continue;
+      };
+      // Lists, enums and args give an error instead
+      let Ok(text) = transpile(&samples::instructions(&code)) else {
+        continue;
+      };
+      let module = wat::assemble(&text).unwrap_or_else(|error| panic!("{}: {}", name, error));
+      assert_eq!(run(&module), expected, "{}", name);
+      built += 1;
+    }
+    assert!(built > 10, "Only {} samples could be built", built);
+  }
+}
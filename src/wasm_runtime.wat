@@ -0,0 +1,787 @@
+  ;; The runtime of a fish script compiled to WebAssembly. Values behave the way they do in the
+  ;; interpreter and errors print the same messages.
+  ;;
+  ;; A value is a tag and a 64 bit payload: 0 for a variable that was not assigned yet, 1 none,
+  ;; 2 a boolean that is 0 or 1, 3 an integer, 4 the bits of a float and 5 a string, whose payload
+  ;; has the address of its bytes in the low half and their length in the high one. Strings are
+  ;; never changed, so they can share bytes, and memory is never freed.
+  ;;
+  ;; (string "...") is replaced by the payload of a string with those bytes when the module is
+  ;; written.
+
+  ;; Memory for the host to put input in, the first address after it is rounded up to 8
+  (func $alloc (export "alloc") (param $size i32) (result i32)
+    (local $address i32)
+    (local $end i64)
+    (local.set $address (global.get $heap))
+    (local.set $end
+      (i64.and
+        (i64.add
+          (i64.add (i64.extend_i32_u (local.get $address)) (i64.extend_i32_u (local.get $size)))
+          (i64.const 7))
+        (i64.const -8)))
+    (if (i64.gt_u (local.get $end) (i64.shl (i64.extend_i32_u (memory.size)) (i64.const 16)))
+      (then
+        (if (i64.gt_u (local.get $end) (i64.const 0xffff0000))
+          (then (call $panic (string "Out of memory"))))
+        (if (i32.eq
+              (memory.grow
+                (i32.sub
+                  (i32.wrap_i64 (i64.shr_u (i64.add (local.get $end) (i64.const 0xffff)) (i64.const 16)))
+                  (memory.size)))
+              (i32.const -1))
+          (then (call $panic (string "Out of memory"))))))
+    (global.set $heap (i32.wrap_i64 (local.get $end)))
+    (local.get $address))
+
+  ;; Gives back what was allocated after the address, when only part of it was used
+  (func $free_after (param $address i32)
+    (global.set $heap (i32.and (i32.add (local.get $address) (i32.const 7)) (i32.const -8))))
+
+  (func $string (param $address i32) (param $length i32) (result i64)
+    (i64.or
+      (i64.extend_i32_u (local.get $address))
+      (i64.shl (i64.extend_i32_u (local.get $length)) (i64.const 32))))
+
+  (func $address (param $string i64) (result i32)
+    (i32.wrap_i64 (local.get $string)))
+
+  (func $length (param $string i64) (result i32)
+    (i32.wrap_i64 (i64.shr_u (local.get $string) (i64.const 32))))
+
+  (func $join (param $left i64) (param $right i64) (result i64)
+    (local $address i32)
+    (local.set $address
+      (call $alloc (i32.add (call $length (local.get $left)) (call $length (local.get $right)))))
+    (memory.copy
+      (local.get $address)
+      (call $address (local.get $left))
+      (call $length (local.get $left)))
+    (memory.copy
+      (i32.add (local.get $address) (call $length (local.get $left)))
+      (call $address (local.get $right))
+      (call $length (local.get $right)))
+    (call $string
+      (local.get $address)
+      (i32.add (call $length (local.get $left)) (call $length (local.get $right)))))
+
+  (func $same_bytes (param $left i64) (param $right i64) (result i32)
+    (local $index i32)
+    (if (i32.ne (call $length (local.get $left)) (call $length (local.get $right)))
+      (then (return (i32.const 0))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $index) (call $length (local.get $left))))
+        (if (i32.ne
+              (i32.load8_u (i32.add (call $address (local.get $left)) (local.get $index)))
+              (i32.load8_u (i32.add (call $address (local.get $right)) (local.get $index))))
+          (then (return (i32.const 0))))
+        (local.set $index (i32.add (local.get $index) (i32.const 1)))
+        (br $next)))
+    (i32.const 1))
+
+  (func $fail (param $message i64)
+    (local $text i64)
+    (local.set $text (call $join (string "Error interpreting code: ") (local.get $message)))
+    (call $host_error (call $address (local.get $text)) (call $length (local.get $text)))
+    (call $host_exit (i32.const 1))
+    (unreachable))
+
+  ;; Where the interpreter panics, which exits with 101
+  (func $panic (param $message i64)
+    (call $host_error (call $address (local.get $message)) (call $length (local.get $message)))
+    (call $host_exit (i32.const 101))
+    (unreachable))
+
+  (func $is_number (param $tag i32) (result i32)
+    (i32.or (i32.eq (local.get $tag) (i32.const 3)) (i32.eq (local.get $tag) (i32.const 4))))
+
+  (func $are_integers (param $left i32) (param $right i32) (result i32)
+    (i32.and (i32.eq (local.get $left) (i32.const 3)) (i32.eq (local.get $right) (i32.const 3))))
+
+  (func $are_numbers (param $left i32) (param $right i32) (result i32)
+    (i32.and (call $is_number (local.get $left)) (call $is_number (local.get $right))))
+
+  (func $to_float (param $tag i32) (param $payload i64) (result f64)
+    (if (result f64) (i32.eq (local.get $tag) (i32.const 3))
+      (then (f64.convert_i64_s (local.get $payload)))
+      (else (f64.reinterpret_i64 (local.get $payload)))))
+
+  (func $float (param $number f64) (result i32 i64)
+    (i32.const 4)
+    (i64.reinterpret_f64 (local.get $number)))
+
+  (func $boolean (param $boolean i32) (result i32 i64)
+    (i32.const 2)
+    (i64.extend_i32_u (local.get $boolean)))
+
+  (func $add (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (local $sum i64)
+    (if (call $are_integers (local.get $lt) (local.get $rt))
+      (then
+        (local.set $sum (i64.add (local.get $lv) (local.get $rv)))
+        (if (i64.lt_s
+              (i64.and
+                (i64.xor (local.get $lv) (local.get $sum))
+                (i64.xor (local.get $rv) (local.get $sum)))
+              (i64.const 0))
+          (then (call $fail (string "Integer overflow when adding"))))
+        (return (i32.const 3) (local.get $sum))))
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (return
+          (call $float
+            (f64.add
+              (call $to_float (local.get $lt) (local.get $lv))
+              (call $to_float (local.get $rt) (local.get $rv)))))))
+    (if (i32.and (i32.eq (local.get $lt) (i32.const 5)) (i32.eq (local.get $rt) (i32.const 5)))
+      (then (return (i32.const 5) (call $join (local.get $lv) (local.get $rv)))))
+    (call $fail (string "Type mismatch: Expected 2 strings, 2 numbers or 2 lists when adding"))
+    (unreachable))
+
+  (func $subtract (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (local $difference i64)
+    (if (call $are_integers (local.get $lt) (local.get $rt))
+      (then
+        (local.set $difference (i64.sub (local.get $lv) (local.get $rv)))
+        (if (i64.lt_s
+              (i64.and
+                (i64.xor (local.get $lv) (local.get $rv))
+                (i64.xor (local.get $lv) (local.get $difference)))
+              (i64.const 0))
+          (then (call $fail (string "Integer overflow when subtracting"))))
+        (return (i32.const 3) (local.get $difference))))
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (return
+          (call $float
+            (f64.sub
+              (call $to_float (local.get $lt) (local.get $lv))
+              (call $to_float (local.get $rt) (local.get $rv)))))))
+    (call $fail (string "Type mismatch: Expected 2 numbers when subtracting"))
+    (unreachable))
+
+  (func $multiply (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (local $product i64)
+    (local $overflow i32)
+    (if (call $are_integers (local.get $lt) (local.get $rt))
+      (then
+        (local.set $product (i64.mul (local.get $lv) (local.get $rv)))
+        ;; Dividing the product by one side gives the other unless it overflowed, -1 can not be
+        ;; divided by
+        (if (i64.eq (local.get $lv) (i64.const -1))
+          (then
+            (local.set $overflow (i64.eq (local.get $rv) (i64.const -9223372036854775808))))
+          (else
+            (if (i64.ne (local.get $lv) (i64.const 0))
+              (then
+                (local.set $overflow
+                  (i64.ne (i64.div_s (local.get $product) (local.get $lv)) (local.get $rv)))))))
+        (if (local.get $overflow)
+          (then (call $fail (string "Integer overflow when multiplying"))))
+        (return (i32.const 3) (local.get $product))))
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (return
+          (call $float
+            (f64.mul
+              (call $to_float (local.get $lt) (local.get $lv))
+              (call $to_float (local.get $rt) (local.get $rv)))))))
+    (call $fail (string "Type mismatch: Expected 2 numbers when multiplying"))
+    (unreachable))
+
+  ;; Integer division rounds towards zero
+  (func $divide (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (if (call $are_integers (local.get $lt) (local.get $rt))
+      (then
+        (if (i64.eqz (local.get $rv))
+          (then (call $fail (string "Division by zero"))))
+        (if (i32.and
+              (i64.eq (local.get $lv) (i64.const -9223372036854775808))
+              (i64.eq (local.get $rv) (i64.const -1)))
+          (then (call $fail (string "Integer overflow when dividing"))))
+        (return (i32.const 3) (i64.div_s (local.get $lv) (local.get $rv)))))
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (return
+          (call $float
+            (f64.div
+              (call $to_float (local.get $lt) (local.get $lv))
+              (call $to_float (local.get $rt) (local.get $rv)))))))
+    (call $fail (string "Type mismatch: Expected 2 numbers when dividing"))
+    (unreachable))
+
+  (func $modulo (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (if (call $are_integers (local.get $lt) (local.get $rt))
+      (then
+        (if (i64.eqz (local.get $rv))
+          (then (call $fail (string "Division by zero"))))
+        (if (i32.and
+              (i64.eq (local.get $lv) (i64.const -9223372036854775808))
+              (i64.eq (local.get $rv) (i64.const -1)))
+          (then (call $fail (string "Integer overflow when taking modulo"))))
+        (return (i32.const 3) (i64.rem_s (local.get $lv) (local.get $rv)))))
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (return
+          (call $float
+            (call $fmod
+              (call $to_float (local.get $lt) (local.get $lv))
+              (call $to_float (local.get $rt) (local.get $rv)))))))
+    (call $fail (string "Type mismatch: Expected 2 numbers when taking modulo"))
+    (unreachable))
+
+  ;; The remainder of a float division, exactly like C's fmod, by long division on the bits
+  (func $fmod (param $x f64) (param $y f64) (result f64)
+    (local $ux i64)
+    (local $uy i64)
+    (local $ex i64)
+    (local $ey i64)
+    (local $sign i64)
+    (local $i i64)
+    (local.set $ux (i64.reinterpret_f64 (local.get $x)))
+    (local.set $uy (i64.reinterpret_f64 (local.get $y)))
+    (local.set $ex (i64.and (i64.shr_u (local.get $ux) (i64.const 52)) (i64.const 0x7ff)))
+    (local.set $ey (i64.and (i64.shr_u (local.get $uy) (i64.const 52)) (i64.const 0x7ff)))
+    (local.set $sign (i64.and (local.get $ux) (i64.const 0x8000000000000000)))
+    (if (i32.or
+          (i32.or
+            (i64.eqz (i64.shl (local.get $uy) (i64.const 1)))
+            (f64.ne (local.get $y) (local.get $y)))
+          (i64.eq (local.get $ex) (i64.const 0x7ff)))
+      (then
+        (return
+          (f64.div
+            (f64.mul (local.get $x) (local.get $y))
+            (f64.mul (local.get $x) (local.get $y))))))
+    (if (i64.le_u (i64.shl (local.get $ux) (i64.const 1)) (i64.shl (local.get $uy) (i64.const 1)))
+      (then
+        (if (i64.eq (i64.shl (local.get $ux) (i64.const 1)) (i64.shl (local.get $uy) (i64.const 1)))
+          (then (return (f64.mul (f64.const 0) (local.get $x)))))
+        (return (local.get $x))))
+    ;; Both become a mantissa with the leading bit at 52
+    (if (i64.eqz (local.get $ex))
+      (then
+        (local.set $i (i64.shl (local.get $ux) (i64.const 12)))
+        (block $done
+          (loop $next
+            (br_if $done (i64.ne (i64.shr_u (local.get $i) (i64.const 63)) (i64.const 0)))
+            (local.set $ex (i64.sub (local.get $ex) (i64.const 1)))
+            (local.set $i (i64.shl (local.get $i) (i64.const 1)))
+            (br $next)))
+        (local.set $ux
+          (i64.shl (local.get $ux) (i64.add (i64.sub (i64.const 0) (local.get $ex)) (i64.const 1)))))
+      (else
+        (local.set $ux
+          (i64.or
+            (i64.and (local.get $ux) (i64.const 0x000fffffffffffff))
+            (i64.const 0x0010000000000000)))))
+    (if (i64.eqz (local.get $ey))
+      (then
+        (local.set $i (i64.shl (local.get $uy) (i64.const 12)))
+        (block $done
+          (loop $next
+            (br_if $done (i64.ne (i64.shr_u (local.get $i) (i64.const 63)) (i64.const 0)))
+            (local.set $ey (i64.sub (local.get $ey) (i64.const 1)))
+            (local.set $i (i64.shl (local.get $i) (i64.const 1)))
+            (br $next)))
+        (local.set $uy
+          (i64.shl (local.get $uy) (i64.add (i64.sub (i64.const 0) (local.get $ey)) (i64.const 1)))))
+      (else
+        (local.set $uy
+          (i64.or
+            (i64.and (local.get $uy) (i64.const 0x000fffffffffffff))
+            (i64.const 0x0010000000000000)))))
+    (block $done
+      (loop $next
+        (br_if $done (i64.le_s (local.get $ex) (local.get $ey)))
+        (local.set $i (i64.sub (local.get $ux) (local.get $uy)))
+        (if (i64.ge_s (local.get $i) (i64.const 0))
+          (then
+            (if (i64.eqz (local.get $i))
+              (then (return (f64.mul (f64.const 0) (local.get $x)))))
+            (local.set $ux (local.get $i))))
+        (local.set $ux (i64.shl (local.get $ux) (i64.const 1)))
+        (local.set $ex (i64.sub (local.get $ex) (i64.const 1)))
+        (br $next)))
+    (local.set $i (i64.sub (local.get $ux) (local.get $uy)))
+    (if (i64.ge_s (local.get $i) (i64.const 0))
+      (then
+        (if (i64.eqz (local.get $i))
+          (then (return (f64.mul (f64.const 0) (local.get $x)))))
+        (local.set $ux (local.get $i))))
+    (block $done
+      (loop $next
+        (br_if $done (i64.ne (i64.shr_u (local.get $ux) (i64.const 52)) (i64.const 0)))
+        (local.set $ux (i64.shl (local.get $ux) (i64.const 1)))
+        (local.set $ex (i64.sub (local.get $ex) (i64.const 1)))
+        (br $next)))
+    (if (i64.gt_s (local.get $ex) (i64.const 0))
+      (then
+        (local.set $ux
+          (i64.or
+            (i64.sub (local.get $ux) (i64.const 0x0010000000000000))
+            (i64.shl (local.get $ex) (i64.const 52)))))
+      (else
+        (local.set $ux
+          (i64.shr_u (local.get $ux) (i64.add (i64.sub (i64.const 0) (local.get $ex)) (i64.const 1))))))
+    (f64.reinterpret_i64 (i64.or (local.get $ux) (local.get $sign))))
+
+  ;; Always a float, even for two integers
+  (func $power (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (return
+          (call $float
+            (call $host_pow
+              (call $to_float (local.get $lt) (local.get $lv))
+              (call $to_float (local.get $rt) (local.get $rv)))))))
+    (call $fail (string "Type mismatch: Expected 2 numbers when taking exponent"))
+    (unreachable))
+
+  ;; Integers and floats are equal when they are the same number
+  (func $equal (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32)
+    (if (call $are_numbers (local.get $lt) (local.get $rt))
+      (then
+        (if (call $are_integers (local.get $lt) (local.get $rt))
+          (then (return (i64.eq (local.get $lv) (local.get $rv)))))
+        (return
+          (f64.eq
+            (call $to_float (local.get $lt) (local.get $lv))
+            (call $to_float (local.get $rt) (local.get $rv))))))
+    (if (i32.ne (local.get $lt) (local.get $rt))
+      (then (return (i32.const 0))))
+    (if (i32.eq (local.get $lt) (i32.const 5))
+      (then (return (call $same_bytes (local.get $lv) (local.get $rv)))))
+    (i64.eq (local.get $lv) (local.get $rv)))
+
+  ;; The operators are ==, !=, <, <=, > and >= in that order
+  (func $compare (param $operator i32) (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32)
+    (local $a f64)
+    (local $b f64)
+    (if (i32.eqz (local.get $operator))
+      (then (return (call $equal (local.get $lt) (local.get $lv) (local.get $rt) (local.get $rv)))))
+    (if (i32.eq (local.get $operator) (i32.const 1))
+      (then
+        (return
+          (i32.eqz (call $equal (local.get $lt) (local.get $lv) (local.get $rt) (local.get $rv))))))
+    (if (i32.eqz (call $are_numbers (local.get $lt) (local.get $rt)))
+      (then (call $fail (string "Type mismatch: Expected 2 numbers "))))
+    (if (call $are_integers (local.get $lt) (local.get $rt))
+      (then
+        (if (i32.eq (local.get $operator) (i32.const 2))
+          (then (return (i64.lt_s (local.get $lv) (local.get $rv)))))
+        (if (i32.eq (local.get $operator) (i32.const 3))
+          (then (return (i64.le_s (local.get $lv) (local.get $rv)))))
+        (if (i32.eq (local.get $operator) (i32.const 4))
+          (then (return (i64.gt_s (local.get $lv) (local.get $rv)))))
+        (return (i64.ge_s (local.get $lv) (local.get $rv)))))
+    (local.set $a (call $to_float (local.get $lt) (local.get $lv)))
+    (local.set $b (call $to_float (local.get $rt) (local.get $rv)))
+    (if (i32.eq (local.get $operator) (i32.const 2))
+      (then (return (f64.lt (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $operator) (i32.const 3))
+      (then (return (f64.le (local.get $a) (local.get $b)))))
+    (if (i32.eq (local.get $operator) (i32.const 4))
+      (then (return (f64.gt (local.get $a) (local.get $b)))))
+    (f64.ge (local.get $a) (local.get $b)))
+
+  (func $comparison (param $operator i32) (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (call $boolean
+      (call $compare
+        (local.get $operator)
+        (local.get $lt)
+        (local.get $lv)
+        (local.get $rt)
+        (local.get $rv))))
+
+  ;; Both sides are evaluated, so && and || only need booleans
+  (func $and (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (if (i32.or (i32.ne (local.get $lt) (i32.const 2)) (i32.ne (local.get $rt) (i32.const 2)))
+      (then (call $fail (string "Type mismatch: Expected 2 booleans "))))
+    (i32.const 2)
+    (i64.and (local.get $lv) (local.get $rv)))
+
+  (func $or (param $lt i32) (param $lv i64) (param $rt i32) (param $rv i64) (result i32 i64)
+    (if (i32.or (i32.ne (local.get $lt) (i32.const 2)) (i32.ne (local.get $rt) (i32.const 2)))
+      (then (call $fail (string "Type mismatch: Expected 2 booleans "))))
+    (i32.const 2)
+    (i64.or (local.get $lv) (local.get $rv)))
+
+  (func $not (param $tag i32) (param $payload i64) (result i32 i64)
+    (if (i32.ne (local.get $tag) (i32.const 2))
+      (then (call $fail (string "Type mismatch: Expected 1 boolean "))))
+    (call $boolean (i64.eqz (local.get $payload))))
+
+  ;; What is the condition of an if, a while or a match guard
+  (func $condition (param $tag i32) (param $payload i64) (param $what i64) (result i32)
+    (if (i32.ne (local.get $tag) (i32.const 2))
+      (then
+        (call $fail (call $join (string "Type mismatch: Expected boolean for ") (local.get $what)))))
+    (i32.wrap_i64 (local.get $payload)))
+
+  (func $undefined_variable (param $name i64)
+    (call $fail
+      (call $join
+        (call $join (string "Variable '") (local.get $name))
+        (string "' is not defined"))))
+
+  ;; A variable that was not assigned yet would be a variant without fields, but there are none
+  (func $variable (param $tag i32) (param $payload i64) (param $name i64) (result i32 i64)
+    (if (i32.eqz (local.get $tag))
+      (then (call $undefined_variable (local.get $name))))
+    (local.get $tag)
+    (local.get $payload))
+
+  (func $out_of_bounds (param $index i64)
+    (call $fail
+      (call $join
+        (call $join (string "Index ") (call $integer_text (local.get $index)))
+        (string " is out of bounds"))))
+
+  ;; Strings are indexed by character, not by byte
+  (func $index (param $tag i32) (param $payload i64) (param $it i32) (param $iv i64) (result i32 i64)
+    (local $offset i32)
+    (local $size i32)
+    (local $skipped i64)
+    (if (i32.ne (local.get $it) (i32.const 3))
+      (then (call $fail (string "Type mismatch: Expected integer as index"))))
+    (if (i64.lt_s (local.get $iv) (i64.const 0))
+      (then (call $out_of_bounds (local.get $iv))))
+    (if (i32.ne (local.get $tag) (i32.const 5))
+      (then
+        (call $fail (string "Type mismatch: Expected list, string or enum value when indexing"))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $offset) (call $length (local.get $payload))))
+        (call $decode
+          (i32.add (call $address (local.get $payload)) (local.get $offset))
+          (i32.sub (call $length (local.get $payload)) (local.get $offset)))
+        (local.set $size)
+        (drop)
+        (if (i64.eq (local.get $skipped) (local.get $iv))
+          (then
+            (return
+              (i32.const 5)
+              (call $string
+                (i32.add (call $address (local.get $payload)) (local.get $offset))
+                (local.get $size)))))
+        (local.set $offset (i32.add (local.get $offset) (local.get $size)))
+        (local.set $skipped (i64.add (local.get $skipped) (i64.const 1)))
+        (br $next)))
+    (call $out_of_bounds (local.get $iv))
+    (unreachable))
+
+  ;; Only enum values have fields
+  (func $field (param $tag i32) (param $payload i64) (result i32 i64)
+    (call $fail (string "Type mismatch: Expected enum value when accessing a field"))
+    (unreachable))
+
+  (func $integer_text (param $integer i64) (result i64)
+    (local $end i32)
+    (local $position i32)
+    (local $rest i64)
+    (local.set $end (i32.add (call $alloc (i32.const 20)) (i32.const 20)))
+    (local.set $position (local.get $end))
+    (local.set $rest
+      (if (result i64) (i64.lt_s (local.get $integer) (i64.const 0))
+        (then (i64.sub (i64.const 0) (local.get $integer)))
+        (else (local.get $integer))))
+    (loop $next
+      (local.set $position (i32.sub (local.get $position) (i32.const 1)))
+      (i32.store8
+        (local.get $position)
+        (i32.add (i32.const 48) (i32.wrap_i64 (i64.rem_u (local.get $rest) (i64.const 10)))))
+      (local.set $rest (i64.div_u (local.get $rest) (i64.const 10)))
+      (br_if $next (i64.ne (local.get $rest) (i64.const 0))))
+    (if (i64.lt_s (local.get $integer) (i64.const 0))
+      (then
+        (local.set $position (i32.sub (local.get $position) (i32.const 1)))
+        (i32.store8 (local.get $position) (i32.const 45))))
+    (call $string (local.get $position) (i32.sub (local.get $end) (local.get $position))))
+
+  ;; The host writes floats like Rust does, which takes at most 400 bytes
+  (func $float_text (param $number f64) (result i64)
+    (local $address i32)
+    (local $length i32)
+    (local.set $address (call $alloc (i32.const 400)))
+    (local.set $length (call $host_float (local.get $number) (local.get $address)))
+    (call $free_after (i32.add (local.get $address) (local.get $length)))
+    (call $string (local.get $address) (local.get $length)))
+
+  (func $display (param $tag i32) (param $payload i64) (result i64)
+    (if (i32.eq (local.get $tag) (i32.const 1))
+      (then (return (string "none"))))
+    (if (i32.eq (local.get $tag) (i32.const 2))
+      (then
+        (return
+          (if (result i64) (i64.eqz (local.get $payload))
+            (then (string "false"))
+            (else (string "true"))))))
+    (if (i32.eq (local.get $tag) (i32.const 3))
+      (then (return (call $integer_text (local.get $payload)))))
+    (if (i32.eq (local.get $tag) (i32.const 4))
+      (then (return (call $float_text (f64.reinterpret_i64 (local.get $payload))))))
+    (local.get $payload))
+
+  (func $put (param $address i32) (param $byte i32) (result i32)
+    (i32.store8 (local.get $address) (local.get $byte))
+    (i32.add (local.get $address) (i32.const 1)))
+
+  (func $hex_digit (param $digit i32) (result i32)
+    (if (result i32) (i32.lt_u (local.get $digit) (i32.const 10))
+      (then (i32.add (local.get $digit) (i32.const 48)))
+      (else (i32.add (local.get $digit) (i32.const 87)))))
+
+  ;; Like display, but strings are quoted the way Rust quotes them
+  (func $describe (param $tag i32) (param $payload i64) (result i64)
+    (local $start i32)
+    (local $out i32)
+    (local $index i32)
+    (local $byte i32)
+    (if (i32.ne (local.get $tag) (i32.const 5))
+      (then (return (call $display (local.get $tag) (local.get $payload)))))
+    ;; A byte takes at most 6 characters
+    (local.set $start
+      (call $alloc (i32.add (i32.mul (call $length (local.get $payload)) (i32.const 6)) (i32.const 2))))
+    (local.set $out (call $put (local.get $start) (i32.const 34)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $index) (call $length (local.get $payload))))
+        (local.set $byte
+          (i32.load8_u (i32.add (call $address (local.get $payload)) (local.get $index))))
+        (local.set $index (i32.add (local.get $index) (i32.const 1)))
+        (if (i32.or (i32.eq (local.get $byte) (i32.const 34)) (i32.eq (local.get $byte) (i32.const 92)))
+          (then
+            (local.set $out (call $put (call $put (local.get $out) (i32.const 92)) (local.get $byte)))
+            (br $next)))
+        (if (i32.eq (local.get $byte) (i32.const 10))
+          (then
+            (local.set $out (call $put (call $put (local.get $out) (i32.const 92)) (i32.const 110)))
+            (br $next)))
+        (if (i32.eq (local.get $byte) (i32.const 13))
+          (then
+            (local.set $out (call $put (call $put (local.get $out) (i32.const 92)) (i32.const 114)))
+            (br $next)))
+        (if (i32.eq (local.get $byte) (i32.const 9))
+          (then
+            (local.set $out (call $put (call $put (local.get $out) (i32.const 92)) (i32.const 116)))
+            (br $next)))
+        (if (i32.eqz (local.get $byte))
+          (then
+            (local.set $out (call $put (call $put (local.get $out) (i32.const 92)) (i32.const 48)))
+            (br $next)))
+        (if (i32.or (i32.lt_u (local.get $byte) (i32.const 0x20)) (i32.eq (local.get $byte) (i32.const 0x7f)))
+          (then
+            ;; \u{1f}
+            (local.set $out (call $put (local.get $out) (i32.const 92)))
+            (local.set $out (call $put (local.get $out) (i32.const 117)))
+            (local.set $out (call $put (local.get $out) (i32.const 123)))
+            (if (i32.ge_u (local.get $byte) (i32.const 16))
+              (then
+                (local.set $out
+                  (call $put
+                    (local.get $out)
+                    (call $hex_digit (i32.shr_u (local.get $byte) (i32.const 4)))))))
+            (local.set $out
+              (call $put (local.get $out) (call $hex_digit (i32.and (local.get $byte) (i32.const 15)))))
+            (local.set $out (call $put (local.get $out) (i32.const 125)))
+            (br $next)))
+        (local.set $out (call $put (local.get $out) (local.get $byte)))
+        (br $next)))
+    (local.set $out (call $put (local.get $out) (i32.const 34)))
+    (call $free_after (local.get $out))
+    (call $string (local.get $start) (i32.sub (local.get $out) (local.get $start))))
+
+  (func $print (param $tag i32) (param $payload i64)
+    (local $text i64)
+    (local.set $text (call $display (local.get $tag) (local.get $payload)))
+    (call $host_print (call $address (local.get $text)) (call $length (local.get $text))))
+
+  ;; The character at the address and how many bytes it takes, with at most length bytes to read
+  (func $decode (param $address i32) (param $length i32) (result i32 i32)
+    (local $first i32)
+    (local $second i32)
+    (local.set $first (i32.load8_u (local.get $address)))
+    (if (i32.or (i32.lt_u (local.get $first) (i32.const 0x80)) (i32.lt_u (local.get $length) (i32.const 2)))
+      (then (return (local.get $first) (i32.const 1))))
+    (local.set $second (i32.and (i32.load8_u offset=1 (local.get $address)) (i32.const 0x3f)))
+    (if (i32.lt_u (local.get $first) (i32.const 0xe0))
+      (then
+        (return
+          (i32.or
+            (i32.shl (i32.and (local.get $first) (i32.const 0x1f)) (i32.const 6))
+            (local.get $second))
+          (i32.const 2))))
+    (if (i32.or (i32.lt_u (local.get $first) (i32.const 0xf0)) (i32.lt_u (local.get $length) (i32.const 4)))
+      (then
+        (if (i32.lt_u (local.get $length) (i32.const 3))
+          (then
+            (return
+              (i32.or
+                (i32.shl (i32.and (local.get $first) (i32.const 0x0f)) (i32.const 12))
+                (i32.shl (local.get $second) (i32.const 6)))
+              (local.get $length))))
+        (return
+          (i32.or
+            (i32.or
+              (i32.shl (i32.and (local.get $first) (i32.const 0x0f)) (i32.const 12))
+              (i32.shl (local.get $second) (i32.const 6)))
+            (i32.and (i32.load8_u offset=2 (local.get $address)) (i32.const 0x3f)))
+          (i32.const 3))))
+    (i32.or
+      (i32.or
+        (i32.shl (i32.and (local.get $first) (i32.const 0x07)) (i32.const 18))
+        (i32.shl (local.get $second) (i32.const 12)))
+      (i32.or
+        (i32.shl (i32.and (i32.load8_u offset=2 (local.get $address)) (i32.const 0x3f)) (i32.const 6))
+        (i32.and (i32.load8_u offset=3 (local.get $address)) (i32.const 0x3f))))
+    (i32.const 4))
+
+  ;; The characters str::trim removes
+  (func $is_space (param $character i32) (result i32)
+    (i32.or
+      (i32.or
+        (i32.or
+          (i32.and
+            (i32.ge_u (local.get $character) (i32.const 0x09))
+            (i32.le_u (local.get $character) (i32.const 0x0d)))
+          (i32.or
+            (i32.eq (local.get $character) (i32.const 0x20))
+            (i32.eq (local.get $character) (i32.const 0x85))))
+        (i32.or
+          (i32.or
+            (i32.eq (local.get $character) (i32.const 0xa0))
+            (i32.eq (local.get $character) (i32.const 0x1680)))
+          (i32.and
+            (i32.ge_u (local.get $character) (i32.const 0x2000))
+            (i32.le_u (local.get $character) (i32.const 0x200a)))))
+      (i32.or
+        (i32.or
+          (i32.eq (local.get $character) (i32.const 0x2028))
+          (i32.eq (local.get $character) (i32.const 0x2029)))
+        (i32.or
+          (i32.or
+            (i32.eq (local.get $character) (i32.const 0x202f))
+            (i32.eq (local.get $character) (i32.const 0x205f)))
+          (i32.eq (local.get $character) (i32.const 0x3000))))))
+
+  ;; A line from the host without the whitespace around it, none at the end of the input
+  (func $input (result i32 i64)
+    (local $address i32)
+    (local $length i32)
+    (local $start i32)
+    (local $end i32)
+    (local $back i32)
+    (local $size i32)
+    (call $host_input)
+    (local.set $length)
+    (local.set $address)
+    (if (i32.lt_s (local.get $length) (i32.const 0))
+      (then (return (i32.const 1) (i64.const 0))))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $start) (local.get $length)))
+        (call $decode
+          (i32.add (local.get $address) (local.get $start))
+          (i32.sub (local.get $length) (local.get $start)))
+        (local.set $size)
+        (br_if $done (i32.eqz (call $is_space)))
+        (local.set $start (i32.add (local.get $start) (local.get $size)))
+        (br $next)))
+    (local.set $end (local.get $length))
+    (block $done
+      (loop $next
+        (br_if $done (i32.le_u (local.get $end) (local.get $start)))
+        (local.set $back (i32.sub (local.get $end) (i32.const 1)))
+        (block $found
+          (loop $continuation
+            (br_if $found (i32.le_u (local.get $back) (local.get $start)))
+            (br_if $found
+              (i32.ne
+                (i32.and
+                  (i32.load8_u (i32.add (local.get $address) (local.get $back)))
+                  (i32.const 0xc0))
+                (i32.const 0x80)))
+            (local.set $back (i32.sub (local.get $back) (i32.const 1)))
+            (br $continuation)))
+        (call $decode
+          (i32.add (local.get $address) (local.get $back))
+          (i32.sub (local.get $end) (local.get $back)))
+        (drop)
+        (br_if $done (i32.eqz (call $is_space)))
+        (local.set $end (local.get $back))
+        (br $next)))
+    (i32.const 5)
+    (call $string
+      (i32.add (local.get $address) (local.get $start))
+      (i32.sub (local.get $end) (local.get $start))))
+
+  (func $enter
+    (if (i32.ge_u (global.get $calls) (global.get $max_calls))
+      (then
+        (call $fail
+          (call $join
+            (call $join
+              (string "Limit exceeded, more than ")
+              (call $integer_text (i64.extend_i32_u (global.get $max_calls))))
+            (string " function calls were nested")))))
+    (global.set $calls (i32.add (global.get $calls) (i32.const 1))))
+
+  (func $leave
+    (global.set $calls (i32.sub (global.get $calls) (i32.const 1))))
+
+  ;; exit(code), the code has to be one a process can exit with, from 0 to 255
+  (func $exit (param $tag i32) (param $payload i64)
+    (if (i32.ne (local.get $tag) (i32.const 3))
+      (then (call $fail (string "Type mismatch: Expected integer as exit code"))))
+    (if (i32.or
+          (i64.lt_s (local.get $payload) (i64.const 0))
+          (i64.gt_s (local.get $payload) (i64.const 255)))
+      (then
+        (call $fail
+          (call $join
+            (call $join (string "Type mismatch: Exit code ") (call $integer_text (local.get $payload)))
+            (string " is not between 0 and 255")))))
+    (call $host_exit (i32.wrap_i64 (local.get $payload)))
+    (unreachable))
+
+  (func $no_arm (param $tag i32) (param $payload i64)
+    (call $fail
+      (call $join
+        (string "No match arm matched the value ")
+        (call $display (local.get $tag) (local.get $payload)))))
+
+  ;; The condition of an assert that is not a comparison
+  (func $assert_condition (param $tag i32) (param $payload i64) (result i32)
+    (if (i32.ne (local.get $tag) (i32.const 2))
+      (then (call $fail (string "Type mismatch: Expected boolean for assert condition"))))
+    (i32.wrap_i64 (local.get $payload)))
+
+  ;; A message with a tag of 0 means there was none, and sides with one that it was no comparison
+  (func $assert_failed
+    (param $position i64)
+    (param $mt i32) (param $mv i64)
+    (param $lt i32) (param $lv i64)
+    (param $operator i64)
+    (param $rt i32) (param $rv i64)
+    (local $text i64)
+    (local.set $text (call $join (string "Assertion failed at ") (local.get $position)))
+    (if (local.get $mt)
+      (then
+        (local.set $text
+          (call $join
+            (call $join (local.get $text) (string ": "))
+            (call $display (local.get $mt) (local.get $mv))))))
+    (if (local.get $lt)
+      (then
+        (local.set $text (call $join (local.get $text) (string " (")))
+        (local.set $text
+          (call $join (local.get $text) (call $describe (local.get $lt) (local.get $lv))))
+        (local.set $text (call $join (local.get $text) (string " ")))
+        (local.set $text (call $join (local.get $text) (local.get $operator)))
+        (local.set $text (call $join (local.get $text) (string " ")))
+        (local.set $text
+          (call $join (local.get $text) (call $describe (local.get $rt) (local.get $rv))))
+        (local.set $text (call $join (local.get $text) (string " is false)")))))
+    (call $fail (local.get $text)))
@@ -0,0 +1,1111 @@
+use std::{collections::HashMap, fmt};
+
+/*
+ Assembles WebAssembly text into a binary module, for the wasm backend. It knows the part of the
+ text format that the backend writes: imported functions, one memory, globals, functions, exports
+ and data, with the instructions on integers, floats and memory written flat or folded. Names are
+ resolved like in the format, so functions can be called before they are defined.
+*/
+pub fn assemble(text: &str) -> Result<Vec<u8>, WatError> {
+  let tokens = tokenize(text)?;
+  let mut position = 0;
+  let module = parse(&tokens, &mut position)?;
+  if position < tokens.len() {
+    return Err(WatError::new(
+      tokens[position].line,
+      "Expected the end of the text",
+    ));
+  }
+  let fields = match &module {
+    Sexp::List(items, _) if items.first().and_then(Sexp::atom) == Some("module") => &items[1..],
+    other => return Err(WatError::new(other.line(), "Expected a module")),
+  };
+  Assembler::default().module(fields)
+}
+
+#[derive(Debug)]
+pub struct WatError {
+  line: usize,
+  message: String,
+}
+
+impl WatError {
+  fn new(line: usize, message: impl Into<String>) -> WatError {
+    WatError {
+      line,
+      message: message.into(),
+    }
+  }
+}
+
+impl fmt::Display for WatError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "line {}: {}", self.line, self.message)
+  }
+}
+
+enum TokenKind {
+  Open,
+  Close,
+  Atom(String),
+  String(Vec<u8>),
+}
+
+struct Token {
+  kind: TokenKind,
+  line: usize,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, WatError> {
+  let bytes = text.as_bytes();
+  let mut tokens = Vec::new();
+  let mut line = 1;
+  let mut index = 0;
+  while index < bytes.len() {
+    match bytes[index] {
+      b'\n' => {
+        line += 1;
+        index += 1;
+      }
+      byte if byte.is_ascii_whitespace() => index += 1,
+      b';' if bytes.get(index + 1) == Some(&b';') => {
+        while index < bytes.len() && bytes[index] != b'\n' {
+          index += 1;
+        }
+      }
+      // Block comments can be nested
+      b'(' if bytes.get(index + 1) == Some(&b';') => {
+        let mut depth = 0;
+        loop {
+          match (bytes.get(index), bytes.get(index + 1)) {
+            (Some(b'('), Some(b';')) => {
+              depth += 1;
+              index += 2;
+            }
+            (Some(b';'), Some(b')')) => {
+              depth -= 1;
+              index += 2;
+              if depth == 0 {
+                break;
+              }
+            }
+            (Some(b'\n'), _) => {
+              line += 1;
+              index += 1;
+            }
+            (Some(_), _) => index += 1,
+            (None, _) => return Err(WatError::new(line, "Unterminated comment")),
+          }
+        }
+      }
+      b'(' => {
+        tokens.push(Token {
+          kind: TokenKind::Open,
+          line,
+        });
+        index += 1;
+      }
+      b')' => {
+        tokens.push(Token {
+          kind: TokenKind::Close,
+          line,
+        });
+        index += 1;
+      }
+      b'"' => {
+        let (string, end) = string(bytes, index + 1, line)?;
+        tokens.push(Token {
+          kind: TokenKind::String(string),
+          line,
+        });
+        index = end;
+      }
+      _ => {
+        let start = index;
+        while index < bytes.len()
+          && !bytes[index].is_ascii_whitespace()
+          && !matches!(bytes[index], b'(' | b')' | b'"' | b';')
+        {
+          index += 1;
+        }
+        tokens.push(Token {
+          kind: TokenKind::Atom(text[start..index].to_string()),
+          line,
+        });
+      }
+    }
+  }
+  Ok(tokens)
+}
+
+// The bytes of a string that starts after its quote, and where it ends
+fn string(bytes: &[u8], mut index: usize, line: usize) -> Result<(Vec<u8>, usize), WatError> {
+  let mut string = Vec::new();
+  loop {
+    match bytes.get(index) {
+      None | Some(b'\n') => return Err(WatError::new(line, "Unterminated string")),
+      Some(b'"') => return Ok((string, index + 1)),
+      Some(b'\\') => {
+        let escaped = *bytes
+          .get(index + 1)
+          .ok_or_else(|| WatError::new(line, "Unterminated string"))?;
+        index += 2;
+        match escaped {
+          b'n' => string.push(b'\n'),
+          b't' => string.push(b'\t'),
+          b'r' => string.push(b'\r'),
+          b'"' | b'\'' | b'\\' => string.push(escaped),
+          _ => {
+            let digits = bytes
+              .get(index - 1..index + 1)
+              .and_then(|digits| std::str::from_utf8(digits).ok())
+              .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+              .ok_or_else(|| WatError::new(line, "Invalid escape in string"))?;
+            string.push(digits);
+            index += 1;
+          }
+        }
+      }
+      Some(byte) => {
+        string.push(*byte);
+        index += 1;
+      }
+    }
+  }
+}
+
+enum Sexp {
+  Atom(String, usize),
+  String(Vec<u8>, usize),
+  List(Vec<Sexp>, usize),
+}
+
+impl Sexp {
+  fn line(&self) -> usize {
+    match self {
+      Sexp::Atom(_, line) | Sexp::String(_, line) | Sexp::List(_, line) => *line,
+    }
+  }
+
+  fn atom(&self) -> Option<&str> {
+    match self {
+      Sexp::Atom(atom, _) => Some(atom),
+      _ => None,
+    }
+  }
+
+  // The items of a list that starts with the keyword
+  fn list(&self, keyword: &str) -> Option<&[Sexp]> {
+    match self {
+      Sexp::List(items, _) if items.first().and_then(Sexp::atom) == Some(keyword) => {
+        Some(&items[1..])
+      }
+      _ => None,
+    }
+  }
+
+  fn id(&self) -> Option<&str> {
+    self.atom().filter(|atom| atom.starts_with('$'))
+  }
+}
+
+fn parse(tokens: &[Token], position: &mut usize) -> Result<Sexp, WatError> {
+  let token = tokens.get(*position).ok_or_else(|| {
+    WatError::new(
+      tokens.last().map_or(1, |token| token.line),
+      "Unexpected end",
+    )
+  })?;
+  *position += 1;
+  match &token.kind {
+    TokenKind::Atom(atom) => Ok(Sexp::Atom(atom.clone(), token.line)),
+    TokenKind::String(string) => Ok(Sexp::String(string.clone(), token.line)),
+    TokenKind::Close => Err(WatError::new(token.line, "Unexpected )")),
+    TokenKind::Open => {
+      let mut items = Vec::new();
+      loop {
+        match tokens.get(*position).map(|token| &token.kind) {
+          Some(TokenKind::Close) => {
+            *position += 1;
+            return Ok(Sexp::List(items, token.line));
+          }
+          Some(_) => items.push(parse(tokens, position)?),
+          None => return Err(WatError::new(token.line, "Unclosed (")),
+        }
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ValueType {
+  I32,
+  I64,
+  F64,
+}
+
+impl ValueType {
+  fn parse(sexp: &Sexp) -> Result<ValueType, WatError> {
+    match sexp.atom() {
+      Some("i32") => Ok(ValueType::I32),
+      Some("i64") => Ok(ValueType::I64),
+      Some("f64") => Ok(ValueType::F64),
+      _ => Err(WatError::new(sexp.line(), "Expected a value type")),
+    }
+  }
+
+  fn byte(self) -> u8 {
+    match self {
+      ValueType::I32 => 0x7f,
+      ValueType::I64 => 0x7e,
+      ValueType::F64 => 0x7c,
+    }
+  }
+}
+
+#[derive(Clone, PartialEq)]
+struct FunctionType {
+  parameters: Vec<ValueType>,
+  results: Vec<ValueType>,
+}
+
+// Where names point to in the function being assembled
+struct Scope {
+  locals: HashMap<String, u32>,
+  // The labels of the blocks around the instruction, innermost last
+  labels: Vec<Option<String>>,
+}
+
+#[derive(Default)]
+struct Assembler {
+  types: Vec<FunctionType>,
+  functions: HashMap<String, u32>,
+  function_count: u32,
+  globals: HashMap<String, u32>,
+  global_count: u32,
+  imports: Vec<u8>,
+  import_count: u32,
+  function_types: Vec<u32>,
+  memories: Vec<u8>,
+  memory_count: u32,
+  global_section: Vec<u8>,
+  exports: Vec<u8>,
+  export_count: u32,
+  code: Vec<u8>,
+  data: Vec<u8>,
+  data_count: u32,
+}
+
+fn unsigned(output: &mut Vec<u8>, mut value: u64) {
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    match value {
+      0 => {
+        output.push(byte);
+        return;
+      }
+      _ => output.push(byte | 0x80),
+    }
+  }
+}
+
+fn signed(output: &mut Vec<u8>, mut value: i64) {
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+      output.push(byte);
+      return;
+    }
+    output.push(byte | 0x80);
+  }
+}
+
+fn name(output: &mut Vec<u8>, name: &[u8]) {
+  unsigned(output, name.len() as u64);
+  output.extend_from_slice(name);
+}
+
+fn section(output: &mut Vec<u8>, id: u8, count: u32, content: &[u8]) {
+  if count == 0 {
+    return;
+  }
+  let mut body = Vec::new();
+  unsigned(&mut body, count as u64);
+  body.extend_from_slice(content);
+  output.push(id);
+  unsigned(output, body.len() as u64);
+  output.extend_from_slice(&body);
+}
+
+// An integer in decimal or hexadecimal, with _ between digits
+fn integer(sexp: &Sexp, bits: u32) -> Result<i64, WatError> {
+  let error = || WatError::new(sexp.line(), "Expected an integer");
+  let text = sexp.atom().ok_or_else(error)?.replace('_', "");
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text.strip_prefix('+').unwrap_or(&text)),
+  };
+  let magnitude = match digits.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16),
+    None => digits.parse::<u64>(),
+  }
+  .map_err(|_| error())?;
+  let limit = 1u128 << bits;
+  match negative {
+    true if magnitude as u128 <= limit / 2 => Ok((magnitude as i64).wrapping_neg()),
+    false if (magnitude as u128) < limit => Ok(magnitude as i64),
+    _ => Err(error()),
+  }
+}
+
+fn float(sexp: &Sexp) -> Result<f64, WatError> {
+  let error = || WatError::new(sexp.line(), "Expected a float");
+  let text = sexp.atom().ok_or_else(error)?.replace('_', "");
+  let (negative, rest) = match text.strip_prefix('-') {
+    Some(rest) => (true, rest),
+    None => (false, text.strip_prefix('+').unwrap_or(&text)),
+  };
+  let magnitude = match rest {
+    "inf" => f64::INFINITY,
+    "nan" => f64::NAN,
+    rest => match rest.strip_prefix("nan:0x") {
+      Some(payload) => {
+        let payload = u64::from_str_radix(payload, 16).map_err(|_| error())?;
+        f64::from_bits(0x7ff0_0000_0000_0000 | payload)
+      }
+      None => rest.parse::<f64>().map_err(|_| error())?,
+    },
+  };
+  Ok(match negative {
+    true => -magnitude,
+    false => magnitude,
+  })
+}
+
+// The code of an instruction that has no immediates
+fn simple(instruction: &str) -> Option<&'static [u8]> {
+  let code: &[u8] = match instruction {
+    "unreachable" => &[0x00],
+    "nop" => &[0x01],
+    "return" => &[0x0f],
+    "drop" => &[0x1a],
+    "select" => &[0x1b],
+    "memory.size" => &[0x3f, 0x00],
+    "memory.grow" => &[0x40, 0x00],
+    "i32.eqz" => &[0x45],
+    "i32.eq" => &[0x46],
+    "i32.ne" => &[0x47],
+    "i32.lt_s" => &[0x48],
+    "i32.lt_u" => &[0x49],
+    "i32.gt_s" => &[0x4a],
+    "i32.gt_u" => &[0x4b],
+    "i32.le_s" => &[0x4c],
+    "i32.le_u" => &[0x4d],
+    "i32.ge_s" => &[0x4e],
+    "i32.ge_u" => &[0x4f],
+    "i64.eqz" => &[0x50],
+    "i64.eq" => &[0x51],
+    "i64.ne" => &[0x52],
+    "i64.lt_s" => &[0x53],
+    "i64.lt_u" => &[0x54],
+    "i64.gt_s" => &[0x55],
+    "i64.gt_u" => &[0x56],
+    "i64.le_s" => &[0x57],
+    "i64.le_u" => &[0x58],
+    "i64.ge_s" => &[0x59],
+    "i64.ge_u" => &[0x5a],
+    "f64.eq" => &[0x61],
+    "f64.ne" => &[0x62],
+    "f64.lt" => &[0x63],
+    "f64.gt" => &[0x64],
+    "f64.le" => &[0x65],
+    "f64.ge" => &[0x66],
+    "i32.add" => &[0x6a],
+    "i32.sub" => &[0x6b],
+    "i32.mul" => &[0x6c],
+    "i32.div_s" => &[0x6d],
+    "i32.div_u" => &[0x6e],
+    "i32.rem_s" => &[0x6f],
+    "i32.rem_u" => &[0x70],
+    "i32.and" => &[0x71],
+    "i32.or" => &[0x72],
+    "i32.xor" => &[0x73],
+    "i32.shl" => &[0x74],
+    "i32.shr_s" => &[0x75],
+    "i32.shr_u" => &[0x76],
+    "i64.add" => &[0x7c],
+    "i64.sub" => &[0x7d],
+    "i64.mul" => &[0x7e],
+    "i64.div_s" => &[0x7f],
+    "i64.div_u" => &[0x80],
+    "i64.rem_s" => &[0x81],
+    "i64.rem_u" => &[0x82],
+    "i64.and" => &[0x83],
+    "i64.or" => &[0x84],
+    "i64.xor" => &[0x85],
+    "i64.shl" => &[0x86],
+    "i64.shr_s" => &[0x87],
+    "i64.shr_u" => &[0x88],
+    "f64.abs" => &[0x99],
+    "f64.neg" => &[0x9a],
+    "f64.trunc" => &[0x9d],
+    "f64.sqrt" => &[0x9f],
+    "f64.add" => &[0xa0],
+    "f64.sub" => &[0xa1],
+    "f64.mul" => &[0xa2],
+    "f64.div" => &[0xa3],
+    "i32.wrap_i64" => &[0xa7],
+    "i64.extend_i32_s" => &[0xac],
+    "i64.extend_i32_u" => &[0xad],
+    "f64.convert_i64_s" => &[0xb9],
+    "i64.reinterpret_f64" => &[0xbd],
+    "f64.reinterpret_i64" => &[0xbf],
+    "memory.copy" => &[0xfc, 0x0a, 0x00, 0x00],
+    "memory.fill" => &[0xfc, 0x0b, 0x00],
+    _ => return None,
+  };
+  Some(code)
+}
+
+// The code of a load or store and the alignment it has unless it says otherwise
+fn memory_access(instruction: &str) -> Option<(u8, u32)> {
+  let access = match instruction {
+    "i32.load" => (0x28, 2),
+    "i64.load" => (0x29, 3),
+    "f64.load" => (0x2b, 3),
+    "i32.load8_s" => (0x2c, 0),
+    "i32.load8_u" => (0x2d, 0),
+    "i32.store" => (0x36, 2),
+    "i64.store" => (0x37, 3),
+    "f64.store" => (0x39, 3),
+    "i32.store8" => (0x3a, 0),
+    _ => return None,
+  };
+  Some(access)
+}
+
+impl Assembler {
+  fn module(mut self, fields: &[Sexp]) -> Result<Vec<u8>, WatError> {
+    // Functions and globals get their index first, so they can be used anywhere
+    let mut definitions = Vec::new();
+    for field in fields {
+      if let Some(import) = field.list("import") {
+        self.import(field.line(), import)?;
+      }
+    }
+    for field in fields {
+      if let Some(function) = field.list("func") {
+        if let Some(id) = function.first().and_then(Sexp::id) {
+          self.functions.insert(id.to_string(), self.function_count);
+        }
+        let (signature, _) = self.signature(function)?;
+        let index = self.type_index(signature);
+        self.function_types.push(index);
+        definitions.push((self.function_count, function));
+        self.function_count += 1;
+      }
+    }
+    for field in fields {
+      match field {
+        Sexp::List(items, line) => match items.first().and_then(Sexp::atom) {
+          Some("import" | "func") => (),
+          Some("memory") => self.memory(*line, &items[1..])?,
+          Some("global") => self.global(*line, &items[1..])?,
+          Some("export") => self.export(*line, &items[1..])?,
+          Some("data") => self.data(*line, &items[1..])?,
+          _ => return Err(WatError::new(*line, "Unknown module field")),
+        },
+        other => return Err(WatError::new(other.line(), "Expected a module field")),
+      }
+    }
+    for (index, function) in definitions {
+      self.function(index, function)?;
+    }
+
+    let mut types = Vec::new();
+    for function_type in &self.types {
+      types.push(0x60);
+      unsigned(&mut types, function_type.parameters.len() as u64);
+      types.extend(function_type.parameters.iter().map(|value| value.byte()));
+      unsigned(&mut types, function_type.results.len() as u64);
+      types.extend(function_type.results.iter().map(|value| value.byte()));
+    }
+    let mut function_types = Vec::new();
+    for index in &self.function_types {
+      unsigned(&mut function_types, *index as u64);
+    }
+    let mut output = b"\0asm\x01\0\0\0".to_vec();
+    section(&mut output, 1, self.types.len() as u32, &types);
+    section(&mut output, 2, self.import_count, &self.imports);
+    section(
+      &mut output,
+      3,
+      self.function_types.len() as u32,
+      &function_types,
+    );
+    section(&mut output, 5, self.memory_count, &self.memories);
+    section(&mut output, 6, self.global_count, &self.global_section);
+    section(&mut output, 7, self.export_count, &self.exports);
+    section(
+      &mut output,
+      10,
+      self.function_types.len() as u32,
+      &self.code,
+    );
+    section(&mut output, 11, self.data_count, &self.data);
+    Ok(output)
+  }
+
+  fn type_index(&mut self, function_type: FunctionType) -> u32 {
+    match self.types.iter().position(|known| *known == function_type) {
+      Some(index) => index as u32,
+      None => {
+        self.types.push(function_type);
+        self.types.len() as u32 - 1
+      }
+    }
+  }
+
+  // The parameters and results at the start of a function, with the names of the parameters
+  fn signature<'a>(
+    &self,
+    items: &'a [Sexp],
+  ) -> Result<(FunctionType, Vec<Option<&'a str>>), WatError> {
+    let mut function_type = FunctionType {
+      parameters: Vec::new(),
+      results: Vec::new(),
+    };
+    let mut names = Vec::new();
+    // They come before the locals and the code, which can have results of their own
+    for item in items {
+      let heading = item.id().is_some()
+        || ["export", "param", "result"]
+          .iter()
+          .any(|keyword| item.list(keyword).is_some());
+      if !heading {
+        break;
+      }
+      if let Some(parameters) = item.list("param") {
+        match parameters.first().and_then(Sexp::id) {
+          Some(id) => {
+            let value = parameters
+              .get(1)
+              .ok_or_else(|| WatError::new(item.line(), "Expected a value type"))?;
+            function_type.parameters.push(ValueType::parse(value)?);
+            names.push(Some(id));
+          }
+          None => {
+            for parameter in parameters {
+              function_type.parameters.push(ValueType::parse(parameter)?);
+              names.push(None);
+            }
+          }
+        }
+      } else if let Some(results) = item.list("result") {
+        for result in results {
+          function_type.results.push(ValueType::parse(result)?);
+        }
+      }
+    }
+    Ok((function_type, names))
+  }
+
+  fn import(&mut self, line: usize, items: &[Sexp]) -> Result<(), WatError> {
+    let (Some(Sexp::String(module, _)), Some(Sexp::String(field, _)), Some(function)) = (
+      items.first(),
+      items.get(1),
+      items.get(2).and_then(|item| item.list("func")),
+    ) else {
+      return Err(WatError::new(line, "Expected an imported function"));
+    };
+    if let Some(id) = function.first().and_then(Sexp::id) {
+      self.functions.insert(id.to_string(), self.function_count);
+    }
+    let (signature, _) = self.signature(function)?;
+    let index = self.type_index(signature);
+    name(&mut self.imports, module);
+    name(&mut self.imports, field);
+    self.imports.push(0x00);
+    unsigned(&mut self.imports, index as u64);
+    self.import_count += 1;
+    self.function_count += 1;
+    Ok(())
+  }
+
+  // Exports written inside of what they export
+  fn inline_exports(&mut self, items: &[Sexp], kind: u8, index: u32) -> Result<(), WatError> {
+    for item in items {
+      if let Some(export) = item.list("export") {
+        let Some(Sexp::String(export_name, _)) = export.first() else {
+          return Err(WatError::new(
+            item.line(),
+            "Expected the name of the export",
+          ));
+        };
+        name(&mut self.exports, export_name);
+        self.exports.push(kind);
+        unsigned(&mut self.exports, index as u64);
+        self.export_count += 1;
+      }
+    }
+    Ok(())
+  }
+
+  fn memory(&mut self, line: usize, items: &[Sexp]) -> Result<(), WatError> {
+    self.inline_exports(items, 0x02, self.memory_count)?;
+    let limits: Vec<&Sexp> = items.iter().filter(|item| item.atom().is_some()).collect();
+    match limits.as_slice() {
+      [minimum] => {
+        self.memories.push(0x00);
+        unsigned(&mut self.memories, integer(minimum, 32)? as u64);
+      }
+      [minimum, maximum] => {
+        self.memories.push(0x01);
+        unsigned(&mut self.memories, integer(minimum, 32)? as u64);
+        unsigned(&mut self.memories, integer(maximum, 32)? as u64);
+      }
+      _ => return Err(WatError::new(line, "Expected the size of the memory")),
+    }
+    self.memory_count += 1;
+    Ok(())
+  }
+
+  fn global(&mut self, line: usize, items: &[Sexp]) -> Result<(), WatError> {
+    let mut rest = items;
+    if let Some(id) = rest.first().and_then(Sexp::id) {
+      self.globals.insert(id.to_string(), self.global_count);
+      rest = &rest[1..];
+    }
+    self.inline_exports(rest, 0x03, self.global_count)?;
+    let rest: Vec<&Sexp> = rest
+      .iter()
+      .filter(|item| item.list("export").is_none())
+      .collect();
+    let [global_type, initial] = rest.as_slice() else {
+      return Err(WatError::new(
+        line,
+        "Expected the type and value of the global",
+      ));
+    };
+    match global_type.list("mut") {
+      Some([value]) => {
+        self.global_section.push(ValueType::parse(value)?.byte());
+        self.global_section.push(0x01);
+      }
+      _ => {
+        self
+          .global_section
+          .push(ValueType::parse(global_type)?.byte());
+        self.global_section.push(0x00);
+      }
+    }
+    self.constant(&mut Vec::new(), initial)?;
+    self.global_count += 1;
+    Ok(())
+  }
+
+  // A constant expression, which is where globals start and data goes
+  fn constant(&mut self, _: &mut Vec<u8>, expression: &Sexp) -> Result<(), WatError> {
+    let mut scope = Scope {
+      locals: HashMap::new(),
+      labels: Vec::new(),
+    };
+    let mut code = Vec::new();
+    self.folded(&mut code, &mut scope, expression)?;
+    code.push(0x0b);
+    self.global_section.extend_from_slice(&code);
+    Ok(())
+  }
+
+  fn export(&mut self, line: usize, items: &[Sexp]) -> Result<(), WatError> {
+    let (Some(Sexp::String(export_name, _)), Some(Sexp::List(target, _))) =
+      (items.first(), items.get(1))
+    else {
+      return Err(WatError::new(line, "Expected an export"));
+    };
+    let (kind, index) = match (target.first().and_then(Sexp::atom), target.get(1)) {
+      (Some("func"), Some(id)) => (0x00, self.function_index(id)?),
+      (Some("memory"), Some(index)) => (0x02, integer(index, 32)? as u32),
+      (Some("global"), Some(id)) => (0x03, self.global_index(id)?),
+      _ => return Err(WatError::new(line, "Expected what is exported")),
+    };
+    name(&mut self.exports, export_name);
+    self.exports.push(kind);
+    unsigned(&mut self.exports, index as u64);
+    self.export_count += 1;
+    Ok(())
+  }
+
+  fn data(&mut self, line: usize, items: &[Sexp]) -> Result<(), WatError> {
+    let Some(offset) = items.first() else {
+      return Err(WatError::new(line, "Expected the offset of the data"));
+    };
+    let offset = offset
+      .list("offset")
+      .and_then(|offset| offset.first())
+      .unwrap_or(offset);
+    let mut scope = Scope {
+      locals: HashMap::new(),
+      labels: Vec::new(),
+    };
+    self.data.push(0x00);
+    let mut code = Vec::new();
+    self.folded(&mut code, &mut scope, offset)?;
+    self.data.extend_from_slice(&code);
+    self.data.push(0x0b);
+    let mut bytes = Vec::new();
+    for item in &items[1..] {
+      match item {
+        Sexp::String(string, _) => bytes.extend_from_slice(string),
+        other => return Err(WatError::new(other.line(), "Expected a string")),
+      }
+    }
+    name(&mut self.data, &bytes);
+    self.data_count += 1;
+    Ok(())
+  }
+
+  fn function_index(&self, sexp: &Sexp) -> Result<u32, WatError> {
+    match sexp.id() {
+      Some(id) => self
+        .functions
+        .get(id)
+        .copied()
+        .ok_or_else(|| WatError::new(sexp.line(), format!("Unknown function {}", id))),
+      None => Ok(integer(sexp, 32)? as u32),
+    }
+  }
+
+  fn global_index(&self, sexp: &Sexp) -> Result<u32, WatError> {
+    match sexp.id() {
+      Some(id) => self
+        .globals
+        .get(id)
+        .copied()
+        .ok_or_else(|| WatError::new(sexp.line(), format!("Unknown global {}", id))),
+      None => Ok(integer(sexp, 32)? as u32),
+    }
+  }
+
+  fn function(&mut self, index: u32, items: &[Sexp]) -> Result<(), WatError> {
+    let mut rest = items;
+    if rest.first().and_then(Sexp::id).is_some() {
+      rest = &rest[1..];
+    }
+    self.inline_exports(rest, 0x00, index)?;
+    let (signature, parameter_names) = self.signature(rest)?;
+    let mut scope = Scope {
+      locals: HashMap::new(),
+      labels: Vec::new(),
+    };
+    for (position, parameter) in parameter_names.iter().enumerate() {
+      if let Some(parameter) = parameter {
+        scope.locals.insert(parameter.to_string(), position as u32);
+      }
+    }
+    let mut locals = Vec::new();
+    let mut body = rest;
+    while let Some(first) = body.first() {
+      if first.list("export").is_some()
+        || first.list("param").is_some()
+        || first.list("result").is_some()
+      {
+        body = &body[1..];
+      } else if let Some(declared) = first.list("local") {
+        match declared.first().and_then(Sexp::id) {
+          Some(id) => {
+            let value = declared
+              .get(1)
+              .ok_or_else(|| WatError::new(first.line(), "Expected a value type"))?;
+            scope.locals.insert(
+              id.to_string(),
+              (signature.parameters.len() + locals.len()) as u32,
+            );
+            locals.push(ValueType::parse(value)?);
+          }
+          None => {
+            for value in declared {
+              locals.push(ValueType::parse(value)?);
+            }
+          }
+        }
+        body = &body[1..];
+      } else {
+        break;
+      }
+    }
+    let mut code = Vec::new();
+    // Locals of the same type next to each other are declared together
+    let mut groups: Vec<(u32, ValueType)> = Vec::new();
+    for local in &locals {
+      match groups.last_mut() {
+        Some((count, value)) if value == local => *count += 1,
+        _ => groups.push((1, *local)),
+      }
+    }
+    unsigned(&mut code, groups.len() as u64);
+    for (count, value) in groups {
+      unsigned(&mut code, count as u64);
+      code.push(value.byte());
+    }
+    self.instructions(&mut code, &mut scope, body)?;
+    code.push(0x0b);
+    unsigned(&mut self.code, code.len() as u64);
+    self.code.extend_from_slice(&code);
+    Ok(())
+  }
+
+  fn instructions(
+    &mut self,
+    code: &mut Vec<u8>,
+    scope: &mut Scope,
+    items: &[Sexp],
+  ) -> Result<(), WatError> {
+    let mut index = 0;
+    while index < items.len() {
+      let item = &items[index];
+      index += 1;
+      let Some(instruction) = item.atom() else {
+        self.folded(code, scope, item)?;
+        continue;
+      };
+      match instruction {
+        "block" | "loop" | "if" => {
+          let start = index;
+          if items.get(index).and_then(Sexp::id).is_some() {
+            index += 1;
+          }
+          while items
+            .get(index)
+            .is_some_and(|item| item.list("result").is_some())
+          {
+            index += 1;
+          }
+          self.block_start(code, scope, instruction, &items[start..index])?;
+        }
+        "else" => {
+          if items.get(index).and_then(Sexp::id).is_some() {
+            index += 1;
+          }
+          code.push(0x05);
+        }
+        "end" => {
+          if items.get(index).and_then(Sexp::id).is_some() {
+            index += 1;
+          }
+          scope
+            .labels
+            .pop()
+            .ok_or_else(|| WatError::new(item.line(), "end without a block"))?;
+          code.push(0x0b);
+        }
+        _ => {
+          let start = index;
+          index += self.immediate_count(instruction, &items[index..]);
+          self.plain(code, scope, item, &items[start..index])?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  // How many of the items after an instruction are its immediates
+  fn immediate_count(&self, instruction: &str, rest: &[Sexp]) -> usize {
+    match instruction {
+      "br" | "br_if" | "call" | "local.get" | "local.set" | "local.tee" | "global.get"
+      | "global.set" | "i32.const" | "i64.const" | "f64.const" => 1.min(rest.len()),
+      instruction if memory_access(instruction).is_some() => rest
+        .iter()
+        .take_while(|item| {
+          item
+            .atom()
+            .is_some_and(|atom| atom.starts_with("offset=") || atom.starts_with("align="))
+        })
+        .count(),
+      _ => 0,
+    }
+  }
+
+  // The start of a block, with its label and the types of its results
+  fn block_start(
+    &mut self,
+    code: &mut Vec<u8>,
+    scope: &mut Scope,
+    instruction: &str,
+    items: &[Sexp],
+  ) -> Result<(), WatError> {
+    let mut label = None;
+    let mut results = Vec::new();
+    for item in items {
+      if let Some(id) = item.id() {
+        label = Some(id.to_string());
+      } else if let Some(types) = item.list("result") {
+        for value in types {
+          results.push(ValueType::parse(value)?);
+        }
+      }
+    }
+    code.push(match instruction {
+      "block" => 0x02,
+      "loop" => 0x03,
+      _ => 0x04,
+    });
+    match results.as_slice() {
+      [] => code.push(0x40),
+      [value] => code.push(value.byte()),
+      _ => {
+        let index = self.type_index(FunctionType {
+          parameters: Vec::new(),
+          results,
+        });
+        signed(code, index as i64);
+      }
+    }
+    scope.labels.push(label);
+    Ok(())
+  }
+
+  // An instruction in parentheses, whose operands come before it
+  fn folded(&mut self, code: &mut Vec<u8>, scope: &mut Scope, sexp: &Sexp) -> Result<(), WatError> {
+    let Sexp::List(items, line) = sexp else {
+      return Err(WatError::new(sexp.line(), "Expected an instruction"));
+    };
+    let Some(instruction) = items.first().and_then(Sexp::atom) else {
+      return Err(WatError::new(*line, "Expected an instruction"));
+    };
+    let rest = &items[1..];
+    let heading = rest
+      .iter()
+      .take_while(|item| item.id().is_some() || item.list("result").is_some())
+      .count();
+    match instruction {
+      "block" | "loop" => {
+        self.block_start(code, scope, instruction, &rest[..heading])?;
+        self.instructions(code, scope, &rest[heading..])?;
+        scope.labels.pop();
+        code.push(0x0b);
+      }
+      "if" => {
+        let mut branches = &rest[heading..];
+        while let Some(condition) = branches.first() {
+          if condition.list("then").is_some() {
+            break;
+          }
+          self.folded(code, scope, condition)?;
+          branches = &branches[1..];
+        }
+        self.block_start(code, scope, instruction, &rest[..heading])?;
+        for branch in branches {
+          if let Some(then) = branch.list("then") {
+            self.instructions(code, scope, then)?;
+          } else if let Some(otherwise) = branch.list("else") {
+            code.push(0x05);
+            self.instructions(code, scope, otherwise)?;
+          } else {
+            return Err(WatError::new(branch.line(), "Expected then or else"));
+          }
+        }
+        scope.labels.pop();
+        code.push(0x0b);
+      }
+      _ => {
+        let immediates = self.immediate_count(instruction, rest);
+        for operand in &rest[immediates..] {
+          self.folded(code, scope, operand)?;
+        }
+        self.plain(code, scope, &items[0], &rest[..immediates])?;
+      }
+    }
+    Ok(())
+  }
+
+  fn plain(
+    &mut self,
+    code: &mut Vec<u8>,
+    scope: &mut Scope,
+    instruction: &Sexp,
+    immediates: &[Sexp],
+  ) -> Result<(), WatError> {
+    let line = instruction.line();
+    let name = instruction.atom().unwrap_or_default();
+    if let Some(simple) = simple(name) {
+      code.extend_from_slice(simple);
+      return Ok(());
+    }
+    if let Some((opcode, natural)) = memory_access(name) {
+      let mut offset = 0;
+      let mut align = natural;
+      for immediate in immediates {
+        let atom = immediate.atom().unwrap_or_default();
+        let (key, value) = atom.split_once('=').unwrap_or((atom, ""));
+        let value = Sexp::Atom(value.to_string(), line);
+        match key {
+          "offset" => offset = integer(&value, 32)? as u64,
+          "align" => align = (integer(&value, 32)? as u32).trailing_zeros(),
+          _ => return Err(WatError::new(line, "Unknown memory immediate")),
+        }
+      }
+      code.push(opcode);
+      unsigned(code, align as u64);
+      unsigned(code, offset);
+      return Ok(());
+    }
+    let immediate = immediates
+      .first()
+      .ok_or_else(|| WatError::new(line, format!("{} needs an immediate", name)))?;
+    match name {
+      "br" | "br_if" => {
+        let depth = match immediate.id() {
+          Some(id) => scope
+            .labels
+            .iter()
+            .rev()
+            .position(|label| label.as_deref() == Some(id))
+            .ok_or_else(|| WatError::new(line, format!("Unknown label {}", id)))?,
+          None => integer(immediate, 32)? as usize,
+        };
+        code.push(if name == "br" { 0x0c } else { 0x0d });
+        unsigned(code, depth as u64);
+      }
+      "call" => {
+        code.push(0x10);
+        unsigned(code, self.function_index(immediate)? as u64);
+      }
+      "local.get" | "local.set" | "local.tee" => {
+        let index = match immediate.id() {
+          Some(id) => *scope
+            .locals
+            .get(id)
+            .ok_or_else(|| WatError::new(line, format!("Unknown local {}", id)))?,
+          None => integer(immediate, 32)? as u32,
+        };
+        code.push(match name {
+          "local.get" => 0x20,
+          "local.set" => 0x21,
+          _ => 0x22,
+        });
+        unsigned(code, index as u64);
+      }
+      "global.get" | "global.set" => {
+        code.push(if name == "global.get" { 0x23 } else { 0x24 });
+        unsigned(code, self.global_index(immediate)? as u64);
+      }
+      "i32.const" => {
+        code.push(0x41);
+        signed(code, integer(immediate, 32)? as i32 as i64);
+      }
+      "i64.const" => {
+        code.push(0x42);
+        signed(code, integer(immediate, 64)?);
+      }
+      "f64.const" => {
+        code.push(0x44);
+        code.extend_from_slice(&float(immediate)?.to_le_bytes());
+      }
+      _ => return Err(WatError::new(line, format!("Unknown instruction {}", name))),
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,702 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+  diagnostic::Diagnostic,
+  interpreter::ARGS,
+  number::Number,
+  parser::{Expression, Identifier, Instruction, InstructionKind, Value},
+  resolver::Slot,
+  tokenizer::{Operator, Span},
+};
+
+const RUNTIME: &str = include_str!("x86_64_runtime.s");
+
+// What a script uses that is more than numbers, booleans and none
+#[derive(Debug)]
+pub enum Unsupported {
+  Strings,
+  Lists,
+  Calls,
+  Access,
+  Input,
+  Functions,
+  Enums,
+  Match,
+  Assert,
+}
+
+impl fmt::Display for Unsupported {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let what = match self {
+      Unsupported::Strings => "Strings are",
+      Unsupported::Lists => "Lists are",
+      Unsupported::Calls => "Function calls are",
+      Unsupported::Access => "Fields and indexes are",
+      Unsupported::Input => "input is",
+      Unsupported::Functions => "Functions are",
+      Unsupported::Enums => "Enums are",
+      Unsupported::Match => "match is",
+      Unsupported::Assert => "assert is",
+    };
+    write!(
+      f,
+      "{} not supported by the x86_64 target, which only has numbers, booleans and none",
+      what
+    )
+  }
+}
+
+/*
+ Turns a script into x86-64 assembly for the GNU assembler, a program for Linux that does what
+ the interpreter would do when running it. The instructions have to be resolved first. Only
+ numbers, booleans and none work, with arithmetic, comparisons, if, while and print, anything
+ else gives an error instead.
+
+ Every value is a tag and a 64 bit payload and every variable has 16 bytes of its own, the
+ script has no functions that could need them twice. Adding, subtracting, multiplying and
+ comparing two integers happens right in the code, everything else calls into the runtime in
+ x86_64_runtime.s, which prints through the C library: `cc -o code code.s -lm` links it.
+*/
+pub fn transpile(instructions: &[Instruction]) -> Result<String, Diagnostic<Unsupported>> {
+  check(instructions)?;
+  let mut generator = Generator::default();
+  generator.open(false);
+  generator.instructions(instructions);
+  generator.close();
+
+  let mut program = String::from("# Compiled from a fish script\n\n");
+  program.push_str(RUNTIME);
+  program.push_str("\n  .globl main\n  .type main, @function\nmain:\n");
+  program.push_str("  push %rbp\n  mov %rsp, %rbp\n");
+  for line in &generator.lines {
+    program.push_str(line);
+    program.push('\n');
+  }
+  program.push_str(".Lend:\n  xor %eax, %eax\n  pop %rbp\n  ret\n");
+  for (number, _) in generator.undefined.iter().enumerate() {
+    program.push_str(&format!(
+      ".Lnot_defined.{}:\n  lea .Lname.{}(%rip), %rdi\n  jmp fish_undefined_variable\n",
+      number, number
+    ));
+  }
+  program.push_str("\n  .section .rodata\n");
+  for (number, name) in generator.undefined.iter().enumerate() {
+    program.push_str(&format!(
+      ".Lname.{}:\n  .asciz \"{}\"\n",
+      number,
+      escape(name)
+    ));
+  }
+  program.push_str("\n  .bss\n  .align 8\n");
+  for (number, name) in generator.variables.iter().enumerate() {
+    program.push_str(&format!("# {}\n.Lvariable.{}:\n  .skip 16\n", name, number));
+  }
+  program.push_str("\n  .section .note.GNU-stack,\"\",@progbits\n");
+  Ok(program)
+}
+
+fn check(instructions: &[Instruction]) -> Result<(), Diagnostic<Unsupported>> {
+  for instruction in instructions {
+    let span = instruction.span;
+    let unsupported = match &instruction.kind {
+      InstructionKind::If {
+        condition,
+        instructions,
+      }
+      | InstructionKind::While {
+        condition,
+        instructions,
+      } => {
+        check_value(condition, span)?;
+        check(instructions)?;
+        continue;
+      }
+      InstructionKind::Else { instructions } | InstructionKind::Scope { instructions } => {
+        check(instructions)?;
+        continue;
+      }
+      InstructionKind::Value { value }
+      | InstructionKind::Print { message: value }
+      | InstructionKind::Let { value, .. }
+      | InstructionKind::Return { value: Some(value) } => {
+        check_value(value, span)?;
+        continue;
+      }
+      InstructionKind::Input { .. } => Unsupported::Input,
+      InstructionKind::Function(_) => Unsupported::Functions,
+      InstructionKind::Enum { .. } => Unsupported::Enums,
+      InstructionKind::Match { .. } => Unsupported::Match,
+      InstructionKind::Assert { .. } => Unsupported::Assert,
+      // Only the test runner runs tests
+      InstructionKind::Break
+      | InstructionKind::Return { value: None }
+      | InstructionKind::Test { .. } => continue,
+    };
+    return Err(Diagnostic::new(unsupported, span));
+  }
+  Ok(())
+}
+
+fn check_value(value: &Value, span: Span) -> Result<(), Diagnostic<Unsupported>> {
+  let unsupported = match value {
+    Value::Number(_) | Value::Boolean(_) | Value::None => return Ok(()),
+    Value::Identifier(Identifier {
+      name,
+      slot: None,
+      span,
+    }) if name == ARGS => return Err(Diagnostic::new(Unsupported::Lists, *span)),
+    Value::Identifier(_) => return Ok(()),
+    Value::Expression(expression) => {
+      check_value(expression.get_left(), span)?;
+      return match expression.get_right() {
+        Some(right) => check_value(right, span),
+        None => Ok(()),
+      };
+    }
+    Value::String(_) => Unsupported::Strings,
+    Value::List(_) => Unsupported::Lists,
+    Value::Call { .. } => Unsupported::Calls,
+    Value::Field { .. } | Value::Index { .. } => Unsupported::Access,
+  };
+  Err(Diagnostic::new(unsupported, span))
+}
+
+// A string for .asciz, which takes the escapes of C
+fn escape(text: &str) -> String {
+  text
+    .bytes()
+    .map(|byte| match byte {
+      b'"' | b'\\' => format!("\\{}", byte as char),
+      0x20..=0x7e => (byte as char).to_string(),
+      byte => format!("\\{:03o}", byte),
+    })
+    .collect()
+}
+
+// A 64 bit immediate, the assembler picks movabs for the ones that need it
+fn immediate(payload: u64) -> String {
+  match i32::try_from(payload as i64) {
+    Ok(small) => format!("${}", small),
+    Err(_) => format!("${:#x}", payload),
+  }
+}
+
+fn runtime_function(operator: Operator) -> &'static str {
+  match operator {
+    Operator::Add | Operator::AddAssign => "fish_add",
+    Operator::Subtract | Operator::SubtractAssign => "fish_subtract",
+    Operator::Multiply | Operator::MultiplyAssign => "fish_multiply",
+    Operator::Divide | Operator::DivideAssign => "fish_divide",
+    Operator::Modulo | Operator::ModuloAssign => "fish_modulo",
+    Operator::Exponent => "fish_power",
+    Operator::And => "fish_and",
+    Operator::Or => "fish_or",
+    operator => panic!("{:?} is not a binary operator", operator),
+  }
+}
+
+// The instruction that does the operator on two integers, and where it fails on overflow
+fn integer_instruction(operator: Operator) -> Option<(&'static str, &'static str)> {
+  match operator {
+    Operator::Add | Operator::AddAssign => Some(("add", "fish_add_overflow")),
+    Operator::Subtract | Operator::SubtractAssign => Some(("sub", "fish_subtract_overflow")),
+    Operator::Multiply | Operator::MultiplyAssign => Some(("imul", "fish_multiply_overflow")),
+    _ => None,
+  }
+}
+
+// The number fish_compare knows a comparison by, and the set instruction for two integers
+fn comparison(operator: Operator) -> Option<(u8, &'static str)> {
+  match operator {
+    Operator::Equal => Some((0, "sete")),
+    Operator::NotEqual => Some((1, "setne")),
+    Operator::LessThan => Some((2, "setl")),
+    Operator::LessThanOrEqual => Some((3, "setle")),
+    Operator::GreaterThan => Some((4, "setg")),
+    Operator::GreaterThanOrEqual => Some((5, "setge")),
+    _ => None,
+  }
+}
+
+// Values that go straight into any two registers without needing others
+fn is_simple(value: &Value) -> bool {
+  matches!(
+    value,
+    Value::Number(_) | Value::Boolean(_) | Value::None | Value::Identifier(_)
+  )
+}
+
+fn is_integer(value: &Value) -> bool {
+  matches!(value, Value::Number(Number::Integer(_)))
+}
+
+// Literals that are never integers, with one of them an operation always needs the runtime
+fn is_other_literal(value: &Value) -> bool {
+  matches!(
+    value,
+    Value::Number(Number::Float(_)) | Value::Boolean(_) | Value::None
+  )
+}
+
+struct Frame {
+  // The variables in the slots, given out when a slot is first used
+  variables: Vec<Option<usize>>,
+  // Where the code of the block starts, its variables are unassigned again there
+  start: usize,
+  reset: bool,
+}
+
+#[derive(Default)]
+struct Generator {
+  lines: Vec<String>,
+  frames: Vec<Frame>,
+  // The names of the variables, for the comments next to them
+  variables: Vec<String>,
+  // The names that can turn out not to be defined, each has code that fails with it
+  undefined: Vec<String>,
+  undefined_numbers: HashMap<String, usize>,
+  // The labels of the loops around the code being generated
+  loops: Vec<usize>,
+  labels: usize,
+}
+
+impl Generator {
+  fn line(&mut self, text: impl Into<String>) {
+    self.lines.push(format!("  {}", text.into()));
+  }
+
+  fn place(&mut self, label: String) {
+    self.lines.push(format!("{}:", label));
+  }
+
+  fn label(&mut self) -> usize {
+    self.labels += 1;
+    self.labels
+  }
+
+  fn open(&mut self, reset: bool) {
+    self.frames.push(Frame {
+      variables: Vec::new(),
+      start: self.lines.len(),
+      reset,
+    });
+  }
+
+  // A block starts with its variables unassigned, which is only known once its code is there
+  fn close(&mut self) {
+    let frame = self.frames.pop().expect("No frame to close");
+    if !frame.reset {
+      return;
+    }
+    let resets: Vec<String> = frame
+      .variables
+      .iter()
+      .flatten()
+      .map(|variable| format!("  movq $FISH_UNASSIGNED, .Lvariable.{}(%rip)", variable))
+      .collect();
+    self.lines.splice(frame.start..frame.start, resets);
+  }
+
+  // The instructions in a frame of their own
+  fn frame(&mut self, instructions: &[Instruction]) {
+    self.open(true);
+    self.instructions(instructions);
+    self.close();
+  }
+
+  // The label of the 16 bytes of the variable in the slot
+  fn variable(&mut self, slot: Slot, name: &str) -> String {
+    let index = self
+      .frames
+      .len()
+      .checked_sub(slot.depth + 1)
+      .expect("Slot is outside of the script");
+    if let Some(Some(variable)) = self.frames[index].variables.get(slot.index) {
+      return format!(".Lvariable.{}", variable);
+    }
+    let variable = self.variables.len();
+    self.variables.push(name.to_string());
+    let frame = &mut self.frames[index];
+    if frame.variables.len() <= slot.index {
+      frame.variables.resize(slot.index + 1, None);
+    }
+    frame.variables[slot.index] = Some(variable);
+    format!(".Lvariable.{}", variable)
+  }
+
+  // The label of the code that fails because the name is not defined
+  fn not_defined(&mut self, name: &str) -> String {
+    let number = match self.undefined_numbers.get(name) {
+      Some(number) => *number,
+      None => {
+        self.undefined.push(name.to_string());
+        self
+          .undefined_numbers
+          .insert(name.to_string(), self.undefined.len() - 1);
+        self.undefined.len() - 1
+      }
+    };
+    format!(".Lnot_defined.{}", number)
+  }
+
+  fn store(&mut self, variable: &str) {
+    self.line(format!("mov %rax, {}(%rip)", variable));
+    self.line(format!("mov %rdx, {}+8(%rip)", variable));
+  }
+
+  // Jumps away unless the value in %rax and %rdx is true, fails when it is not a boolean
+  fn condition(&mut self, failure: &str, target: &str) {
+    self.line("cmp $FISH_BOOLEAN, %rax");
+    self.line(format!("jne {}", failure));
+    self.line("test %rdx, %rdx");
+    self.line(format!("jz {}", target));
+  }
+
+  fn instructions(&mut self, instructions: &[Instruction]) {
+    let mut index = 0;
+    while index < instructions.len() {
+      let instruction = &instructions[index];
+      index += 1;
+      if !matches!(
+        instruction.kind,
+        InstructionKind::Else { .. } | InstructionKind::Test { .. }
+      ) {
+        self.line(format!("# {}", instruction.span.start));
+      }
+      match &instruction.kind {
+        InstructionKind::If {
+          condition,
+          instructions: body,
+        } => {
+          let label = self.label();
+          self.value(condition);
+          self.condition("fish_bad_if_condition", &format!(".Lelse.{}", label));
+          self.frame(body);
+          // An else only runs right after an if that did not, one anywhere else never runs
+          match instructions.get(index) {
+            Some(Instruction {
+              kind: InstructionKind::Else { instructions },
+              ..
+            }) => {
+              self.line(format!("jmp .Lend_if.{}", label));
+              self.place(format!(".Lelse.{}", label));
+              self.frame(instructions);
+              self.place(format!(".Lend_if.{}", label));
+              index += 1;
+            }
+            _ => self.place(format!(".Lelse.{}", label)),
+          }
+        }
+        InstructionKind::Else { .. } => (),
+        InstructionKind::While {
+          condition,
+          instructions,
+        } => {
+          let label = self.label();
+          self.place(format!(".Lloop.{}", label));
+          self.value(condition);
+          self.condition("fish_bad_while_condition", &format!(".Lloop_end.{}", label));
+          self.loops.push(label);
+          self.frame(instructions);
+          self.loops.pop();
+          self.line(format!("jmp .Lloop.{}", label));
+          self.place(format!(".Lloop_end.{}", label));
+        }
+        InstructionKind::Scope { instructions } => self.frame(instructions),
+        InstructionKind::Value { value } => self.value(value),
+        // Outside of a loop a break ends the script
+        InstructionKind::Break => match self.loops.last() {
+          Some(label) => self.line(format!("jmp .Lloop_end.{}", label)),
+          None => self.line("jmp .Lend"),
+        },
+        InstructionKind::Print { message } => {
+          self.value(message);
+          self.line("mov %rax, %rdi");
+          self.line("mov %rdx, %rsi");
+          self.line("call fish_print");
+        }
+        InstructionKind::Let {
+          variable, value, ..
+        } => {
+          self.value(value);
+          let slot = variable.slot.expect("Let was not resolved");
+          let variable = self.variable(slot, &variable.name);
+          self.store(&variable);
+        }
+        InstructionKind::Return { value } => {
+          if let Some(value) = value {
+            self.value(value);
+          }
+          self.line("jmp .Lend");
+        }
+        InstructionKind::Test { .. } => (),
+        _ => unreachable!("Unsupported instructions are turned down before generating"),
+      }
+    }
+  }
+
+  // Code that puts the value into the two registers, for values that need no others
+  fn load(&mut self, value: &Value, tag: &str, payload: &str) {
+    match value {
+      Value::Number(Number::Integer(integer)) => {
+        self.line(format!("mov $FISH_INTEGER, {}", tag));
+        self.line(format!("mov {}, {}", immediate(*integer as u64), payload));
+      }
+      Value::Number(Number::Float(float)) => {
+        self.line(format!("mov $FISH_FLOAT, {}", tag));
+        self.line(format!(
+          "mov {}, {} # {:?}",
+          immediate(float.to_bits()),
+          payload,
+          float
+        ));
+      }
+      Value::Boolean(boolean) => {
+        self.line(format!("mov $FISH_BOOLEAN, {}", tag));
+        self.line(format!("mov ${}, {}", *boolean as u8, payload));
+      }
+      Value::None => {
+        self.line(format!("mov $FISH_NONE, {}", tag));
+        self.line(format!("xor {}, {}", payload, payload));
+      }
+      Value::Identifier(identifier) => match identifier.slot {
+        Some(slot) => {
+          let variable = self.variable(slot, &identifier.name);
+          let not_defined = self.not_defined(&identifier.name);
+          self.line(format!("mov {}(%rip), {}", variable, tag));
+          self.line(format!("mov {}+8(%rip), {}", variable, payload));
+          self.line(format!("test {}, {}", tag, tag));
+          self.line(format!("jz {}", not_defined));
+        }
+        None => {
+          let not_defined = self.not_defined(&identifier.name);
+          self.line(format!("jmp {}", not_defined));
+        }
+      },
+      value => unreachable!("{:?} is not a simple value", value),
+    }
+  }
+
+  // Code that puts the tag of the value into %rax and its payload into %rdx, evaluating
+  // everything in the order the interpreter does
+  fn value(&mut self, value: &Value) {
+    match value {
+      Value::Expression(expression) => self.expression(expression),
+      value => self.load(value, "%rax", "%rdx"),
+    }
+  }
+
+  // Puts the left value into %rdi and %rsi and the right one into %rdx and %rcx
+  fn operands(&mut self, left: &Value, right: &Value) {
+    match (is_simple(left), is_simple(right)) {
+      (true, true) => {
+        self.load(left, "%rdi", "%rsi");
+        self.load(right, "%rdx", "%rcx");
+      }
+      (_, true) => {
+        self.value(left);
+        self.line("mov %rax, %rdi");
+        self.line("mov %rdx, %rsi");
+        self.load(right, "%rdx", "%rcx");
+      }
+      _ => {
+        self.value(left);
+        self.line("push %rdx");
+        self.line("push %rax");
+        self.value(right);
+        self.line("mov %rdx, %rcx");
+        self.line("mov %rax, %rdx");
+        self.line("pop %rdi");
+        self.line("pop %rsi");
+      }
+    }
+  }
+
+  // Skips to the runtime unless both operands are integers, which only needs checking for the
+  // ones that are not integer literals
+  fn integers(&mut self, left: &Value, right: &Value) {
+    if !is_integer(left) {
+      self.line("cmp $FISH_INTEGER, %rdi");
+      self.line("jne 1f");
+    }
+    if !is_integer(right) {
+      self.line("cmp $FISH_INTEGER, %rdx");
+      self.line("jne 1f");
+    }
+  }
+
+  fn expression(&mut self, expression: &Expression) {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    match operator {
+      Operator::Brackets => self.value(left),
+      Operator::Not => {
+        self.value(left);
+        self.line("mov %rax, %rdi");
+        self.line("mov %rdx, %rsi");
+        self.line("call fish_not");
+      }
+      operator if operator.is_assignment() => self.assignment(expression),
+      // The right side is only evaluated when the left is none
+      Operator::Coalesce => {
+        let label = self.label();
+        self.value(left);
+        self.line("cmp $FISH_NONE, %rax");
+        self.line(format!("jne .Lcoalesce.{}", label));
+        self.value(expression.get_right().expect("No right for coalesce"));
+        self.place(format!(".Lcoalesce.{}", label));
+      }
+      operator => {
+        let right = expression.get_right().expect("No right for operator");
+        self.operands(left, right);
+        self.operation(operator, left, right);
+      }
+    }
+  }
+
+  // The operator on the operands, see operands
+  fn operation(&mut self, operator: Operator, left: &Value, right: &Value) {
+    let comparison = comparison(operator);
+    let integer = integer_instruction(operator);
+    let fast = (comparison.is_some() || integer.is_some())
+      && !is_other_literal(left)
+      && !is_other_literal(right);
+    if fast {
+      self.integers(left, right);
+      match (comparison, integer) {
+        (Some((_, set)), _) => {
+          self.line("cmp %rcx, %rsi");
+          self.line(format!("{} %dl", set));
+          self.line("movzbl %dl, %edx");
+          self.line("mov $FISH_BOOLEAN, %eax");
+        }
+        (_, Some((instruction, overflow))) => {
+          self.line("mov %rsi, %rdx");
+          self.line(format!("{} %rcx, %rdx", instruction));
+          self.line(format!("jo {}", overflow));
+          self.line("mov $FISH_INTEGER, %eax");
+        }
+        _ => unreachable!(),
+      }
+      self.line("jmp 2f");
+      self.place("1".to_string());
+    }
+    match comparison {
+      Some((code, _)) => {
+        self.line(format!("mov ${}, %r8d", code));
+        self.line("call fish_compare");
+      }
+      None => self.line(format!("call {}", runtime_function(operator))),
+    }
+    if fast {
+      self.place("2".to_string());
+    }
+  }
+
+  // An assignment, which leaves the value in %rax and %rdx
+  fn assignment(&mut self, expression: &Expression) {
+    let operator = *expression.get_operator();
+    let left = expression.get_left();
+    let right = expression.get_right().expect("No right for assignment");
+    let (slot, name) = match left {
+      Value::Identifier(Identifier {
+        slot: Some(slot),
+        name,
+        ..
+      }) => (*slot, name),
+      Value::Identifier(identifier) => {
+        let not_defined = self.not_defined(&identifier.name);
+        return self.line(format!("jmp {}", not_defined));
+      }
+      _ => return self.line("jmp fish_bad_assignment"),
+    };
+    match operator {
+      Operator::Assign => self.value(right),
+      operator => {
+        self.operands(left, right);
+        self.operation(operator, left, right);
+      }
+    }
+    let variable = self.variable(slot, name);
+    self.store(&variable);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::samples;
+  use std::fs;
+
+  #[test]
+  #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+  #[cfg_attr(miri, ignore)]
+  fn programs_do_what_the_interpreter_does() {
+    let dir = samples::temp_dir("x86_64");
+    let mut built = 0;
+    let samples = samples::all().into_iter().chain(samples::edge_cases());
+    for (index, (name, code)) in samples.enumerate() {
+      let Some(expected) = samples::interpret(&code) else {
+        continue;
+      };
+      // Anything that is more than numbers gives an error instead
+      let Ok(assembly) = transpile(&samples::instructions(&code)) else {
+        continue;
+      };
+      let source = dir.join(format!("{}.s", index));
+      let program = dir.join(index.to_string());
+      fs::write(&source, assembly).unwrap();
+      let args = [
+        "-o".as_ref(),
+        program.as_os_str(),
+        source.as_os_str(),
+        "-lm".as_ref(),
+      ];
+      let Some(linked) = samples::run_program("cc", &args) else {
+        eprintln!("Skipping the x86_64 programs, there is no cc to assemble and link them");
+        return;
+      };
+      assert_eq!(
+        linked.code, 0,
+        "{} does not assemble: {}",
+        name, linked.error
+      );
+      let ended = samples::run_program(&program, &[]).unwrap();
+      assert_eq!(ended, expected, "{}", name);
+      built += 1;
+    }
+    fs::remove_dir_all(dir).unwrap();
+    assert!(built > 10, "Only {} samples could be built", built);
+  }
+
+  #[test]
+  fn unsupported_code_is_an_error_where_it_is() {
+    for (code, expected) in [
+      ("x = 1;\nprint(\"x\");", "2:1: Strings are"),
+      ("x = 1;\nx = [x];", "2:1: Lists are"),
+      ("x = 1;\nprint(args);", "2:7: Lists are"),
+      ("print(f(1));", "1:1: Function calls are"),
+      ("x = 1;\nprint(x.y);", "2:1: Fields and indexes are"),
+      ("input x;", "1:1: input is"),
+      ("fn f() { print(1); };", "1:1: Functions are"),
+      ("enum E { A };", "1:1: Enums are"),
+      ("x = 1;\nmatch x { _ => { print(x); } };", "2:1: match is"),
+      ("assert(1 == 1, \"x\");", "1:1: assert is"),
+      (
+        "x = true;\nwhile (x) {\n  if (x) {\n    input y;\n  };\n};",
+        "4:5: input is",
+      ),
+    ] {
+      let diagnostic = transpile(&samples::instructions(code)).unwrap_err();
+      assert_eq!(
+        diagnostic.to_string(),
+        format!(
+          "{} not supported by the x86_64 target, which only has numbers, booleans and none",
+          expected
+        ),
+        "{}",
+        code
+      );
+    }
+  }
+}
@@ -0,0 +1,522 @@
+# The runtime of a script built with --target x86_64, the code of the script comes after it.
+# Every value is a tag and a 64 bit payload, in a pair of registers or in 16 bytes of memory. The
+# operations take the left value in %rdi and %rsi and the right one in %rdx and %rcx, and return
+# the result in %rax and %rdx. They only need the C library, which does the output and floats.
+
+  .set FISH_UNASSIGNED, 0
+  .set FISH_NONE, 1
+  .set FISH_BOOLEAN, 2
+  .set FISH_INTEGER, 3
+  .set FISH_FLOAT, 4
+
+  .section .rodata
+.Lerror:
+  .asciz "Error interpreting code: "
+.Lnewline:
+  .asciz "\n"
+.Linteger:
+  .asciz "%ld\n"
+.Lexponent:
+  .asciz "%.*e"
+.Lnone:
+  .asciz "none"
+.Ltrue:
+  .asciz "true"
+.Lfalse:
+  .asciz "false"
+.Lundefined:
+  .asciz "Variable '%s' is not defined"
+.Lif_condition:
+  .asciz "Type mismatch: Expected boolean for if condition"
+.Lwhile_condition:
+  .asciz "Type mismatch: Expected boolean for while condition"
+.Lassignment:
+  .asciz "Type mismatch: Expected identifier on left side of assignment"
+.Ladd_types:
+  .asciz "Type mismatch: Expected 2 strings, 2 numbers or 2 lists when adding"
+.Lsubtract_types:
+  .asciz "Type mismatch: Expected 2 numbers when subtracting"
+.Lmultiply_types:
+  .asciz "Type mismatch: Expected 2 numbers when multiplying"
+.Ldivide_types:
+  .asciz "Type mismatch: Expected 2 numbers when dividing"
+.Lmodulo_types:
+  .asciz "Type mismatch: Expected 2 numbers when taking modulo"
+.Lpower_types:
+  .asciz "Type mismatch: Expected 2 numbers when taking exponent"
+.Lcompare_types:
+  .asciz "Type mismatch: Expected 2 numbers "
+.Lbooleans:
+  .asciz "Type mismatch: Expected 2 booleans "
+.Lboolean:
+  .asciz "Type mismatch: Expected 1 boolean "
+.Ladd_overflow:
+  .asciz "Integer overflow when adding"
+.Lsubtract_overflow:
+  .asciz "Integer overflow when subtracting"
+.Lmultiply_overflow:
+  .asciz "Integer overflow when multiplying"
+.Ldivide_overflow:
+  .asciz "Integer overflow when dividing"
+.Lmodulo_overflow:
+  .asciz "Integer overflow when taking modulo"
+.Ldivision_by_zero:
+  .asciz "Division by zero"
+
+  .bss
+# The text of the float being printed. The longest is 0. with 323 zeros and 17 digits
+.Lfloat_text:
+  .skip 400
+
+  .text
+
+# Fails with the message in %rdi, which is a printf format for the argument in %rsi. Like
+# everything that never returns it can be jumped to from anywhere, it aligns the stack itself
+fish_fail:
+  and $-16, %rsp
+  mov %rdi, %rbx
+  mov %rsi, %r12
+  xor %edi, %edi
+  call fflush@PLT
+  mov $2, %edi
+  lea .Lerror(%rip), %rsi
+  xor %eax, %eax
+  call dprintf@PLT
+  mov $2, %edi
+  mov %rbx, %rsi
+  mov %r12, %rdx
+  xor %eax, %eax
+  call dprintf@PLT
+  mov $2, %edi
+  lea .Lnewline(%rip), %rsi
+  xor %eax, %eax
+  call dprintf@PLT
+  mov $1, %edi
+  call exit@PLT
+
+# The name of the variable in %rdi
+fish_undefined_variable:
+  mov %rdi, %rsi
+  lea .Lundefined(%rip), %rdi
+  jmp fish_fail
+
+fish_bad_if_condition:
+  lea .Lif_condition(%rip), %rdi
+  jmp fish_fail
+
+fish_bad_while_condition:
+  lea .Lwhile_condition(%rip), %rdi
+  jmp fish_fail
+
+fish_bad_assignment:
+  lea .Lassignment(%rip), %rdi
+  jmp fish_fail
+
+fish_add_overflow:
+  lea .Ladd_overflow(%rip), %rdi
+  jmp fish_fail
+
+fish_subtract_overflow:
+  lea .Lsubtract_overflow(%rip), %rdi
+  jmp fish_fail
+
+fish_multiply_overflow:
+  lea .Lmultiply_overflow(%rip), %rdi
+  jmp fish_fail
+
+# Both values as floats in %xmm0 and %xmm1, or fails with the message in %r9 when one of them is
+# not a number
+fish_floats:
+  lea -FISH_INTEGER(%rdi), %rax
+  cmp $1, %rax
+  ja 3f
+  lea -FISH_INTEGER(%rdx), %rax
+  cmp $1, %rax
+  ja 3f
+  movq %rsi, %xmm0
+  cmp $FISH_INTEGER, %rdi
+  jne 1f
+  cvtsi2sd %rsi, %xmm0
+1:
+  movq %rcx, %xmm1
+  cmp $FISH_INTEGER, %rdx
+  jne 2f
+  cvtsi2sd %rcx, %xmm1
+2:
+  ret
+3:
+  mov %r9, %rdi
+  jmp fish_fail
+
+# Returns the float in %xmm0
+fish_float:
+  movq %xmm0, %rdx
+  mov $FISH_FLOAT, %eax
+  ret
+
+# The code of a script adds, subtracts and multiplies two integers itself and calls these for
+# everything else
+fish_add:
+  lea .Ladd_types(%rip), %r9
+  call fish_floats
+  addsd %xmm1, %xmm0
+  jmp fish_float
+
+fish_subtract:
+  lea .Lsubtract_types(%rip), %r9
+  call fish_floats
+  subsd %xmm1, %xmm0
+  jmp fish_float
+
+fish_multiply:
+  lea .Lmultiply_types(%rip), %r9
+  call fish_floats
+  mulsd %xmm1, %xmm0
+  jmp fish_float
+
+# Integer division rounds towards zero, like idiv
+fish_divide:
+  cmp $FISH_INTEGER, %rdi
+  jne 2f
+  cmp $FISH_INTEGER, %rdx
+  jne 2f
+  lea .Ldivision_by_zero(%rip), %rdi
+  test %rcx, %rcx
+  jz fish_fail
+  cmp $-1, %rcx
+  jne 1f
+  lea .Ldivide_overflow(%rip), %rdi
+  mov $0x8000000000000000, %rax
+  cmp %rax, %rsi
+  je fish_fail
+1:
+  mov %rsi, %rax
+  cqo
+  idiv %rcx
+  mov %rax, %rdx
+  mov $FISH_INTEGER, %eax
+  ret
+2:
+  lea .Ldivide_types(%rip), %r9
+  call fish_floats
+  divsd %xmm1, %xmm0
+  jmp fish_float
+
+fish_modulo:
+  cmp $FISH_INTEGER, %rdi
+  jne 2f
+  cmp $FISH_INTEGER, %rdx
+  jne 2f
+  lea .Ldivision_by_zero(%rip), %rdi
+  test %rcx, %rcx
+  jz fish_fail
+  cmp $-1, %rcx
+  jne 1f
+  lea .Lmodulo_overflow(%rip), %rdi
+  mov $0x8000000000000000, %rax
+  cmp %rax, %rsi
+  je fish_fail
+1:
+  mov %rsi, %rax
+  cqo
+  idiv %rcx
+  mov $FISH_INTEGER, %eax
+  ret
+2:
+  lea .Lmodulo_types(%rip), %r9
+  call fish_floats
+  push %rbp
+  mov %rsp, %rbp
+  and $-16, %rsp
+  call fmod@PLT
+  leave
+  jmp fish_float
+
+# Always a float, even for two integers
+fish_power:
+  lea .Lpower_types(%rip), %r9
+  call fish_floats
+  push %rbp
+  mov %rsp, %rbp
+  and $-16, %rsp
+  call pow@PLT
+  leave
+  jmp fish_float
+
+# 1 in %eax when the values are equal, integers and floats are equal when they are the same
+# number and other values of different types never are
+fish_equal:
+  lea -FISH_INTEGER(%rdi), %rax
+  cmp $1, %rax
+  ja 2f
+  lea -FISH_INTEGER(%rdx), %rax
+  cmp $1, %rax
+  ja 2f
+  cmp $FISH_INTEGER, %rdi
+  jne 1f
+  cmp $FISH_INTEGER, %rdx
+  jne 1f
+  xor %eax, %eax
+  cmp %rcx, %rsi
+  sete %al
+  ret
+1:
+  call fish_floats
+  ucomisd %xmm1, %xmm0
+  sete %al
+  setnp %dl
+  and %dl, %al
+  movzbl %al, %eax
+  ret
+2:
+  xor %eax, %eax
+  cmp %rdx, %rdi
+  jne 3f
+  cmp %rcx, %rsi
+  sete %al
+3:
+  ret
+
+# The comparison in %r8 is 0 for ==, 1 for !=, 2 for <, 3 for <=, 4 for > and 5 for >=. Only
+# numbers have an order, > and >= are < and <= the other way around
+fish_compare:
+  cmp $2, %r8
+  jae 1f
+  call fish_equal
+  xor %r8d, %eax
+  mov %rax, %rdx
+  mov $FISH_BOOLEAN, %eax
+  ret
+1:
+  cmp $4, %r8
+  jb 2f
+  xchg %rdi, %rdx
+  xchg %rsi, %rcx
+  sub $2, %r8
+2:
+  xor %eax, %eax
+  cmp $FISH_INTEGER, %rdi
+  jne 4f
+  cmp $FISH_INTEGER, %rdx
+  jne 4f
+  cmp $3, %r8
+  je 3f
+  cmp %rcx, %rsi
+  setl %al
+  jmp 6f
+3:
+  cmp %rcx, %rsi
+  setle %al
+  jmp 6f
+4:
+  lea .Lcompare_types(%rip), %r9
+  call fish_floats
+  xor %eax, %eax
+  cmp $3, %r8
+  je 5f
+  ucomisd %xmm0, %xmm1
+  seta %al
+  jmp 6f
+5:
+  ucomisd %xmm0, %xmm1
+  setae %al
+6:
+  mov %rax, %rdx
+  mov $FISH_BOOLEAN, %eax
+  ret
+
+# Both sides are evaluated, so && and || only need booleans
+fish_and:
+  cmp $FISH_BOOLEAN, %rdi
+  jne 1f
+  cmp $FISH_BOOLEAN, %rdx
+  jne 1f
+  mov %rsi, %rdx
+  and %rcx, %rdx
+  mov $FISH_BOOLEAN, %eax
+  ret
+1:
+  lea .Lbooleans(%rip), %rdi
+  jmp fish_fail
+
+fish_or:
+  cmp $FISH_BOOLEAN, %rdi
+  jne 1f
+  cmp $FISH_BOOLEAN, %rdx
+  jne 1f
+  mov %rsi, %rdx
+  or %rcx, %rdx
+  mov $FISH_BOOLEAN, %eax
+  ret
+1:
+  lea .Lbooleans(%rip), %rdi
+  jmp fish_fail
+
+fish_not:
+  cmp $FISH_BOOLEAN, %rdi
+  jne 1f
+  mov %rsi, %rdx
+  xor $1, %rdx
+  mov $FISH_BOOLEAN, %eax
+  ret
+1:
+  lea .Lboolean(%rip), %rdi
+  jmp fish_fail
+
+fish_print:
+  push %rbp
+  mov %rsp, %rbp
+  and $-16, %rsp
+  lea .Lnone(%rip), %rax
+  cmp $FISH_NONE, %rdi
+  je 1f
+  lea .Ltrue(%rip), %rax
+  lea .Lfalse(%rip), %rdx
+  test %rsi, %rsi
+  cmovz %rdx, %rax
+  cmp $FISH_BOOLEAN, %rdi
+  jne 2f
+1:
+  mov %rax, %rdi
+  call puts@PLT
+  leave
+  ret
+2:
+  cmp $FISH_INTEGER, %rdi
+  jne 3f
+  lea .Linteger(%rip), %rdi
+  xor %eax, %eax
+  call printf@PLT
+  leave
+  ret
+3:
+  movq %rsi, %xmm0
+  call fish_write_float
+  lea .Lfloat_text(%rip), %rdi
+  call puts@PLT
+  leave
+  ret
+
+# Writes the float in %xmm0 to .Lfloat_text like Rust writes it: the fewest digits that read
+# back as the same float, never with an exponent, and without a fraction when there is none
+fish_write_float:
+  push %rbp
+  mov %rsp, %rbp
+  push %rbx
+  push %r12
+  push %r13
+  push %r14
+  # The number at 0, the text printf writes at 16 and its digits at 56
+  sub $80, %rsp
+  lea .Lfloat_text(%rip), %r12
+  ucomisd %xmm0, %xmm0
+  jnp 1f
+  movl $0x004e614e, (%r12)
+  jmp 13f
+1:
+  movq %xmm0, %rax
+  test %rax, %rax
+  jns 2f
+  movb $'-', (%r12)
+  inc %r12
+2:
+  btr $63, %rax
+  test %rax, %rax
+  jnz 3f
+  movw $'0', (%r12)
+  jmp 13f
+3:
+  mov $0x7ff0000000000000, %rcx
+  cmp %rcx, %rax
+  jne 4f
+  movl $0x00666e69, (%r12)
+  jmp 13f
+4:
+  mov %rax, (%rsp)
+  mov $1, %ebx
+5:
+  lea 16(%rsp), %rdi
+  mov $40, %esi
+  lea .Lexponent(%rip), %rdx
+  lea -1(%rbx), %ecx
+  movsd (%rsp), %xmm0
+  mov $1, %eax
+  call snprintf@PLT
+  lea 16(%rsp), %rdi
+  xor %esi, %esi
+  call strtod@PLT
+  ucomisd (%rsp), %xmm0
+  je 6f
+  inc %ebx
+  cmp $17, %ebx
+  jbe 5b
+6:
+  lea 16(%rsp), %rsi
+  xor %r13d, %r13d
+7:
+  movzbl (%rsi), %eax
+  cmp $'e', %al
+  je 8f
+  inc %rsi
+  cmp $'.', %al
+  je 7b
+  mov %al, 56(%rsp,%r13)
+  inc %r13
+  jmp 7b
+8:
+  lea 1(%rsi), %rdi
+  call atoi@PLT
+  movslq %eax, %r14
+  inc %r14
+9:
+  cmp $1, %r13
+  jbe 10f
+  cmpb $'0', 55(%rsp,%r13)
+  jne 10f
+  dec %r13
+  jmp 9b
+10:
+  # The digits are in %r13 and the point goes after %r14 of them
+  mov %r12, %rdi
+  test %r14, %r14
+  jg 11f
+  movw $0x2e30, (%rdi)
+  add $2, %rdi
+  mov %r14, %rcx
+  neg %rcx
+  mov $'0', %al
+  rep stosb
+  lea 56(%rsp), %rsi
+  mov %r13, %rcx
+  rep movsb
+  jmp 12f
+11:
+  cmp %r13, %r14
+  jl 14f
+  lea 56(%rsp), %rsi
+  mov %r13, %rcx
+  rep movsb
+  mov %r14, %rcx
+  sub %r13, %rcx
+  mov $'0', %al
+  rep stosb
+  jmp 12f
+14:
+  lea 56(%rsp), %rsi
+  mov %r14, %rcx
+  rep movsb
+  movb $'.', (%rdi)
+  inc %rdi
+  mov %r13, %rcx
+  sub %r14, %rcx
+  rep movsb
+12:
+  movb $0, (%rdi)
+13:
+  lea -32(%rbp), %rsp
+  pop %r14
+  pop %r13
+  pop %r12
+  pop %rbx
+  pop %rbp
+  ret
@@ -0,0 +1,371 @@
+// Runs the fish-lang binary the way a user would, checking what the commands print and the exit
+// codes that tell what went wrong
+use std::{
+  env, fs,
+  io::Write,
+  path::{Path, PathBuf},
+  process::{self, Command, Stdio},
+};
+
+const SCRIPT: &str = "fn add(a, b) { return a + b; }\nprint(add(1, 2));\nprint(args);\n";
+
+#[derive(Debug)]
+struct Ran {
+  code: i32,
+  output: String,
+  error: String,
+}
+
+// A directory of its own for every test, with the files in it
+fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+  let dir = env::temp_dir().join(format!("fish-cli-{}-{}", name, process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).expect("Temporary directory can not be created");
+  for (file, contents) in files {
+    fs::write(dir.join(file), contents).unwrap();
+  }
+  dir
+}
+
+fn fish_with_input(dir: &Path, args: &[&str], input: &[u8]) -> Ran {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_fish-lang"))
+    .args(args)
+    .current_dir(dir)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("fish-lang can not be started");
+  child.stdin.take().unwrap().write_all(input).unwrap();
+  let output = child.wait_with_output().unwrap();
+  Ran {
+    code: output.status.code().expect("fish-lang was killed"),
+    output: String::from_utf8_lossy(&output.stdout).into_owned(),
+    error: String::from_utf8_lossy(&output.stderr).into_owned(),
+  }
+}
+
+fn fish(dir: &Path, args: &[&str]) -> Ran {
+  fish_with_input(dir, args, b"")
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn run_gives_the_script_its_arguments() {
+  let dir = temp_dir("run", &[("add.fsh", SCRIPT)]);
+  let ran = fish(&dir, &["run", "add.fsh", "a", "b"]);
+  assert_eq!(
+    (ran.code, ran.output.as_str(), ran.error.as_str()),
+    (0, "3\n[a, b]\n", "")
+  );
+  // Without a command the file is run
+  assert_eq!(fish(&dir, &["add.fsh", "c"]).output, "3\n[c]\n");
+  assert_eq!(fish(&dir, &["run", "-e", "print(2 * 3);"]).output, "6\n");
+  let ran = fish_with_input(&dir, &["run", "-", "d"], SCRIPT.as_bytes());
+  assert_eq!(ran.output, "3\n[d]\n");
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn exit_codes_tell_what_went_wrong() {
+  let dir = temp_dir(
+    "exit",
+    &[
+      ("runtime.fsh", "print(1);\nprint(1 / 0);\n"),
+      ("tokenizer.fsh", "print(\"a);\n"),
+      ("parser.fsh", "print(1;\n"),
+      ("types.fsh", "fn f(x: int) { return x; }\nf(\"a\");\n"),
+      ("exit.fsh", "print(1);\nexit(7);\n"),
+    ],
+  );
+  for (args, code, output, error) in [
+    (
+      &["run", "runtime.fsh"][..],
+      1,
+      "1\n",
+      "Error interpreting code: Division by zero\n",
+    ),
+    (
+      &["run", "missing.fsh"],
+      2,
+      "",
+      "Error reading 'missing.fsh'",
+    ),
+    (
+      &["run", "--no-such-option", "runtime.fsh"],
+      2,
+      "",
+      "Usage: ",
+    ),
+    (
+      &["run", "--max-steps", "abc", "runtime.fsh"],
+      2,
+      "",
+      "Error: 'abc' is not a valid value for --max-steps\n",
+    ),
+    (
+      &["run", "tokenizer.fsh"],
+      3,
+      "",
+      "Error tokenizing code at tokenizer.fsh:1:7: Unterminated string",
+    ),
+    (
+      &["run", "parser.fsh"],
+      4,
+      "",
+      "Error parsing code at parser.fsh:1:8: ",
+    ),
+    (
+      &["run", "--check-types", "types.fsh"],
+      5,
+      "",
+      "Type error at types.fsh:2:1: Argument 'x' of 'f' has to be int, found string\n",
+    ),
+    (&["run", "exit.fsh"], 7, "1\n", ""),
+    (
+      &["run", "-e", "exit(300);"],
+      1,
+      "",
+      "Error interpreting code: Type mismatch: Exit code 300 is not between 0 and 255\n",
+    ),
+  ] {
+    let ran = fish(&dir, args);
+    assert_eq!(ran.code, code, "{:?} ended with {:?}", args, ran);
+    assert_eq!(ran.output, output, "{:?}", args);
+    assert!(
+      ran.error.starts_with(error),
+      "{:?} ended with {:?}",
+      args,
+      ran
+    );
+  }
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn input_that_is_not_text_is_an_error() {
+  let dir = temp_dir("input", &[]);
+  let code = "input line; print(line); input line; print(line);";
+  let ran = fish_with_input(&dir, &["run", "-e", code], b"fish\n");
+  assert_eq!((ran.code, ran.output.as_str()), (0, "fish\nnone\n"));
+  let ran = fish_with_input(&dir, &["run", "-e", code], b"\xff\n");
+  assert_eq!(ran.code, 1);
+  assert_eq!(
+    ran.error,
+    "Error interpreting code: Could not read input: stream did not contain valid UTF-8\n"
+  );
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn a_closed_output_ends_the_script_quietly() {
+  let dir = temp_dir("closed", &[]);
+  let mut child = Command::new(env!("CARGO_BIN_EXE_fish-lang"))
+    .args(["run", "-e", "while (true) { print(1); }"])
+    .current_dir(&dir)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("fish-lang can not be started");
+  drop(child.stdout.take());
+  let output = child.wait_with_output().unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn check_finds_errors_without_running() {
+  let dir = temp_dir(
+    "check",
+    &[
+      ("add.fsh", SCRIPT),
+      ("runtime.fsh", "print(1 / 0);\n"),
+      ("types.fsh", "let x: float = 1;\n"),
+    ],
+  );
+  let ran = fish(&dir, &["check", "add.fsh", "runtime.fsh"]);
+  assert_eq!(
+    (ran.code, ran.output.as_str(), ran.error.as_str()),
+    (0, "", "")
+  );
+  let ran = fish(&dir, &["check", "add.fsh", "types.fsh"]);
+  assert_eq!(ran.code, 5);
+  assert_eq!(
+    ran.error,
+    "Type error at types.fsh:1:1: Variable 'x' has to be float, found int\n"
+  );
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn lint_warns_and_fails_on_warnings() {
+  let dir = temp_dir(
+    "lint",
+    &[
+      ("add.fsh", SCRIPT),
+      ("unused.fsh", "x = 1;\nprint(y);\ny = 2;\nprint(y);\n"),
+    ],
+  );
+  let ran = fish(&dir, &["lint", "add.fsh"]);
+  assert_eq!((ran.code, ran.output.as_str()), (0, ""));
+  let ran = fish(&dir, &["lint", "unused.fsh"]);
+  assert_eq!(ran.code, 1);
+  assert_eq!(
+    ran.output,
+    "Warning at unused.fsh:1:1: Variable 'x' is assigned but never used [unused-variable]\n\
+     Warning at unused.fsh:2:7: Variable 'y' is used before it is assigned \
+     [use-before-assignment]\n"
+  );
+  let ran = fish(&dir, &["lint", "missing.fsh"]);
+  assert_eq!(ran.code, 2);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn test_fails_when_a_test_fails() {
+  let tests = "fn add(a, b) { return a + b; }\n\
+               test \"adds\" { assert(add(1, 2) == 3); }\n\
+               test \"fails\" { assert(add(1, 1) == 3); }\n";
+  let dir = temp_dir("test", &[("add.fsh", tests)]);
+  let ran = fish(&dir, &["test"]);
+  assert_eq!(ran.code, 1);
+  assert!(
+    ran.output.starts_with("test add.fsh: adds ... ok\n"),
+    "{}",
+    ran.output
+  );
+  assert!(
+    ran.output.ends_with("1 passed, 1 failed, 0 errors\n"),
+    "{}",
+    ran.output
+  );
+  let ran = fish(&dir, &["test", "--filter", "adds"]);
+  assert_eq!(ran.code, 0);
+  assert!(
+    ran.output.ends_with("1 passed, 0 failed, 0 errors\n"),
+    "{}",
+    ran.output
+  );
+  let ran = fish(&dir, &["test", "--format", "xml"]);
+  assert_eq!(ran.code, 2);
+  assert!(ran
+    .error
+    .starts_with("Error: 'xml' is not a valid value for --format\n"));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn tokens_and_ast_show_how_a_script_is_read() {
+  let dir = temp_dir("syntax", &[("add.fsh", SCRIPT)]);
+  let ran = fish(&dir, &["tokens", "add.fsh"]);
+  assert_eq!(ran.code, 0);
+  assert!(ran
+    .output
+    .starts_with("1:1-1:3 Keyword(Fn)\n1:4-1:7 Identifier(\"add\")\n1:7-1:8 BracketOpen\n"));
+  let ran = fish(&dir, &["tokens", "--json", "add.fsh"]);
+  let tokens: serde_json::Value = serde_json::from_str(&ran.output).unwrap();
+  assert_eq!(
+    tokens["tokens"].as_array().map(Vec::len),
+    Some(30),
+    "{}",
+    ran.output
+  );
+  let ran = fish(&dir, &["ast", "add.fsh"]);
+  assert_eq!(ran.code, 0);
+  assert!(ran.output.contains("name: \"add\""), "{}", ran.output);
+  // The JSON runs like the script it came from
+  let ran = fish(&dir, &["ast", "--json", "add.fsh"]);
+  fs::write(dir.join("add.json"), ran.output).unwrap();
+  assert_eq!(fish(&dir, &["run", "--ast", "add.json"]).output, "3\n[]\n");
+  // JSON that is cut off, or is more than the AST, is a parser error and nothing runs
+  for (json, error) in [
+    (
+      "{\"version\": 1, \"kind\"",
+      "EOF while parsing an object at line 1 column 21",
+    ),
+    ("{} {}", "There is more than one JSON value"),
+    ("", "There is no JSON"),
+  ] {
+    fs::write(dir.join("broken.json"), json).unwrap();
+    let ran = fish(&dir, &["run", "--ast", "broken.json"]);
+    assert_eq!(ran.code, 4);
+    assert_eq!(ran.output, "");
+    assert_eq!(
+      ran.error,
+      format!("Error loading the AST at broken.json: {}\n", error)
+    );
+  }
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn compiled_programs_run_like_the_script() {
+  let dir = temp_dir(
+    "compile",
+    &[
+      ("add.fsh", SCRIPT),
+      ("count.fsh", "FSHCount = 5;\nprint(FSHCount);\n"),
+    ],
+  );
+  assert_eq!(fish(&dir, &["compile", "add.fsh"]).code, 0);
+  assert_eq!(fish(&dir, &["run", "add.fshc", "a"]).output, "3\n[a]\n");
+  assert_eq!(
+    fish(&dir, &["compile", "-o", "other.fshc", "add.fsh"]).code,
+    0
+  );
+  assert_eq!(fish(&dir, &["other.fshc"]).output, "3\n[]\n");
+  // Code that starts like the old magic bytes is still code
+  let ran = fish(&dir, &["run", "count.fsh"]);
+  assert_eq!((ran.code, ran.output.as_str()), (0, "5\n"));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn build_writes_a_program_for_the_target() {
+  let code = "x = 2;\nprint(x * 3);\n";
+  let dir = temp_dir("build", &[("six.fsh", code), ("add.fsh", SCRIPT)]);
+  for (target, file, start) in [
+    ("c", "six.c", "/* Compiled from a fish script"),
+    ("js", "six.js", "// Compiled from a fish script"),
+    ("wat", "six.wat", ";; Compiled from a fish script"),
+    ("wasm", "six.wasm", "\0asm"),
+    ("x86_64", "six.s", "# Compiled from a fish script"),
+  ] {
+    let ran = fish(&dir, &["build", "--target", target, "six.fsh"]);
+    assert_eq!((ran.code, ran.error.as_str()), (0, ""), "{}", target);
+    let built = fs::read(dir.join(file)).unwrap();
+    assert!(
+      built.starts_with(start.as_bytes()),
+      "{} starts differently",
+      file
+    );
+  }
+  let ran = fish(
+    &dir,
+    &["build", "--target", "wasm", "-o", "add.wasm", "add.fsh"],
+  );
+  assert_eq!(ran.code, 2);
+  assert_eq!(
+    ran.error,
+    "Error building code at add.fsh:3:7: The list args is not supported by the wasm target yet\n"
+  );
+  let ran = fish(&dir, &["build", "--target", "go", "six.fsh"]);
+  assert_eq!(ran.code, 2);
+  assert!(
+    ran.error.ends_with("Unknown target 'go'\n"),
+    "{}",
+    ran.error
+  );
+  fs::remove_dir_all(dir).unwrap();
+}